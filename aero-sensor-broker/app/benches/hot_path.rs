@@ -0,0 +1,125 @@
+// hot_path.rs
+//
+// The work done for every frame on the gateway: parsing a representative 20-item JSON frame,
+// and averaging a window of 600 points. Run with `cargo bench --bench hot_path`; criterion
+// compares every run with the previous one, so a regression shows up as such.
+
+use aero_sensor_broker::clock::SystemClock;
+use aero_sensor_broker::clock_skew::ClockSkewCorrector;
+use aero_sensor_broker::config::{AggregationConfig, ParserConfig};
+use aero_sensor_broker::data_manipulation::{
+    parse_sensor_data, Aggregator, MyDataPoint, Protocol, Reading,
+};
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::hint::black_box;
+use std::sync::Arc;
+
+const MEASUREMENTS: [&str; 10] = [
+    "temperature",
+    "humidity",
+    "pressure",
+    "air_quality",
+    "co2",
+    "voc",
+    "light",
+    "noise",
+    "wind_speed",
+    "rain",
+];
+
+// The tags of a source as the read loop passes them, global tags included.
+fn source_tags() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("source".to_string(), "intake".to_string()),
+        ("location".to_string(), "hangar-2".to_string()),
+        ("site".to_string(), "eu-south-1".to_string()),
+        ("device_id".to_string(), "board-7f3a".to_string()),
+    ])
+}
+
+// 20 protocol 3 items: a scalar and an object per measurement, timestamped by the device.
+fn frame() -> String {
+    let now = Utc::now().timestamp() as f64;
+    let items: Vec<Value> = MEASUREMENTS
+        .iter()
+        .enumerate()
+        .flat_map(|(index, measurement)| {
+            let value = 20.0 + index as f64 * 1.25;
+            [
+                json!({"sensor": measurement, "value": value, "seq": 4242, "timestamp": now}),
+                json!({
+                    "sensor": format!("{}_detail", measurement),
+                    "value": {"min": value - 0.5, "max": value + 0.5, "raw": {"adc": 512}},
+                    "seq": 4242,
+                    "timestamp": now,
+                }),
+            ]
+        })
+        .collect();
+    Value::Array(items).to_string()
+}
+
+// A window of 600 samples: 10 measurements of 2 devices, one sample a second for 30 seconds.
+fn window() -> Vec<MyDataPoint> {
+    let start = Utc::now().timestamp_nanos_opt().unwrap();
+    let devices = ["board-7f3a", "board-91c4"].map(|device| {
+        let mut tags = source_tags();
+        tags.insert("device_id".to_string(), device.to_string());
+        Arc::new(tags)
+    });
+    (0..30)
+        .flat_map(|second| {
+            let devices = &devices;
+            MEASUREMENTS.iter().flat_map(move |measurement| {
+                devices.iter().map(move |tags| {
+                    MyDataPoint::from_reading(
+                        measurement.to_string(),
+                        Arc::clone(tags),
+                        Reading::Value(20.0 + second as f64 * 0.1),
+                        start + second * 1_000_000_000,
+                    )
+                })
+            })
+        })
+        .collect()
+}
+
+fn parse(c: &mut Criterion) {
+    let frame = frame();
+    let tags = source_tags();
+    let config = ParserConfig::default();
+    let mut skew = ClockSkewCorrector::new(&config);
+    c.bench_function("parse_20_item_frame", |b| {
+        b.iter(|| {
+            let points = parse_sensor_data(
+                black_box(frame.clone()),
+                &tags,
+                &config,
+                Some(Protocol::V3),
+                &mut skew,
+                &SystemClock,
+            );
+            black_box(points.unwrap())
+        })
+    });
+}
+
+fn aggregate(c: &mut Criterion) {
+    let config: AggregationConfig = serde_json::from_value(json!({})).unwrap();
+    let mut aggregator = Aggregator::new(&config);
+    let window = window();
+    c.bench_function("aggregate_600_points", |b| {
+        b.iter_batched(
+            || window.clone(),
+            |points| black_box(aggregator.aggregate(points)),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, parse, aggregate);
+criterion_main!(benches);
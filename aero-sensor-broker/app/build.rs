@@ -0,0 +1,61 @@
+// build.rs
+//
+// Captures the build information served on `/version`: the git commit, the build time, and the
+// rustc version. Image builds have no `.git` directory, so the commit can be passed in the
+// `GIT_COMMIT` environment variable; `SOURCE_DATE_EPOCH` pins the build time for reproducible
+// builds.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=AERO_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=AERO_BUILD_TIMESTAMP={}", built_at);
+    println!("cargo:rustc-env=AERO_RUSTC_VERSION={}", rustc_version);
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+    if let Some(git_dir) = command_output("git", &["rev-parse", "--git-dir"]) {
+        let head = Path::new(&git_dir).join("HEAD");
+        if head.exists() {
+            println!("cargo:rerun-if-changed={}", head.display());
+        }
+        let refs = Path::new(&git_dir).join("refs");
+        if refs.exists() {
+            println!("cargo:rerun-if-changed={}", refs.display());
+        }
+    }
+}
+
+// Trimmed standard output of a successful command.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string()).filter(|stdout| !stdout.is_empty())
+}
@@ -0,0 +1,129 @@
+// access_log.rs
+//
+// Wraps the HTTP routes to log one line per request through the `log` facade: request id,
+// method, path, status, latency, and remote address. Every response carries the request id in
+// `x-request-id`; an id sent by the client or a load balancer is kept, otherwise one is
+// generated. Failed requests are logged as warnings (4xx) or errors (5xx), so the id of a
+// failing request can be looked up in the logs. Handlers that log errors of their own take the
+// id from `request_id` and return it with `with_request_id`, so that their entries and the
+// access log entry share it.
+
+use log::{log, Level};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use warp::http::header::{HeaderMap, HeaderValue};
+use warp::http::Method;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Paths of the probes, silenced while they succeed unless `log_probes` is set.
+const PROBE_PATHS: [&str; 3] = ["/livez", "/readyz", "/healthz"];
+
+#[derive(Clone, Copy)]
+pub struct AccessLog {
+    pub enabled: bool,
+    pub log_probes: bool,
+}
+
+impl AccessLog {
+    pub fn wrap<F, R>(
+        self,
+        routes: F,
+    ) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone + Send + Sync + 'static
+    where
+        F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+        R: Reply,
+    {
+        warp::any()
+            .map(Instant::now)
+            .and(warp::method())
+            .and(warp::path::full())
+            .and(warp::addr::remote())
+            .and(warp::header::headers_cloned())
+            .and(routes)
+            .map(
+                move |started: Instant,
+                      method: Method,
+                      path: FullPath,
+                      remote: Option<SocketAddr>,
+                      headers: HeaderMap,
+                      reply: R| {
+                    let mut response = reply.into_response();
+                    // The handler may have generated the id already
+                    let request_id = header_value(response.headers())
+                        .or_else(|| header_value(&headers))
+                        .map_or_else(next_request_id, str::to_string);
+                    let status = response.status();
+
+                    let level = if status.is_server_error() {
+                        Level::Error
+                    } else if status.is_client_error() {
+                        Level::Warn
+                    } else {
+                        Level::Info
+                    };
+                    let probe = PROBE_PATHS.contains(&path.as_str());
+                    if self.enabled && (level != Level::Info || !probe || self.log_probes) {
+                        log!(
+                            level,
+                            "request_id={} method={} path={} status={} latency_ms={:.1} remote={}",
+                            request_id,
+                            method,
+                            path.as_str(),
+                            status.as_u16(),
+                            started.elapsed().as_secs_f64() * 1000.0,
+                            remote.map_or_else(|| "-".to_string(), |addr| addr.to_string()),
+                        );
+                    }
+
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                    }
+                    response
+                },
+            )
+    }
+}
+
+// The id of the request, for handlers logging with it: the one sent with the request, or a new
+// one, which the handler returns with `with_request_id`.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        header_value(&headers).map_or_else(next_request_id, str::to_string)
+    })
+}
+
+// The reply, carrying the request id in `x-request-id`.
+pub fn with_request_id(reply: impl Reply, request_id: &str) -> Response {
+    let mut response = reply.into_response();
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+fn header_value(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+// Process-unique ids: the start time of the process followed by a request counter.
+fn next_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static STARTED: OnceLock<u64> = OnceLock::new();
+
+    let started = STARTED.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs())
+    });
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:06x}", started, count)
+}
@@ -3,52 +3,376 @@
 // Manages the interaction with Arduino devices. It handles serial communication to read sensor data
 // from an Arduino and validates the data's format. This module is critical for ensuring data integrity
 // before it is forwarded to the database.
+//
+// Once opened, the port is owned by a single reader task, on a blocking thread since serial I/O
+// blocks. It splits what the device sends into lines and writes the queued commands one at a
+// time; the line answering the pending command goes back to its sender, every other line goes on
+// to `read_data`, so that commands and the read loop never compete for the port. The reader never
+// waits for `read_data`: a line that does not fit in its queue is dropped and counted, so that
+// commands are still answered while nothing takes the frames.
+//
+// The port is read `read_chunk_bytes` at a time into a buffer reused for every read, whatever
+// piled up in the meantime. A line growing beyond `max_frame_bytes` is discarded up to its end,
+// so a firmware streaming garbage without newlines cannot make the reader task grow without
+// bound, and an input buffer holding more than `max_input_bytes` is cleared unread.
+//
+// Every time the port is opened, the device is asked which frame protocol it speaks with
+// `PROTOCOL?`, answered by `PROTO 1`, `PROTO 2` or `PROTO 3`. Firmware older than the question
+// does not answer it, or not that way, and speaks protocol 1.
 
+use crate::clock::{self, Clock};
 use crate::config::ArduinoConfig;
+use crate::data_manipulation::Protocol;
+use crate::errors::AppError;
+use crate::frame_log::FrameLog;
+use crate::health_cache::CachedHealth;
+use crate::metrics::{Metrics, SourceMetrics};
+use crate::source::SensorSource;
 
-use serialport::{available_ports, SerialPort, SerialPortType};
-use std::error::Error;
-use std::io::{BufRead, BufReader, Write};
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use async_trait::async_trait;
+use serde::Serialize;
+use serialport::{available_ports, ClearBuffer, SerialPort, SerialPortType};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, PoisonError};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
 
 use log::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub struct ArduinoManager {
-    pub port: Arc<Mutex<Box<dyn SerialPort + Send>>>,
+    // The reader task owning the port; `None` until `connect` opened the port, and once
+    // `shutdown` closed it.
+    link: Arc<std::sync::Mutex<Option<Link>>>,
+    // The lines the reader tasks pass on to `read_data`, whichever port they read.
+    lines_sender: mpsc::Sender<Result<String, AppError>>,
+    lines: Arc<Mutex<mpsc::Receiver<Result<String, AppError>>>>,
+    // Name of the port currently open, which may change when the device is reconnected.
+    port_name: Arc<std::sync::Mutex<String>>,
+    name: String,
+    config: Arc<ArduinoConfig>,
+    // Unix time in milliseconds of the last valid frame, 0 before the first one.
+    last_frame_ms: Arc<AtomicU64>,
+    // Number of the protocol negotiated with the device, 0 until it was.
+    protocol: Arc<AtomicU8>,
+    health: CachedHealth,
+    metrics: Arc<Metrics>,
+    source_metrics: Arc<SourceMetrics>,
+    // Every line read from the port, valid frame or not, for the `/ws/device` sessions.
+    raw_lines: broadcast::Sender<String>,
+    frames: FrameLog,
+    clock: Arc<dyn Clock>,
+}
+
+// How the answer to a command is told from the frames the device keeps sending meanwhile.
+#[derive(Clone, Debug)]
+pub enum ResponseMatcher {
+    // The first line starting with the prefix, e.g. "PONG" or "VERSION ".
+    Prefix(String),
+    // The next line that is not a valid frame.
+    NextNonData,
+}
+
+impl ResponseMatcher {
+    fn matches(&self, line: &str) -> bool {
+        match self {
+            ResponseMatcher::Prefix(prefix) => line.starts_with(prefix.as_str()),
+            ResponseMatcher::NextNonData => !is_valid_frame(line),
+        }
+    }
+}
+
+// A command queued for the reader task.
+struct Command {
+    line: String,
+    // `None` when no answer is expected: the command is done once written.
+    expect: Option<ResponseMatcher>,
+    reply: oneshot::Sender<Result<String, String>>,
+}
+
+// The reader task of the open port, and its command queue.
+struct Link {
+    commands: mpsc::Sender<Command>,
+    reader: JoinHandle<()>,
+    // Set to stop the reader task, which cannot be aborted.
+    stop: Arc<AtomicBool>,
 }
 
+// Raw lines buffered per subscriber before the oldest are dropped.
+const RAW_LINES_CAPACITY: usize = 256;
+
+// Lines read but not yet taken by `read_data`; the reader task drops the lines beyond that.
+const LINES_CAPACITY: usize = 256;
+
+// Commands waiting for the one being answered.
+const COMMANDS_CAPACITY: usize = 16;
+
+// How often the reader task looks for bytes to read and commands to write when idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
 impl ArduinoManager {
-    // Attempts to connect to an Arduino device based on configuration settings. It will validate
-    // the connection by matching the configured product name with available serial ports.
-    pub fn new(config: &ArduinoConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let port = find_and_validate_arduino(&config)?;
+    // Prepares the client of an Arduino device based on configuration settings; the port is only
+    // opened by `connect`, so that a missing device does not prevent the broker from starting.
+    // Frames are counted for the source `name` as well as in total.
+    pub fn new(
+        name: &str,
+        config: &ArduinoConfig,
+        metrics: Arc<Metrics>,
+        frames: FrameLog,
+    ) -> Self {
+        let (lines_sender, lines) = mpsc::channel(LINES_CAPACITY);
+        Self {
+            link: Arc::new(std::sync::Mutex::new(None)),
+            lines_sender,
+            lines: Arc::new(Mutex::new(lines)),
+            port_name: Arc::new(std::sync::Mutex::new(String::new())),
+            name: name.to_string(),
+            config: Arc::new(config.clone()),
+            last_frame_ms: Arc::new(AtomicU64::new(0)),
+            protocol: Arc::new(AtomicU8::new(0)),
+            health: CachedHealth::new(
+                Duration::from_secs(config.health_cache_ttl_secs),
+                Duration::from_millis(config.health_first_check_wait_ms),
+            ),
+            source_metrics: metrics.source(name),
+            metrics,
+            raw_lines: broadcast::channel(RAW_LINES_CAPACITY).0,
+            frames,
+            clock: clock::system(),
+        }
+    }
+
+    // Ages the frames and the health checks by `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.health = self.health.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    // Sends a command and waits for the line answering it, told from the frames by `expect`;
+    // the frames received meanwhile still go to `read_data`. Commands are sent one at a time in
+    // the order they were queued, and `timeout` includes the wait for the previous ones.
+    pub async fn send_command(
+        &self,
+        command: &str,
+        expect: ResponseMatcher,
+        timeout: Duration,
+    ) -> Result<String, AppError> {
+        self.exchange(command, Some(expect), timeout).await
+    }
+
+    async fn exchange(
+        &self,
+        command: &str,
+        expect: Option<ResponseMatcher>,
+        deadline: Duration,
+    ) -> Result<String, AppError> {
+        let closed = || AppError::Device("serial port not open".to_string());
+        let commands = self
+            .link
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(|link| link.commands.clone())
+            .ok_or_else(closed)?;
+        let (reply, answer) = oneshot::channel();
+        let command = Command {
+            line: command.trim_end().to_string(),
+            expect,
+            reply,
+        };
+        let line = command.line.clone();
+        let exchange = async move {
+            commands.send(command).await.map_err(|_| closed())?;
+            answer
+                .await
+                .map_err(|_| closed())?
+                .map_err(AppError::Device)
+        };
+        match timeout(deadline, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(AppError::Device(format!(
+                "no answer to '{}' within {:?}",
+                line, deadline
+            ))),
+        }
+    }
+
+    // Finds the device by matching the configured product name with the available serial
+    // ports, and replaces the port with a newly opened one. Returns the name of the port.
+    async fn open(&self) -> Result<String, AppError> {
+        self.close().await;
+        let new_port = find_and_validate_arduino(&self.config)?;
+        let port_name = new_port.name().unwrap_or_default();
+
+        // Whatever the previous port sent and `read_data` did not take is stale now
+        if let Ok(mut lines) = self.lines.try_lock() {
+            while lines.try_recv().is_ok() {}
+        }
+        let (commands, queue) = mpsc::channel(COMMANDS_CAPACITY);
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (lines, raw_lines) = (self.lines_sender.clone(), self.raw_lines.clone());
+            let (config, metrics, stop) = (
+                self.config.clone(),
+                self.source_metrics.clone(),
+                stop.clone(),
+            );
+            tokio::task::spawn_blocking(move || {
+                read_port(new_port, queue, lines, raw_lines, config, metrics, &stop)
+            })
+        };
+        *self.link.lock().unwrap_or_else(PoisonError::into_inner) = Some(Link {
+            commands,
+            reader,
+            stop,
+        });
+        *self
+            .port_name
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = port_name.clone();
+        Ok(port_name)
+    }
+
+    // Stops the reader task, which closes the port. Commands in progress fail. Returns whether a
+    // port was open.
+    async fn close(&self) -> bool {
+        let link = self
+            .link
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let Some(link) = link else {
+            return false;
+        };
+        link.stop.store(true, Ordering::Relaxed);
+        // The port is closed once the task is gone, at most a poll interval or a read later
+        let _ = link.reader.await;
+        true
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        let timeout = Duration::from_millis(self.config.timeout);
+        match self
+            .send_command("PING", ResponseMatcher::NextNonData, timeout)
+            .await?
+            .as_str()
+        {
+            "PONG" => {
+                debug!("Health check successful");
+                Ok(())
+            }
+            answer => {
+                error!("Health check failed");
+                Err(AppError::Device(format!(
+                    "health check failed: expected PONG, got '{}'",
+                    answer
+                )))
+            }
+        }
+    }
+
+    // Asks the device which protocol it speaks, protocol 1 when it does not tell.
+    async fn negotiate_protocol(&self) -> Protocol {
+        let timeout = Duration::from_millis(self.config.timeout);
+        let protocol = match self
+            .send_command("PROTOCOL?", ResponseMatcher::NextNonData, timeout)
+            .await
+        {
+            Ok(answer) => Protocol::from_answer(&answer).unwrap_or_else(|| {
+                warn!(
+                    "Source {} answered '{}' to PROTOCOL?, assuming protocol 1",
+                    self.name, answer
+                );
+                Protocol::V1
+            }),
+            Err(e) => {
+                info!(
+                    "Source {} did not tell its protocol, assuming protocol 1: {}",
+                    self.name, e
+                );
+                Protocol::V1
+            }
+        };
+        self.protocol.store(protocol.number(), Ordering::Relaxed);
+        protocol
+    }
+
+    fn count_frame(&self, valid: bool) {
+        self.metrics.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.source_metrics
+            .frames_received
+            .fetch_add(1, Ordering::Relaxed);
+        if !valid {
+            self.metrics.frames_invalid.fetch_add(1, Ordering::Relaxed);
+            self.source_metrics
+                .frames_invalid
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait]
+impl SensorSource for ArduinoManager {
+    async fn connect(&self) -> Result<(), AppError> {
+        let port_name = self.open().await?;
+        let protocol = self.negotiate_protocol().await;
         info!(
-            "New Arduino serial client created for port: {}",
-            port.name().unwrap()
+            "New Arduino serial client created for source {} on port: {}, protocol {}",
+            self.name, port_name, protocol
         );
-        Ok(Self {
-            port: Arc::new(Mutex::new(port)),
-        })
+        Ok(())
+    }
+
+    // Finds the device again and replaces the port with a newly opened one, e.g. after the
+    // device was unplugged or reset. Fails when the device is not back yet.
+    async fn reconnect(&self) -> Result<(), AppError> {
+        let port_name = self.open().await?;
+        let protocol = self.negotiate_protocol().await;
+        info!(
+            "Source {} reconnected on port: {}, protocol {}",
+            self.name, port_name, protocol
+        );
+        self.metrics
+            .serial_reconnects
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
-    // Reads data from the Arduino. This function continuously checks for new data,
-    // validates its format, and returns the data if it's correctly formatted.
-    pub async fn read_data(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+    // Reads data from the Arduino: waits for the next line that is not the answer to a command,
+    // and returns it if it's correctly formatted. Lines that are not are counted and skipped.
+    async fn read_data(&self) -> Result<String, AppError> {
+        if self
+            .link
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_none()
+        {
+            return Err(AppError::Device("serial port not open".to_string()));
+        }
+        let mut lines = self.lines.lock().await;
         loop {
-            match self.try_read_data().await {
-                Ok(Some(data_string)) if self.is_valid_data(&data_string) => {
+            // The manager keeps a sender, so the channel never closes
+            let Some(line) = lines.recv().await else {
+                return Err(AppError::Device("serial port not open".to_string()));
+            };
+            match line {
+                Ok(data_string) if is_valid_frame(&data_string) => {
                     debug!("Received valid data: '{}'", data_string);
+                    self.count_frame(true);
+                    self.frames.record(&data_string, true);
+                    self.last_frame_ms.store(
+                        self.clock.now_utc().timestamp_millis() as u64,
+                        Ordering::Relaxed,
+                    );
                     return Ok(data_string);
                 }
-                Ok(Some(data_string)) => {
+                Ok(data_string) => {
                     warn!("Invalid data format: '{}'", data_string);
-                }
-                Ok(None) => {
-                    debug!("No data available; will check again after delay.");
-                    sleep(Duration::from_millis(1000)).await;
+                    self.count_frame(false);
+                    self.frames.record(&data_string, false);
                 }
                 Err(e) => {
                     error!("Error reading data: {}", e);
@@ -58,87 +382,366 @@ impl ArduinoManager {
         }
     }
 
-    async fn try_read_data(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
-        let mut port = self.port.lock().await;
-        match port.bytes_to_read() {
-            Ok(available_bytes) if available_bytes > 0 => {
-                let mut buffer = vec![0; available_bytes as usize];
-                port.read_exact(&mut buffer)?;
-                let data_string = String::from_utf8(buffer)?.trim().to_string();
-                Ok(Some(data_string))
+    // Writes one command line to the Arduino, after the commands queued before it; the answers
+    // arrive as raw lines.
+    async fn write_command(&self, command: &str) -> Result<(), AppError> {
+        self.exchange(command, None, Duration::from_millis(self.config.timeout))
+            .await
+            .map(|_| ())
+    }
+
+    // Receives every line read from the port from now on.
+    fn subscribe_raw_lines(&self) -> broadcast::Receiver<String> {
+        self.raw_lines.subscribe()
+    }
+
+    // Reports the cached result of the PING/PONG exchange, refreshing it in the background once
+    // it is older than the configured TTL.
+    async fn check_health(&self) -> Result<(), AppError> {
+        let manager = self.clone();
+        self.health
+            .get(|| async move { manager.ping().await.map_err(|e| e.to_string()) })
+            .await
+            .map_err(AppError::Device)
+    }
+
+    // Age of the result `check_health` reports.
+    fn health_age(&self) -> Option<Duration> {
+        self.health.age()
+    }
+
+    fn source_metrics(&self) -> &SourceMetrics {
+        &self.source_metrics
+    }
+
+    fn port_name(&self) -> String {
+        self.port_name
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // The last lines received, for `/admin/raw`.
+    fn recent_frames(&self) -> &FrameLog {
+        &self.frames
+    }
+
+    fn protocol(&self) -> Option<Protocol> {
+        Protocol::from_number(self.protocol.load(Ordering::Relaxed))
+    }
+
+    // Time since the last valid frame was read, if any was.
+    fn last_frame_age(&self) -> Option<Duration> {
+        match self.last_frame_ms.load(Ordering::Relaxed) {
+            0 => None,
+            at => {
+                let now = self.clock.now_utc().timestamp_millis() as u64;
+                Some(Duration::from_millis(now.saturating_sub(at)))
             }
-            Ok(_) => Ok(None),
-            Err(e) => Err(Box::new(e)),
         }
     }
 
-    fn is_valid_data(&self, data: &str) -> bool {
-        data.starts_with('<') && data.ends_with('>')
+    // Closes the port at shutdown; the commands in progress fail.
+    async fn shutdown(&self) -> Result<(), AppError> {
+        if self.close().await {
+            info!("Source {} closed port {}", self.name, self.port_name());
+        }
+        Ok(())
     }
+}
 
-    pub async fn check_health(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut port = self.port.lock().await;
-        port.write_all(b"PING\n")?;
-        port.flush()?;
-        sleep(Duration::from_millis(100)).await;
+// The reader task, blocking: owns the port until `stop` is set, or a read or write fails. Lines
+// are trimmed, and empty ones skipped.
+fn read_port(
+    mut port: Box<dyn SerialPort>,
+    mut commands: mpsc::Receiver<Command>,
+    lines: mpsc::Sender<Result<String, AppError>>,
+    raw_lines: broadcast::Sender<String>,
+    config: Arc<ArduinoConfig>,
+    metrics: Arc<SourceMetrics>,
+    stop: &AtomicBool,
+) {
+    let mut chunk = vec![0; config.read_chunk_bytes];
+    let mut assembler = LineAssembler::new(config.max_frame_bytes);
+    // The command written last, waiting for its answer.
+    let mut pending: Option<(ResponseMatcher, oneshot::Sender<Result<String, String>>)> = None;
 
-        let mut buffer = String::new();
-        let mut reader = BufReader::new(&mut *port);
-        reader.read_line(&mut buffer)?;
+    while !stop.load(Ordering::Relaxed) {
+        // The sender gave up waiting, the next command can go
+        if pending.as_ref().is_some_and(|(_, reply)| reply.is_closed()) {
+            pending = None;
+        }
+        if pending.is_none() {
+            match commands.try_recv() {
+                Ok(command) => match write_line(&mut *port, &command.line) {
+                    Ok(()) => match command.expect {
+                        Some(expect) => pending = Some((expect, command.reply)),
+                        None => {
+                            let _ = command.reply.send(Ok(String::new()));
+                        }
+                    },
+                    Err(e) => {
+                        let _ = command.reply.send(Err(e.to_string()));
+                        send_error(&lines, e.into(), stop);
+                        return;
+                    }
+                },
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
 
-        match buffer.trim() {
-            "PONG" => {
-                debug!("Health check successful");
-                Ok(())
+        let available = match port.bytes_to_read() {
+            Ok(available) => available as usize,
+            Err(e) => {
+                send_error(&lines, e.into(), stop);
+                return;
             }
-            _ => {
-                error!("Health check failed");
-                Err("Health check failed".into())
+        };
+        if available == 0 {
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        if available > config.max_input_bytes {
+            warn!(
+                "Input buffer of port {} holds {} bytes, more than max_input_bytes ({}), cleared",
+                port.name().unwrap_or_default(),
+                available,
+                config.max_input_bytes
+            );
+            if let Err(e) = port.clear(ClearBuffer::Input) {
+                send_error(&lines, e.into(), stop);
+                return;
+            }
+            assembler.skip_line();
+            continue;
+        }
+        let read = available.min(chunk.len());
+        if let Err(e) = port.read_exact(&mut chunk[..read]) {
+            send_error(&lines, e.into(), stop);
+            return;
+        }
+
+        let (complete, oversized) = assembler.push(&chunk[..read]);
+        if oversized > 0 {
+            metrics
+                .frames_oversized
+                .fetch_add(oversized, Ordering::Relaxed);
+            warn!(
+                "Discarded {} line(s) of port {} longer than max_frame_bytes ({})",
+                oversized,
+                port.name().unwrap_or_default(),
+                config.max_frame_bytes
+            );
+        }
+        for line in complete {
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            if raw_lines.receiver_count() > 0 {
+                // Sending only fails when every subscriber has gone away in the meantime.
+                let _ = raw_lines.send(line.clone());
+            }
+            match pending.take() {
+                Some((expect, reply)) if expect.matches(&line) => {
+                    let _ = reply.send(Ok(line));
+                    continue;
+                }
+                still_pending => pending = still_pending,
+            }
+            match lines.try_send(Ok(line)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    metrics.lines_dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Queue of port {} is full, dropped a line",
+                        port.name().unwrap_or_default()
+                    );
+                }
+                Err(TrySendError::Closed(_)) => return,
             }
         }
     }
 }
 
-fn find_and_validate_arduino(
-    config: &ArduinoConfig,
-) -> Result<Box<dyn SerialPort>, Box<dyn Error + Send + Sync>> {
-    let target_product = config.device_name.as_str();
-    let ports = available_ports().map_err(|e| Box::<dyn Error + Send + Sync>::from(e))?;
-
-    debug!("Available ports: {:?}", ports);
-
-    let arduino_port = ports
-        .iter()
-        .find(|p| {
-            if let SerialPortType::UsbPort(ref info) = p.port_type {
-                if let Some(product) = &info.product {
-                    let product_normalized = normalize_product_name(product);
-                    debug!(
-                        "Checking port: {:?}, normalized product: {}",
-                        p, product_normalized
-                    );
-                    return product_normalized == normalize_product_name(target_product);
+// Passes the error ending the reader task on to `read_data`, waiting for room in the queue
+// unless the task is stopped meanwhile, since the error is what makes the source reconnect.
+fn send_error(lines: &mpsc::Sender<Result<String, AppError>>, error: AppError, stop: &AtomicBool) {
+    let mut error = Err(error);
+    while !stop.load(Ordering::Relaxed) {
+        match lines.try_send(error) {
+            Err(TrySendError::Full(unsent)) => error = unsent,
+            Ok(()) | Err(TrySendError::Closed(_)) => return,
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Splits the bytes read into lines, without keeping more than `max_len` bytes of a line.
+struct LineAssembler {
+    partial: Vec<u8>,
+    max_len: usize,
+    // Set while the rest of a line already discarded is skipped.
+    skipping: bool,
+}
+
+impl LineAssembler {
+    fn new(max_len: usize) -> Self {
+        Self {
+            partial: Vec::new(),
+            max_len,
+            skipping: false,
+        }
+    }
+
+    // The lines completed by `bytes`, without their newline, and how many lines were found
+    // longer than `max_len` meanwhile.
+    fn push(&mut self, bytes: &[u8]) -> (Vec<Vec<u8>>, u64) {
+        let mut lines = Vec::new();
+        let mut oversized = 0;
+        let mut segments = bytes.split(|&byte| byte == b'\n').peekable();
+        while let Some(segment) = segments.next() {
+            let complete = segments.peek().is_some();
+            if !self.skipping {
+                if self.partial.len() + segment.len() > self.max_len {
+                    oversized += 1;
+                    self.partial.clear();
+                    self.skipping = true;
+                } else {
+                    self.partial.extend_from_slice(segment);
+                }
+            }
+            if complete {
+                if !self.skipping {
+                    lines.push(std::mem::take(&mut self.partial));
                 }
+                self.skipping = false;
             }
+        }
+        (lines, oversized)
+    }
+
+    // Drops the line in progress, along with the rest of it still to come.
+    fn skip_line(&mut self) {
+        self.partial.clear();
+        self.skipping = true;
+    }
+}
+
+fn write_line(port: &mut dyn SerialPort, line: &str) -> std::io::Result<()> {
+    port.write_all(line.as_bytes())?;
+    port.write_all(b"\n")?;
+    port.flush()
+}
+
+// Accepts the legacy `<...>` frames as well as JSON object/array frames. An empty object or
+// array carries no reading, so it is not a frame.
+pub fn is_valid_frame(data: &str) -> bool {
+    let encloses = |open, close| data.len() >= 2 && data.starts_with(open) && data.ends_with(close);
+    let holds_something = || !data[1..data.len() - 1].trim().is_empty();
+    encloses('<', '>') || ((encloses('{', '}') || encloses('[', ']')) && holds_something())
+}
+
+// Opens the first port whose USB product is the configured device name. When none is, the
+// reason each port was passed over is logged.
+fn find_and_validate_arduino(config: &ArduinoConfig) -> Result<Box<dyn SerialPort>, AppError> {
+    let target_product = config.device_name.as_str();
+    let ports = list_candidate_ports()?;
+    let mut mismatches = Vec::new();
+    let arduino_port = ports.iter().find(|port| match port.check(target_product) {
+        Ok(()) => true,
+        Err(reason) => {
+            debug!("Skipping port {}: {}", port.port_name, reason);
+            mismatches.push(format!("{}: {}", port.port_name, reason));
             false
-        })
-        .ok_or_else(|| {
-            error!("Arduino not found");
-            Box::new(std::fmt::Error) as Box<dyn Error + Send + Sync>
-        })?;
+        }
+    });
+    let Some(arduino_port) = arduino_port else {
+        match mismatches.is_empty() {
+            true => error!("Arduino not found, there is no serial port"),
+            false => error!("Arduino not found: {}", mismatches.join("; ")),
+        }
+        return Err(AppError::DeviceNotFound(target_product.to_string()));
+    };
 
     debug!("Arduino found on port: {}", arduino_port.port_name);
 
     serialport::new(&arduino_port.port_name, config.baud_rate)
         .timeout(Duration::from_millis(config.timeout))
         .open()
-        .map_err(|e| e.into())
-        .map(|port| {
+        .map_err(AppError::from)
+        .inspect(|_| {
             debug!("Successfully opened port: {}", arduino_port.port_name);
-            port
         })
 }
 
+// A serial port and what it reports about the device behind it. The USB vendor and product
+// IDs are in hexadecimal, as `lsusb` shows them.
+#[derive(Serialize, Debug)]
+pub struct PortCandidate {
+    pub port_name: String,
+    // "usb", "pci", "bluetooth", or "unknown".
+    pub port_type: &'static str,
+    pub vid: Option<String>,
+    pub pid: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl PortCandidate {
+    // Why `ArduinoManager::connect` would not pick this port for `device_name`, if it would not.
+    pub fn check(&self, device_name: &str) -> Result<(), String> {
+        if self.port_type != "usb" {
+            return Err(format!("not a USB port ({})", self.port_type));
+        }
+        let Some(product) = &self.product else {
+            return Err("the USB device reports no product".to_string());
+        };
+        match normalize_product_name(product) == normalize_product_name(device_name) {
+            true => Ok(()),
+            false => Err(format!(
+                "product '{}' != configured '{}'",
+                product, device_name
+            )),
+        }
+    }
+}
+
+// Lists the available serial ports.
+pub fn list_candidate_ports() -> Result<Vec<PortCandidate>, AppError> {
+    let ports = available_ports()?;
+    Ok(ports
+        .into_iter()
+        .map(|port| {
+            let mut candidate = PortCandidate {
+                port_name: port.port_name,
+                port_type: "unknown",
+                vid: None,
+                pid: None,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+            };
+            match port.port_type {
+                SerialPortType::UsbPort(info) => {
+                    candidate.port_type = "usb";
+                    candidate.vid = Some(format!("{:04x}", info.vid));
+                    candidate.pid = Some(format!("{:04x}", info.pid));
+                    candidate.manufacturer = info.manufacturer;
+                    candidate.product = info.product;
+                    candidate.serial_number = info.serial_number;
+                }
+                SerialPortType::PciPort => candidate.port_type = "pci",
+                SerialPortType::BluetoothPort => candidate.port_type = "bluetooth",
+                SerialPortType::Unknown => {}
+            }
+            candidate
+        })
+        .collect())
+}
+
 // Normalize product names by removing spaces, underscores, hyphens, and converting to lowercase
 fn normalize_product_name(name: &str) -> String {
     name.to_lowercase()
@@ -146,3 +749,391 @@ fn normalize_product_name(name: &str) -> String {
         .replace("_", "")
         .replace("-", "")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use serde_json::json;
+    use std::collections::{HashMap, VecDeque};
+    use std::io::{self, Read, Write};
+    use tokio::time::sleep;
+
+    const ANSWER_WITHIN: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn legacy_and_json_frames_are_valid() {
+        assert!(is_valid_frame("<temperature:21.5>"));
+        assert!(is_valid_frame(r#"{"temperature": 21.5}"#));
+        assert!(is_valid_frame(r#"[{"temperature": 21.5}]"#));
+    }
+
+    #[test]
+    fn empty_objects_and_arrays_are_not_frames() {
+        for line in ["{}", "[]", "{ }", "[\t]", "{", "]", "", "temperature:21.5"] {
+            assert!(!is_valid_frame(line), "{:?}", line);
+        }
+    }
+
+    #[derive(Default)]
+    struct Script {
+        // Bytes the device has sent and the reader task not read yet.
+        input: VecDeque<u8>,
+        // Everything written to the port.
+        written: Vec<u8>,
+        // What the device sends back once a line is written to it.
+        answers: HashMap<String, String>,
+    }
+
+    // A serial port answering the command lines written to it as scripted.
+    #[derive(Clone, Default)]
+    struct ScriptedPort {
+        script: Arc<std::sync::Mutex<Script>>,
+    }
+
+    impl ScriptedPort {
+        fn answering(answers: &[(&str, &str)]) -> Self {
+            let port = Self::default();
+            port.lock().answers = answers
+                .iter()
+                .map(|(line, answer)| (line.to_string(), answer.to_string()))
+                .collect();
+            port
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, Script> {
+            self.script.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        // The device sends `bytes` unprompted.
+        fn send(&self, bytes: &str) {
+            self.lock().input.extend(bytes.bytes());
+        }
+
+        fn written(&self) -> String {
+            String::from_utf8_lossy(&self.lock().written).into_owned()
+        }
+    }
+
+    impl Read for ScriptedPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut script = self.lock();
+            let read = buf.len().min(script.input.len());
+            for (byte, input) in buf.iter_mut().zip(script.input.drain(..read)) {
+                *byte = input;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for ScriptedPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut script = self.lock();
+            script.written.extend_from_slice(buf);
+            if buf == b"\n" {
+                let written = String::from_utf8_lossy(&script.written).into_owned();
+                let line = written.trim_end().rsplit('\n').next().unwrap_or_default();
+                if let Some(answer) = script.answers.get(line).cloned() {
+                    script.input.extend(answer.bytes());
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialPort for ScriptedPort {
+        fn name(&self) -> Option<String> {
+            Some("/dev/scripted".to_string())
+        }
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(9600)
+        }
+        fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+            Ok(serialport::DataBits::Eight)
+        }
+        fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+            Ok(serialport::FlowControl::None)
+        }
+        fn parity(&self) -> serialport::Result<serialport::Parity> {
+            Ok(serialport::Parity::None)
+        }
+        fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+            Ok(serialport::StopBits::One)
+        }
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(100)
+        }
+        fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_data_bits(&mut self, _: serialport::DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_flow_control(&mut self, _: serialport::FlowControl) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_parity(&mut self, _: serialport::Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_stop_bits(&mut self, _: serialport::StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            Ok(self.lock().input.len() as u32)
+        }
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+            if let ClearBuffer::Input | ClearBuffer::All = buffer_to_clear {
+                self.lock().input.clear();
+            }
+            Ok(())
+        }
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+            Ok(Box::new(self.clone()))
+        }
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A reader task owning `port`, with its command queue and the lines it passes on.
+    struct Reader {
+        commands: mpsc::Sender<Command>,
+        lines: mpsc::Receiver<Result<String, AppError>>,
+        metrics: Arc<SourceMetrics>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl Reader {
+        fn spawn(port: &ScriptedPort) -> Self {
+            Self::spawn_with_capacity(port, LINES_CAPACITY)
+        }
+
+        fn spawn_with_capacity(port: &ScriptedPort, capacity: usize) -> Self {
+            let config: ArduinoConfig = serde_json::from_value(json!({})).unwrap();
+            let (commands, queue) = mpsc::channel(COMMANDS_CAPACITY);
+            let (lines_sender, lines) = mpsc::channel(capacity);
+            let metrics = Metrics::default().source("scripted");
+            let stop = Arc::new(AtomicBool::new(false));
+            let (port, source_metrics, stopped) =
+                (Box::new(port.clone()), metrics.clone(), stop.clone());
+            tokio::task::spawn_blocking(move || {
+                read_port(
+                    port,
+                    queue,
+                    lines_sender,
+                    broadcast::channel(RAW_LINES_CAPACITY).0,
+                    Arc::new(config),
+                    source_metrics,
+                    &stopped,
+                )
+            });
+            Self {
+                commands,
+                lines,
+                metrics,
+                stop,
+            }
+        }
+
+        // Queues a command; the receiver gets its answer.
+        async fn command(
+            &self,
+            line: &str,
+            expect: ResponseMatcher,
+        ) -> oneshot::Receiver<Result<String, String>> {
+            let (reply, answer) = oneshot::channel();
+            let command = Command {
+                line: line.to_string(),
+                expect: Some(expect),
+                reply,
+            };
+            self.commands.send(command).await.unwrap();
+            answer
+        }
+
+        async fn next_line(&mut self) -> String {
+            timeout(ANSWER_WITHIN, self.lines.recv())
+                .await
+                .expect("no line within the deadline")
+                .unwrap()
+                .unwrap()
+        }
+    }
+
+    impl Drop for Reader {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    async fn answer(answer: oneshot::Receiver<Result<String, String>>) -> String {
+        timeout(ANSWER_WITHIN, answer)
+            .await
+            .expect("no answer within the deadline")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_arriving_while_a_command_is_pending_still_reach_read_data() {
+        let port = ScriptedPort::answering(&[("VERSION?", "<21.5,40>\nVERSION 1.2\n<21.6,41>\n")]);
+        let mut reader = Reader::spawn(&port);
+
+        let version = reader
+            .command("VERSION?", ResponseMatcher::Prefix("VERSION ".to_string()))
+            .await;
+
+        assert_eq!(answer(version).await, "VERSION 1.2");
+        assert_eq!(reader.next_line().await, "<21.5,40>");
+        assert_eq!(reader.next_line().await, "<21.6,41>");
+    }
+
+    #[tokio::test]
+    async fn a_prefix_skips_other_lines_where_next_non_data_takes_them() {
+        let port = ScriptedPort::answering(&[
+            ("VERSION?", "<21.5,40>\nDEBUG boot\nVERSION 1.2\n"),
+            ("PING", "<21.6,41>\nDEBUG boot\nPONG\n"),
+        ]);
+        let mut reader = Reader::spawn(&port);
+
+        let version = reader
+            .command("VERSION?", ResponseMatcher::Prefix("VERSION ".to_string()))
+            .await;
+        assert_eq!(answer(version).await, "VERSION 1.2");
+        assert_eq!(reader.next_line().await, "<21.5,40>");
+        assert_eq!(reader.next_line().await, "DEBUG boot");
+
+        let ping = reader.command("PING", ResponseMatcher::NextNonData).await;
+        assert_eq!(answer(ping).await, "DEBUG boot");
+        assert_eq!(reader.next_line().await, "<21.6,41>");
+        assert_eq!(reader.next_line().await, "PONG");
+    }
+
+    #[tokio::test]
+    async fn commands_are_answered_while_nothing_takes_the_lines() {
+        let port = ScriptedPort::answering(&[("PING", "PONG\n")]);
+        let mut reader = Reader::spawn_with_capacity(&port, 2);
+        port.send("<21.5,40>\n<21.6,41>\n<21.7,42>\n<21.8,43>\n");
+
+        let ping = reader
+            .command("PING", ResponseMatcher::Prefix("PONG".to_string()))
+            .await;
+
+        assert_eq!(answer(ping).await, "PONG");
+        assert_eq!(reader.metrics.lines_dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(reader.next_line().await, "<21.5,40>");
+        assert_eq!(reader.next_line().await, "<21.6,41>");
+    }
+
+    #[tokio::test]
+    async fn a_command_given_up_on_releases_the_queue() {
+        let port = ScriptedPort::answering(&[("PING", "PONG\n")]);
+        let mut reader = Reader::spawn(&port);
+
+        // Never answered: the sender times out, which drops its receiver
+        let silent = reader
+            .command("CALIBRATE", ResponseMatcher::Prefix("OK".to_string()))
+            .await;
+        let ping = reader.command("PING", ResponseMatcher::NextNonData).await;
+        assert!(timeout(Duration::from_millis(200), silent).await.is_err());
+
+        assert_eq!(answer(ping).await, "PONG");
+        assert_eq!(port.written(), "CALIBRATE\nPING\n");
+
+        // The line a late answer would have been is data again
+        port.send("OK\n");
+        assert_eq!(reader.next_line().await, "OK");
+    }
+
+    #[tokio::test]
+    async fn queued_commands_are_answered_in_order() {
+        let port = ScriptedPort::answering(&[
+            ("SECOND?", "ANSWER 2\n"),
+            ("THIRD?", "ANSWER 3\n<21.6,41>\n"),
+        ]);
+        let mut reader = Reader::spawn(&port);
+
+        let expect = || ResponseMatcher::Prefix("ANSWER ".to_string());
+        let first = reader.command("FIRST?", expect()).await;
+        let second = reader.command("SECOND?", expect()).await;
+        let third = reader.command("THIRD?", expect()).await;
+
+        // The next command is only written once the one before is answered
+        sleep(POLL_INTERVAL * 5).await;
+        assert_eq!(port.written(), "FIRST?\n");
+        port.send("<21.5,40>\nANSWER 1\n");
+
+        assert_eq!(answer(first).await, "ANSWER 1");
+        assert_eq!(answer(second).await, "ANSWER 2");
+        assert_eq!(answer(third).await, "ANSWER 3");
+        assert_eq!(port.written(), "FIRST?\nSECOND?\nTHIRD?\n");
+        assert_eq!(reader.next_line().await, "<21.5,40>");
+        assert_eq!(reader.next_line().await, "<21.6,41>");
+    }
+
+    #[test]
+    fn an_oversized_line_is_dropped_without_holding_on_to_it() {
+        let mut assembler = LineAssembler::new(16);
+
+        // A line without an end in sight, over several reads
+        let (lines, oversized) = assembler.push(&[b'x'; 12]);
+        assert!(lines.is_empty());
+        assert_eq!(oversized, 0);
+        let (lines, oversized) = assembler.push(&[b'x'; 4096]);
+        assert!(lines.is_empty());
+        assert_eq!(oversized, 1);
+        assert!(assembler.partial.len() <= 16);
+        let (lines, oversized) = assembler.push(&[b'x'; 4096]);
+        assert!(lines.is_empty());
+        assert_eq!(oversized, 0);
+        assert!(assembler.partial.is_empty());
+
+        // The rest of it is skipped, and the frame after it comes through whole
+        let (lines, oversized) = assembler.push(b"xxxx\n<21.5,40>\n<21.6");
+        assert_eq!(lines, [b"<21.5,40>".to_vec()]);
+        assert_eq!(oversized, 0);
+        let (lines, _) = assembler.push(b",41>\n");
+        assert_eq!(lines, [b"<21.6,41>".to_vec()]);
+    }
+
+    #[test]
+    fn a_line_of_the_maximum_length_is_kept() {
+        let mut assembler = LineAssembler::new(9);
+
+        let (lines, oversized) = assembler.push(b"<21.5,40>\n<21.5,400>\n");
+
+        assert_eq!(lines, [b"<21.5,40>".to_vec()]);
+        assert_eq!(oversized, 1);
+    }
+}
@@ -0,0 +1,85 @@
+// broker_stats.rs
+//
+// Self-monitoring for sites without Prometheus: a `broker_stats` point, added to the cache
+// before every periodic flush, with a snapshot of the counters `/metrics` exposes. The point is
+// tagged with the global tags and written like any other, so `bucket_routing` can send it to a
+// bucket of its own. The fields are stable, dashboards depend on them:
+//
+//   frames_valid     frames read from the sources with a known framing
+//   frames_invalid   frames with an unknown framing
+//   points_written   points the sink accepted
+//   flush_failures   flushes that failed
+//   cache_len        points waiting to be flushed
+//   evictions        points dropped because the cache was full
+//   reconnects       reconnections of the serial ports and the MQTT client
+//   rss_bytes        resident memory of the process, left out where it cannot be read
+//
+// The counters are totals since the broker started, like their Prometheus counterparts.
+
+use crate::clock::{self, Clock};
+use crate::metrics::Metrics;
+
+use influxdb2::models::DataPoint;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const STATS_MEASUREMENT: &str = "broker_stats";
+
+pub struct BrokerStats {
+    metrics: Arc<Metrics>,
+    tags: BTreeMap<String, String>,
+    clock: Arc<dyn Clock>,
+}
+
+impl BrokerStats {
+    pub fn new(metrics: Arc<Metrics>, tags: BTreeMap<String, String>) -> Self {
+        Self {
+            metrics,
+            tags,
+            clock: clock::system(),
+        }
+    }
+
+    // Stamps the snapshots with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // The snapshot, given the length of the cache before the flush.
+    pub fn point(&self, cache_len: usize) -> Result<DataPoint, String> {
+        let timestamp = clock::now_nanos(self.clock.as_ref()).map_err(|e| e.to_string())?;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as i64;
+        let frames_received = load(&self.metrics.frames_received);
+        let frames_invalid = load(&self.metrics.frames_invalid);
+        let mut builder = DataPoint::builder(STATS_MEASUREMENT)
+            .field("frames_valid", (frames_received - frames_invalid).max(0))
+            .field("frames_invalid", frames_invalid)
+            .field("points_written", load(&self.metrics.points_written))
+            .field("flush_failures", load(&self.metrics.flush_failures))
+            .field("cache_len", cache_len as i64)
+            .field("evictions", load(&self.metrics.cache_evictions))
+            .field(
+                "reconnects",
+                load(&self.metrics.serial_reconnects) + load(&self.metrics.mqtt_reconnects),
+            )
+            .timestamp(timestamp);
+        if let Some(rss_bytes) = resident_memory() {
+            builder = builder.field("rss_bytes", rss_bytes as i64);
+        }
+        self.tags
+            .iter()
+            .fold(builder, |builder, (key, value)| builder.tag(key, value))
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+// The resident set size of the process, from the `VmRSS` line of /proc/self/status.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
@@ -0,0 +1,337 @@
+// buffered_fan_out.rs
+//
+// Writes to several sinks independently of one another, so that a slow or unreachable sink (an
+// MQTT broker timing out) never holds up the others. Each sink gets a bounded queue and a task
+// of its own: `write` only appends the batch to every queue and wakes the tasks, which write
+// what their queue holds as soon as their sink is done with the previous batch. A batch a sink
+// failed to write is put back in front of its queue and tried again a flush interval later,
+// unless the sink rejected it for good, in which case it goes to the dead-letter directory. A
+// queue holding more than `max_size` points drops the oldest, like the cache.
+//
+// The health of every sink is still checked on its own, and the status of each sink shows its
+// queue. At shutdown, `close` waits for the queues to be written out; a sink failing meanwhile
+// loses what is left in its queue.
+
+use crate::cache::flush_deadline;
+use crate::dead_letter::DeadLetterWriter;
+use crate::metrics::Metrics;
+use crate::sink::{DataSink, SinkError};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use influxdb2::models::DataPoint;
+use log::{error, warn};
+use serde_json::{json, Map, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+pub struct BufferedFanOutSink {
+    lanes: Vec<Arc<Lane>>,
+    // The task of every lane, returning the points it could not write before closing.
+    tasks: Mutex<Vec<JoinHandle<usize>>>,
+}
+
+// The queue of one sink.
+struct Lane {
+    sink: Arc<dyn DataSink>,
+    queue: Mutex<VecDeque<DataPoint>>,
+    max_size: usize,
+    wake: Notify,
+    closing: CancellationToken,
+    metrics: Arc<Metrics>,
+    evicted: AtomicU64,
+    written: AtomicU64,
+    failures: AtomicU64,
+    // When the last write finished and whether it succeeded.
+    last_write: Mutex<Option<(Instant, bool)>>,
+}
+
+impl BufferedFanOutSink {
+    // Starts the task of every sink. Each write must finish within the `flush_deadline` of the
+    // flush interval, like the flushes of the cache.
+    pub fn start(
+        sinks: Vec<Arc<dyn DataSink>>,
+        max_size: usize,
+        metrics: Arc<Metrics>,
+        flush_interval: watch::Receiver<Duration>,
+        dead_letter: Option<DeadLetterWriter>,
+    ) -> Self {
+        let lanes: Vec<Arc<Lane>> = sinks
+            .into_iter()
+            .map(|sink| {
+                Arc::new(Lane {
+                    sink,
+                    queue: Mutex::new(VecDeque::new()),
+                    max_size,
+                    wake: Notify::new(),
+                    closing: CancellationToken::new(),
+                    metrics: metrics.clone(),
+                    evicted: AtomicU64::new(0),
+                    written: AtomicU64::new(0),
+                    failures: AtomicU64::new(0),
+                    last_write: Mutex::new(None),
+                })
+            })
+            .collect();
+        let tasks = lanes
+            .iter()
+            .map(|lane| {
+                tokio::spawn(
+                    lane.clone()
+                        .run(flush_interval.clone(), dead_letter.clone()),
+                )
+            })
+            .collect();
+        Self {
+            lanes,
+            tasks: Mutex::new(tasks),
+        }
+    }
+}
+
+#[async_trait]
+impl DataSink for BufferedFanOutSink {
+    fn name(&self) -> &str {
+        "fan-out"
+    }
+
+    // Queues the batch for every sink; it never fails, the sinks report their own failures.
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        for lane in &self.lanes {
+            lane.push(points.clone());
+        }
+        Ok(())
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        let checks = self
+            .lanes
+            .iter()
+            .map(|lane| async move { (lane.sink.name(), lane.sink.check_health().await) });
+
+        let unhealthy: Vec<String> = join_all(checks)
+            .await
+            .into_iter()
+            .filter_map(|(name, result)| result.err().map(|e| format!("{}: {}", name, e)))
+            .collect();
+
+        if unhealthy.is_empty() {
+            Ok(())
+        } else {
+            Err(SinkError::Unavailable(unhealthy.join("; ")))
+        }
+    }
+
+    // The oldest cached result among the sinks.
+    fn health_age(&self) -> Option<Duration> {
+        self.lanes
+            .iter()
+            .filter_map(|lane| lane.sink.health_age())
+            .max()
+    }
+
+    fn status(&self) -> Value {
+        self.lanes
+            .iter()
+            .map(|lane| (lane.sink.name().to_string(), lane.status()))
+            .collect::<Map<_, _>>()
+            .into()
+    }
+
+    fn parts(&self) -> Vec<Arc<dyn DataSink>> {
+        self.lanes.iter().map(|lane| lane.sink.clone()).collect()
+    }
+
+    async fn close(&self) -> Result<(), SinkError> {
+        for lane in &self.lanes {
+            lane.closing.cancel();
+        }
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap_or_else(PoisonError::into_inner));
+        let lost: Vec<String> = self
+            .lanes
+            .iter()
+            .zip(join_all(tasks).await)
+            .filter_map(|(lane, result)| match result {
+                Ok(0) => None,
+                Ok(lost) => Some(format!("{}: {} points", lane.sink.name(), lost)),
+                Err(e) => Some(format!("{}: {}", lane.sink.name(), e)),
+            })
+            .collect();
+        match lost.is_empty() {
+            true => Ok(()),
+            false => Err(SinkError::Unavailable(format!(
+                "not written before shutdown: {}",
+                lost.join("; ")
+            ))),
+        }
+    }
+}
+
+impl Lane {
+    fn queue(&self) -> std::sync::MutexGuard<'_, VecDeque<DataPoint>> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, points: Vec<DataPoint>) {
+        let mut queue = self.queue();
+        queue.extend(points);
+        self.evict(&mut queue);
+        drop(queue);
+        self.wake.notify_one();
+    }
+
+    // Puts a batch that failed back in front of the points queued meanwhile.
+    fn requeue(&self, points: Vec<DataPoint>) {
+        let mut queue = self.queue();
+        let newer = std::mem::take(&mut *queue);
+        queue.extend(points);
+        queue.extend(newer);
+        self.evict(&mut queue);
+    }
+
+    fn evict(&self, queue: &mut VecDeque<DataPoint>) {
+        let excess = queue.len().saturating_sub(self.max_size);
+        if excess > 0 {
+            queue.drain(..excess);
+            self.evicted.fetch_add(excess as u64, Ordering::Relaxed);
+            self.metrics
+                .cache_evictions
+                .fetch_add(excess as u64, Ordering::Relaxed);
+            warn!(
+                "Queue of sink {} is full, dropped its {} oldest points",
+                self.sink.name(),
+                excess
+            );
+        }
+    }
+
+    // Writes the queue whenever points are added, until it is empty once closing.
+    async fn run(
+        self: Arc<Self>,
+        mut flush_interval: watch::Receiver<Duration>,
+        dead_letter: Option<DeadLetterWriter>,
+    ) -> usize {
+        loop {
+            let batch: Vec<DataPoint> = self.queue().drain(..).collect();
+            if batch.is_empty() {
+                if self.closing.is_cancelled() {
+                    return 0;
+                }
+                tokio::select! {
+                    _ = self.wake.notified() => {}
+                    _ = self.closing.cancelled() => {}
+                }
+                continue;
+            }
+
+            let period = *flush_interval.borrow_and_update();
+            let retry = self
+                .write(batch, flush_deadline(period), dead_letter.as_ref())
+                .await;
+            if retry.is_empty() {
+                continue;
+            }
+            if self.closing.is_cancelled() {
+                let lost = retry.len() + self.queue().len();
+                error!(
+                    "Sink {} failed at shutdown, {} points were not written",
+                    self.sink.name(),
+                    lost
+                );
+                return lost;
+            }
+            self.requeue(retry);
+            tokio::select! {
+                _ = sleep(period) => {}
+                _ = self.closing.cancelled() => {}
+            }
+        }
+    }
+
+    // Writes the batch within `deadline`, returning the points to try again.
+    async fn write(
+        &self,
+        batch: Vec<DataPoint>,
+        deadline: Duration,
+        dead_letter: Option<&DeadLetterWriter>,
+    ) -> Vec<DataPoint> {
+        let total = batch.len();
+        let result = match timeout(deadline, self.sink.write(batch.clone())).await {
+            Ok(result) => result,
+            Err(_) => Err(SinkError::TimedOut(format!(
+                "write did not complete within {:?}",
+                deadline
+            ))),
+        };
+        *self
+            .last_write
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some((Instant::now(), result.is_ok()));
+        let Err(e) = result else {
+            self.written.fetch_add(total as u64, Ordering::Relaxed);
+            return Vec::new();
+        };
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        error!(
+            "Sink {} failed to write {} points: {}",
+            self.sink.name(),
+            total,
+            e
+        );
+
+        // Only the points the sink rejected go to the dead-letter directory, the rest is retried
+        let mut retry = Vec::new();
+        let mut failed = 0;
+        for (error, points) in e.into_failures(batch) {
+            failed += points.len();
+            if !error.is_permanent() {
+                retry.extend(points);
+                continue;
+            }
+            if let Some(dead_letter) = dead_letter {
+                match dead_letter.write(&points) {
+                    Ok(path) => warn!(
+                        "Batch of {} points rejected by sink {} saved to {}",
+                        points.len(),
+                        self.sink.name(),
+                        path.display()
+                    ),
+                    Err(e) => error!("Failed to write dead-letter file: {}", e),
+                }
+            }
+        }
+        self.written
+            .fetch_add(total.saturating_sub(failed) as u64, Ordering::Relaxed);
+        retry
+    }
+
+    // The status of the sink, with the state of its queue.
+    fn status(&self) -> Value {
+        let mut status = match self.sink.status() {
+            Value::Object(status) => status,
+            Value::Null => Map::new(),
+            other => Map::from_iter([("status".to_string(), other)]),
+        };
+        let last_write = *self
+            .last_write
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        status.insert(
+            "queue".to_string(),
+            json!({
+                "len": self.queue().len(),
+                "evicted": self.evicted.load(Ordering::Relaxed),
+                "written": self.written.load(Ordering::Relaxed),
+                "failures": self.failures.load(Ordering::Relaxed),
+                "last_write_ok": last_write.map(|(_, ok)| ok),
+                "last_write_secs_ago": last_write.map(|(at, _)| at.elapsed().as_secs()),
+            }),
+        );
+        Value::Object(status)
+    }
+}
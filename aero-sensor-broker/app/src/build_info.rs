@@ -0,0 +1,41 @@
+// build_info.rs
+//
+// Identifies the build that is running: crate version, git commit, build time, and rustc
+// version, all captured at compile time by `build.rs`. Served on `/version`, included in the
+// health payload, logged once at startup, and optionally attached to written points as tags.
+
+use chrono::DateTime;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub built_at: String,
+    pub rustc: &'static str,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let built_at = env!("AERO_BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map_or_else(|| "unknown".to_string(), |at| at.to_rfc3339());
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("AERO_GIT_COMMIT"),
+            built_at,
+            rustc: env!("AERO_RUSTC_VERSION"),
+        }
+    }
+
+    // Tags recording which build produced a point.
+    pub fn tags(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("build_version".to_string(), self.version.to_string()),
+            ("build_commit".to_string(), self.commit.to_string()),
+        ])
+    }
+}
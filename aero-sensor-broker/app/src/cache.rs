@@ -2,74 +2,401 @@
 
 // This module defines a `Cache` struct for managing a collection of `DataPoint` instances
 // in a thread-safe manner. The cache supports adding new data points, periodically flushing
-// the cached data to an InfluxDB instance, and maintaining a maximum cache size.
+// the cached data to a data sink (InfluxDB), and maintaining a maximum cache size.
 // It uses an asynchronous approach to handle operations in a non-blocking way, suitable for
 // concurrent environments.
+//
+// Points leave the cache in the order they entered it. Only one flush runs at a time, and the
+// points of a flush that failed for a reason worth retrying go back to the front of the cache, so
+// the next flush writes them before anything cached since. With `flush_seq` set, every point is
+// stamped with the number of the flush that first tried to write it: the numbers a consumer sees
+// never go down unless the points were reordered.
+//
+// With pacing, a periodic flush of a large backlog is written in batches spread over part of the
+// interval rather than at once, see `pacing`. The flush at shutdown is never paced.
 
-use crate::influxdb::InfluxDBManager;
+use crate::broker_stats::BrokerStats;
+use crate::clock::{self, Clock};
+use crate::coalesce::coalesce;
+use crate::config::{CoalesceMode, PacingConfig, FLUSH_DEADLINE_SHARE};
+use crate::dead_letter::DeadLetterWriter;
+use crate::heartbeat::Heartbeat;
+use crate::line_protocol::decode;
+use crate::metrics::Metrics;
+use crate::pacing::Pacing;
+use crate::sink::{DataSink, SinkError};
 use influxdb2::models::DataPoint;
-use log::{debug, error};
+use log::{debug, error, warn};
 use std::collections::VecDeque;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{field, info_span, Instrument, Span};
+
+// The field the number of the flush is written in.
+const FLUSH_SEQ_FIELD: &str = "flush_seq";
 
 #[derive(Clone)]
 pub struct Cache {
     inner: Arc<Mutex<VecDeque<DataPoint>>>,
     max_size: usize,
+    metrics: Arc<Metrics>,
+    // When the last flush finished and whether it succeeded.
+    last_flush: Arc<std::sync::Mutex<Option<(Instant, bool)>>>,
+    coalesce: CoalesceMode,
+    clock: Arc<dyn Clock>,
+    // Held for the whole of a flush, so that two flushes never interleave their writes.
+    flushing: Arc<Mutex<()>>,
+    // The number of the last flush, when points are stamped with it.
+    flush_seq: Option<Arc<AtomicU64>>,
+    // Wakes the periodic flush before its interval is over.
+    flush_now: Arc<Notify>,
+    // Whether the periodic flushes are held back, the sink being known to be unreachable.
+    held: Arc<AtomicBool>,
+    pacing: Option<Pacing>,
 }
 
 impl Cache {
     // Creates a new Cache instance with a specified maximum size
-    pub fn new(max_size: usize) -> Self {
+    pub fn new(max_size: usize, metrics: Arc<Metrics>) -> Self {
         Self {
             inner: Arc::new(Mutex::new(VecDeque::new())),
             max_size,
+            metrics,
+            last_flush: Arc::new(std::sync::Mutex::new(None)),
+            coalesce: CoalesceMode::Off,
+            clock: clock::system(),
+            flushing: Arc::new(Mutex::new(())),
+            flush_seq: None,
+            flush_now: Arc::new(Notify::new()),
+            held: Arc::new(AtomicBool::new(false)),
+            pacing: None,
         }
     }
 
+    // Times the flushes with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Coalesces the points of each series at every flush, see `coalesce`.
+    pub fn with_coalesce(mut self, coalesce: CoalesceMode) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    // Stamps every point with the number of the flush that first tried to write it.
+    pub fn with_flush_seq(mut self, enabled: bool) -> Self {
+        self.flush_seq = enabled.then(|| Arc::new(AtomicU64::new(0)));
+        self
+    }
+
+    // Spreads the periodic flushes of a large backlog over part of their interval.
+    pub fn with_pacing(mut self, pacing: Option<&PacingConfig>) -> Self {
+        self.pacing = pacing.map(Pacing::new);
+        self
+    }
+
+    // Has the periodic flush run right away instead of at the end of its interval, e.g. once the
+    // sink is reachable again.
+    pub fn flush_soon(&self) {
+        self.flush_now.notify_one();
+    }
+
+    // Holds the periodic flushes back while the sink is known to be unreachable, so that the
+    // points pile up in the cache instead of failing a write at every interval. The flush at
+    // shutdown is never held back.
+    pub fn hold(&self, held: bool) {
+        self.held.store(held, Ordering::Relaxed);
+    }
+
+    pub async fn len(&self) -> usize {
+        self.inner.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.inner.lock().await.is_empty()
+    }
+
+    // Points dropped so far because the cache was full.
+    pub fn evicted(&self) -> u64 {
+        self.metrics.cache_evictions.load(Ordering::Relaxed)
+    }
+
+    // Time since the last flush and whether it succeeded.
+    pub fn last_flush(&self) -> Option<(Duration, bool)> {
+        let last_flush = self
+            .last_flush
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = self.clock.now_monotonic();
+        last_flush.map(|(at, succeeded)| (now.saturating_duration_since(at), succeeded))
+    }
+
     // Adds a collection of data points to the cache
     pub async fn add(&self, data_points: Vec<DataPoint>) {
         debug!("Adding {:?} data points to cache", data_points);
         let mut cache = self.inner.lock().await;
 
         // Remove oldest entries if necessary to make room for new data points
-        while cache.len() + data_points.len() > self.max_size {
-            cache.pop_front();
+        while cache.len() + data_points.len() > self.max_size && cache.pop_front().is_some() {
+            self.metrics.cache_evictions.fetch_add(1, Ordering::Relaxed);
         }
 
         // Add new data points to the end of the cache
         cache.extend(data_points.clone());
+        self.metrics
+            .cache_length
+            .store(cache.len() as u64, Ordering::Relaxed);
+    }
+
+    // Puts points that could not be written back in front of those cached since, dropping the
+    // oldest if the cache is full.
+    async fn requeue(&self, data_points: Vec<DataPoint>) {
+        let mut cache = self.inner.lock().await;
+        let room = self.max_size.saturating_sub(cache.len());
+        let dropped = data_points.len().saturating_sub(room);
+        self.metrics
+            .cache_evictions
+            .fetch_add(dropped as u64, Ordering::Relaxed);
+        for point in data_points.into_iter().skip(dropped).rev() {
+            cache.push_front(point);
+        }
+        self.metrics
+            .cache_length
+            .store(cache.len() as u64, Ordering::Relaxed);
     }
 
     // Retrieves all cached data points and clears the cache
     pub async fn retrieve_and_clear(&self) -> Vec<DataPoint> {
-        self.inner.lock().await.drain(..).collect()
+        let points = self.inner.lock().await.drain(..).collect();
+        self.metrics.cache_length.store(0, Ordering::Relaxed);
+        points
     }
 
-    // Periodically flushes the cache to InfluxDB
+    // Periodically flushes the cache to the sink, and right away after `flush_soon`, until
+    // `shutdown` is cancelled. Each flush must finish within the `flush_deadline` of the
+    // interval so a slow sink can never make two flushes overlap. A reloaded interval takes
+    // effect from the next flush on.
     pub async fn periodic_flush(
         &self,
-        influxdb_manager: InfluxDBManager,
-        bucket: &str,
-        interval: Duration,
+        sink: Arc<dyn DataSink>,
+        mut interval: watch::Receiver<Duration>,
+        dead_letter: Option<DeadLetterWriter>,
+        heartbeat: Option<Arc<Heartbeat>>,
+        broker_stats: Option<Arc<BrokerStats>>,
+        shutdown: CancellationToken,
     ) {
         loop {
-            sleep(interval).await;
+            let period = *interval.borrow_and_update();
+            let deadline = flush_deadline(period);
+            tokio::select! {
+                _ = sleep(period) => {}
+                _ = self.flush_now.notified() => {}
+                _ = shutdown.cancelled() => return,
+            }
 
-            // Retrieve and clear the cache
-            let points_to_flush = self.retrieve_and_clear().await;
+            // The heartbeat is written whether the sources sent anything or not
+            if let Some(heartbeat) = &heartbeat {
+                let points = heartbeat.points(self.len().await, self.last_flush());
+                self.add(points).await;
+            }
+            if let Some(broker_stats) = &broker_stats {
+                match broker_stats.point(self.len().await) {
+                    Ok(point) => self.add(vec![point]).await,
+                    Err(e) => warn!("Failed to build the broker stats point: {}", e),
+                }
+            }
 
-            // Skip processing if the cache is empty
-            if points_to_flush.is_empty() {
+            if self.held.load(Ordering::Relaxed) {
+                debug!("Flush held back, {} point(s) cached", self.len().await);
                 continue;
             }
 
-            // Write data to InfluxDB and handle potential errors
-            if let Err(e) = influxdb_manager.write_data(bucket, points_to_flush).await {
-                error!("Failed to flush cache to InfluxDB: {}", e);
+            // Errors are logged by `flush`, the next flush tries again
+            let paced = Some((period, &shutdown));
+            let _ = self
+                .flush(sink.as_ref(), deadline, dead_letter.as_ref(), paced)
+                .await;
+        }
+    }
+
+    // Writes what is left in the cache to the sink once nothing is added anymore, at shutdown.
+    pub async fn shutdown(
+        &self,
+        sink: &dyn DataSink,
+        deadline: Duration,
+        dead_letter: Option<&DeadLetterWriter>,
+    ) -> Result<(), String> {
+        self.flush(sink, deadline, dead_letter, None).await
+    }
+
+    // Writes the cached points to the sink within `deadline`, in a span of its own. Batches
+    // the sink permanently rejects are handed to the dead-letter writer, when one is configured.
+    // A flush `paced` by its interval, until the shutdown, spreads a large backlog over it.
+    async fn flush(
+        &self,
+        sink: &dyn DataSink,
+        deadline: Duration,
+        dead_letter: Option<&DeadLetterWriter>,
+        paced: Option<(Duration, &CancellationToken)>,
+    ) -> Result<(), String> {
+        let span = info_span!(
+            "flush",
+            sink = sink.name(),
+            points = field::Empty,
+            outcome = field::Empty
+        );
+        let result = self
+            .write_cached(sink, deadline, dead_letter, paced)
+            .instrument(span.clone())
+            .await;
+        span.record("outcome", if result.is_ok() { "ok" } else { "error" });
+        result
+    }
+
+    async fn write_cached(
+        &self,
+        sink: &dyn DataSink,
+        deadline: Duration,
+        dead_letter: Option<&DeadLetterWriter>,
+        paced: Option<(Duration, &CancellationToken)>,
+    ) -> Result<(), String> {
+        let _flushing = self.flushing.lock().await;
+
+        // Retrieve and clear the cache
+        let points_to_flush = self
+            .retrieve_and_clear()
+            .instrument(info_span!("batch"))
+            .await;
+        let mut points_to_flush = coalesce(points_to_flush, self.coalesce);
+        Span::current().record("points", points_to_flush.len());
+
+        // Skip processing if the cache is empty
+        if points_to_flush.is_empty() {
+            return Ok(());
+        }
+
+        // Points retried keep the number of their first flush
+        if let Some(flush_seq) = &self.flush_seq {
+            let seq = flush_seq.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut unstamped = 0;
+            points_to_flush = points_to_flush
+                .into_iter()
+                .map(|point| {
+                    stamp_flush_seq(point, seq as i64).unwrap_or_else(|point| {
+                        unstamped += 1;
+                        point
+                    })
+                })
+                .collect();
+            if unstamped > 0 {
+                warn!(
+                    "{} point(s) of flush {} could not be decoded, written without {}",
+                    unstamped, seq, FLUSH_SEQ_FIELD
+                );
+                self.metrics
+                    .points_unstamped
+                    .fetch_add(unstamped, Ordering::Relaxed);
             }
         }
+
+        // Write data to the sink and handle potential errors, a large backlog in paced batches
+        let pace = match (&self.pacing, paced) {
+            (Some(pacing), Some((interval, shutdown))) => pacing
+                .delay(points_to_flush.len(), interval)
+                .map(|delay| (pacing, delay, shutdown)),
+            _ => None,
+        };
+        let result = match pace {
+            Some((pacing, delay, shutdown)) => {
+                let points = points_to_flush.clone();
+                pacing
+                    .write(sink, points, delay, deadline, self.clock.as_ref(), shutdown)
+                    .await
+            }
+            None => match timeout(deadline, sink.write(points_to_flush.clone())).await {
+                Ok(result) => result,
+                Err(_) => Err(SinkError::TimedOut(format!(
+                    "flush did not complete within {:?}",
+                    deadline
+                ))),
+            },
+        };
+        let counter = match &result {
+            Ok(()) => &self.metrics.flush_successes,
+            Err(_) => &self.metrics.flush_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        *self
+            .last_flush
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            Some((self.clock.now_monotonic(), result.is_ok()));
+
+        let Err(e) = result else {
+            return Ok(());
+        };
+        error!("Failed to flush cache: {}", e);
+        let failure = e.to_string();
+
+        // Only the points the sink rejected go to the dead-letter directory, the others are
+        // written again by the next flush, before the points cached meanwhile
+        let mut retried = Vec::new();
+        for (error, points) in e.into_failures(points_to_flush) {
+            if !error.is_permanent() {
+                retried.extend(points);
+                continue;
+            }
+            if let Some(dead_letter) = dead_letter {
+                match dead_letter.write(&points) {
+                    Ok(path) => warn!(
+                        "Rejected batch of {} points saved to {}",
+                        points.len(),
+                        path.display()
+                    ),
+                    Err(e) => error!("Failed to write dead-letter file: {}", e),
+                }
+            }
+        }
+        if !retried.is_empty() {
+            warn!(
+                "{} points kept in the cache for the next flush",
+                retried.len()
+            );
+            self.requeue(retried).await;
+        }
+        Err(failure)
+    }
+}
+
+// The time a flush may take when flushes are `period` apart.
+pub fn flush_deadline(period: Duration) -> Duration {
+    period.mul_f64(FLUSH_DEADLINE_SHARE)
+}
+
+// Adds the `flush_seq` field to a point that does not have it yet. `DataPoint` cannot be added
+// to, so the point is decoded and built again; one that cannot be is handed back as the error.
+fn stamp_flush_seq(point: DataPoint, seq: i64) -> Result<DataPoint, DataPoint> {
+    let Some(decoded) = decode(&point) else {
+        return Err(point);
+    };
+    if decoded.fields.contains_key(FLUSH_SEQ_FIELD) {
+        return Ok(point);
+    }
+    let mut builder = DataPoint::builder(decoded.measurement.as_str());
+    for (key, value) in &decoded.tags {
+        builder = builder.tag(key, value);
+    }
+    for (name, value) in decoded.fields {
+        builder = builder.field(name, value);
+    }
+    builder = builder.field(FLUSH_SEQ_FIELD, seq);
+    if let Some(timestamp) = decoded.timestamp {
+        builder = builder.timestamp(timestamp);
     }
+    builder.build().map_err(|_| point)
 }
@@ -0,0 +1,140 @@
+// cap.rs
+//
+// The limits keeping the memory of the streaming features bounded on small gateways, whatever a
+// misbehaving subscriber or an explosion of series does. A `Cap` knows whether its limit is
+// reached: it logs once when it gets there and once when usage is back under it, rather than at
+// every event refused or evicted meanwhile, which it only counts.
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub struct Cap {
+    // What is limited, for the logs, e.g. "stream subscribers".
+    what: &'static str,
+    limit: usize,
+    reached: AtomicBool,
+    // Events refused or evicted because the limit was reached.
+    hits: AtomicU64,
+}
+
+impl Cap {
+    pub fn new(what: &'static str, limit: usize) -> Self {
+        Self {
+            what,
+            limit,
+            reached: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // Records the usage after it changed, logging when the limit is reached or left.
+    pub fn observe(&self, used: usize) {
+        let reached = used >= self.limit;
+        if self.reached.swap(reached, Ordering::Relaxed) == reached {
+            return;
+        }
+        match reached {
+            true => warn!("{} reached their limit of {}", self.what, self.limit),
+            false => info!(
+                "{} back under their limit of {}, {} refused or evicted so far",
+                self.what,
+                self.limit,
+                self.hits.load(Ordering::Relaxed)
+            ),
+        }
+    }
+
+    // Counts an event refused or evicted because the limit is reached.
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self, used: usize) -> Value {
+        json!({
+            "used": used,
+            "limit": self.limit,
+            "reached": self.reached.load(Ordering::Relaxed),
+            "hits": self.hits.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use std::sync::{Mutex, Once, PoisonError};
+
+    // Keeps the messages logged by the tests of the crate, which run in this process.
+    struct Capture;
+
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LOGGED
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    // The messages logged about `what` so far.
+    fn logged(what: &str) -> Vec<String> {
+        LOGGED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|message| message.starts_with(what))
+            .cloned()
+            .collect()
+    }
+
+    fn capture_logs() {
+        static CAPTURE: Capture = Capture;
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(LevelFilter::Info);
+        });
+    }
+
+    #[test]
+    fn the_limit_is_logged_once_per_transition() {
+        capture_logs();
+        let cap = Cap::new("Test widgets", 100);
+
+        // Up to three times the limit, every event beyond it refused, then down and up again
+        let usage = (0..=300).chain((50..300).rev()).chain(50..=120);
+        for used in usage {
+            cap.observe(used.min(100));
+            if used >= 100 {
+                cap.hit();
+            }
+        }
+
+        assert_eq!(
+            logged("Test widgets"),
+            [
+                "Test widgets reached their limit of 100",
+                "Test widgets back under their limit of 100, 401 refused or evicted so far",
+                "Test widgets reached their limit of 100",
+            ]
+        );
+        assert_eq!(
+            cap.to_json(100),
+            json!({"used": 100, "limit": 100, "reached": true, "hits": 422})
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// cli.rs
+//
+// Command-line interface of the broker. Without a subcommand the broker runs; `check-config`,
+// `list-ports`, and `selftest` help preparing a deployment without starting the pipeline.
+
+use aero_sensor_broker::config::ConfigSource;
+use clap::{Parser, Subcommand, ValueEnum};
+use config::FileFormat;
+use log::LevelFilter;
+use std::env;
+use std::path::PathBuf;
+
+// Exit code of a failure while running, e.g. the Arduino cannot be opened.
+pub const EXIT_RUNTIME_FAILURE: i32 = 1;
+
+// Exit code of an invalid or unreadable configuration.
+pub const EXIT_CONFIG_ERROR: i32 = 2;
+
+#[derive(Parser)]
+#[command(version, about = "Forwards Arduino sensor readings to InfluxDB")]
+pub struct Cli {
+    /// Configuration file; repeat the option to layer files, later ones overriding earlier ones.
+    /// Defaults to Settings.toml and, if present, Settings.local.toml in $SENSORFLOW_CONFIG_DIR
+    /// (settings/ when unset)
+    #[arg(long = "config", value_name = "PATH", global = true)]
+    pub config: Vec<PathBuf>,
+
+    /// Format of configuration files without a .toml, .yaml, .yml, or .json extension, such as
+    /// files mounted from a secret
+    #[arg(long, value_name = "FORMAT", global = true)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Configuration profile, e.g. dev or prod, layering Settings.<PROFILE>.toml and the
+    /// [profile.<PROFILE>] table over the settings; defaults to $SENSORFLOW_PROFILE
+    #[arg(long, value_name = "PROFILE", global = true)]
+    pub profile: Option<String>,
+
+    /// Log level (error, warn, info, debug, trace); overrides RUST_LOG
+    #[arg(long, value_name = "LEVEL", global = true)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Log the points instead of writing them to the sinks
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    pub fn config_source(&self) -> ConfigSource {
+        ConfigSource {
+            paths: self.config.clone(),
+            format: self.config_format.map(Into::into),
+            profile: self
+                .profile
+                .clone()
+                .or_else(|| env::var("SENSORFLOW_PROFILE").ok()),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl From<ConfigFormat> for FileFormat {
+    fn from(format: ConfigFormat) -> Self {
+        match format {
+            ConfigFormat::Toml => FileFormat::Toml,
+            ConfigFormat::Yaml => FileFormat::Yaml,
+            ConfigFormat::Json => FileFormat::Json,
+        }
+    }
+}
+
+#[derive(Subcommand, Clone, PartialEq)]
+pub enum Command {
+    /// Run the broker (the default)
+    Run,
+    /// Load and validate the configuration, then print it with secrets redacted
+    CheckConfig,
+    /// List the serial ports and whether they match the configured device
+    ListPorts,
+    /// Check once that a source sends frames that parse and that InfluxDB is up, then print a
+    /// report of every stage; exits with 1 when a stage failed
+    Selftest {
+        /// Source to check; defaults to the first source
+        #[arg(long, value_name = "NAME")]
+        source: Option<String>,
+
+        /// Seconds to wait for a valid frame
+        #[arg(long, value_name = "SECS", default_value_t = 10)]
+        frame_timeout: u64,
+
+        /// Also write a `selftest` point to InfluxDB
+        #[arg(long)]
+        write: bool,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Feed a recording of raw device lines through the pipeline to the configured sinks, then
+    /// print a summary
+    Replay {
+        /// Recording to replay
+        #[arg(value_name = "FILE")]
+        recording: PathBuf,
+
+        /// Source whose tags and parser settings apply; defaults to the first source
+        #[arg(long, value_name = "NAME")]
+        source: Option<String>,
+
+        /// Replay speed relative to the recording, e.g. 10 for ten times faster; 0 replays the
+        /// lines without waiting
+        #[arg(long, value_name = "FACTOR", default_value_t = 1.0)]
+        speed: f64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `--profile` wins over `SENSORFLOW_PROFILE`, which applies without it. The only test of
+    // the binary setting the variable, so it cannot race with another.
+    #[test]
+    fn the_profile_option_overrides_the_environment() {
+        let profile = |args: &[&str]| {
+            Cli::parse_from([&["aero-sensor-broker"], args].concat())
+                .config_source()
+                .profile
+        };
+        env::remove_var("SENSORFLOW_PROFILE");
+        assert_eq!(profile(&[]), None);
+        assert_eq!(profile(&["--profile", "dev"]).as_deref(), Some("dev"));
+
+        env::set_var("SENSORFLOW_PROFILE", "prod");
+        assert_eq!(profile(&[]).as_deref(), Some("prod"));
+        assert_eq!(profile(&["--profile", "dev"]).as_deref(), Some("dev"));
+        assert_eq!(
+            profile(&["check-config", "--profile", "dev"]).as_deref(),
+            Some("dev")
+        );
+        env::remove_var("SENSORFLOW_PROFILE");
+    }
+}
@@ -0,0 +1,80 @@
+// clock.rs
+//
+// The clocks the time-dependent parts of the pipeline read: the wall clock, which stamps points
+// and ages readings, and the monotonic clock, which times windows and flushes. They are read
+// through `Clock` so that window rollover, staleness, and clock jumps can be driven by a
+// `MockClock` advanced by hand instead of by real sleeps. Everything uses the system clock unless
+// given another one.
+
+use crate::errors::AppError;
+
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now_utc(&self) -> DateTime<Utc>;
+
+    fn now_monotonic(&self) -> Instant;
+}
+
+// The clocks of the host.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn now_monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// The wall clock in Unix nanoseconds, which stop fitting in an `i64` in 2262.
+pub fn now_nanos(clock: &dyn Clock) -> Result<i64, AppError> {
+    clock.now_utc().timestamp_nanos_opt().ok_or_else(|| {
+        AppError::Runtime("the wall clock is past the range of timestamps".to_string())
+    })
+}
+
+// Clocks that only move when told to.
+pub struct MockClock {
+    now: Mutex<(DateTime<Utc>, Instant)>,
+}
+
+impl MockClock {
+    // Starts at `utc` on the wall clock and at the current instant on the monotonic one.
+    pub fn new(utc: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new((utc, Instant::now())),
+        }
+    }
+
+    // Moves both clocks forward, as time passing.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        now.0 += chrono::Duration::from_std(by).unwrap_or(chrono::Duration::MAX);
+        now.1 += by;
+    }
+
+    // Moves the wall clock alone, forwards or backwards, as a suspend or a clock step does.
+    pub fn jump(&self, by: chrono::Duration) {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        now.0 += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.now.lock().unwrap_or_else(PoisonError::into_inner).0
+    }
+
+    fn now_monotonic(&self) -> Instant {
+        self.now.lock().unwrap_or_else(PoisonError::into_inner).1
+    }
+}
@@ -0,0 +1,212 @@
+// clock_skew.rs
+//
+// Detects and corrects drift between the device clock and the host clock. Device-supplied
+// timestamps are compared with the time the frame was received; when the difference exceeds
+// the configured threshold the timestamp is rewritten, shifted by a continuously estimated
+// offset, or the sample is dropped. The observed skew is summarized once per aggregation
+// window as a `clock_skew_ms` point so drift can be graphed.
+
+use crate::clock::{self, Clock};
+use crate::config::{ClockSkewMode, ParserConfig};
+
+use influxdb2::models::DataPoint;
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// Weight of the newest observation in the moving offset estimate.
+const OFFSET_SMOOTHING: f64 = 0.1;
+
+/// Measurement carrying the per-window skew summary.
+const CLOCK_SKEW_MEASUREMENT: &str = "clock_skew_ms";
+
+#[derive(Default)]
+struct SkewWindow {
+    samples: i64,
+    sum_ms: i64,
+    max_abs_ms: i64,
+    corrected: i64,
+    dropped: i64,
+}
+
+pub struct ClockSkewCorrector {
+    threshold_ms: i64,
+    mode: ClockSkewMode,
+    // Moving average of (host - device) in milliseconds.
+    estimated_offset_ms: Option<f64>,
+    window: SkewWindow,
+    clock: Arc<dyn Clock>,
+}
+
+impl ClockSkewCorrector {
+    pub fn new(config: &ParserConfig) -> Self {
+        Self {
+            threshold_ms: (config.clock_skew_threshold_secs * 1000) as i64,
+            mode: config.clock_skew_mode,
+            estimated_offset_ms: None,
+            window: SkewWindow::default(),
+            clock: clock::system(),
+        }
+    }
+
+    // Stamps the window summaries with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Applies reloaded settings, keeping the offset estimated so far.
+    pub fn reconfigure(&mut self, config: &ParserConfig) {
+        self.threshold_ms = (config.clock_skew_threshold_secs * 1000) as i64;
+        self.mode = config.clock_skew_mode;
+    }
+
+    // Checks a device timestamp against the host clock. Returns the timestamp to use, in
+    // nanoseconds, or `None` when the sample must be dropped.
+    pub fn correct(&mut self, device_ns: i64, host_ns: i64) -> Option<i64> {
+        // Saturates rather than overflows, a timestamp that far off is out of threshold anyway
+        let skew_ms = host_ns.saturating_sub(device_ns) / 1_000_000;
+        let estimate = match self.estimated_offset_ms {
+            Some(estimate) => estimate + OFFSET_SMOOTHING * (skew_ms as f64 - estimate),
+            None => skew_ms as f64,
+        };
+        self.estimated_offset_ms = Some(estimate);
+
+        self.window.samples += 1;
+        self.window.sum_ms = self.window.sum_ms.saturating_add(skew_ms);
+        self.window.max_abs_ms = self.window.max_abs_ms.max(skew_ms.abs());
+
+        if skew_ms.abs() <= self.threshold_ms {
+            return Some(device_ns);
+        }
+
+        debug!(
+            "Device clock off by {} ms (estimated offset {:.0} ms)",
+            skew_ms, estimate
+        );
+        match self.mode {
+            ClockSkewMode::Host => {
+                self.window.corrected += 1;
+                Some(host_ns)
+            }
+            ClockSkewMode::Offset => {
+                self.window.corrected += 1;
+                Some(device_ns.saturating_add((estimate * 1_000_000.0) as i64))
+            }
+            ClockSkewMode::Drop => {
+                self.window.dropped += 1;
+                warn!(
+                    "Dropping sample with device timestamp {} ms off the host clock",
+                    skew_ms
+                );
+                None
+            }
+        }
+    }
+
+    // Summarizes the skew observed since the previous call, if any device timestamp was seen.
+    pub fn window_point(&mut self, tags: &BTreeMap<String, String>) -> Option<DataPoint> {
+        let window = std::mem::take(&mut self.window);
+        if window.samples == 0 {
+            return None;
+        }
+
+        let builder = DataPoint::builder(CLOCK_SKEW_MEASUREMENT)
+            .field("value", window.sum_ms / window.samples)
+            .field("max_abs", window.max_abs_ms)
+            .field("corrected", window.corrected)
+            .field("dropped", window.dropped)
+            .timestamp(clock::now_nanos(self.clock.as_ref()).ok()?);
+
+        tags.iter()
+            .fold(builder, |builder, (key, value)| builder.tag(key, value))
+            .build()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-11-14T22:13:20Z
+    const HOST_NS: i64 = 1_700_000_000_000_000_000;
+    const MS: i64 = 1_000_000;
+
+    fn corrector(mode: ClockSkewMode) -> ClockSkewCorrector {
+        ClockSkewCorrector::new(&ParserConfig {
+            clock_skew_threshold_secs: 30,
+            clock_skew_mode: mode,
+            ..ParserConfig::default()
+        })
+    }
+
+    #[test]
+    fn a_skew_just_inside_the_threshold_keeps_the_device_timestamp() {
+        for mode in [
+            ClockSkewMode::Host,
+            ClockSkewMode::Offset,
+            ClockSkewMode::Drop,
+        ] {
+            let mut skew = corrector(mode);
+            let behind = HOST_NS - 29_999 * MS;
+            let ahead = HOST_NS + 29_999 * MS;
+            assert_eq!(skew.correct(behind, HOST_NS), Some(behind));
+            assert_eq!(skew.correct(ahead, HOST_NS), Some(ahead));
+            // The threshold itself is still within
+            let at_threshold = HOST_NS - 30_000 * MS;
+            assert_eq!(skew.correct(at_threshold, HOST_NS), Some(at_threshold));
+        }
+    }
+
+    #[test]
+    fn a_skew_just_outside_the_threshold_is_replaced_by_the_host_clock() {
+        let mut skew = corrector(ClockSkewMode::Host);
+        assert_eq!(skew.correct(HOST_NS - 30_001 * MS, HOST_NS), Some(HOST_NS));
+        assert_eq!(skew.correct(HOST_NS + 30_001 * MS, HOST_NS), Some(HOST_NS));
+    }
+
+    #[test]
+    fn a_skew_just_outside_the_threshold_is_dropped() {
+        let mut skew = corrector(ClockSkewMode::Drop);
+        assert_eq!(skew.correct(HOST_NS - 30_001 * MS, HOST_NS), None);
+        assert_eq!(skew.correct(HOST_NS + 30_001 * MS, HOST_NS), None);
+    }
+
+    #[test]
+    fn a_skew_just_outside_the_threshold_is_shifted_by_the_offset() {
+        let mut skew = corrector(ClockSkewMode::Offset);
+        // The first observation is the whole estimate
+        let device = HOST_NS - 30_001 * MS;
+        assert_eq!(skew.correct(device, HOST_NS), Some(HOST_NS));
+    }
+
+    #[test]
+    fn a_timestamp_at_the_end_of_the_range_does_not_overflow() {
+        let mut skew = corrector(ClockSkewMode::Offset);
+        let corrected = skew.correct(i64::MAX, HOST_NS).unwrap();
+        assert!((corrected - HOST_NS).abs() < MS, "{}", corrected);
+        assert!(skew.correct(i64::MIN, HOST_NS).is_some());
+        assert!(skew.window_point(&BTreeMap::new()).is_some());
+
+        let mut skew = corrector(ClockSkewMode::Host);
+        assert_eq!(skew.correct(i64::MAX, i64::MIN), Some(i64::MIN));
+    }
+
+    #[test]
+    fn the_window_point_counts_what_was_corrected_and_dropped() {
+        let host = chrono::DateTime::from_timestamp(HOST_NS / 1_000_000_000, 0).unwrap();
+        let clock = Arc::new(crate::clock::MockClock::new(host));
+        let mut skew = corrector(ClockSkewMode::Drop).with_clock(clock);
+        skew.correct(HOST_NS - 29_999 * MS, HOST_NS);
+        skew.correct(HOST_NS - 30_001 * MS, HOST_NS);
+
+        let point = skew.window_point(&BTreeMap::new()).unwrap();
+        assert_eq!(
+            crate::line_protocol::render(&point),
+            "clock_skew_ms corrected=0i,dropped=1i,max_abs=30001i,value=30000i 1700000000000000000"
+        );
+        // Nothing seen since
+        assert!(skew.window_point(&BTreeMap::new()).is_none());
+    }
+}
@@ -0,0 +1,232 @@
+// coalesce.rs
+//
+// Coalesces the points of a flush to one per series, for when the aggregation window is shorter
+// than the flush interval and a single point per series and flush is preferred to one per window.
+// `last` keeps the latest point of each series. `mean` averages the points of each series again,
+// weighting each by the samples it summarizes, so that the result is the average of all the
+// samples of the flush; it relies on the `count` field (`aggregation.sample_count = "field"`),
+// and series whose points lack it are written as they are. Points that cannot be decoded are
+// never coalesced.
+
+use crate::config::CoalesceMode;
+use crate::line_protocol::{decode, DecodedPoint};
+
+use influxdb2::models::data_point::DataPointBuilder;
+use influxdb2::models::{DataPoint, FieldValue};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+
+// The field an averaged point carries its number of samples in.
+const COUNT_FIELD: &str = "count";
+
+type SeriesKey = (String, BTreeMap<String, String>);
+
+// The points with one per series, in the order the series first appear in `points`.
+pub fn coalesce(points: Vec<DataPoint>, mode: CoalesceMode) -> Vec<DataPoint> {
+    if mode == CoalesceMode::Off {
+        return points;
+    }
+
+    // The points of each series, in the order the series first appear
+    let mut entries: Vec<Vec<(DataPoint, DecodedPoint)>> = Vec::new();
+    let mut passed_through = Vec::new();
+    let mut series_index: HashMap<SeriesKey, usize> = HashMap::new();
+    for point in points {
+        let Some(decoded) = decode(&point) else {
+            passed_through.push(point);
+            continue;
+        };
+        let key = (decoded.measurement.clone(), decoded.tags.clone());
+        let index = *series_index.entry(key).or_insert_with(|| {
+            entries.push(Vec::new());
+            entries.len() - 1
+        });
+        entries[index].push((point, decoded));
+    }
+
+    let mut coalesced = passed_through;
+    for series in entries {
+        match mode {
+            CoalesceMode::Last => coalesced.extend(last(series)),
+            _ => coalesced.extend(mean(series)),
+        }
+    }
+    coalesced
+}
+
+// The point with the latest timestamp, the last one given among those without.
+fn last(series: Vec<(DataPoint, DecodedPoint)>) -> Option<DataPoint> {
+    series
+        .into_iter()
+        .enumerate()
+        .max_by_key(|(index, (_, decoded))| (decoded.timestamp, *index))
+        .map(|(_, (point, _))| point)
+}
+
+// A single point averaging the float fields of the series weighted by `count`, the counts
+// summed, at the weighted average timestamp. The other fields are taken from the latest point.
+// The points are returned as they are when any of them lacks a count.
+fn mean(series: Vec<(DataPoint, DecodedPoint)>) -> Vec<DataPoint> {
+    if series.len() < 2 {
+        return series.into_iter().map(|(point, _)| point).collect();
+    }
+    let counts: Option<Vec<i64>> = series
+        .iter()
+        .map(|(_, decoded)| match decoded.fields.get(COUNT_FIELD) {
+            Some(FieldValue::I64(count)) if *count >= 0 => Some(*count),
+            _ => None,
+        })
+        .collect();
+    let Some(counts) = counts else {
+        return series.into_iter().map(|(point, _)| point).collect();
+    };
+
+    let decoded: Vec<&DecodedPoint> = series.iter().map(|(_, decoded)| decoded).collect();
+    match weighted_mean(&decoded, &counts).build() {
+        Ok(point) => vec![point],
+        Err(e) => {
+            warn!(
+                "Failed to coalesce the points of {}, writing them as they are: {}",
+                decoded[0].measurement, e
+            );
+            series.into_iter().map(|(point, _)| point).collect()
+        }
+    }
+}
+
+fn weighted_mean(points: &[&DecodedPoint], counts: &[i64]) -> DataPointBuilder {
+    let total: i64 = counts.iter().sum();
+    let latest = points
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, point)| (point.timestamp, *index))
+        .map(|(_, point)| *point)
+        .unwrap_or(points[points.len() - 1]);
+
+    // Each float field is averaged over the points carrying it
+    let mut sums: BTreeMap<&str, (f64, i64)> = BTreeMap::new();
+    for (point, &count) in points.iter().zip(counts) {
+        for (name, value) in &point.fields {
+            if let FieldValue::F64(value) = value {
+                let (sum, weight) = sums.entry(name.as_str()).or_insert((0.0, 0));
+                *sum += value * count as f64;
+                *weight += count;
+            }
+        }
+    }
+
+    // Timestamps are averaged the same way, in 128 bits so that the products cannot overflow
+    let timestamp = {
+        let weighted: Option<(i128, i128)> = points.iter().zip(counts).try_fold(
+            (0_i128, 0_i128),
+            |(sum, weight), (point, &count)| {
+                point.timestamp.map(|timestamp| {
+                    (
+                        sum + timestamp as i128 * count as i128,
+                        weight + count as i128,
+                    )
+                })
+            },
+        );
+        match weighted {
+            Some((sum, weight)) if weight > 0 => Some((sum / weight) as i64),
+            _ => latest.timestamp,
+        }
+    };
+
+    let mut builder = DataPoint::builder(latest.measurement.as_str());
+    for (key, value) in &latest.tags {
+        builder = builder.tag(key, value);
+    }
+    for (name, value) in &latest.fields {
+        if name != COUNT_FIELD && !matches!(value, FieldValue::F64(_)) {
+            builder = builder.field(name, value.clone());
+        }
+    }
+    for (name, (sum, weight)) in sums {
+        // Series without any sample in the flush only keep their count
+        if weight > 0 {
+            builder = builder.field(name, sum / weight as f64);
+        }
+    }
+    builder = builder.field(COUNT_FIELD, total);
+    match timestamp {
+        Some(timestamp) => builder.timestamp(timestamp),
+        None => builder,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line_protocol::render;
+
+    fn point(measurement: &str, value: f64, count: Option<i64>, timestamp: i64) -> DataPoint {
+        let builder = DataPoint::builder(measurement)
+            .tag("rack", "a1")
+            .field("value", value)
+            .field("unit", format!("unit-{}", timestamp));
+        let builder = match count {
+            Some(count) => builder.field(COUNT_FIELD, count),
+            None => builder,
+        };
+        builder.timestamp(timestamp).build().unwrap()
+    }
+
+    fn lines(points: &[DataPoint]) -> Vec<String> {
+        points.iter().map(render).collect()
+    }
+
+    #[test]
+    fn the_mean_weights_each_point_by_its_count() {
+        let points = vec![
+            point("temperature", 20.0, Some(3), 1000),
+            point("humidity", 40.0, Some(2), 1500),
+            point("temperature", 24.0, Some(1), 2000),
+        ];
+
+        let coalesced = coalesce(points, CoalesceMode::Mean);
+
+        // (3 × 20 + 1 × 24) / 4, at (3 × 1000 + 1 × 2000) / 4, the other fields of the latest
+        assert_eq!(
+            lines(&coalesced),
+            [
+                "temperature,rack=a1 count=4i,unit=\"unit-2000\",value=21 1250",
+                "humidity,rack=a1 count=2i,unit=\"unit-1500\",value=40 1500",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_series_with_a_point_without_a_count_is_not_averaged() {
+        let points = vec![
+            point("temperature", 20.0, Some(3), 1000),
+            point("temperature", 24.0, None, 2000),
+        ];
+
+        let coalesced = coalesce(points.clone(), CoalesceMode::Mean);
+
+        assert_eq!(lines(&coalesced), lines(&points));
+    }
+
+    #[test]
+    fn last_keeps_the_latest_point_of_each_series() {
+        let points = vec![
+            point("temperature", 24.0, Some(1), 2000),
+            point("humidity", 40.0, None, 1500),
+            point("temperature", 20.0, Some(3), 1000),
+            point("humidity", 41.0, None, 1500),
+        ];
+
+        let coalesced = coalesce(points, CoalesceMode::Last);
+
+        // The latest by timestamp, not by position; the later one given on a tie
+        assert_eq!(
+            lines(&coalesced),
+            [
+                "temperature,rack=a1 count=1i,unit=\"unit-2000\",value=24 2000",
+                "humidity,rack=a1 unit=\"unit-1500\",value=41 1500",
+            ]
+        );
+    }
+}
@@ -1,30 +1,2308 @@
-use config::{Config, File};
-use serde::Deserialize;
+use config::{Config, Environment, File, FileFormat};
+use log::{info, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSettings {
     pub influxdb: InfluxDBConfig,
-    pub arduino: ArduinoConfig,
+    // Devices the readings come from, each feeding its own pipeline branch.
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
+    // Single-device configuration of earlier releases, read as a source named "arduino".
+    pub arduino: Option<ArduinoConfig>,
+    #[serde(default)]
+    pub parser: ParserConfig,
+    #[serde(default)]
+    pub aggregation: AggregationConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub raw: RawConfig,
+    pub dead_letter: Option<DeadLetterConfig>,
+    // Keep every raw line read from the sources on disk, in the format `replay` reads.
+    pub record: Option<RecordConfig>,
+    // Outputs every flushed batch is written to: "influxdb", "mqtt", and/or "file".
+    #[serde(default = "default_sinks")]
+    pub sinks: Vec<String>,
+    pub mqtt: Option<MqttConfig>,
+    pub file: Option<FileSinkConfig>,
+    // Log the line protocol of every flushed point instead of writing to the sinks.
+    #[serde(default)]
+    pub dry_run: bool,
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    // Tag every point with the version and commit of the broker that produced it.
+    #[serde(default)]
+    pub build_tags: bool,
+    // Static tags added to every point, e.g. `location`, `site`, or `rack`. The
+    // `CLUSTER_DISPLAY_NAME` environment variable overrides `location`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    // Constant fields added to every averaged and raw point, e.g. `site_altitude_m`, for the
+    // queries that need them as values rather than tag strings.
+    #[serde(default)]
+    pub fields: BTreeMap<String, StaticField>,
+    // Refuse to start when no location is configured instead of tagging points "Default".
+    #[serde(default)]
+    pub require_location: bool,
+    // Unit, display name, description, and decimals of each measurement, served by
+    // `/api/sensors` and inline by `/api/latest`.
+    #[serde(default)]
+    pub sensors: BTreeMap<String, SensorMetadata>,
+    // Tag the points of a measurement with the unit of its `[sensors]` entry.
+    #[serde(default)]
+    pub unit_tag: bool,
+    // Measurements whose points are dropped as they are parsed, until enabled through
+    // `/admin/measurements`.
+    #[serde(default)]
+    pub disabled_measurements: Vec<String>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    // Export traces of the flush and write path over OTLP; disabled without the section.
+    pub tracing: Option<TracingConfig>,
+    // Profile the settings were loaded with (`--profile` or `SENSORFLOW_PROFILE`); a
+    // `[profile.<name>]` table in the files holds the overrides of a profile, not its name.
+    #[serde(skip_deserializing)]
+    pub active_profile: Option<String>,
+    // Tag every point with the active profile.
+    #[serde(default)]
+    pub profile_tag: bool,
+    // Give up when the sources and InfluxDB are not all up this long after startup; 0 keeps
+    // retrying forever.
+    #[serde(default = "default_startup_deadline_secs")]
+    pub startup_deadline_secs: u64,
+    // File recording the last panic, reported and removed on the next start.
+    #[serde(default = "default_crash_marker")]
+    pub crash_marker: String,
+    // File the state of the run is kept in, to tell the downtime on the next start; next to the
+    // first configuration file when unset.
+    #[serde(default)]
+    pub state_file: String,
 }
 
-#[derive(Deserialize)]
+fn default_startup_deadline_secs() -> u64 {
+    300
+}
+
+fn default_crash_marker() -> String {
+    "aero-sensor-broker.crash".to_string()
+}
+
+fn default_sinks() -> Vec<String> {
+    vec!["influxdb".to_string()]
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct InfluxDBConfig {
     pub url: String,
     pub bucket: String,
     pub org: String,
-    pub auth_token: String,
+    // The token can also come from an environment variable or a file (see `resolve_secret`).
+    pub auth_token: Option<Secret<String>>,
+    pub auth_token_env: Option<String>,
+    pub auth_token_file: Option<String>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    // Upper bound of a single write request; a request that takes longer is retried.
+    #[serde(default = "default_write_timeout_secs")]
+    pub write_timeout_secs: u64,
+    // Upper bound of health checks and of the startup bucket validation requests.
+    #[serde(default = "default_health_timeout_secs")]
+    pub health_timeout_secs: u64,
+    // How long a health check result is reused before InfluxDB is asked again.
+    #[serde(default = "default_health_cache_ttl_secs")]
+    pub health_cache_ttl_secs: u64,
+    // How long a probe waits for the very first health check, before anything is cached.
+    #[serde(default = "default_health_first_check_wait_ms")]
+    pub health_first_check_wait_ms: u64,
+    // Endpoints tried in order when the primary fails with a transient error. After a failover
+    // writes stay on the fallback for `failover_stickiness_secs` before the primary is retried.
+    #[serde(default)]
+    pub fallbacks: Vec<InfluxDBEndpointConfig>,
+    #[serde(default = "default_failover_stickiness_secs")]
+    pub failover_stickiness_secs: u64,
+    // InfluxDB is probed at startup for `startup_grace_secs`. When it does not answer by then,
+    // the broker gives up if `require_at_startup` is set, and otherwise starts offline, caching
+    // the points until InfluxDB is reachable.
+    #[serde(default)]
+    pub require_at_startup: bool,
+    #[serde(default = "default_startup_grace_secs")]
+    pub startup_grace_secs: u64,
+    // Create missing buckets at startup (dev setups), with this retention; 0 keeps data forever.
+    #[serde(default)]
+    pub create_bucket_if_missing: bool,
+    #[serde(default)]
+    pub bucket_retention_secs: u64,
+    // How the startup validation checks that the token may write the buckets.
+    #[serde(default)]
+    pub preflight: Preflight,
+    // Upper bound of the whole startup validation. When it runs out the validation is skipped,
+    // and `/health` reports the preflight as skipped.
+    #[serde(default = "default_validation_timeout_secs")]
+    pub validation_timeout_secs: u64,
+    // Measurement name to bucket. A "*" entry replaces `bucket` as the default for
+    // measurements without their own route.
+    #[serde(default)]
+    pub bucket_routing: BTreeMap<String, String>,
+    // Gzip write bodies of at least `gzip_min_bytes`; smaller batches are sent as is.
+    #[serde(default)]
+    pub gzip: bool,
+    #[serde(default = "default_gzip_min_bytes")]
+    pub gzip_min_bytes: usize,
+    // TLS: extra CA certificate (PEM), client certificate and key (PEM) for mutual TLS, and an
+    // escape hatch that disables certificate verification altogether.
+    pub ca_cert_path: Option<String>,
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+impl InfluxDBConfig {
+    pub fn auth_token(&self) -> Result<String, String> {
+        resolve_secret(
+            "influxdb.auth_token",
+            self.auth_token.as_ref().map(Secret::as_str),
+            self.auth_token_env.as_deref(),
+            self.auth_token_file.as_deref(),
+        )
+    }
+}
+
+// How the token is checked for write permission on the buckets at startup.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Preflight {
+    // The permissions of the token are read from `/api/v2/authorizations`.
+    #[default]
+    Authorizations,
+    // A point is written to the `aero_preflight` measurement of every bucket, for tokens that
+    // cannot read their authorizations.
+    TrialWrite,
+    // Not checked, for locked-down environments.
+    Off,
+}
+
+// The value of a `[fields]` entry, typed as written in the configuration: `7` is an integer,
+// `7.0` a float.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum StaticField {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfluxDBEndpointConfig {
+    pub url: String,
+    pub org: String,
+    // Replaces the default bucket; routed buckets keep their names.
+    pub bucket: String,
+    pub auth_token: Option<Secret<String>>,
+    pub auth_token_env: Option<String>,
+    pub auth_token_file: Option<String>,
+}
+
+impl InfluxDBEndpointConfig {
+    pub fn auth_token(&self) -> Result<String, String> {
+        resolve_secret(
+            &format!("auth_token of fallback {}", self.url),
+            self.auth_token.as_ref().map(Secret::as_str),
+            self.auth_token_env.as_deref(),
+            self.auth_token_file.as_deref(),
+        )
+    }
+}
+
+fn default_failover_stickiness_secs() -> u64 {
+    300
+}
+
+fn default_startup_grace_secs() -> u64 {
+    60
+}
+
+fn default_write_timeout_secs() -> u64 {
+    10
+}
+
+fn default_health_timeout_secs() -> u64 {
+    5
+}
+
+fn default_validation_timeout_secs() -> u64 {
+    20
+}
+
+fn default_gzip_min_bytes() -> usize {
+    1024
+}
+
+// Retry policy for InfluxDB writes. Transient failures are retried with exponential backoff
+// and jitter until `max_attempts` is reached or the whole call exceeds `deadline_secs`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetryConfig {
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_retry_deadline_secs")]
+    pub deadline_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff_ms: default_retry_initial_backoff_ms(),
+            max_backoff_ms: default_retry_max_backoff_ms(),
+            deadline_secs: default_retry_deadline_secs(),
+        }
+    }
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    10_000
 }
 
-#[derive(Deserialize)]
+fn default_retry_deadline_secs() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ArduinoConfig {
+    #[serde(default = "default_baud_rate")]
     pub baud_rate: u32,
+    // Serial read timeout in milliseconds.
+    #[serde(default = "default_serial_timeout_ms")]
     pub timeout: u64,
+    // USB product name of the device; only serial sources have one.
+    #[serde(default)]
     pub device_name: String,
+    // How long a health check result is reused before the device is pinged again.
+    #[serde(default = "default_health_cache_ttl_secs")]
+    pub health_cache_ttl_secs: u64,
+    // How long a probe waits for the very first health check, before anything is cached.
+    #[serde(default = "default_health_first_check_wait_ms")]
+    pub health_first_check_wait_ms: u64,
+    // Read errors and rejected frames in a row after which the broker gives up and exits, so
+    // that it gets restarted; 0 never gives up.
+    #[serde(default = "default_max_consecutive_errors")]
+    pub max_consecutive_errors: u32,
+    // Bytes taken from the port at a time, into a buffer reused for every read.
+    #[serde(default = "default_read_chunk_bytes")]
+    pub read_chunk_bytes: usize,
+    // Lines longer than this are discarded, and counted as oversized frames.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    // Bytes waiting in the input buffer of the port beyond which it is cleared unread.
+    #[serde(default = "default_max_input_bytes")]
+    pub max_input_bytes: usize,
+}
+
+// A device the broker reads frames from. Its tags are added to those of `[tags]`, overriding
+// them, and every point is tagged `source` with its name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SourceConfig {
+    pub name: String,
+    #[serde(default)]
+    pub kind: SourceKind,
+    #[serde(flatten)]
+    pub serial: ArduinoConfig,
+    // Frames a `simulated` source generates, the defaults when unset.
+    pub simulation: Option<SimulationConfig>,
+    // Parser settings of this source instead of `[parser]`; changing them requires a restart.
+    pub parser: Option<ParserConfig>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    // Caps the frames parsed, and optionally the points kept, per second; unlimited when unset.
+    pub ingest_limit: Option<IngestLimitConfig>,
+}
+
+// The frames of a source beyond `frames_per_second` are not parsed: they are dropped, or one in
+// `sample_one_in` of them is parsed. The points beyond `points_per_measurement_per_second` of a
+// measurement, after parsing, are dropped; 0 keeps them all.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IngestLimitConfig {
+    pub frames_per_second: u32,
+    #[serde(default)]
+    pub overflow: IngestOverflow,
+    #[serde(default = "default_sample_one_in")]
+    pub sample_one_in: u32,
+    #[serde(default)]
+    pub points_per_measurement_per_second: u32,
+}
+
+fn default_sample_one_in() -> u32 {
+    10
+}
+
+// What becomes of the frames beyond the cap.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestOverflow {
+    #[default]
+    Drop,
+    Sample,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    // An Arduino on a USB serial port, found by its product name.
+    #[default]
+    Serial,
+    // Synthetic frames, to run the pipeline without hardware.
+    Simulated,
+}
+
+// Frames generated by a `simulated` source: one JSON frame per interval, with an item per
+// measurement.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SimulationConfig {
+    #[serde(default = "default_simulation_interval_ms")]
+    pub interval_ms: u64,
+    // Seeds the random generator, so that every run generates the same values and malformed
+    // frames; a random seed is used when unset.
+    pub seed: Option<u64>,
+    // Share of frames, from 0 to 1, replaced with a malformed frame to exercise rejection.
+    #[serde(default)]
+    pub malformed_ratio: f64,
+    #[serde(default = "default_simulated_measurements")]
+    pub measurements: Vec<SimulatedMeasurement>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: default_simulation_interval_ms(),
+            seed: None,
+            malformed_ratio: 0.0,
+            measurements: default_simulated_measurements(),
+        }
+    }
+}
+
+// A measurement of a simulated source and the range of its values.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SimulatedMeasurement {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    #[serde(default)]
+    pub pattern: SimulationPattern,
+    // Seconds a sine wave takes to go through the whole range and back.
+    #[serde(default = "default_simulation_period_secs")]
+    pub period_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SimulationPattern {
+    // Uniformly distributed values.
+    #[default]
+    Random,
+    // A sine wave over the range, with a little noise.
+    Sine,
+}
+
+fn default_simulation_interval_ms() -> u64 {
+    1000
+}
+
+fn default_simulation_period_secs() -> u64 {
+    600
+}
+
+// The readings of the bundled sketch.
+fn default_simulated_measurements() -> Vec<SimulatedMeasurement> {
+    let measurement = |name: &str, min, max, pattern| SimulatedMeasurement {
+        name: name.to_string(),
+        min,
+        max,
+        pattern,
+        period_secs: default_simulation_period_secs(),
+    };
+    vec![
+        measurement("temperature", 18.0, 26.0, SimulationPattern::Sine),
+        measurement("humidity", 30.0, 60.0, SimulationPattern::Sine),
+        measurement("air_quality", 0.0, 200.0, SimulationPattern::Random),
+    ]
+}
+
+// Name of the source the legacy `[arduino]` section becomes.
+const LEGACY_SOURCE: &str = "arduino";
+
+fn default_baud_rate() -> u32 {
+    115200
+}
+
+fn default_serial_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_health_cache_ttl_secs() -> u64 {
+    15
+}
+
+fn default_health_first_check_wait_ms() -> u64 {
+    2000
+}
+
+fn default_max_consecutive_errors() -> u32 {
+    20
+}
+
+fn default_read_chunk_bytes() -> usize {
+    1024
+}
+
+fn default_max_frame_bytes() -> usize {
+    4096
 }
 
-pub fn load_settings() -> Result<ConfigSettings, config::ConfigError> {
-    Config::builder()
-        .add_source(File::with_name("settings/Settings.toml"))
+fn default_max_input_bytes() -> usize {
+    65536
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ParserConfig {
+    // Value some firmware revisions report when a probe is unplugged (e.g. 99999.9).
+    // Readings equal to it are treated as missing, just like NaN and infinity.
+    #[serde(default)]
+    pub missing_sentinel: Option<f64>,
+    // Maximum nesting of object-valued readings; deeper objects are rejected.
+    #[serde(default = "default_max_nesting_depth")]
+    pub max_nesting_depth: usize,
+    // Skip the items of a JSON frame that cannot be decoded instead of rejecting the frame.
+    #[serde(default)]
+    pub lenient: bool,
+    // Maximum accepted difference between a device timestamp and the host clock.
+    #[serde(default = "default_clock_skew_threshold_secs")]
+    pub clock_skew_threshold_secs: u64,
+    #[serde(default)]
+    pub clock_skew_mode: ClockSkewMode,
+    // Device timestamps before this Unix time (2020-01-01 by default) come from a clock that was
+    // never set, e.g. seconds since boot, and are replaced with the host clock.
+    #[serde(default = "default_device_time_floor_secs")]
+    pub device_time_floor_secs: i64,
+}
+
+// What to do with a device timestamp that is too far off the host clock.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockSkewMode {
+    // Replace it with the time the frame was received.
+    #[default]
+    Host,
+    // Shift it by the estimated offset between the device and host clocks.
+    Offset,
+    // Discard the sample.
+    Drop,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            missing_sentinel: None,
+            max_nesting_depth: default_max_nesting_depth(),
+            lenient: false,
+            clock_skew_threshold_secs: default_clock_skew_threshold_secs(),
+            clock_skew_mode: ClockSkewMode::default(),
+            device_time_floor_secs: default_device_time_floor_secs(),
+        }
+    }
+}
+
+fn default_clock_skew_threshold_secs() -> u64 {
+    30
+}
+
+fn default_device_time_floor_secs() -> i64 {
+    1_577_836_800
+}
+
+fn default_max_nesting_depth() -> usize {
+    2
+}
+
+// How the number of samples behind each averaged point is reported.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleCountMode {
+    #[default]
+    Off,
+    // A companion point in the `sample_count` measurement for every series.
+    Measurement,
+    // A `count` field on the averaged point itself.
+    Field,
+}
+
+// Tag of the points of the `sample_count` measurement naming the measurement counted, which no
+// other tag may be named like.
+pub const SAMPLE_COUNT_MEASUREMENT_TAG: &str = "measurement";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregationConfig {
+    // Length of the windows parsed samples are averaged over.
+    #[serde(default = "default_aggregation_window_secs")]
+    pub window_secs: u64,
+    #[serde(default)]
+    pub sample_count: SampleCountMode,
+    // Number of windows a series is remembered after its last sample, emitting a count of 0
+    // for each of them.
+    #[serde(default = "default_series_memory_windows")]
+    pub series_memory_windows: u32,
+    // Measurement name to the change of its average below which a window is not written; the
+    // measurements left out are always written.
+    #[serde(default)]
+    pub deadband: BTreeMap<String, f64>,
+    // Longest a series within its deadband goes without a point.
+    #[serde(default = "default_max_suppression_secs")]
+    pub max_suppression_secs: u64,
+    // Jump of the wall clock, past what the monotonic clock advanced (e.g. after a suspend),
+    // beyond which the window open at the time is ended; 0 turns the check off.
+    #[serde(default = "default_time_jump_secs")]
+    pub time_jump_secs: u64,
+    #[serde(default)]
+    pub on_time_jump: TimeJumpAction,
+    // Measurement name to the type its averages are written as, so that InfluxDB never sees a
+    // field change type; the measurements left out are written as floats.
+    #[serde(default)]
+    pub field_types: BTreeMap<String, FieldType>,
+    // Measurement name to how its samples are averaged; the measurements left out use the mean.
+    #[serde(default)]
+    pub reducers: BTreeMap<String, Reducer>,
+}
+
+// How the samples of a series are averaged over a window.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Reducer {
+    // Every sample weighs the same.
+    #[default]
+    Mean,
+    // Every sample weighs the time until the next one, the last one until the end of the window.
+    TimeWeightedMean,
+}
+
+// Type of the fields of a measurement, once written.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    #[default]
+    Float,
+    // Rounded to the nearest integer.
+    Int,
+    // Averages of exactly 0 or 1 only.
+    Bool,
+    String,
+}
+
+// What becomes of the window open when the wall clock jumps.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeJumpAction {
+    // Its points are averaged on their own, at their own timestamps.
+    #[default]
+    Close,
+    // Its points are dropped.
+    Discard,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: default_aggregation_window_secs(),
+            sample_count: SampleCountMode::default(),
+            series_memory_windows: default_series_memory_windows(),
+            deadband: BTreeMap::new(),
+            max_suppression_secs: default_max_suppression_secs(),
+            time_jump_secs: default_time_jump_secs(),
+            on_time_jump: TimeJumpAction::default(),
+            field_types: BTreeMap::new(),
+            reducers: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_aggregation_window_secs() -> u64 {
+    60
+}
+
+fn default_series_memory_windows() -> u32 {
+    5
+}
+
+fn default_max_suppression_secs() -> u64 {
+    600
+}
+
+fn default_time_jump_secs() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheConfig {
+    // Points kept while waiting for a flush; the oldest are dropped beyond that.
+    #[serde(default = "default_cache_max_size")]
+    pub max_size: usize,
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+    // Add a `broker_heartbeat` point per source to every periodic flush.
+    #[serde(default = "default_heartbeat")]
+    pub heartbeat: bool,
+    // Add a `broker_stats` point with the counters of the broker to every periodic flush.
+    #[serde(default)]
+    pub broker_stats: bool,
+    // Whether the points of a series cached over several windows are written as one.
+    #[serde(default)]
+    pub coalesce: CoalesceMode,
+    // Stamp every point with the number of the flush that first tried to write it.
+    #[serde(default)]
+    pub flush_seq: bool,
+    // Spread the batches of a large periodic flush over part of the interval.
+    pub pacing: Option<PacingConfig>,
+}
+
+// Share of the flush interval a flush, or the write of a sink queue, may take, so that a slow
+// sink can never make two flushes overlap.
+pub const FLUSH_DEADLINE_SHARE: f64 = 0.9;
+
+// How a large flush is spread over the flush interval.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PacingConfig {
+    // Points written at once.
+    #[serde(default = "default_pacing_batch_size")]
+    pub batch_size: usize,
+    // Fraction of the flush interval the batches are spread over.
+    #[serde(default = "default_pacing_spread")]
+    pub spread: f64,
+    // Smallest backlog that is paced; smaller ones are written at once.
+    #[serde(default = "default_pacing_min_points")]
+    pub min_points: usize,
+}
+
+fn default_pacing_batch_size() -> usize {
+    500
+}
+
+fn default_pacing_spread() -> f64 {
+    0.5
+}
+
+fn default_pacing_min_points() -> usize {
+    2000
+}
+
+// How the points of a series are coalesced at each flush.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CoalesceMode {
+    // Every point is written, one per window.
+    #[default]
+    Off,
+    // A single point averaging those of the series, weighted by their `count` field.
+    Mean,
+    // The latest point of the series.
+    Last,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_cache_max_size(),
+            flush_interval_secs: default_flush_interval_secs(),
+            heartbeat: default_heartbeat(),
+            broker_stats: false,
+            coalesce: CoalesceMode::default(),
+            flush_seq: false,
+            pacing: None,
+        }
+    }
+}
+
+fn default_cache_max_size() -> usize {
+    1000
+}
+
+fn default_flush_interval_secs() -> u64 {
+    60
+}
+
+fn default_heartbeat() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LoggingConfig {
+    // Default level: off, error, warn, info, debug, or trace. RUST_LOG applies when unset.
+    pub level: Option<String>,
+    #[serde(default)]
+    pub format: LogFormat,
+    // Levels of single modules, e.g. `"aero_sensor_broker::influxdb" = "debug"`.
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, for log aggregators.
+    Json,
+}
+
+// The OTLP collector traces are exported to, over gRPC.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TracingConfig {
+    #[serde(default = "default_tracing_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_tracing_service_name")]
+    pub service_name: String,
+    // Share of the traces started by the broker that are exported, from 0 to 1.
+    #[serde(default = "default_tracing_sampling_ratio")]
+    pub sampling_ratio: f64,
+}
+
+fn default_tracing_endpoint() -> String {
+    "http://localhost:4317".to_string()
+}
+
+fn default_tracing_service_name() -> String {
+    "aero-sensor-broker".to_string()
+}
+
+fn default_tracing_sampling_ratio() -> f64 {
+    1.0
+}
+
+// Where the raw lines of the sources are recorded. Each source writes its own files, started
+// anew once one reaches its size or age limit; the oldest files are pruned once the directory
+// grows beyond `max_total_bytes`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordConfig {
+    pub path: String,
+    #[serde(default = "default_record_max_file_bytes")]
+    pub max_file_bytes: u64,
+    #[serde(default = "default_record_max_file_secs")]
+    pub max_file_secs: u64,
+    #[serde(default = "default_record_max_total_bytes")]
+    pub max_total_bytes: u64,
+}
+
+fn default_record_max_file_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_record_max_file_secs() -> u64 {
+    3600
+}
+
+fn default_record_max_total_bytes() -> u64 {
+    500 * 1024 * 1024
+}
+
+// The raw samples, written at their own timestamps to a bucket of their own next to the
+// averages, e.g. with a short retention for incident forensics.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RawConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub bucket: String,
+    // Samples kept per second and series at most; 0 keeps every sample.
+    #[serde(default)]
+    pub max_rate_hz: f64,
+}
+
+// Where batches permanently rejected by InfluxDB are kept for later re-submission.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetterConfig {
+    pub directory: String,
+    #[serde(default = "default_dead_letter_max_total_bytes")]
+    pub max_total_bytes: u64,
+}
+
+fn default_dead_letter_max_total_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    // Topic of each point; `{measurement}` and `{<tag name>}` placeholders are substituted.
+    #[serde(default = "default_mqtt_topic_template")]
+    pub topic_template: String,
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub password_env: Option<String>,
+    pub password_file: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    // CA certificate (PEM) used instead of the system roots when TLS is enabled.
+    pub ca_cert_path: Option<String>,
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    // Messages kept while the broker is unreachable; further messages are dropped.
+    #[serde(default = "default_mqtt_max_buffered_messages")]
+    pub max_buffered_messages: usize,
+}
+
+impl MqttConfig {
+    // The password is optional, so no configured source at all yields `None`.
+    pub fn password(&self) -> Result<Option<String>, String> {
+        if self.password.is_none() && self.password_env.is_none() && self.password_file.is_none() {
+            return Ok(None);
+        }
+        resolve_secret(
+            "mqtt.password",
+            self.password.as_ref().map(Secret::as_str),
+            self.password_env.as_deref(),
+            self.password_file.as_deref(),
+        )
+        .map(Some)
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "aero-sensor-broker".to_string()
+}
+
+fn default_mqtt_topic_template() -> String {
+    "sensors/{location}/{measurement}".to_string()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_keep_alive_secs() -> u64 {
+    30
+}
+
+fn default_mqtt_max_buffered_messages() -> usize {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileSinkConfig {
+    pub directory: String,
+    // Files are named `<prefix>-<YYYY-MM-DD>[.<segment>].csv`.
+    #[serde(default = "default_file_prefix")]
+    pub file_prefix: String,
+    // Gzip the previous day's files once the day changes.
+    #[serde(default)]
+    pub gzip_rotated: bool,
+}
+
+fn default_file_prefix() -> String {
+    "sensors".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HttpConfig {
+    // Run without the HTTP server when false.
+    #[serde(default = "default_http_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_http_bind_address")]
+    pub bind_address: String,
+    #[serde(default = "default_http_port")]
+    pub port: u16,
+    // Values served by `/api/latest` that were not updated for this long are flagged as stale.
+    #[serde(default = "default_latest_stale_secs")]
+    pub latest_stale_secs: u64,
+    // Lines each source keeps for `/admin/raw`, valid frames or not; 0 keeps none.
+    #[serde(default = "default_recent_frames")]
+    pub recent_frames: usize,
+    // Series `/api/latest` keeps; the least recently updated are evicted beyond that.
+    #[serde(default = "default_latest_max_series")]
+    pub latest_max_series: usize,
+    // Events `/api/stream` buffers for its subscribers; a subscriber further behind loses them.
+    #[serde(default = "default_stream_buffer")]
+    pub stream_buffer: usize,
+    // `/api/stream` subscribers at a time; more are refused.
+    #[serde(default = "default_max_stream_subscribers")]
+    pub max_stream_subscribers: usize,
+    // Measurements `/admin/measurements` keeps counters of; the least recently seen are
+    // forgotten beyond that.
+    #[serde(default = "default_max_measurements")]
+    pub max_measurements: usize,
+    // Bearer token required on every route but the probes. Like the InfluxDB token it can come
+    // from an environment variable or a file (see `resolve_secret`).
+    pub auth_token: Option<Secret<String>>,
+    pub auth_token_env: Option<String>,
+    pub auth_token_file: Option<String>,
+    // Log one line per request; successful `/livez`, `/readyz`, and `/healthz` probes are only
+    // logged when `access_log_probes` is set.
+    #[serde(default = "default_access_log")]
+    pub access_log: bool,
+    #[serde(default)]
+    pub access_log_probes: bool,
+    // Upper bound of each dependency check behind `/readyz`; a check that takes longer is
+    // reported as "timeout".
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub health_check_timeout_ms: u64,
+    // A `/ws/device` session without client messages for this long is closed.
+    #[serde(default = "default_device_session_idle_secs")]
+    pub device_session_idle_secs: u64,
+    // How long `/admin/read-now` waits for the frame it asked for.
+    #[serde(default = "default_read_now_timeout_secs")]
+    pub read_now_timeout_secs: u64,
+    // Longest time range `/api/history` accepts.
+    #[serde(default = "default_history_max_span_secs")]
+    pub history_max_span_secs: u64,
+    // Tag set to "true" on the points injected with `/admin/inject`.
+    #[serde(default = "default_inject_tag")]
+    pub inject_tag: String,
+    // Cross-origin access for browser dashboards; disabled unless configured.
+    pub cors: Option<CorsConfig>,
+    // Serve over TLS instead of plain HTTP.
+    pub tls: Option<HttpTlsConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_http_enabled(),
+            bind_address: default_http_bind_address(),
+            port: default_http_port(),
+            latest_stale_secs: default_latest_stale_secs(),
+            recent_frames: default_recent_frames(),
+            latest_max_series: default_latest_max_series(),
+            stream_buffer: default_stream_buffer(),
+            max_stream_subscribers: default_max_stream_subscribers(),
+            max_measurements: default_max_measurements(),
+            auth_token: None,
+            auth_token_env: None,
+            auth_token_file: None,
+            access_log: default_access_log(),
+            access_log_probes: false,
+            health_check_timeout_ms: default_health_check_timeout_ms(),
+            device_session_idle_secs: default_device_session_idle_secs(),
+            read_now_timeout_secs: default_read_now_timeout_secs(),
+            history_max_span_secs: default_history_max_span_secs(),
+            inject_tag: default_inject_tag(),
+            cors: None,
+            tls: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        let ip: IpAddr = self
+            .bind_address
+            .parse()
+            .map_err(|e| format!("invalid http.bind_address '{}': {}", self.bind_address, e))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    // Authentication is optional, so no configured source at all yields `None`.
+    pub fn auth_token(&self) -> Result<Option<String>, String> {
+        if self.auth_token.is_none()
+            && self.auth_token_env.is_none()
+            && self.auth_token_file.is_none()
+        {
+            return Ok(None);
+        }
+        resolve_secret(
+            "http.auth_token",
+            self.auth_token.as_ref().map(Secret::as_str),
+            self.auth_token_env.as_deref(),
+            self.auth_token_file.as_deref(),
+        )
+        .map(Some)
+    }
+}
+
+fn default_http_enabled() -> bool {
+    true
+}
+
+fn default_http_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_http_port() -> u16 {
+    3030
+}
+
+fn default_latest_stale_secs() -> u64 {
+    300
+}
+
+fn default_recent_frames() -> usize {
+    200
+}
+
+fn default_access_log() -> bool {
+    true
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_device_session_idle_secs() -> u64 {
+    300
+}
+
+fn default_read_now_timeout_secs() -> u64 {
+    10
+}
+
+fn default_history_max_span_secs() -> u64 {
+    31 * 86_400
+}
+
+fn default_latest_max_series() -> usize {
+    10_000
+}
+
+fn default_stream_buffer() -> usize {
+    256
+}
+
+fn default_max_stream_subscribers() -> usize {
+    16
+}
+
+fn default_max_measurements() -> usize {
+    1_000
+}
+
+fn default_inject_tag() -> String {
+    "test".to_string()
+}
+
+// How `/readyz` judges the broker.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HealthConfig {
+    // Components whose failing check makes the broker unhealthy: the source names and the names
+    // listed in `sinks`. A failing component that is not required only makes it degraded. Every
+    // component is required when unset.
+    pub required: Option<Vec<String>>,
+    // Upper bounds of the source and sink checks; `http.health_check_timeout_ms` when unset.
+    pub arduino_timeout_ms: Option<u64>,
+    pub sink_timeout_ms: Option<u64>,
+    // Without a frame, or without a flush, for this long the broker reports itself as degraded.
+    #[serde(default = "default_stale_frame_secs")]
+    pub stale_frame_secs: u64,
+    #[serde(default = "default_stale_flush_secs")]
+    pub stale_flush_secs: u64,
+    // A source that sent no frame yet keeps the broker starting, rather than degraded, for this
+    // long after startup.
+    #[serde(default = "default_first_frame_grace_secs")]
+    pub first_frame_grace_secs: u64,
+    // A source that parsed no frame for this long is marked stale, which a `sensor_stale` point
+    // records; 0 disables the watchdog.
+    #[serde(default = "default_stale_after_secs")]
+    pub stale_after_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            required: None,
+            arduino_timeout_ms: None,
+            sink_timeout_ms: None,
+            stale_frame_secs: default_stale_frame_secs(),
+            stale_flush_secs: default_stale_flush_secs(),
+            first_frame_grace_secs: default_first_frame_grace_secs(),
+            stale_after_secs: default_stale_after_secs(),
+        }
+    }
+}
+
+fn default_stale_frame_secs() -> u64 {
+    120
+}
+
+fn default_stale_flush_secs() -> u64 {
+    300
+}
+
+fn default_first_frame_grace_secs() -> u64 {
+    60
+}
+
+fn default_stale_after_secs() -> u64 {
+    120
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CorsConfig {
+    // Origins such as "https://dashboard.example.com"; "*" allows any origin, which is only
+    // accepted while no `http.auth_token` is configured.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    // How long browsers may cache a preflight response.
+    #[serde(default = "default_cors_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string()]
+}
+
+fn default_cors_allowed_headers() -> Vec<String> {
+    vec!["authorization".to_string(), "content-type".to_string()]
+}
+
+fn default_cors_max_age_secs() -> u64 {
+    600
+}
+
+// Certificate chain and key of the HTTP server (PEM), and the CA client certificates must be
+// signed by, for mutual TLS.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HttpTlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+}
+
+// Slowest write rate accepted, one point every 100 seconds.
+pub const MIN_POINTS_PER_SECOND: f64 = 0.01;
+
+// Caps the rate at which flushed points are written to the sinks.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RateLimitConfig {
+    pub points_per_second: f64,
+    // Largest number of points written at once; bigger batches are split.
+    pub burst: usize,
+}
+
+// A credential configured inline. It prints as `Secret(***)` and serializes as "***", so it
+// cannot leak through a debug log or the config endpoint; `expose` is the only way to the value.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl Secret<String> {
+    pub fn as_str(&self) -> &str {
+        self.expose()
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl<T> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("***")
+    }
+}
+
+// Looks up a secret that may be configured inline, as the name of an environment variable, or
+// as the path of a file. Sources are tried in that order of precedence: environment variable,
+// file (trailing newline trimmed), inline value; empty values count as missing. The error names
+// every source that was tried but never contains a secret value.
+pub fn resolve_secret(
+    name: &str,
+    inline: Option<&str>,
+    env_var: Option<&str>,
+    file: Option<&str>,
+) -> Result<String, String> {
+    let mut attempts = Vec::new();
+
+    if let Some(var) = env_var {
+        match env::var(var) {
+            Ok(value) if !value.is_empty() => return Ok(value),
+            Ok(_) => attempts.push(format!("environment variable {} is empty", var)),
+            Err(_) => attempts.push(format!("environment variable {} is not set", var)),
+        }
+    }
+
+    if let Some(path) = file {
+        match fs::read_to_string(path) {
+            Ok(value) if !value.trim_end_matches(['\r', '\n']).is_empty() => {
+                return Ok(value.trim_end_matches(['\r', '\n']).to_string());
+            }
+            Ok(_) => attempts.push(format!("file {} is empty", path)),
+            Err(e) => attempts.push(format!("file {} could not be read ({})", path, e)),
+        }
+    }
+
+    match inline {
+        Some(value) if !value.is_empty() => {
+            if !attempts.is_empty() {
+                warn!(
+                    "Using the inline value of {} ({})",
+                    name,
+                    attempts.join(", ")
+                );
+            }
+            Ok(value.to_string())
+        }
+        Some(_) => {
+            attempts.push("inline value is empty".to_string());
+            Err(format!("no value for {}: {}", name, attempts.join(", ")))
+        }
+        None if attempts.is_empty() => Err(format!("no value for {} configured", name)),
+        None => Err(format!("no value for {}: {}", name, attempts.join(", "))),
+    }
+}
+
+// Metadata of a measurement, for the frontends; every key is optional.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct SensorMetadata {
+    pub unit: Option<String>,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    // Decimals worth displaying; the values are served unrounded.
+    pub decimals: Option<u32>,
+}
+
+// Baud rates the Arduino serial monitor offers.
+const STANDARD_BAUD_RATES: [u32; 15] = [
+    300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 28800, 31250, 38400, 57600, 115200, 230400,
+    250000,
+];
+
+// Line protocol escapes commas, spaces, and equal signs, but has no way to carry a line break;
+// InfluxDB drops empty tag values.
+pub(crate) fn valid_tag(text: &str) -> bool {
+    !text.is_empty() && !text.chars().any(char::is_control)
+}
+
+fn valid_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+impl ConfigSettings {
+    // Checks what can be checked without connecting anywhere. Every problem found is returned,
+    // each prefixed with the key it concerns, so a broken file can be fixed in one pass.
+    // Unusual but workable values, such as a non-standard baud rate, only log a warning.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, key: &str, problem: &str| {
+            if !ok {
+                problems.push(format!("{}: {}", key, problem));
+            }
+        };
+
+        for sink in &self.sinks {
+            match sink.as_str() {
+                "influxdb" => {}
+                "mqtt" => check(
+                    self.mqtt.is_some(),
+                    "sinks",
+                    "the mqtt sink requires an [mqtt] section",
+                ),
+                "file" => check(
+                    self.file.is_some(),
+                    "sinks",
+                    "the file sink requires a [file] section",
+                ),
+                other => check(false, "sinks", &format!("unknown sink '{}'", other)),
+            }
+        }
+
+        let influxdb = &self.influxdb;
+        check(
+            valid_url(&influxdb.url),
+            "influxdb.url",
+            "must be an http:// or https:// URL",
+        );
+        check(
+            !influxdb.bucket.is_empty(),
+            "influxdb.bucket",
+            "must not be empty",
+        );
+        check(
+            !influxdb.org.is_empty(),
+            "influxdb.org",
+            "must not be empty",
+        );
+        check(
+            influxdb.write_timeout_secs > 0,
+            "influxdb.write_timeout_secs",
+            "must be greater than 0",
+        );
+        check(
+            influxdb.health_timeout_secs > 0,
+            "influxdb.health_timeout_secs",
+            "must be greater than 0",
+        );
+        check(
+            influxdb.validation_timeout_secs > 0,
+            "influxdb.validation_timeout_secs",
+            "must be greater than 0",
+        );
+        check(
+            influxdb.retry.max_attempts > 0,
+            "influxdb.retry.max_attempts",
+            "must be greater than 0",
+        );
+        check(
+            influxdb.retry.initial_backoff_ms <= influxdb.retry.max_backoff_ms,
+            "influxdb.retry.initial_backoff_ms",
+            "must not exceed influxdb.retry.max_backoff_ms",
+        );
+        check(
+            influxdb.retry.deadline_secs >= influxdb.write_timeout_secs,
+            "influxdb.retry.deadline_secs",
+            "must be at least influxdb.write_timeout_secs",
+        );
+        check(
+            !self.http.inject_tag.trim().is_empty(),
+            "http.inject_tag",
+            "must not be empty",
+        );
+        check(
+            self.http.latest_max_series > 0,
+            "http.latest_max_series",
+            "must be greater than 0",
+        );
+        check(
+            self.http.stream_buffer > 0,
+            "http.stream_buffer",
+            "must be greater than 0",
+        );
+        check(
+            self.http.max_measurements > 0,
+            "http.max_measurements",
+            "must be greater than 0",
+        );
+        if let Some(tls) = &self.http.tls {
+            check(
+                !tls.cert_path.is_empty(),
+                "http.tls.cert_path",
+                "must not be empty",
+            );
+            check(
+                !tls.key_path.is_empty(),
+                "http.tls.key_path",
+                "must not be empty",
+            );
+            check(
+                tls.client_ca_path.iter().all(|path| !path.is_empty()),
+                "http.tls.client_ca_path",
+                "must not be empty",
+            );
+        }
+        if let Some(cors) = &self.http.cors {
+            // Checked on the configured fields, so a token that cannot be resolved is reported
+            // once below and still rules out "*"
+            let auth_configured = self.http.auth_token.is_some()
+                || self.http.auth_token_env.is_some()
+                || self.http.auth_token_file.is_some();
+            check(
+                !(auth_configured && cors.allowed_origins.iter().any(|origin| origin == "*")),
+                "http.cors.allowed_origins",
+                "cannot allow any origin (\"*\") while http.auth_token is set",
+            );
+        }
+        for (index, fallback) in influxdb.fallbacks.iter().enumerate() {
+            let key = format!("influxdb.fallbacks[{}]", index);
+            check(
+                valid_url(&fallback.url),
+                &format!("{}.url", key),
+                "must be an http:// or https:// URL",
+            );
+            check(
+                !fallback.bucket.is_empty(),
+                &format!("{}.bucket", key),
+                "must not be empty",
+            );
+            check(
+                !fallback.org.is_empty(),
+                &format!("{}.org", key),
+                "must not be empty",
+            );
+        }
+
+        check(
+            self.cache.max_size > 0,
+            "cache.max_size",
+            "must be greater than 0",
+        );
+        check(
+            self.aggregation.window_secs > 0,
+            "aggregation.window_secs",
+            "must be greater than 0",
+        );
+        check(
+            self.cache.flush_interval_secs >= self.aggregation.window_secs,
+            "cache.flush_interval_secs",
+            "must be at least aggregation.window_secs",
+        );
+        for (measurement, deadband) in &self.aggregation.deadband {
+            check(
+                deadband.is_finite() && *deadband >= 0.0,
+                &format!("aggregation.deadband.{}", measurement),
+                "must be a finite number of at least 0",
+            );
+        }
+        check(
+            self.aggregation.deadband.is_empty() || self.aggregation.max_suppression_secs > 0,
+            "aggregation.max_suppression_secs",
+            "must be greater than 0 when a deadband is set",
+        );
+
+        check(
+            !self.sources.is_empty(),
+            "sources",
+            "at least one source is required, as [[sources]] or [arduino]",
+        );
+        check(
+            self.arduino.is_none(),
+            "arduino",
+            "cannot be combined with [[sources]], configure the device as a source",
+        );
+        for (index, source) in self.sources.iter().enumerate() {
+            let key = match source.name.as_str() {
+                "" => format!("sources[{}]", index),
+                name => format!("sources.{}", name),
+            };
+            check(
+                valid_tag(&source.name),
+                &format!("{}.name", key),
+                "must be non-empty and without control characters",
+            );
+            check(
+                self.sources[..index]
+                    .iter()
+                    .all(|other| other.name != source.name),
+                &format!("{}.name", key),
+                "is used by another source",
+            );
+            check(
+                !self.sinks.contains(&source.name),
+                &format!("{}.name", key),
+                "is the name of a sink",
+            );
+            let serial = &source.serial;
+            match source.kind {
+                SourceKind::Serial => {
+                    check(
+                        serial.timeout > 0,
+                        &format!("{}.timeout", key),
+                        "must be greater than 0",
+                    );
+                    check(
+                        !serial.device_name.is_empty(),
+                        &format!("{}.device_name", key),
+                        "must not be empty",
+                    );
+                    check(
+                        serial.read_chunk_bytes > 0,
+                        &format!("{}.read_chunk_bytes", key),
+                        "must be greater than 0",
+                    );
+                    check(
+                        serial.max_frame_bytes > 0,
+                        &format!("{}.max_frame_bytes", key),
+                        "must be greater than 0",
+                    );
+                    check(
+                        serial.max_input_bytes >= serial.read_chunk_bytes,
+                        &format!("{}.max_input_bytes", key),
+                        "must be at least read_chunk_bytes",
+                    );
+                    check(
+                        source.simulation.is_none(),
+                        &format!("{}.simulation", key),
+                        "only simulated sources take a simulation section",
+                    );
+                    if !STANDARD_BAUD_RATES.contains(&serial.baud_rate) {
+                        warn!(
+                            "{}.baud_rate: {} is not a standard baud rate, check it matches the firmware",
+                            key, serial.baud_rate
+                        );
+                    }
+                }
+                SourceKind::Simulated => {
+                    let simulation = source.simulation.clone().unwrap_or_default();
+                    check(
+                        simulation.interval_ms > 0,
+                        &format!("{}.simulation.interval_ms", key),
+                        "must be greater than 0",
+                    );
+                    check(
+                        (0.0..=1.0).contains(&simulation.malformed_ratio),
+                        &format!("{}.simulation.malformed_ratio", key),
+                        "must be between 0 and 1",
+                    );
+                    check(
+                        !simulation.measurements.is_empty(),
+                        &format!("{}.simulation.measurements", key),
+                        "at least one measurement is required",
+                    );
+                    for measurement in &simulation.measurements {
+                        let key = format!("{}.simulation.measurements.{}", key, measurement.name);
+                        check(
+                            valid_tag(&measurement.name),
+                            &format!("{}.name", key),
+                            "must be non-empty and without control characters",
+                        );
+                        check(
+                            measurement.min.is_finite()
+                                && measurement.max.is_finite()
+                                && measurement.min <= measurement.max,
+                            &key,
+                            "min and max must be finite, with min not above max",
+                        );
+                        check(
+                            measurement.period_secs > 0,
+                            &format!("{}.period_secs", key),
+                            "must be greater than 0",
+                        );
+                    }
+                }
+            }
+            if let Some(limit) = &source.ingest_limit {
+                check(
+                    limit.frames_per_second > 0,
+                    &format!("{}.ingest_limit.frames_per_second", key),
+                    "must be greater than 0",
+                );
+                check(
+                    limit.sample_one_in > 0,
+                    &format!("{}.ingest_limit.sample_one_in", key),
+                    "must be greater than 0",
+                );
+            }
+            for (tag, value) in &source.tags {
+                check(
+                    valid_tag(tag) && !tag.starts_with('_') && tag != "source",
+                    &format!("{}.tags.{}", key, tag),
+                    "tag keys must be non-empty, without control characters, not start with '_', and not be 'source'",
+                );
+                check(
+                    valid_tag(value),
+                    &format!("{}.tags.{}", key, tag),
+                    "must be non-empty and without control characters",
+                );
+            }
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            check(!mqtt.host.is_empty(), "mqtt.host", "must not be empty");
+            check(mqtt.qos <= 2, "mqtt.qos", "must be 0, 1, or 2");
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            check(
+                !dead_letter.directory.is_empty(),
+                "dead_letter.directory",
+                "must not be empty",
+            );
+        }
+        check(
+            !self.crash_marker.is_empty(),
+            "crash_marker",
+            "must not be empty",
+        );
+        if self.aggregation.sample_count == SampleCountMode::Measurement {
+            let tagged = self.tags.contains_key(SAMPLE_COUNT_MEASUREMENT_TAG)
+                || self
+                    .sources
+                    .iter()
+                    .any(|source| source.tags.contains_key(SAMPLE_COUNT_MEASUREMENT_TAG));
+            check(
+                !tagged,
+                "aggregation.sample_count",
+                &format!(
+                    "\"measurement\" tags the sample counts with the measurement counted, which a tag named {} would hide",
+                    SAMPLE_COUNT_MEASUREMENT_TAG
+                ),
+            );
+        }
+        check(
+            self.cache.coalesce != CoalesceMode::Mean
+                || self.aggregation.sample_count == SampleCountMode::Field,
+            "cache.coalesce",
+            "\"mean\" weights the points by their count, which requires aggregation.sample_count = \"field\"",
+        );
+        if let Some(pacing) = &self.cache.pacing {
+            check(
+                pacing.batch_size > 0,
+                "cache.pacing.batch_size",
+                "must be greater than 0",
+            );
+            // The batches are written within the deadline of the flush
+            check(
+                pacing.spread > 0.0 && pacing.spread <= FLUSH_DEADLINE_SHARE,
+                "cache.pacing.spread",
+                &format!(
+                    "must be greater than 0 and at most {}",
+                    FLUSH_DEADLINE_SHARE
+                ),
+            );
+        }
+        if self.raw.enabled {
+            check(
+                !self.raw.bucket.is_empty(),
+                "raw.bucket",
+                "must not be empty",
+            );
+            check(
+                self.raw.max_rate_hz.is_finite() && self.raw.max_rate_hz >= 0.0,
+                "raw.max_rate_hz",
+                "must be 0 or a positive rate",
+            );
+            check(
+                self.dry_run || self.sinks.iter().any(|sink| sink == "influxdb"),
+                "raw.enabled",
+                "raw samples are written to InfluxDB, which `sinks` must include",
+            );
+        }
+        if let Some(tracing) = &self.tracing {
+            check(
+                !tracing.endpoint.is_empty(),
+                "tracing.endpoint",
+                "must not be empty",
+            );
+            check(
+                (0.0..=1.0).contains(&tracing.sampling_ratio),
+                "tracing.sampling_ratio",
+                "must be between 0 and 1",
+            );
+        }
+        if let Some(record) = &self.record {
+            check(!record.path.is_empty(), "record.path", "must not be empty");
+            check(
+                record.max_file_bytes > 0,
+                "record.max_file_bytes",
+                "must be greater than 0",
+            );
+            check(
+                record.max_file_secs > 0,
+                "record.max_file_secs",
+                "must be greater than 0",
+            );
+            check(
+                record.max_total_bytes >= record.max_file_bytes,
+                "record.max_total_bytes",
+                "must be at least record.max_file_bytes",
+            );
+        }
+        if let Some(file) = &self.file {
+            check(
+                !file.directory.is_empty(),
+                "file.directory",
+                "must not be empty",
+            );
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            check(
+                rate_limit.points_per_second.is_finite()
+                    && rate_limit.points_per_second >= MIN_POINTS_PER_SECOND,
+                "rate_limit.points_per_second",
+                &format!("must be at least {}", MIN_POINTS_PER_SECOND),
+            );
+            check(
+                rate_limit.burst > 0,
+                "rate_limit.burst",
+                "must be greater than 0",
+            );
+        }
+        check(
+            self.http.health_check_timeout_ms > 0,
+            "http.health_check_timeout_ms",
+            "must be greater than 0",
+        );
+        check(
+            self.http.read_now_timeout_secs > 0,
+            "http.read_now_timeout_secs",
+            "must be greater than 0",
+        );
+        let health = &self.health;
+        for component in health.required.iter().flatten() {
+            check(
+                self.sources.iter().any(|source| source.name == *component)
+                    || self.sinks.contains(component),
+                "health.required",
+                &format!(
+                    "unknown component '{}', expected one of the sources or sinks",
+                    component
+                ),
+            );
+        }
+        check(
+            health.arduino_timeout_ms != Some(0),
+            "health.arduino_timeout_ms",
+            "must be greater than 0",
+        );
+        check(
+            health.sink_timeout_ms != Some(0),
+            "health.sink_timeout_ms",
+            "must be greater than 0",
+        );
+        check(
+            health.stale_frame_secs > 0,
+            "health.stale_frame_secs",
+            "must be greater than 0",
+        );
+        check(
+            health.stale_flush_secs > self.cache.flush_interval_secs,
+            "health.stale_flush_secs",
+            "must be greater than cache.flush_interval_secs",
+        );
+        check(
+            self.tags.contains_key("location"),
+            "tags.location",
+            &format!("must be configured, or {} set", LOCATION_ENV),
+        );
+        check(
+            !self.tags.contains_key("source"),
+            "tags.source",
+            "is set to the name of the source of every point",
+        );
+        let valid_level = |level: &str| level.parse::<log::LevelFilter>().is_ok();
+        if let Some(level) = &self.logging.level {
+            check(
+                valid_level(level),
+                "logging.level",
+                "must be off, error, warn, info, debug, or trace",
+            );
+        }
+        for (module, level) in &self.logging.modules {
+            check(
+                valid_level(level),
+                &format!("logging.modules.{}", module),
+                "must be off, error, warn, info, debug, or trace",
+            );
+        }
+        for (key, value) in &self.tags {
+            check(
+                valid_tag(key) && !key.starts_with('_'),
+                &format!("tags.{}", key),
+                "tag keys must be non-empty, without control characters, and not start with '_'",
+            );
+            check(
+                valid_tag(value),
+                &format!("tags.{}", key),
+                "must be non-empty and without control characters",
+            );
+        }
+        for (name, value) in &self.fields {
+            check(
+                valid_tag(name) && !name.starts_with('_'),
+                &format!("fields.{}", name),
+                "field names must be non-empty, without control characters, and not start with '_'",
+            );
+            check(
+                !self.tags.contains_key(name),
+                &format!("fields.{}", name),
+                "is also a tag",
+            );
+            check(
+                !matches!(value, StaticField::Float(value) if !value.is_finite()),
+                &format!("fields.{}", name),
+                "must be a finite number",
+            );
+        }
+        check(
+            !(self.unit_tag && self.tags.contains_key("unit")),
+            "tags.unit",
+            "is set to the unit of the [sensors] entries by unit_tag",
+        );
+        for (measurement, sensor) in &self.sensors {
+            check(
+                sensor.unit.iter().all(|unit| valid_tag(unit)),
+                &format!("sensors.{}.unit", measurement),
+                "must be non-empty and without control characters",
+            );
+            check(
+                sensor
+                    .display_name
+                    .iter()
+                    .all(|name| !name.trim().is_empty()),
+                &format!("sensors.{}.display_name", measurement),
+                "must not be empty",
+            );
+            check(
+                sensor.decimals.iter().all(|&decimals| decimals <= 15),
+                &format!("sensors.{}.decimals", measurement),
+                "must not exceed 15",
+            );
+        }
+
+        // Secrets and addresses report the key in their own messages
+        let secrets = [
+            self.influxdb.auth_token().err(),
+            self.mqtt.as_ref().and_then(|mqtt| mqtt.password().err()),
+            self.http.auth_token().err(),
+        ];
+        problems.extend(secrets.into_iter().flatten());
+        problems.extend(
+            self.influxdb
+                .fallbacks
+                .iter()
+                .filter_map(|fallback| fallback.auth_token().err()),
+        );
+        problems.extend(self.http.socket_addr().err());
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // Reads the legacy `[arduino]` section as the only source. Alongside `[[sources]]` it is left
+    // in place for validation to reject.
+    fn resolve_sources(&mut self) {
+        if !self.sources.is_empty() {
+            return;
+        }
+        if let Some(arduino) = self.arduino.take() {
+            self.sources.push(SourceConfig {
+                name: LEGACY_SOURCE.to_string(),
+                kind: SourceKind::Serial,
+                serial: arduino,
+                simulation: None,
+                parser: None,
+                tags: BTreeMap::new(),
+                ingest_limit: None,
+            });
+        }
+    }
+
+    // The location tag of every point, once `resolve_location` ran.
+    pub fn location(&self) -> &str {
+        self.tags.get("location").map_or("", String::as_str)
+    }
+
+    // Applies the `CLUSTER_DISPLAY_NAME` override of the `location` tag. Without any location,
+    // points are tagged "Default", unless `require_location` makes that a validation error.
+    fn resolve_location(&mut self) {
+        match env::var(LOCATION_ENV) {
+            Ok(location) if !location.is_empty() => {
+                if let Some(configured) = self.tags.get("location").filter(|c| **c != location) {
+                    warn!(
+                        "{} overrides tags.location ('{}' instead of '{}')",
+                        LOCATION_ENV, location, configured
+                    );
+                }
+                self.tags.insert("location".to_string(), location);
+            }
+            _ if self.tags.contains_key("location") || self.require_location => {}
+            _ => {
+                warn!(
+                    "Neither tags.location nor {} is set, tagging points with location 'Default'",
+                    LOCATION_ENV
+                );
+                self.tags
+                    .insert("location".to_string(), "Default".to_string());
+            }
+        }
+    }
+
+    // The effective settings, defaults included. Inline secrets serialize as "***"; the `_env`
+    // and `_file` variants only name where a secret is read from and are reported as they are.
+    pub fn redacted(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+// Overrides the `location` tag, as it did before tags were configurable.
+const LOCATION_ENV: &str = "CLUSTER_DISPLAY_NAME";
+
+// Directory searched for the configuration files when none is given on the command line.
+const CONFIG_DIR_ENV: &str = "SENSORFLOW_CONFIG_DIR";
+const DEFAULT_CONFIG_DIR: &str = "settings";
+
+// Name of the state file, in the directory of the configuration files unless configured.
+const DEFAULT_STATE_FILE: &str = "aero-sensor-broker.state.json";
+
+// Environment variables such as `SENSORFLOW_CACHE__MAX_SIZE` override the files, `__`
+// separating the keys of nested sections.
+const ENV_PREFIX: &str = "SENSORFLOW";
+
+// Where the settings are loaded from; kept by the reloader to load them again.
+#[derive(Clone, Default)]
+pub struct ConfigSource {
+    // Files layered in order; the default files when empty, see `load_settings`.
+    pub paths: Vec<PathBuf>,
+    // Format of the files whose extension does not tell.
+    pub format: Option<FileFormat>,
+    pub profile: Option<String>,
+}
+
+// Extensions the format of a configuration file is recognized by.
+const CONFIG_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+// Files with a known extension are parsed accordingly, any other file as `format`, when given.
+fn config_file(
+    path: &Path,
+    format: Option<FileFormat>,
+) -> File<config::FileSourceFile, FileFormat> {
+    let known = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| CONFIG_EXTENSIONS.contains(&extension));
+    match format {
+        Some(format) if !known => File::from(path).format(format),
+        _ => File::from(path),
+    }
+}
+
+// The file of a profile sits next to the base file: `Settings.dev.toml` for `Settings.toml`.
+fn profile_file(base: &Path, profile: &str) -> PathBuf {
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match base.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, profile, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, profile),
+    };
+    base.with_file_name(name)
+}
+
+// Loads the settings in layers, each overriding the previous ones:
+//   1. the built-in defaults,
+//   2. the given files, or `Settings.toml` from `$SENSORFLOW_CONFIG_DIR` (`settings/` when
+//      unset) when none is given,
+//   3. with a profile, the `[profile.<name>]` table of those files, then `Settings.<name>.toml`
+//      next to the first file; a profile with neither is an error, so that a misspelled name
+//      cannot silently run with the base settings,
+//   4. `Settings.local.toml`, if present, when the default files are used,
+//   5. the environment variables.
+// TOML, YAML, and JSON files all deserialize into the same settings, see `config_file`. The
+// settings are validated, and all problems are reported in a single error.
+pub fn load_settings(source: &ConfigSource) -> Result<ConfigSettings, config::ConfigError> {
+    let (base, local) = match source.paths.as_slice() {
+        [] => {
+            let dir = env::var_os(CONFIG_DIR_ENV)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_DIR));
+            (
+                vec![dir.join("Settings.toml")],
+                Some(dir.join("Settings.local.toml")),
+            )
+        }
+        paths => (paths.to_vec(), None),
+    };
+
+    let mut builder = Config::builder();
+    let mut loaded = Vec::new();
+    for path in &base {
+        builder = builder.add_source(config_file(path, source.format));
+        loaded.push(path.display().to_string());
+    }
+
+    if let Some(profile) = &source.profile {
+        if profile.is_empty()
+            || !profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(config::ConfigError::Message(format!(
+                "invalid profile name '{}'",
+                profile
+            )));
+        }
+        let inline = builder
+            .build_cloned()?
+            .get::<serde_json::Value>(&format!("profile.{}", profile))
+            .ok();
+        let file = profile_file(&base[0], profile);
+        if inline.is_none() && !file.exists() {
+            return Err(config::ConfigError::Message(format!(
+                "profile '{}' has neither a [profile.{}] table nor a {} file",
+                profile,
+                profile,
+                file.display()
+            )));
+        }
+        if let Some(table) = inline {
+            builder = builder.add_source(File::from_str(&table.to_string(), FileFormat::Json));
+            loaded.push(format!("[profile.{}]", profile));
+        }
+        if file.exists() {
+            builder = builder.add_source(config_file(&file, source.format));
+            loaded.push(file.display().to_string());
+        }
+        info!("Using configuration profile '{}'", profile);
+    }
+
+    if let Some(local) = local.filter(|local| local.exists()) {
+        builder = builder.add_source(config_file(&local, source.format));
+        loaded.push(local.display().to_string());
+    }
+
+    let mut settings = builder
+        .add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator("__")
+                .try_parsing(true),
+        )
         .build()?
-        .try_deserialize::<ConfigSettings>()
+        .try_deserialize::<ConfigSettings>()?;
+    info!("Configuration loaded from {}", loaded.join(", "));
+    settings.active_profile = source.profile.clone();
+    settings.resolve_sources();
+    settings.resolve_location();
+    if settings.state_file.is_empty() {
+        settings.state_file = base[0]
+            .with_file_name(DEFAULT_STATE_FILE)
+            .display()
+            .to_string();
+    }
+
+    settings.validate().map_err(|problems| {
+        config::ConfigError::Message(format!(
+            "{} problem(s) found:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        ))
+    })?;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Settings passing every check, for the tests to break one rule at a time.
+    fn valid() -> ConfigSettings {
+        serde_json::from_value(json!({
+            "influxdb": {
+                "url": "http://localhost:8086",
+                "org": "aero",
+                "bucket": "sensors",
+                "auth_token": "token",
+            },
+            "sources": [{"name": "bench", "device_name": "Arduino"}],
+            "tags": {"location": "lab"},
+        }))
+        .unwrap()
+    }
+
+    // The problems found, which must be there.
+    fn problems(settings: &ConfigSettings) -> Vec<String> {
+        settings.validate().expect_err("the settings passed")
+    }
+
+    #[test]
+    fn valid_settings_pass() {
+        assert_eq!(valid().validate(), Ok(()));
+    }
+
+    #[test]
+    fn a_url_without_a_scheme_is_rejected() {
+        let mut settings = valid();
+        settings.influxdb.url = "localhost:8086".to_string();
+
+        assert_eq!(
+            problems(&settings),
+            ["influxdb.url: must be an http:// or https:// URL"]
+        );
+    }
+
+    #[test]
+    fn a_non_standard_baud_rate_only_warns() {
+        let mut settings = valid();
+        settings.sources[0].serial.baud_rate = 115_201;
+
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn a_timeout_of_0_is_rejected() {
+        let mut settings = valid();
+        settings.sources[0].serial.timeout = 0;
+        settings.influxdb.write_timeout_secs = 0;
+
+        let problems = problems(&settings);
+        assert!(problems.contains(&"sources.bench.timeout: must be greater than 0".to_string()));
+        assert!(
+            problems.contains(&"influxdb.write_timeout_secs: must be greater than 0".to_string())
+        );
+    }
+
+    #[test]
+    fn an_empty_bucket_or_org_is_rejected() {
+        let mut settings = valid();
+        settings.influxdb.bucket = String::new();
+        settings.influxdb.org = String::new();
+
+        assert_eq!(
+            problems(&settings),
+            [
+                "influxdb.bucket: must not be empty",
+                "influxdb.org: must not be empty"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_token_without_any_source_is_rejected() {
+        let mut settings = valid();
+        settings.influxdb.auth_token = None;
+
+        assert_eq!(
+            problems(&settings),
+            ["no value for influxdb.auth_token configured"]
+        );
+    }
+
+    #[test]
+    fn a_flush_interval_shorter_than_the_window_is_rejected() {
+        let mut settings = valid();
+        settings.aggregation.window_secs = 60;
+        settings.cache.flush_interval_secs = 60;
+        settings.health.stale_flush_secs = 600;
+        assert_eq!(settings.validate(), Ok(()));
+
+        settings.cache.flush_interval_secs = 59;
+
+        assert_eq!(
+            problems(&settings),
+            ["cache.flush_interval_secs: must be at least aggregation.window_secs"]
+        );
+    }
+
+    #[test]
+    fn a_tag_named_like_the_tag_of_the_sample_counts_is_rejected() {
+        let mut settings = valid();
+        settings.sources[0]
+            .tags
+            .insert("measurement".to_string(), "intake".to_string());
+        settings.aggregation.sample_count = SampleCountMode::Field;
+        assert_eq!(settings.validate(), Ok(()));
+
+        settings.aggregation.sample_count = SampleCountMode::Measurement;
+
+        assert_eq!(
+            problems(&settings),
+            ["aggregation.sample_count: \"measurement\" tags the sample counts with the measurement counted, which a tag named measurement would hide"]
+        );
+    }
+
+    #[test]
+    fn an_unknown_required_component_is_rejected() {
+        let mut settings = valid();
+        settings.health.required = Some(vec!["bench".to_string(), "influx".to_string()]);
+
+        assert_eq!(
+            problems(&settings),
+            ["health.required: unknown component 'influx', expected one of the sources or sinks"]
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_with_its_key() {
+        let mut settings = valid();
+        settings.influxdb.url = "influxdb".to_string();
+        settings.influxdb.org = String::new();
+        settings.sources[0].serial.timeout = 0;
+        settings.cache.max_size = 0;
+
+        assert_eq!(
+            problems(&settings),
+            [
+                "influxdb.url: must be an http:// or https:// URL",
+                "influxdb.org: must not be empty",
+                "cache.max_size: must be greater than 0",
+                "sources.bench.timeout: must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn cors_cannot_allow_any_origin_while_auth_is_enabled() {
+        let mut settings = valid();
+        settings.http.cors =
+            Some(serde_json::from_value(json!({"allowed_origins": ["*"]})).unwrap());
+        assert_eq!(settings.validate(), Ok(()));
+
+        settings.http.auth_token = Some(serde_json::from_value(json!("http-token")).unwrap());
+        assert_eq!(
+            problems(&settings),
+            ["http.cors.allowed_origins: cannot allow any origin (\"*\") while http.auth_token is set"]
+        );
+    }
+
+    #[tokio::test]
+    async fn secrets_neither_print_nor_reach_the_config_route() {
+        let mut settings = valid();
+        let secret = |value: &str| Some(serde_json::from_value(json!(value)).unwrap());
+        settings.influxdb.auth_token = secret("influx-token");
+        settings.influxdb.fallbacks = vec![serde_json::from_value(json!({
+            "url": "http://fallback:8086",
+            "org": "aero",
+            "bucket": "sensors",
+            "auth_token": "fallback-token",
+        }))
+        .unwrap()];
+        settings.mqtt = Some(
+            serde_json::from_value(json!({"host": "mqtt", "password": "mqtt-password"})).unwrap(),
+        );
+        settings.http.auth_token = secret("http-token");
+        let values = [
+            "influx-token",
+            "fallback-token",
+            "mqtt-password",
+            "http-token",
+        ];
+
+        let printed = format!("{:?}", settings);
+        for value in values {
+            assert!(!printed.contains(value), "{} printed", value);
+        }
+
+        let (_, running) = tokio::sync::watch::channel(settings.redacted());
+        let route = crate::routes::create_config_route(running);
+        let response = warp::test::request()
+            .path("/admin/config")
+            .reply(&route)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        for value in values {
+            assert!(!body.to_string().contains(value), "{} served", value);
+        }
+        assert_eq!(body["influxdb"]["auth_token"], "***");
+        assert_eq!(body["influxdb"]["fallbacks"][0]["auth_token"], "***");
+        assert_eq!(body["mqtt"]["password"], "***");
+        assert_eq!(body["http"]["auth_token"], "***");
+    }
 }
@@ -0,0 +1,230 @@
+// crash.rs
+//
+// Last-resort reporting of panics. The panic hook logs the message, location, and backtrace of
+// a panic as a single entry, since the default report on stderr is mangled by the log pipeline.
+// A fatal panic, on the main thread or in a task the supervisor gives up on, also leaves a crash
+// marker file; a task panic the supervisor recovers from does not. The marker is reported and
+// removed on the next start, so that a crash is noticed even when the logs rotated away, and an
+// orderly exit removes any marker left. Each panic, and the crash found in the marker at
+// startup, is also written to the sinks as a `broker_crash` point while they can still be
+// reached. Whether a marker was found also tells the run state whether the previous run ended in
+// a panic.
+
+use crate::sink::DataSink;
+use crate::supervisor::panic_message;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb2::models::DataPoint;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::thread;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+const CRASH_MEASUREMENT: &str = "broker_crash";
+
+// How long writing a crash point may take, since the sink may be what is failing.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A panic, as logged, kept in the marker file, and written to the sinks.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CrashReport {
+    // RFC 3339 time of the panic.
+    pub at: String,
+    pub thread: String,
+    pub message: String,
+    // Source file, line, and column of the panic.
+    pub location: String,
+}
+
+type ReportChannel = (
+    UnboundedSender<CrashReport>,
+    Mutex<Option<UnboundedReceiver<CrashReport>>>,
+);
+
+// Reports waiting to be written to the sinks. The panic hook cannot wait for a write, so it
+// queues its report for the task started by `write_crash_points`.
+static REPORTS: OnceLock<ReportChannel> = OnceLock::new();
+
+// Set when a marker was found at startup.
+static PREVIOUS_RUN_CRASHED: AtomicBool = AtomicBool::new(false);
+
+// Where the marker of this run goes, set by `install_panic_hook`.
+static MARKER: OnceLock<PathBuf> = OnceLock::new();
+
+// The latest panic, written to the marker should the supervisor give up on its task.
+static LAST_PANIC: Mutex<Option<CrashReport>> = Mutex::new(None);
+
+// Name of the thread the runtime runs `main` on.
+const MAIN_THREAD: &str = "main";
+
+fn reports() -> &'static ReportChannel {
+    REPORTS.get_or_init(|| {
+        let (sender, receiver) = unbounded_channel();
+        (sender, Mutex::new(Some(receiver)))
+    })
+}
+
+// Replaces the default panic hook with one reporting panics through the `log` facade and, for
+// a panic of the main thread, to the crash marker file at `marker`. Only the first marker given
+// is used.
+pub fn install_panic_hook(marker: PathBuf) {
+    let _ = MARKER.set(marker);
+    std::panic::set_hook(Box::new(move |info| {
+        let report = CrashReport {
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            thread: thread::current().name().unwrap_or("unnamed").to_string(),
+            message: panic_message(info.payload()).to_string(),
+            location: info
+                .location()
+                .map(|location| location.to_string())
+                .unwrap_or_else(|| "unknown location".to_string()),
+        };
+        error!(
+            "Thread {} panicked at {}: {}\nbacktrace:\n{}",
+            report.thread,
+            report.location,
+            report.message,
+            Backtrace::force_capture()
+        );
+        // Tasks run on the worker threads, where the supervisor decides whether it is fatal
+        if report.thread == MAIN_THREAD {
+            write_marker(&report);
+        }
+        *LAST_PANIC.lock().unwrap_or_else(PoisonError::into_inner) = Some(report.clone());
+        // Sending only fails when the channel is closed, which never happens.
+        let _ = reports().0.send(report);
+    }));
+}
+
+// Leaves the marker of the latest panic, once the task that panicked cannot be recovered.
+pub fn record_fatal_panic() {
+    let report = LAST_PANIC
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    if let Some(report) = report {
+        write_marker(&report);
+    }
+}
+
+// Removes the marker of this run, if any, on an orderly exit.
+pub fn clear_marker() {
+    let Some(marker) = MARKER.get() else {
+        return;
+    };
+    match fs::remove_file(marker) {
+        Ok(()) => info!("Removed crash marker {}", marker.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove crash marker {}: {}", marker.display(), e),
+    }
+}
+
+fn write_marker(report: &CrashReport) {
+    let Some(marker) = MARKER.get() else {
+        return;
+    };
+    let written = serde_json::to_vec(report)
+        .map_err(|e| e.to_string())
+        .and_then(|marker_json| fs::write(marker, marker_json).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        error!("Failed to write crash marker {}: {}", marker.display(), e);
+    }
+}
+
+// Reports the crash of a previous run left in the marker file, if any, then removes the file.
+pub fn report_previous_crash(marker: &Path) {
+    let contents = match fs::read_to_string(marker) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Failed to read crash marker {}: {}", marker.display(), e);
+            return;
+        }
+    };
+    PREVIOUS_RUN_CRASHED.store(true, Ordering::Relaxed);
+    match serde_json::from_str::<CrashReport>(&contents) {
+        Ok(report) => {
+            warn!(
+                "The previous run panicked at {}, in thread {} at {}: {}",
+                report.at, report.thread, report.location, report.message
+            );
+            let _ = reports().0.send(report);
+        }
+        Err(e) => warn!(
+            "The previous run crashed, but its marker {} cannot be read ({}): {}",
+            marker.display(),
+            e,
+            contents.trim()
+        ),
+    }
+    if let Err(e) = fs::remove_file(marker) {
+        warn!("Failed to remove crash marker {}: {}", marker.display(), e);
+    }
+}
+
+// Whether `report_previous_crash` found the marker of a panic of the previous run.
+pub fn previous_run_crashed() -> bool {
+    PREVIOUS_RUN_CRASHED.load(Ordering::Relaxed)
+}
+
+// Writes the queued and future crash reports to the sink as points with the given tags, until
+// `shutdown` is cancelled. Reports are only written by the first caller.
+pub async fn write_crash_points(
+    sink: Arc<dyn DataSink>,
+    tags: BTreeMap<String, String>,
+    shutdown: CancellationToken,
+) {
+    let receiver = reports()
+        .1
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    let Some(mut receiver) = receiver else {
+        return;
+    };
+    loop {
+        let report = tokio::select! {
+            report = receiver.recv() => report,
+            _ = shutdown.cancelled() => None,
+        };
+        let Some(report) = report else {
+            break;
+        };
+        let point = match crash_point(&report, &tags) {
+            Ok(point) => point,
+            Err(e) => {
+                warn!("Failed to build the crash point: {}", e);
+                continue;
+            }
+        };
+        match timeout(WRITE_TIMEOUT, sink.write(vec![point])).await {
+            Ok(Ok(())) => info!("Reported the panic of {} to the sinks", report.at),
+            Ok(Err(e)) => warn!("Failed to write the crash point: {}", e),
+            Err(_) => warn!("Timed out writing the crash point"),
+        }
+    }
+}
+
+fn crash_point(report: &CrashReport, tags: &BTreeMap<String, String>) -> Result<DataPoint, String> {
+    let at = DateTime::parse_from_rfc3339(&report.at)
+        .map(|at| at.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now());
+    let builder = DataPoint::builder(CRASH_MEASUREMENT)
+        .field("message", report.message.clone())
+        .field("thread", report.thread.clone())
+        .field("panic_location", report.location.clone())
+        .timestamp(at.timestamp_nanos_opt().unwrap_or_default());
+    tags.iter()
+        .fold(builder, |builder, (key, value)| builder.tag(key, value))
+        .build()
+        .map_err(|e| e.to_string())
+}
@@ -2,213 +2,1071 @@
 //
 // This module processes a collection of MyDataPoints, which are custom data points containing measurements,
 // tags, fields, and timestamps. The goal is to:
-// 1. Group the data points by their measurement type.
+// 1. Group the data points by series (the source they were read from, measurement type, and
+//    tags), so that two boards measuring the same thing at different places are never averaged
+//    together.
 // 2. Filter out any data points that do not have both a field value and a timestamp.
-// 3. Calculate the average value and timestamp for each group of data points.
+// 3. Calculate the average of each field and the average timestamp for each group of data points,
+//    either the mean or, for the measurements configured so, the mean weighted by the time
+//    each sample held until the next, so that a sensor sampling faster while its values change
+//    does not over-weight its bursts.
 // 4. Build new DataPoint instances from these averages, maintaining the original tags.
+// 5. Optionally report how many samples each series contributed, so a dropping sample rate
+//    is visible even while the averages still look plausible.
 //
 // This process helps in reducing the amount of data sent to InfluxDB by summarizing it.
+//
+// A measurement given a deadband is only written when its average moved by more than the
+// deadband since the point last written for the series, or when `max_suppression_secs` passed
+// since then, so that a door or relay reporting the same value for hours still shows up
+// regularly without a point every window.
+//
+// The averages of a measurement are written as the field type pinned for it in `field_types`,
+// floats unless pinned, so that InfluxDB never rejects a window for a field conflicting with
+// the type it was first written as. A window whose averages cannot be converted (a boolean
+// measurement averaging 0.5) is not written but kept aside for the dead-letter directory.
+//
+// The constant fields of the `[fields]` table are added to every averaged point, and to the raw
+// samples, unless the point already has a field of that name: the sensor data wins, and the
+// collision is warned about once per field and measurement.
+//
+// The aggregator keeps the points of the window being read until it is closed. A window closed
+// before it is over, at shutdown, is averaged all the same, and its points carry the seconds it
+// actually covered in a `covered_secs` field, so they can be told from those of full windows.
+//
+// The firmware generations in the field send JSON items of slightly different shapes: protocol 1
+// names the measurement in `type`, protocol 2 in `sensor`, and protocol 3 adds the sequence
+// number of the frame in `seq`. The protocol a device speaks is negotiated when it is opened,
+// and the items of every generation end up as the same MyDataPoints.
+//
+// Frames are parsed and windows averaged on small gateways, for every frame of every source: the
+// points of a frame share the tag set of their source instead of each holding a copy, and the
+// tag sets are only copied when a point gets a tag of its own.
+//
+// Readings the probe could not take (NaN, infinity, or a configured sentinel value) are kept
+// as explicitly missing samples: they are counted per measurement but never averaged, so an
+// unplugged probe produces a gap instead of a garbage value.
+
+use crate::clock::{self, Clock};
+use crate::clock_skew::ClockSkewCorrector;
+use crate::config::{
+    AggregationConfig, FieldType, ParserConfig, Reducer, SampleCountMode, StaticField,
+    SAMPLE_COUNT_MEASUREMENT_TAG,
+};
+use crate::errors::AppError;
+use crate::source::SOURCE_TAG;
 
-use chrono::Utc;
+use influxdb2::models::data_point::DataPointBuilder;
 use influxdb2::models::{DataPoint, FieldValue};
-use log::{debug, error, trace};
-use std::collections::BTreeMap;
-use std::error::Error;
+use log::{debug, error, trace, warn};
+use serde_json::Value;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::time::Instant;
+
+/// Tag telling which clock the timestamp of a point carrying a device timestamp comes from.
+pub const TIMESTAMP_SOURCE_TAG: &str = "ts_source";
+pub const TIMESTAMP_SOURCE_DEVICE: &str = "device";
+/// The device clock was unset, the host clock was used instead.
+pub const TIMESTAMP_SOURCE_HOST: &str = "host";
+
+/// Tag telling which protocol the device of a point negotiated.
+pub const PROTOCOL_TAG: &str = "protocol";
+
+/// The frame protocol of a firmware generation, as answered to `PROTOCOL?`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// Items name their measurement in `type`; firmware that does not answer speaks it.
+    #[default]
+    V1,
+    /// Items name their measurement in `sensor`.
+    V2,
+    /// As protocol 2, with the sequence number of the frame in `seq`.
+    V3,
+}
+
+impl Protocol {
+    /// Reads the answer of the device, `PROTO 1`, `PROTO 2` or `PROTO 3`.
+    pub fn from_answer(answer: &str) -> Option<Self> {
+        let number = answer.trim().strip_prefix("PROTO ")?.trim().parse().ok()?;
+        Self::from_number(number)
+    }
+
+    pub fn from_number(number: u8) -> Option<Self> {
+        match number {
+            1 => Some(Protocol::V1),
+            2 => Some(Protocol::V2),
+            3 => Some(Protocol::V3),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u8 {
+        match self {
+            Protocol::V1 => 1,
+            Protocol::V2 => 2,
+            Protocol::V3 => 3,
+        }
+    }
+
+    /// The member of a JSON item naming its measurement.
+    fn measurement_key(self) -> &'static str {
+        match self {
+            Protocol::V1 => "type",
+            Protocol::V2 | Protocol::V3 => "sensor",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+/// A single sensor reading as decoded from a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    Value(f64),
+    /// The probe reported no usable value (NaN, infinity, or the configured sentinel).
+    Missing,
+}
+
+impl Reading {
+    /// Classifies a decoded number, turning non-finite values and the sentinel into `Missing`.
+    fn from_f64(value: f64, config: &ParserConfig) -> Self {
+        let is_sentinel = config.missing_sentinel.is_some_and(|sentinel| {
+            (value - sentinel).abs() < f64::EPSILON * sentinel.abs().max(1.0)
+        });
+
+        if !value.is_finite() || is_sentinel {
+            Reading::Missing
+        } else {
+            Reading::Value(value)
+        }
+    }
+
+    /// Parses a textual reading. `f64::from_str` already understands "NaN", "inf" and
+    /// "infinity" in any case, which covers what the firmware prints for a failed read.
+    fn parse(token: &str, config: &ParserConfig) -> Option<Self> {
+        token
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|value| Self::from_f64(value, config))
+    }
+
+    /// Decodes the `value` member of a JSON frame. Numbers are used as-is, `null` and the
+    /// strings "NaN"/"Inf"/"-Inf" mark the reading as missing.
+    fn from_json(value: &Value, config: &ParserConfig) -> Result<Self, String> {
+        match value {
+            Value::Number(number) => number
+                .as_f64()
+                .map(|value| Self::from_f64(value, config))
+                .ok_or_else(|| format!("unrepresentable number {}", number)),
+            Value::Null => Ok(Reading::Missing),
+            Value::String(text) => match Self::parse(text, config) {
+                Some(Reading::Missing) => Ok(Reading::Missing),
+                _ => Err(format!("unexpected string value '{}'", text)),
+            },
+            other => Err(format!("unsupported value {}", other)),
+        }
+    }
+}
 
 /// Represents a custom data point.
-#[derive(Debug, Clone)]
+///
+/// A point usually carries a single `value` field, but sensors reporting structured objects
+/// produce one field per (flattened) key. Readings that were missing are not stored as fields;
+/// only their number is kept so they can be accounted for. The tags are shared with the other
+/// points of the frame.
+#[derive(Debug, Clone, PartialEq)]
 pub struct MyDataPoint {
     measurement: String,
-    tags: BTreeMap<String, String>,
+    tags: Arc<BTreeMap<String, String>>,
     fields: BTreeMap<String, FieldValue>,
     timestamp: Option<i64>,
+    missing_readings: usize,
+    /// Sequence number of the frame, sent by protocol 3 devices.
+    seq: Option<u32>,
 }
 
 impl MyDataPoint {
-    pub fn new(
+    /// Creates a point from named readings, keeping only the valid ones as fields.
+    pub fn from_readings(
         measurement: String,
-        tags: BTreeMap<String, String>,
-        field: FieldValue,
+        tags: impl Into<Arc<BTreeMap<String, String>>>,
+        readings: BTreeMap<String, Reading>,
         timestamp: i64,
     ) -> Self {
+        let mut missing_readings = 0;
+        let fields = readings
+            .into_iter()
+            .filter_map(|(name, reading)| match reading {
+                Reading::Value(value) => Some((name, FieldValue::from(value))),
+                Reading::Missing => {
+                    missing_readings += 1;
+                    None
+                }
+            })
+            .collect();
+
         Self {
             measurement,
-            tags,
-            fields: BTreeMap::from([("value".into(), field)]),
+            tags: tags.into(),
+            fields,
             timestamp: Some(timestamp),
+            missing_readings,
+            seq: None,
         }
     }
 
+    /// Sets the sequence number of the frame the point was read from.
+    pub fn with_seq(mut self, seq: Option<u32>) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    pub fn from_reading(
+        measurement: String,
+        tags: impl Into<Arc<BTreeMap<String, String>>>,
+        reading: Reading,
+        timestamp: i64,
+    ) -> Self {
+        Self::from_readings(
+            measurement,
+            tags,
+            BTreeMap::from([("value".into(), reading)]),
+            timestamp,
+        )
+    }
+
+    /// Number of readings of this sample the probe could not take.
+    pub fn missing_readings(&self) -> usize {
+        self.missing_readings
+    }
+
     pub fn get_measurement(&self) -> &str {
         &self.measurement
     }
 
-    pub fn get_field_value(&self) -> Option<f64> {
-        if let Some(FieldValue::F64(value)) = self.fields.get("value") {
-            Some(*value)
-        } else {
-            None
-        }
+    /// Iterates over the finite floating point fields of this point.
+    pub fn get_float_fields(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.fields.iter().filter_map(|(name, field)| match field {
+            FieldValue::F64(value) if value.is_finite() => Some((name.as_str(), *value)),
+            _ => None,
+        })
+    }
+
+    pub fn get_fields(&self) -> &BTreeMap<String, FieldValue> {
+        &self.fields
     }
 
     pub fn get_timestamp(&self) -> Option<i64> {
         self.timestamp
     }
 
-    pub fn get_tags(&self) -> BTreeMap<String, String> {
-        self.tags.clone()
+    pub fn get_tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    pub fn get_tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+
+    /// Sets a tag, replacing its previous value. The point gets a tag set of its own, unless it
+    /// already has the tag.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        if self.get_tag(key) == Some(value) {
+            return;
+        }
+        Arc::make_mut(&mut self.tags).insert(key.to_string(), value.to_string());
+    }
+
+    pub fn get_seq(&self) -> Option<u32> {
+        self.seq
+    }
+}
+
+/// Counts the samples flagged as missing, per measurement.
+fn count_missing_per_measurement(data_points: &[MyDataPoint]) -> BTreeMap<String, usize> {
+    data_points
+        .iter()
+        .filter(|point| point.missing_readings() > 0)
+        .fold(BTreeMap::new(), |mut acc, point| {
+            *acc.entry(point.get_measurement().to_string()).or_insert(0) +=
+                point.missing_readings();
+            acc
+        })
+}
+
+/// Identifies a series: the source its points were read from, their measurement, and their tag
+/// set. The tags are shared by the clones of a key, which the aggregator keeps across windows.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct SeriesKey {
+    source: Option<String>,
+    measurement: String,
+    tags: Arc<BTreeMap<String, String>>,
+}
+
+impl SeriesKey {
+    /// The key of a point, taking over its tags: the points of a window are not asked for their
+    /// tags anymore once grouped.
+    fn take_from(point: &mut MyDataPoint) -> Self {
+        let tags = sanitize_tags(std::mem::take(&mut point.tags));
+        Self {
+            source: tags.get(SOURCE_TAG).cloned(),
+            measurement: point.get_measurement().trim().to_string(),
+            tags,
+        }
     }
 }
 
-/// Groups and filters data points by measurement type.
+/// Groups and filters data points by series (measurement and tags). Measurement names and tags
+/// are trimmed first; points without a measurement name are dropped, and so are tags left
+/// without a key or a value, which line protocol cannot carry.
 fn group_and_filter_data_points(
     data_points: Vec<MyDataPoint>,
-) -> BTreeMap<String, Vec<MyDataPoint>> {
-    let valid_points: Vec<MyDataPoint> = data_points
+) -> BTreeMap<SeriesKey, Vec<MyDataPoint>> {
+    data_points
         .into_iter()
         .filter(|point| {
-            let is_valid = point.get_field_value().is_some()
+            let is_valid = point.get_float_fields().next().is_some()
                 && point.get_timestamp().is_some()
-                && !point.get_measurement().is_empty();
+                && !point.get_measurement().trim().is_empty();
             trace!("Filtering point: {:?}, valid: {}", point, is_valid);
             is_valid
         })
-        .collect();
-
-    valid_points
-        .into_iter()
-        .fold(BTreeMap::new(), |mut acc, point| {
-            acc.entry(point.get_measurement().to_string())
+        .fold(BTreeMap::new(), |mut acc, mut point| {
+            acc.entry(SeriesKey::take_from(&mut point))
                 .or_insert_with(Vec::new)
                 .push(point);
             acc
         })
 }
 
-/// Calculates the average value and timestamp for a group of data points.
-fn calculate_average_for_group(points: &[MyDataPoint]) -> Option<(f64, i64)> {
-    let count = points.len() as f64;
+/// Trims tag keys and values, dropping the tags left empty. Tags already clean, as they usually
+/// are, are kept as they are, still shared.
+fn sanitize_tags(tags: Arc<BTreeMap<String, String>>) -> Arc<BTreeMap<String, String>> {
+    let clean = |text: &str| !text.is_empty() && text.trim().len() == text.len();
+    if tags.iter().all(|(key, value)| clean(key) && clean(value)) {
+        return tags;
+    }
+    let sanitized: BTreeMap<String, String> = tags
+        .iter()
+        .filter_map(|(key, value)| {
+            let (key, value) = (key.trim(), value.trim());
+            if key.is_empty() || value.is_empty() {
+                debug!("Dropping empty tag '{}'='{}'", key, value);
+                return None;
+            }
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect();
+    Arc::new(sanitized)
+}
+
+/// Calculates the average of every field and the average timestamp for a group of data points.
+/// Each field is averaged independently over the samples that carry a finite value for it;
+/// `None` is returned when nothing valid remains.
+fn calculate_average_for_group(points: &[MyDataPoint]) -> Option<(BTreeMap<String, f64>, i64)> {
+    let mut sums: BTreeMap<&str, (f64, usize)> = BTreeMap::new();
+    let mut timestamps = Vec::new();
 
-    // Handle case with no data points
-    if count == 0.0 {
+    for point in points {
+        let mut contributed = false;
+        for (name, value) in point.get_float_fields() {
+            let (sum, count) = sums.entry(name).or_insert((0.0, 0));
+            *sum += value;
+            *count += 1;
+            contributed = true;
+        }
+        if let (true, Some(timestamp)) = (contributed, point.get_timestamp()) {
+            timestamps.push(timestamp);
+        }
+    }
+
+    // Handle case with no valid data points
+    if timestamps.is_empty() {
         return None;
     }
 
-    let average_value = points
-        .iter()
-        .filter_map(|p| p.get_field_value())
-        .sum::<f64>()
-        / count;
+    let averages: BTreeMap<String, f64> = sums
+        .into_iter()
+        .map(|(name, (sum, count))| (name.to_string(), sum / count as f64))
+        // Guard against overflow to infinity when summing extreme values.
+        .filter(|(name, average)| {
+            let finite = average.is_finite();
+            if !finite {
+                warn!("Average of field '{}' is not finite, skipping", name);
+            }
+            finite
+        })
+        .collect();
 
-    let average_timestamp = points
-        .iter()
-        .filter_map(|p| p.get_timestamp())
-        .map(|ts| ts as f64)
-        .sum::<f64>() as i64
-        / count as i64;
+    if averages.is_empty() {
+        return None;
+    }
+
+    // Summed exactly: a sum of nanosecond timestamps as f64 loses the precision of each of them
+    let count = timestamps.len();
+    let average_timestamp =
+        (timestamps.iter().map(|ts| *ts as i128).sum::<i128>() / count as i128) as i64;
 
     debug!(
-        "Calculated averages - Value: {}, Timestamp: {} for {} points",
-        average_value, average_timestamp, count
+        "Calculated averages - Values: {:?}, Timestamp: {} for {} points",
+        averages, average_timestamp, count
     );
 
-    Some((average_value, average_timestamp))
+    Some((averages, average_timestamp))
+}
+
+/// Calculates the time-weighted average of every field for a group of data points, which must
+/// all have a timestamp. Every sample of a field weighs the time until the next sample of that
+/// field, the last one the time until `window_end`, so that a burst of samples counts for the
+/// time it lasted rather than for its number. The average timestamp is the one of
+/// `calculate_average_for_group`.
+///
+/// A field whose samples all lie at the end of the window, leaving nothing to weigh, gets the
+/// plain mean.
+fn calculate_time_weighted_average_for_group(
+    points: &[MyDataPoint],
+    window_end: i64,
+) -> Option<(BTreeMap<String, f64>, i64)> {
+    let (means, average_timestamp) = calculate_average_for_group(points)?;
+
+    let mut samples: BTreeMap<&str, Vec<(i64, f64)>> = BTreeMap::new();
+    for point in points {
+        let Some(timestamp) = point.get_timestamp() else {
+            continue;
+        };
+        for (name, value) in point.get_float_fields() {
+            samples.entry(name).or_default().push((timestamp, value));
+        }
+    }
+
+    let averages = samples
+        .into_iter()
+        // The fields whose mean was not finite are left out
+        .filter_map(|(name, samples)| Some((name, samples, *means.get(name)?)))
+        .filter_map(|(name, mut samples, mean)| {
+            samples.sort_by_key(|(timestamp, _)| *timestamp);
+            let ends = samples
+                .iter()
+                .skip(1)
+                .map(|(timestamp, _)| *timestamp)
+                .chain(std::iter::once(window_end));
+            let (weighted_sum, total_weight) = samples.iter().zip(ends).fold(
+                (0.0, 0.0),
+                |(weighted_sum, total_weight), ((timestamp, value), end)| {
+                    // Device timestamps may lie past the end of the window
+                    let weight = end.saturating_sub(*timestamp).max(0) as f64;
+                    (weighted_sum + value * weight, total_weight + weight)
+                },
+            );
+            let average = match total_weight > 0.0 {
+                true => weighted_sum / total_weight,
+                false => mean,
+            };
+            if !average.is_finite() {
+                warn!(
+                    "Time-weighted average of field '{}' is not finite, skipping",
+                    name
+                );
+                return None;
+            }
+            Some((name.to_string(), average))
+        })
+        .collect::<BTreeMap<String, f64>>();
+
+    if averages.is_empty() {
+        return None;
+    }
+    Some((averages, average_timestamp))
+}
+
+/// Converts an average to the field type pinned for its measurement.
+fn coerce_average(value: f64, field_type: FieldType) -> Result<FieldValue, String> {
+    match field_type {
+        FieldType::Float => Ok(FieldValue::F64(value)),
+        FieldType::Int => {
+            let rounded = value.round();
+            if (i64::MIN as f64..i64::MAX as f64).contains(&rounded) {
+                Ok(FieldValue::I64(rounded as i64))
+            } else {
+                Err(format!("{} is out of the range of an integer", value))
+            }
+        }
+        FieldType::Bool if value == 0.0 || value == 1.0 => Ok(FieldValue::Bool(value == 1.0)),
+        FieldType::Bool => Err(format!("{} is neither 0 nor 1", value)),
+        FieldType::String => Ok(FieldValue::String(value.to_string())),
+    }
+}
+
+/// Constant fields added to every emitted point, from the `[fields]` table.
+#[derive(Clone, Default)]
+pub struct StaticFields {
+    fields: Arc<BTreeMap<String, FieldValue>>,
+    /// Fields and measurements whose collision was warned about already.
+    collisions: Arc<Mutex<BTreeSet<(String, String)>>>,
+}
+
+impl StaticFields {
+    pub fn new(fields: &BTreeMap<String, StaticField>) -> Self {
+        let fields = fields
+            .iter()
+            .map(|(name, value)| (name.clone(), FieldValue::from(value)))
+            .collect();
+        Self {
+            fields: Arc::new(fields),
+            collisions: Arc::default(),
+        }
+    }
+
+    /// Adds the fields to `builder`, except those `produced` tells the point has already.
+    pub fn add_to(
+        &self,
+        measurement: &str,
+        builder: DataPointBuilder,
+        produced: impl Fn(&str) -> bool,
+    ) -> DataPointBuilder {
+        self.fields.iter().fold(builder, |builder, (name, value)| {
+            if !produced(name) {
+                return builder.field(name, value.clone());
+            }
+            let collision = (name.clone(), measurement.to_string());
+            let mut collisions = self
+                .collisions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if collisions.insert(collision) {
+                warn!(
+                    "Measurement {} has a field {} of its own, the [fields] entry is not added \
+                     to its points",
+                    measurement, name
+                );
+            }
+            builder
+        })
+    }
+}
+
+impl From<&StaticField> for FieldValue {
+    fn from(value: &StaticField) -> Self {
+        match value {
+            StaticField::Bool(value) => FieldValue::Bool(*value),
+            StaticField::Int(value) => FieldValue::I64(*value),
+            StaticField::Float(value) => FieldValue::F64(*value),
+            StaticField::String(value) => FieldValue::String(value.clone()),
+        }
+    }
 }
 
-/// Creates a new averaged DataPoint from a group of MyDataPoints.
+/// Creates a new averaged DataPoint from a group of MyDataPoints, optionally carrying the
+/// number of samples it summarizes in a `count` field, and the static fields it does not have.
+/// Fails when the builder rejects the point.
 fn create_averaged_data_point(
     measurement: &str,
-    average_value: f64,
+    fields: &BTreeMap<String, FieldValue>,
     average_timestamp: i64,
-    tags: BTreeMap<String, String>,
-) -> DataPoint {
-    let builder = DataPoint::builder(measurement)
-        .field("value", average_value)
-        .timestamp(average_timestamp);
+    tags: &BTreeMap<String, String>,
+    sample_count: Option<i64>,
+    static_fields: &StaticFields,
+) -> Result<DataPoint, String> {
+    let builder = fields.iter().fold(
+        DataPoint::builder(measurement).timestamp(average_timestamp),
+        |builder, (name, value)| builder.field(name, value.clone()),
+    );
+    let builder = match sample_count {
+        Some(count) => builder.field("count", count),
+        None => builder,
+    };
+    let builder = static_fields.add_to(measurement, builder, |name| {
+        fields.contains_key(name) || (sample_count.is_some() && name == "count")
+    });
 
     tags.iter()
         .fold(builder, |builder, (key, value)| builder.tag(key, value))
         .build()
-        .unwrap()
+        .map_err(|e| e.to_string())
 }
 
-/// Main function to calculate average data points from a vector of MyDataPoints.
-pub fn calculate_average(data_points: Vec<MyDataPoint>) -> Vec<DataPoint> {
-    let grouped_points = group_and_filter_data_points(data_points);
+/// Creates the data point reporting how many samples a series received in a window. Fails when
+/// the builder rejects the point, or when a tag of the series is named like the tag carrying its
+/// measurement in the `sample_count` measurement, which would hide it.
+fn create_sample_count_data_point(
+    mode: SampleCountMode,
+    series: &SeriesKey,
+    count: i64,
+    timestamp: i64,
+) -> Result<DataPoint, String> {
+    let measurement = &series.measurement;
+    let builder = match mode {
+        SampleCountMode::Field => DataPoint::builder(measurement).field("count", count),
+        _ if series.tags.contains_key(SAMPLE_COUNT_MEASUREMENT_TAG) => {
+            return Err(format!(
+                "the series has a tag named {}",
+                SAMPLE_COUNT_MEASUREMENT_TAG
+            ))
+        }
+        _ => DataPoint::builder(SAMPLE_COUNT_MEASUREMENT)
+            .tag(SAMPLE_COUNT_MEASUREMENT_TAG, measurement)
+            .field("value", count),
+    };
 
-    grouped_points
-        .into_iter()
-        .filter(|(_, points)| !points.is_empty())
-        .filter_map(|(measurement, points)| {
+    series
+        .tags
+        .iter()
+        .fold(builder.timestamp(timestamp), |builder, (key, value)| {
+            builder.tag(key, value)
+        })
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Measurement used for the per-window sample counts in `SampleCountMode::Measurement`.
+const SAMPLE_COUNT_MEASUREMENT: &str = "sample_count";
+/// Field the points of a window closed before it was over carry the seconds it covered in.
+const COVERED_FIELD: &str = "covered_secs";
+
+/// Collects the raw points of the current window and turns them into averaged DataPoints when
+/// the window is closed.
+///
+/// The aggregator remembers which series it has seen in the last few windows so that, when
+/// sample counts are enabled, a series that suddenly stops reporting shows up as a count of 0
+/// rather than as a silent gap.
+///
+/// It also remembers the averages last written for the series of the measurements given a
+/// deadband, to leave out the windows that did not move beyond it, and keeps the points whose
+/// averages do not fit their pinned field type until they are taken.
+pub struct Aggregator {
+    sample_count: SampleCountMode,
+    series_memory_windows: u32,
+    // Number of consecutive windows each recently seen series went without samples.
+    recent_series: BTreeMap<SeriesKey, u32>,
+    deadband: BTreeMap<String, f64>,
+    max_suppression_ns: i64,
+    // The averages and timestamp of the point last written, per series with a deadband.
+    last_emitted: HashMap<SeriesKey, (BTreeMap<String, f64>, i64)>,
+    // Windows left out since `take_suppressed` was last called.
+    suppressed: u64,
+    // Readings missing per measurement since `take_missing` was last called.
+    missing: BTreeMap<String, u64>,
+    field_types: BTreeMap<String, FieldType>,
+    reducers: BTreeMap<String, Reducer>,
+    static_fields: StaticFields,
+    // Points not fitting their field type since `take_rejected` was last called, as averaged.
+    rejected: Vec<DataPoint>,
+    // The points of the current window and when it was opened, on the monotonic clock.
+    window: Vec<MyDataPoint>,
+    window_opened: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl Aggregator {
+    pub fn new(config: &AggregationConfig) -> Self {
+        Self {
+            sample_count: config.sample_count,
+            series_memory_windows: config.series_memory_windows,
+            recent_series: BTreeMap::new(),
+            deadband: config.deadband.clone(),
+            max_suppression_ns: suppression_ns(config),
+            last_emitted: HashMap::new(),
+            suppressed: 0,
+            missing: BTreeMap::new(),
+            field_types: config.field_types.clone(),
+            reducers: config.reducers.clone(),
+            static_fields: StaticFields::default(),
+            rejected: Vec::new(),
+            window: Vec::new(),
+            window_opened: Instant::now(),
+            clock: clock::system(),
+        }
+    }
+
+    /// Stamps the zero counts of idle series and times the window with `clock` instead of the
+    /// system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.window_opened = clock.now_monotonic();
+        self.clock = clock;
+        self
+    }
+
+    /// Adds the static fields to every averaged point.
+    pub fn with_static_fields(mut self, static_fields: StaticFields) -> Self {
+        self.static_fields = static_fields;
+        self
+    }
+
+    /// Applies reloaded settings, keeping the series seen so far.
+    pub fn reconfigure(&mut self, config: &AggregationConfig) {
+        self.sample_count = config.sample_count;
+        self.series_memory_windows = config.series_memory_windows;
+        self.deadband = config.deadband.clone();
+        self.max_suppression_ns = suppression_ns(config);
+        self.field_types = config.field_types.clone();
+        self.reducers = config.reducers.clone();
+        let deadband = &self.deadband;
+        self.last_emitted
+            .retain(|series, _| deadband.contains_key(&series.measurement));
+    }
+
+    /// Number of series windows left out by their deadband since the last call.
+    pub fn take_suppressed(&mut self) -> u64 {
+        std::mem::take(&mut self.suppressed)
+    }
+
+    /// Number of readings the probes could not take since the last call, per measurement.
+    pub fn take_missing(&mut self) -> BTreeMap<String, u64> {
+        std::mem::take(&mut self.missing)
+    }
+
+    /// Points of the windows averaged since the last call whose averages could not be converted
+    /// to their pinned field type, with their averages as floats.
+    pub fn take_rejected(&mut self) -> Vec<DataPoint> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    /// Adds points to the current window.
+    pub fn push(&mut self, data_points: Vec<MyDataPoint>) {
+        self.window.extend(data_points);
+    }
+
+    /// Number of points in the current window.
+    pub fn pending(&self) -> usize {
+        self.window.len()
+    }
+
+    /// Averages the points of the window, which is over, and opens the next one.
+    pub fn close_window(&mut self) -> Vec<DataPoint> {
+        let data_points = self.take_window();
+        self.average(data_points, None)
+    }
+
+    /// Averages the points of the window although it is not over yet, as at shutdown. The
+    /// averaged points carry the seconds the window covered. Opens the next window.
+    pub fn close_current_window(&mut self) -> Vec<DataPoint> {
+        let covered = self
+            .clock
+            .now_monotonic()
+            .saturating_duration_since(self.window_opened);
+        let data_points = self.take_window();
+        if data_points.is_empty() {
+            return Vec::new();
+        }
+        self.average(data_points, Some(covered.as_secs_f64()))
+    }
+
+    /// Drops the points of the window and opens the next one. Returns how many were dropped.
+    pub fn discard_window(&mut self) -> usize {
+        self.take_window().len()
+    }
+
+    fn take_window(&mut self) -> Vec<MyDataPoint> {
+        self.window_opened = self.clock.now_monotonic();
+        std::mem::take(&mut self.window)
+    }
+
+    /// Calculates the average data points of a window from a vector of MyDataPoints.
+    pub fn aggregate(&mut self, data_points: Vec<MyDataPoint>) -> Vec<DataPoint> {
+        self.average(data_points, None)
+    }
+
+    /// Averages the points of a window, adding the seconds it covered to the averaged points
+    /// when given.
+    fn average(
+        &mut self,
+        data_points: Vec<MyDataPoint>,
+        covered_secs: Option<f64>,
+    ) -> Vec<DataPoint> {
+        for (measurement, missing) in count_missing_per_measurement(&data_points) {
+            warn!(
+                "{} missing readings (NaN, infinity or sentinel) for measurement: {}",
+                missing, measurement
+            );
+            *self.missing.entry(measurement).or_insert(0) += missing as u64;
+        }
+
+        let grouped_points = group_and_filter_data_points(data_points);
+        let mut output = Vec::new();
+        // The window ends as it is averaged
+        let window_end = match clock::now_nanos(self.clock.as_ref()) {
+            Ok(window_end) => window_end,
+            Err(e) => {
+                error!("Failed to close the window: {}", e);
+                return Vec::new();
+            }
+        };
+
+        for (series, points) in &grouped_points {
+            let SeriesKey {
+                measurement, tags, ..
+            } = series;
             debug!("Averaging points for measurement: {}", measurement);
-            match calculate_average_for_group(&points) {
-                Some((average_value, average_timestamp)) => {
+            let count = points.len() as i64;
+
+            match self.reduce(series, points, window_end) {
+                Some((averages, average_timestamp)) => {
                     debug!(
-                        "Calculated average - Measurement: {}, Average Value: {}, Average Timestamp: {}",
-                        measurement, average_value, average_timestamp
+                        "Calculated average - Measurement: {}, Average Values: {:?}, Average Timestamp: {}",
+                        measurement, averages, average_timestamp
                     );
 
-                    let first_point = points.first()?;
-                    Some(create_averaged_data_point(
-                        &measurement,
-                        average_value,
+                    if self.within_deadband(series, &averages, average_timestamp) {
+                        debug!("Series {:?} within its deadband, not written", series);
+                        self.suppressed += 1;
+                        continue;
+                    }
+
+                    let field_type = self
+                        .field_types
+                        .get(measurement)
+                        .copied()
+                        .unwrap_or_default();
+                    let fields = averages
+                        .iter()
+                        .map(|(name, value)| {
+                            Ok((name.clone(), coerce_average(*value, field_type)?))
+                        })
+                        .collect::<Result<BTreeMap<_, _>, String>>();
+                    let mut fields = match fields {
+                        Ok(fields) => fields,
+                        Err(e) => {
+                            warn!(
+                                "Series {:?} of this window is not a {:?}: {}",
+                                series, field_type, e
+                            );
+                            let floats = averages
+                                .iter()
+                                .map(|(name, value)| (name.clone(), FieldValue::F64(*value)))
+                                .collect();
+                            match create_averaged_data_point(
+                                measurement,
+                                &floats,
+                                average_timestamp,
+                                tags,
+                                None,
+                                &self.static_fields,
+                            ) {
+                                Ok(point) => self.rejected.push(point),
+                                Err(e) => {
+                                    warn!("Skipping series {:?} of this window: {}", series, e)
+                                }
+                            }
+                            continue;
+                        }
+                    };
+
+                    if let Some(secs) = covered_secs {
+                        fields.insert(COVERED_FIELD.to_string(), FieldValue::F64(secs));
+                    }
+                    let count_field =
+                        (self.sample_count == SampleCountMode::Field).then_some(count);
+                    let mut points = vec![create_averaged_data_point(
+                        measurement,
+                        &fields,
                         average_timestamp,
-                        first_point.get_tags(),
-                    ))
+                        tags,
+                        count_field,
+                        &self.static_fields,
+                    )];
+                    if self.sample_count == SampleCountMode::Measurement {
+                        points.push(create_sample_count_data_point(
+                            self.sample_count,
+                            series,
+                            count,
+                            average_timestamp,
+                        ));
+                    }
+                    // A series the builder rejects is skipped, the rest of the window is kept
+                    for point in points {
+                        match point {
+                            Ok(point) => output.push(point),
+                            Err(e) => warn!("Skipping series {:?} of this window: {}", series, e),
+                        }
+                    }
                 }
                 None => {
                     debug!("No valid points for measurement: {}", measurement);
-                    None
                 }
             }
-        })
-        .collect()
+        }
+
+        if self.sample_count != SampleCountMode::Off {
+            output.extend(self.track_series(&grouped_points, window_end));
+        }
+
+        output
+    }
+
+    /// Averages the points of a series with the reducer of its measurement. Every point has a
+    /// timestamp by now, from the device or from the host clock as it was parsed.
+    fn reduce(
+        &self,
+        series: &SeriesKey,
+        points: &[MyDataPoint],
+        window_end: i64,
+    ) -> Option<(BTreeMap<String, f64>, i64)> {
+        let reducer = self
+            .reducers
+            .get(&series.measurement)
+            .copied()
+            .unwrap_or_default();
+        match reducer {
+            Reducer::Mean => calculate_average_for_group(points),
+            Reducer::TimeWeightedMean => {
+                calculate_time_weighted_average_for_group(points, window_end)
+            }
+        }
+    }
+
+    /// Whether the averages of the series moved by no more than its deadband since the point
+    /// last written, which was less than the longest suppression ago. The averages are
+    /// remembered as written otherwise.
+    fn within_deadband(
+        &mut self,
+        series: &SeriesKey,
+        averages: &BTreeMap<String, f64>,
+        timestamp: i64,
+    ) -> bool {
+        let Some(&deadband) = self.deadband.get(&series.measurement) else {
+            return false;
+        };
+        if let Some((last, last_timestamp)) = self.last_emitted.get(series) {
+            let unchanged = last.len() == averages.len()
+                && averages.iter().all(|(name, value)| {
+                    last.get(name)
+                        .is_some_and(|last| (value - last).abs() <= deadband)
+                });
+            // A clock stepping back writes the point
+            let elapsed = timestamp - last_timestamp;
+            if unchanged && (0..self.max_suppression_ns).contains(&elapsed) {
+                return true;
+            }
+        }
+        self.last_emitted
+            .insert(series.clone(), (averages.clone(), timestamp));
+        false
+    }
+
+    /// Updates the recently seen series and returns zero-count points, at the end of the
+    /// window, for those that received no samples in this window.
+    fn track_series(
+        &mut self,
+        grouped_points: &BTreeMap<SeriesKey, Vec<MyDataPoint>>,
+        window_end: i64,
+    ) -> Vec<DataPoint> {
+        let memory = self.series_memory_windows;
+        let mut zero_counts = Vec::new();
+
+        self.recent_series.retain(|series, idle_windows| {
+            if grouped_points.contains_key(series) {
+                return true;
+            }
+            *idle_windows += 1;
+            if *idle_windows > memory {
+                debug!(
+                    "Forgetting series {:?} after {} idle windows",
+                    series, memory
+                );
+                return false;
+            }
+            warn!("No samples received for series {:?} in this window", series);
+            zero_counts.push(series.clone());
+            true
+        });
+
+        for series in grouped_points.keys() {
+            self.recent_series.insert(series.clone(), 0);
+        }
+
+        zero_counts
+            .iter()
+            .filter_map(|series| {
+                create_sample_count_data_point(self.sample_count, series, 0, window_end)
+                    .map_err(|e| warn!("Skipping the sample count of series {:?}: {}", series, e))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+fn suppression_ns(config: &AggregationConfig) -> i64 {
+    (config.max_suppression_secs as i64).saturating_mul(1_000_000_000)
 }
 
 /// Parses sensor data from a formatted string and creates a set of data points for InfluxDB.
+///
+/// Two frame formats are understood:
+/// - the legacy `<temperature|humidity|air_quality>` frame sent by the bundled sketch;
+/// - JSON frames, either a single `{"type": "...", "value": ...}` object or an array of them,
+///   in the shape of the `protocol` the device negotiated.
+///
+/// The points of a device that negotiated its protocol are tagged with it; `None` is for the
+/// devices that do not negotiate, which speak protocol 1.
+///
+/// JSON items may carry their own `timestamp` (seconds since the epoch); those are checked
+/// against the host clock by `skew` and corrected or dropped according to its mode. Timestamps
+/// before the configured floor come from an unset device clock and are replaced with the host
+/// clock; the `ts_source` tag of these points tells which clock was used. The host clock is
+/// read from `clock`.
 pub fn parse_sensor_data(
     input: String,
-    location: &str,
-) -> Result<Vec<MyDataPoint>, Box<dyn Error + Send + Sync>> {
+    tags: &BTreeMap<String, String>,
+    config: &ParserConfig,
+    protocol: Option<Protocol>,
+    skew: &mut ClockSkewCorrector,
+    clock: &dyn Clock,
+) -> Result<Vec<MyDataPoint>, AppError> {
+    let timestamp = clock::now_nanos(clock)?;
+    let mut tags = tags.clone();
+    if let Some(protocol) = protocol {
+        tags.insert(PROTOCOL_TAG.to_string(), protocol.to_string());
+    }
+    let tags = Arc::new(tags);
+    let protocol = protocol.unwrap_or_default();
+
+    let trimmed = input.trim();
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        let tags = FrameTags::new(tags);
+        let frame = parse_json_frame(trimmed, &tags, timestamp, config, protocol, skew);
+        return frame.map_err(|reason| AppError::Parse {
+            reason,
+            raw_frame: input.clone(),
+        });
+    }
+
     // Sanitize and split the input data.
-    let parts: Vec<f64> = input
-        .trim()
+    let parts: Vec<Reading> = trimmed
         .trim_matches(|c: char| c == '<' || c == '>')
         .split('|')
-        .map(str::trim)
-        .filter_map(|s| s.parse::<f64>().ok())
+        .filter_map(|s| Reading::parse(s, config))
         .collect();
 
     // Use pattern matching to validate and destructure the parts directly.
     match parts.as_slice() {
         [temperature, humidity, air_quality] => {
             debug!(
-                "Data {}, {}, {} parsed successfully from input: {}",
+                "Data {:?}, {:?}, {:?} parsed successfully from input: {}",
                 temperature, humidity, air_quality, input
             );
-            let timestamp = Utc::now().timestamp_nanos_opt().unwrap();
-
-            let tags = BTreeMap::from([("location".into(), location.into())]);
 
             let points: Vec<MyDataPoint> = vec![
-                MyDataPoint::new(
+                MyDataPoint::from_reading(
                     "temperature".into(),
-                    tags.clone(),
-                    FieldValue::from(*temperature),
+                    Arc::clone(&tags),
+                    *temperature,
                     timestamp,
                 ),
-                MyDataPoint::new(
+                MyDataPoint::from_reading(
                     "humidity".into(),
-                    tags.clone(),
-                    FieldValue::from(*humidity),
-                    timestamp,
-                ),
-                MyDataPoint::new(
-                    "air_quality".into(),
-                    tags,
-                    FieldValue::from(*air_quality),
+                    Arc::clone(&tags),
+                    *humidity,
                     timestamp,
                 ),
+                MyDataPoint::from_reading("air_quality".into(), tags, *air_quality, timestamp),
             ];
             Ok(points)
         }
@@ -217,7 +1075,825 @@ pub fn parse_sensor_data(
                 "Incorrect data format or incomplete data in input: {}",
                 input
             );
-            Err("Incorrect data format or incomplete data".into())
+            Err(AppError::Parse {
+                reason: "Incorrect data format or incomplete data".to_string(),
+                raw_frame: input,
+            })
         }
     }
 }
+
+/// The tag sets the items of a frame share: the tags of the source, and those plus the clock
+/// an item was timestamped by, each built once per frame at most.
+struct FrameTags {
+    tags: Arc<BTreeMap<String, String>>,
+    by_clock: [OnceCell<Arc<BTreeMap<String, String>>>; 2],
+}
+
+impl FrameTags {
+    fn new(tags: Arc<BTreeMap<String, String>>) -> Self {
+        Self {
+            tags,
+            by_clock: Default::default(),
+        }
+    }
+
+    fn tags(&self) -> Arc<BTreeMap<String, String>> {
+        Arc::clone(&self.tags)
+    }
+
+    /// The tags with `ts_source` set to `clock`, one of the `TIMESTAMP_SOURCE_*` values.
+    fn timestamped_by(&self, clock: &'static str) -> Arc<BTreeMap<String, String>> {
+        let index = usize::from(clock == TIMESTAMP_SOURCE_DEVICE);
+        let tags = self.by_clock[index].get_or_init(|| {
+            let mut tags = (*self.tags).clone();
+            tags.insert(TIMESTAMP_SOURCE_TAG.to_string(), clock.to_string());
+            Arc::new(tags)
+        });
+        Arc::clone(tags)
+    }
+}
+
+/// Parses a JSON frame made of one or several `{"type": "...", "value": ...}` items, named by
+/// the member of their `protocol` instead of `type`.
+///
+/// An object-valued `value` is flattened into one field per key, using dotted names for nested
+/// objects (`{"pm": {"2_5": 1}}` becomes `pm.2_5`). In lenient mode items that cannot be decoded
+/// are logged and skipped; otherwise the first bad item rejects the whole frame.
+fn parse_json_frame(
+    input: &str,
+    tags: &FrameTags,
+    timestamp: i64,
+    config: &ParserConfig,
+    protocol: Protocol,
+    skew: &mut ClockSkewCorrector,
+) -> Result<Vec<MyDataPoint>, String> {
+    let frame: Value = serde_json::from_str(input).map_err(|e| {
+        error!("Invalid JSON frame '{}': {}", input, e);
+        e.to_string()
+    })?;
+
+    let items = match frame {
+        Value::Array(items) => items,
+        item @ Value::Object(_) => vec![item],
+        _ => return Err("JSON frame must be an object or an array of objects".to_string()),
+    };
+
+    let mut points = Vec::with_capacity(items.len());
+    for (index, item) in items.iter().enumerate() {
+        match parse_json_item(item, tags, timestamp, config, protocol, skew) {
+            Ok(Some(point)) => points.push(point),
+            Ok(None) => {}
+            Err(e) if config.lenient => {
+                error!("Skipping item {} of frame '{}': {}", index, input, e);
+            }
+            Err(e) => {
+                error!("Incorrect JSON frame '{}': item {}: {}", input, index, e);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(points)
+}
+
+/// Converts a single JSON item into a MyDataPoint. Returns `None` when the item was dropped
+/// because of its device timestamp.
+fn parse_json_item(
+    item: &Value,
+    tags: &FrameTags,
+    timestamp: i64,
+    config: &ParserConfig,
+    protocol: Protocol,
+    skew: &mut ClockSkewCorrector,
+) -> Result<Option<MyDataPoint>, String> {
+    let key = protocol.measurement_key();
+    let measurement = item
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("item without a '{}' member: {}", key, item))?;
+    let value = item
+        .get("value")
+        .ok_or_else(|| format!("item without a 'value' member: {}", item))?;
+
+    // Older protocols have no sequence number, whatever the item carries
+    let seq = match (protocol, item.get("seq")) {
+        (Protocol::V3, Some(seq)) if !seq.is_null() => Some(
+            seq.as_u64()
+                .and_then(|seq| u32::try_from(seq).ok())
+                .ok_or_else(|| format!("invalid seq {}", seq))?,
+        ),
+        _ => None,
+    };
+
+    let mut readings = BTreeMap::new();
+    match value {
+        Value::Object(_) => flatten_json_value(value, "", 0, config, &mut readings),
+        _ => Reading::from_json(value, config).map(|reading| {
+            readings.insert("value".to_string(), reading);
+        }),
+    }
+    .map_err(|e| format!("invalid value for '{}': {}", measurement, e))?;
+
+    if readings.is_empty() {
+        return Err(format!("empty value object for '{}'", measurement));
+    }
+
+    let (tags, timestamp) = match item.get("timestamp") {
+        None | Some(Value::Null) => (tags.tags(), timestamp),
+        Some(device_timestamp) => {
+            let seconds = device_timestamp
+                .as_f64()
+                .ok_or_else(|| format!("invalid timestamp {}", device_timestamp))?;
+            // A clock that was never set counts from 0, so it is not skewed but meaningless
+            if seconds < config.device_time_floor_secs as f64 {
+                debug!(
+                    "Device timestamp {} of '{}' is before the floor, using the host clock",
+                    seconds, measurement
+                );
+                (tags.timestamped_by(TIMESTAMP_SOURCE_HOST), timestamp)
+            } else {
+                // `as` would saturate, and a saturated timestamp is not the one the device sent
+                let nanos = seconds * 1e9;
+                if !(i64::MIN as f64..i64::MAX as f64).contains(&nanos) {
+                    return Err(format!("timestamp {} is out of range", device_timestamp));
+                }
+                match skew.correct(nanos as i64, timestamp) {
+                    Some(timestamp) => (tags.timestamped_by(TIMESTAMP_SOURCE_DEVICE), timestamp),
+                    None => return Ok(None),
+                }
+            }
+        }
+    };
+
+    trace!("Parsed JSON readings {:?} for {}", readings, measurement);
+    let point = MyDataPoint::from_readings(measurement.to_string(), tags, readings, timestamp);
+    Ok(Some(point.with_seq(seq)))
+}
+
+/// Flattens a JSON object into dotted field names, up to the configured nesting depth.
+fn flatten_json_value(
+    value: &Value,
+    prefix: &str,
+    depth: usize,
+    config: &ParserConfig,
+    readings: &mut BTreeMap<String, Reading>,
+) -> Result<(), String> {
+    match value {
+        Value::Object(members) => {
+            if depth >= config.max_nesting_depth {
+                return Err(format!(
+                    "'{}' exceeds the maximum nesting depth of {}",
+                    prefix, config.max_nesting_depth
+                ));
+            }
+            for (key, member) in members {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_json_value(member, &name, depth + 1, config, readings)?;
+            }
+            Ok(())
+        }
+        Value::Array(_) => Err(format!("arrays are not supported ('{}')", prefix)),
+        scalar => {
+            let reading =
+                Reading::from_json(scalar, config).map_err(|e| format!("'{}': {}", prefix, e))?;
+            readings.insert(prefix.to_string(), reading);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use serde_json::json;
+
+    // 2023-11-14T22:13:20Z
+    const TIMESTAMP: i64 = 1_700_000_000_000_000_000;
+
+    // The readings of a JSON object flattened with the default parser settings.
+    fn flattened(value: Value) -> Result<BTreeMap<String, Reading>, String> {
+        flattened_within(value, &ParserConfig::default())
+    }
+
+    fn flattened_within(
+        value: Value,
+        config: &ParserConfig,
+    ) -> Result<BTreeMap<String, Reading>, String> {
+        let mut readings = BTreeMap::new();
+        flatten_json_value(&value, "", 0, config, &mut readings)?;
+        Ok(readings)
+    }
+
+    #[test]
+    fn nested_objects_are_flattened_into_dotted_names() {
+        let readings = flattened(json!({
+            "temperature": 21.5,
+            "wind": {"speed": 3.5, "gust": null},
+            "status": "NaN",
+        }));
+
+        let expected = BTreeMap::from([
+            ("status".to_string(), Reading::Missing),
+            ("temperature".to_string(), Reading::Value(21.5)),
+            ("wind.gust".to_string(), Reading::Missing),
+            ("wind.speed".to_string(), Reading::Value(3.5)),
+        ]);
+        assert_eq!(readings, Ok(expected));
+    }
+
+    #[test]
+    fn objects_nested_deeper_than_the_limit_are_refused() {
+        let frame = json!({"wind": {"speed": {"max": 7.5}}});
+        assert_eq!(
+            flattened(frame.clone()),
+            Err("'wind.speed' exceeds the maximum nesting depth of 2".to_string())
+        );
+
+        let config = ParserConfig {
+            max_nesting_depth: 3,
+            ..ParserConfig::default()
+        };
+        let expected = BTreeMap::from([("wind.speed.max".to_string(), Reading::Value(7.5))]);
+        assert_eq!(flattened_within(frame, &config), Ok(expected));
+    }
+
+    #[test]
+    fn arrays_and_unexpected_values_are_refused() {
+        assert_eq!(
+            flattened(json!({"wind": {"speeds": [3.5, 4.0]}})),
+            Err("arrays are not supported ('wind.speeds')".to_string())
+        );
+        assert_eq!(
+            flattened(json!({"door": true})),
+            Err("'door': unsupported value true".to_string())
+        );
+        assert_eq!(
+            flattened(json!({"door": "open"})),
+            Err("'door': unexpected string value 'open'".to_string())
+        );
+    }
+
+    fn aggregator() -> Aggregator {
+        let config: AggregationConfig = serde_json::from_value(json!({})).unwrap();
+        Aggregator::new(&config)
+    }
+
+    fn sample(measurement: &str, reading: Reading, offset_secs: i64) -> MyDataPoint {
+        MyDataPoint::from_reading(
+            measurement.to_string(),
+            BTreeMap::from([("source".to_string(), "intake".to_string())]),
+            reading,
+            TIMESTAMP + offset_secs * 1_000_000_000,
+        )
+    }
+
+    #[test]
+    fn a_window_of_missing_readings_emits_nothing() {
+        let mut aggregator = aggregator();
+        let points = (0..5)
+            .map(|offset| sample("temperature", Reading::Missing, offset))
+            .collect();
+
+        assert!(aggregator.aggregate(points).is_empty());
+        assert_eq!(
+            aggregator.take_missing(),
+            BTreeMap::from([("temperature".to_string(), 5)])
+        );
+    }
+
+    #[test]
+    fn missing_readings_are_left_out_of_the_average() {
+        let mut aggregator = aggregator();
+        let points = vec![
+            sample("temperature", Reading::Value(20.0), 0),
+            sample("temperature", Reading::Missing, 10),
+            sample("temperature", Reading::Value(22.0), 20),
+            sample("humidity", Reading::Missing, 0),
+        ];
+
+        let averaged = aggregator.aggregate(points);
+
+        assert_eq!(averaged.len(), 1);
+        assert_eq!(
+            crate::line_protocol::render(&averaged[0]),
+            "temperature,source=intake value=21 1700000010000000000"
+        );
+        assert_eq!(
+            aggregator.take_missing(),
+            BTreeMap::from([("humidity".to_string(), 1), ("temperature".to_string(), 1)])
+        );
+        assert!(aggregator.take_missing().is_empty());
+    }
+
+    #[test]
+    fn nan_infinity_and_the_sentinel_are_missing() {
+        let config = ParserConfig {
+            missing_sentinel: Some(99999.9),
+            ..ParserConfig::default()
+        };
+
+        for token in ["NaN", "nan", "inf", "-Inf", "99999.9"] {
+            assert_eq!(
+                Reading::parse(token, &config),
+                Some(Reading::Missing),
+                "{}",
+                token
+            );
+        }
+        assert_eq!(
+            Reading::from_json(&json!("NaN"), &config),
+            Ok(Reading::Missing)
+        );
+        assert_eq!(
+            Reading::from_json(&Value::Null, &config),
+            Ok(Reading::Missing)
+        );
+        assert_eq!(
+            Reading::from_json(&json!(21.5), &config),
+            Ok(Reading::Value(21.5))
+        );
+    }
+
+    #[test]
+    fn an_empty_tag_value_or_measurement_name_leaves_the_other_series_alone() {
+        let mut aggregator = aggregator();
+        let with_tag = |measurement: &str, value: &str, reading: f64| {
+            let tags = BTreeMap::from([
+                ("source".to_string(), "intake".to_string()),
+                ("rack".to_string(), value.to_string()),
+            ]);
+            MyDataPoint::from_reading(
+                measurement.to_string(),
+                tags,
+                Reading::Value(reading),
+                TIMESTAMP,
+            )
+        };
+        let points = vec![
+            with_tag("temperature", "", 20.0),
+            with_tag("", "a1", 50.0),
+            with_tag("  ", "a1", 50.0),
+            with_tag("humidity", "a1", 40.0),
+            with_tag("pressure", " ", 1013.0),
+        ];
+
+        let lines: Vec<String> = aggregator
+            .aggregate(points)
+            .iter()
+            .map(crate::line_protocol::render)
+            .collect();
+
+        assert_eq!(
+            lines,
+            [
+                "humidity,rack=a1,source=intake value=40 1700000000000000000",
+                "pressure,source=intake value=1013 1700000000000000000",
+                "temperature,source=intake value=20 1700000000000000000",
+            ]
+        );
+    }
+
+    // Counts the samples in the `sample_count` measurement, in a window ending a minute after
+    // `TIMESTAMP`.
+    fn counting() -> (Aggregator, Arc<crate::clock::MockClock>) {
+        let config: AggregationConfig =
+            serde_json::from_value(json!({"sample_count": "measurement"})).unwrap();
+        let window_end = chrono::DateTime::from_timestamp(TIMESTAMP / 1_000_000_000 + 60, 0);
+        let clock = Arc::new(crate::clock::MockClock::new(window_end.unwrap()));
+        (Aggregator::new(&config).with_clock(clock.clone()), clock)
+    }
+
+    fn render_all(points: &[DataPoint]) -> Vec<String> {
+        points.iter().map(crate::line_protocol::render).collect()
+    }
+
+    #[test]
+    fn each_series_is_averaged_and_counted_on_its_own() {
+        let (mut aggregator, _) = counting();
+        let points = vec![
+            sample("temperature", Reading::Value(20.0), 0),
+            sample("temperature", Reading::Value(22.0), 20),
+            sample("humidity", Reading::Value(40.0), 10),
+        ];
+
+        assert_eq!(
+            render_all(&aggregator.aggregate(points)),
+            [
+                "humidity,source=intake value=40 1700000010000000000",
+                "sample_count,measurement=humidity,source=intake value=1i 1700000010000000000",
+                "temperature,source=intake value=21 1700000010000000000",
+                "sample_count,measurement=temperature,source=intake value=2i 1700000010000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_series_gone_quiet_is_counted_at_the_end_of_the_window() {
+        let (mut aggregator, clock) = counting();
+        aggregator.aggregate(vec![sample("temperature", Reading::Value(20.0), 0)]);
+        clock.advance(std::time::Duration::from_secs(60));
+
+        assert_eq!(
+            render_all(&aggregator.aggregate(Vec::new())),
+            ["sample_count,measurement=temperature,source=intake value=0i 1700000120000000000"]
+        );
+    }
+
+    #[test]
+    fn a_series_with_a_measurement_tag_keeps_its_average_but_is_not_counted() {
+        let (mut aggregator, _) = counting();
+        let tags = BTreeMap::from([("measurement".to_string(), "intake".to_string())]);
+        let point = MyDataPoint::from_reading(
+            "temperature".to_string(),
+            tags,
+            Reading::Value(20.0),
+            TIMESTAMP,
+        );
+
+        assert_eq!(
+            render_all(&aggregator.aggregate(vec![point])),
+            ["temperature,measurement=intake value=20 1700000000000000000"]
+        );
+    }
+
+    // Weighs the temperature by time, in a window ending a minute after `TIMESTAMP`.
+    fn time_weighted() -> Aggregator {
+        let config: AggregationConfig =
+            serde_json::from_value(json!({"reducers": {"temperature": "time_weighted_mean"}}))
+                .unwrap();
+        let window_end = chrono::DateTime::from_timestamp(TIMESTAMP / 1_000_000_000 + 60, 0);
+        let clock = crate::clock::MockClock::new(window_end.unwrap());
+        Aggregator::new(&config).with_clock(Arc::new(clock))
+    }
+
+    #[test]
+    fn each_sample_weighs_the_time_until_the_next_one() {
+        let mut aggregator = time_weighted();
+        let points = vec![
+            sample("temperature", Reading::Value(30.0), 40),
+            sample("temperature", Reading::Value(12.0), 0),
+            sample("temperature", Reading::Value(24.0), 10),
+        ];
+
+        let averaged = aggregator.aggregate(points);
+
+        // (10 × 12 + 30 × 24 + 20 × 30) / 60, the last sample weighing the time until the end
+        // of the window, against 22 for the plain mean; the timestamp is the plain average
+        assert_eq!(
+            crate::line_protocol::render(&averaged[0]),
+            "temperature,source=intake value=24 1700000016666666666"
+        );
+    }
+
+    #[test]
+    fn a_series_within_its_deadband_is_written_once_per_longest_suppression() {
+        let config: AggregationConfig = serde_json::from_value(json!({
+            "deadband": {"temperature": 0.1},
+            "max_suppression_secs": 300,
+        }))
+        .unwrap();
+        let mut aggregator = Aggregator::new(&config);
+        // One window a minute, moving by less than the deadband from the point written
+        let mut window = |value: f64, offset_secs: i64| {
+            let points = vec![sample("temperature", Reading::Value(value), offset_secs)];
+            aggregator.aggregate(points).len()
+        };
+
+        let written: Vec<usize> = [20.0, 20.05, 19.95, 20.09, 19.91, 20.02, 20.0]
+            .into_iter()
+            .zip((0..).step_by(60))
+            .map(|(value, offset)| window(value, offset))
+            .collect();
+
+        // The first point, then a single one once the five minutes passed
+        assert_eq!(written, [1, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(aggregator.take_suppressed(), 5);
+    }
+
+    #[test]
+    fn a_slow_drift_is_measured_from_the_point_written() {
+        let config: AggregationConfig =
+            serde_json::from_value(json!({"deadband": {"temperature": 0.1}})).unwrap();
+        let mut aggregator = Aggregator::new(&config);
+
+        let written: Vec<usize> = [20.0, 20.08, 20.16, 20.24]
+            .into_iter()
+            .zip((0..).step_by(60))
+            .map(|(value, offset)| {
+                let points = vec![sample("temperature", Reading::Value(value), offset)];
+                aggregator.aggregate(points).len()
+            })
+            .collect();
+
+        // 20.16 is within the deadband of 20.08 but not of 20.0, written last
+        assert_eq!(written, [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn a_device_timestamp_before_the_floor_is_replaced_with_the_host_clock() {
+        // A floor 10 seconds before the host clock, so that a device timestamp at the floor is
+        // within the skew threshold and kept as it is
+        let host = chrono::DateTime::from_timestamp(TIMESTAMP / 1_000_000_000, 0).unwrap();
+        let clock = crate::clock::MockClock::new(host);
+        let floor = host.timestamp() - 10;
+        let config = ParserConfig {
+            device_time_floor_secs: floor,
+            ..ParserConfig::default()
+        };
+        let mut skew = ClockSkewCorrector::new(&config);
+        let frame = json!([
+            {"type": "temperature", "value": 20.0, "timestamp": floor},
+            {"type": "humidity", "value": 40.0, "timestamp": floor - 1},
+            {"type": "pressure", "value": 1013.0},
+        ]);
+
+        let points = parse_sensor_data(
+            frame.to_string(),
+            &BTreeMap::new(),
+            &config,
+            None,
+            &mut skew,
+            &clock,
+        )
+        .unwrap();
+
+        let by_clock: Vec<(&str, Option<&str>)> = points
+            .iter()
+            .map(|point| {
+                let clock = point.get_tags().get(TIMESTAMP_SOURCE_TAG);
+                (point.get_measurement(), clock.map(String::as_str))
+            })
+            .collect();
+        assert_eq!(
+            by_clock,
+            [
+                ("temperature", Some(TIMESTAMP_SOURCE_DEVICE)),
+                ("humidity", Some(TIMESTAMP_SOURCE_HOST)),
+                // Without a device timestamp, there is no clock to tell
+                ("pressure", None),
+            ]
+        );
+        assert_eq!(points[0].get_timestamp(), Some(floor * 1_000_000_000));
+        for point in &points[1..] {
+            assert_eq!(point.get_timestamp(), Some(TIMESTAMP));
+        }
+    }
+
+    #[test]
+    fn a_far_future_device_timestamp_rejects_the_item() {
+        let config = ParserConfig::default();
+        let mut skew = ClockSkewCorrector::new(&config);
+        // Year 33658 in seconds, past what nanoseconds fit in an i64
+        let frame = json!({"type": "temperature", "value": 20.0, "timestamp": 1e12});
+
+        let result = parse_sensor_data(
+            frame.to_string(),
+            &BTreeMap::new(),
+            &config,
+            None,
+            &mut skew,
+            &SystemClock,
+        );
+
+        assert!(result.is_err());
+        assert!(skew.window_point(&BTreeMap::new()).is_none());
+    }
+
+    #[test]
+    fn an_average_is_converted_to_the_pinned_field_type() {
+        let coerce = |value, field_type| coerce_average(value, field_type).ok();
+
+        assert_eq!(coerce(40.0, FieldType::Float), Some(FieldValue::F64(40.0)));
+        assert_eq!(coerce(21.4, FieldType::Int), Some(FieldValue::I64(21)));
+        // Halves are rounded away from zero
+        assert_eq!(coerce(2.5, FieldType::Int), Some(FieldValue::I64(3)));
+        assert_eq!(coerce(-2.5, FieldType::Int), Some(FieldValue::I64(-3)));
+        assert_eq!(coerce(1e20, FieldType::Int), None);
+        assert_eq!(coerce(0.0, FieldType::Bool), Some(FieldValue::Bool(false)));
+        assert_eq!(coerce(1.0, FieldType::Bool), Some(FieldValue::Bool(true)));
+        assert_eq!(coerce(0.5, FieldType::Bool), None);
+    }
+
+    #[test]
+    fn a_window_not_fitting_its_field_type_is_set_aside() {
+        let config: AggregationConfig = serde_json::from_value(json!({
+            "field_types": {"people": "int", "door": "bool"},
+        }))
+        .unwrap();
+        let mut aggregator = Aggregator::new(&config);
+        let points = vec![
+            sample("people", Reading::Value(2.0), 0),
+            sample("people", Reading::Value(3.0), 20),
+            sample("door", Reading::Value(0.0), 0),
+            sample("door", Reading::Value(1.0), 20),
+            sample("humidity", Reading::Value(40.0), 0),
+        ];
+
+        let lines: Vec<String> = aggregator
+            .aggregate(points)
+            .iter()
+            .map(crate::line_protocol::render)
+            .collect();
+
+        assert_eq!(
+            lines,
+            [
+                "humidity,source=intake value=40 1700000000000000000",
+                "people,source=intake value=3i 1700000010000000000",
+            ]
+        );
+        // The door was open half of the window, which is no bool: the point is kept as a float
+        // for the dead-letter file
+        let rejected: Vec<String> = aggregator
+            .take_rejected()
+            .iter()
+            .map(crate::line_protocol::render)
+            .collect();
+        assert_eq!(
+            rejected,
+            ["door,source=intake value=0.5 1700000010000000000"]
+        );
+        assert!(aggregator.take_rejected().is_empty());
+    }
+
+    // Parses a frame of `tests/fixtures/frames` the way a device speaking `protocol` sends it,
+    // returning the points and the tags they should carry.
+    fn parse_fixture(
+        frame: &str,
+        protocol: Protocol,
+    ) -> Result<(Vec<MyDataPoint>, BTreeMap<String, String>), AppError> {
+        let config = ParserConfig::default();
+        let mut skew = ClockSkewCorrector::new(&config);
+        let tags = BTreeMap::from([("source".to_string(), "bench".to_string())]);
+        let points = parse_sensor_data(
+            frame.to_string(),
+            &tags,
+            &config,
+            Some(protocol),
+            &mut skew,
+            &SystemClock,
+        )?;
+        let mut expected_tags = tags;
+        expected_tags.insert(PROTOCOL_TAG.to_string(), protocol.to_string());
+        Ok((points, expected_tags))
+    }
+
+    // The readings of a point, all stamped with the host clock at the same time.
+    fn expected(
+        measurement: &str,
+        tags: &BTreeMap<String, String>,
+        readings: &[(&str, Reading)],
+        points: &[MyDataPoint],
+    ) -> MyDataPoint {
+        let readings = readings
+            .iter()
+            .map(|(name, reading)| (name.to_string(), *reading))
+            .collect();
+        let timestamp = points[0].get_timestamp().unwrap();
+        MyDataPoint::from_readings(measurement.to_string(), tags.clone(), readings, timestamp)
+    }
+
+    #[test]
+    fn protocol_1_names_the_measurement_in_type() {
+        let frame = include_str!("../tests/fixtures/frames/protocol1.json");
+
+        let (points, tags) = parse_fixture(frame, Protocol::V1).unwrap();
+
+        assert_eq!(
+            points,
+            [
+                expected(
+                    "temperature",
+                    &tags,
+                    &[("value", Reading::Value(21.5))],
+                    &points
+                ),
+                expected(
+                    "humidity",
+                    &tags,
+                    &[("value", Reading::Value(40.0))],
+                    &points
+                ),
+            ]
+        );
+        // A later protocol looks for `sensor` instead
+        assert!(parse_fixture(frame, Protocol::V2).is_err());
+    }
+
+    #[test]
+    fn protocol_2_names_the_measurement_in_sensor() {
+        let frame = include_str!("../tests/fixtures/frames/protocol2.json");
+
+        let (points, tags) = parse_fixture(frame, Protocol::V2).unwrap();
+
+        let air = [
+            ("pm.10", Reading::Value(7.0)),
+            ("pm.2_5", Reading::Value(3.5)),
+        ];
+        assert_eq!(
+            points,
+            [
+                expected(
+                    "temperature",
+                    &tags,
+                    &[("value", Reading::Value(21.5))],
+                    &points
+                ),
+                expected("air", &tags, &air, &points),
+            ]
+        );
+        assert!(parse_fixture(frame, Protocol::V1).is_err());
+    }
+
+    #[test]
+    fn protocol_3_adds_the_sequence_number_of_the_frame() {
+        let frame = include_str!("../tests/fixtures/frames/protocol3.json");
+
+        let (points, tags) = parse_fixture(frame, Protocol::V3).unwrap();
+
+        assert_eq!(
+            points,
+            [
+                expected(
+                    "temperature",
+                    &tags,
+                    &[("value", Reading::Value(21.5))],
+                    &points
+                )
+                .with_seq(Some(42)),
+                expected("humidity", &tags, &[("value", Reading::Missing)], &points)
+                    .with_seq(Some(42)),
+            ]
+        );
+        // Protocol 2 has no sequence number, whatever the items carry
+        let (points, _) = parse_fixture(frame, Protocol::V2).unwrap();
+        assert!(points.iter().all(|point| point.get_seq().is_none()));
+    }
+
+    // A `[fields]` table of every type, with a `value` entry sensors produce too.
+    fn static_fields() -> StaticFields {
+        let fields: BTreeMap<String, StaticField> = serde_json::from_value(json!({
+            "building": "hangar-2",
+            "floor": 3,
+            "gain": 1.5,
+            "calibrated": true,
+            "value": 99.0,
+        }))
+        .unwrap();
+        StaticFields::new(&fields)
+    }
+
+    #[test]
+    fn each_static_field_keeps_the_type_it_was_written_with() {
+        let fields = static_fields();
+
+        assert_eq!(
+            *fields.fields,
+            BTreeMap::from([
+                (
+                    "building".to_string(),
+                    FieldValue::String("hangar-2".into())
+                ),
+                ("calibrated".to_string(), FieldValue::Bool(true)),
+                ("floor".to_string(), FieldValue::I64(3)),
+                ("gain".to_string(), FieldValue::F64(1.5)),
+                ("value".to_string(), FieldValue::F64(99.0)),
+            ])
+        );
+    }
+
+    #[test]
+    fn a_field_of_the_sensor_wins_over_a_static_one() {
+        let fields = static_fields();
+        let point = |measurement: &str| {
+            let builder = DataPoint::builder(measurement)
+                .field("value", 21.5)
+                .timestamp(TIMESTAMP);
+            let builder = fields.add_to(measurement, builder, |name| name == "value");
+            crate::line_protocol::render(&builder.build().unwrap())
+        };
+
+        assert_eq!(
+            point("temperature"),
+            "temperature building=\"hangar-2\",calibrated=t,floor=3i,gain=1.5,value=21.5 \
+             1700000000000000000"
+        );
+        // The collision is warned about once per measurement
+        point("temperature");
+        point("humidity");
+        let collisions = fields.collisions.lock().unwrap().clone();
+        assert_eq!(
+            collisions,
+            BTreeSet::from([
+                ("value".to_string(), "humidity".to_string()),
+                ("value".to_string(), "temperature".to_string()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,233 @@
+// dead_letter.rs
+//
+// Keeps the batches InfluxDB permanently rejected (bad line protocol, field type conflicts) so
+// they can be inspected and re-submitted once the cause is fixed. Each rejected batch is stored
+// as line protocol in its own timestamped file under the configured directory; the oldest files
+// are pruned once the directory grows beyond its size budget.
+//
+// When the writer knows the InfluxDB org and buckets, the file records them in comment lines,
+// `# org=<org>` once and `# bucket=<bucket>` before the points headed for each bucket, so that
+// re-submitting a file writes every point where it was meant to go.
+
+use crate::config::{DeadLetterConfig, InfluxDBConfig};
+use crate::influxdb::route;
+use crate::line_protocol::{measurement_of_line, render};
+
+use chrono::Utc;
+use influxdb2::models::DataPoint;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const FILE_PREFIX: &str = "dead-letter-";
+const FILE_EXTENSION: &str = "lp";
+const ORG_HEADER: &str = "# org=";
+const BUCKET_HEADER: &str = "# bucket=";
+
+// The InfluxDB org and buckets the points of a batch were headed for.
+#[derive(Clone)]
+struct Target {
+    org: String,
+    bucket: String,
+    bucket_routing: BTreeMap<String, String>,
+}
+
+// Points of a dead-letter file headed for the same bucket, as line protocol. The org and bucket
+// are missing from the files written without a target.
+#[derive(Debug, PartialEq)]
+pub struct DeadLetterBatch {
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub lines: String,
+}
+
+#[derive(Clone)]
+pub struct DeadLetterWriter {
+    directory: PathBuf,
+    max_total_bytes: u64,
+    target: Option<Target>,
+    // Tells apart the files written within the same microsecond.
+    seq: Arc<AtomicU64>,
+}
+
+impl DeadLetterWriter {
+    pub fn new(config: &DeadLetterConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        info!("Dead-letter directory: {}", config.directory);
+        Ok(Self {
+            directory: PathBuf::from(&config.directory),
+            max_total_bytes: config.max_total_bytes,
+            target: None,
+            seq: Arc::default(),
+        })
+    }
+
+    // Records the org and routed bucket of every point in the files.
+    pub fn with_target(mut self, config: &InfluxDBConfig) -> Self {
+        self.target = Some(Target {
+            org: config.org.clone(),
+            bucket: config.bucket.clone(),
+            bucket_routing: config.bucket_routing.clone(),
+        });
+        self
+    }
+
+    // A writer recording `bucket` for every point, e.g. for the raw samples.
+    pub fn for_bucket(&self, bucket: &str) -> Self {
+        let mut writer = self.clone();
+        if let Some(target) = &mut writer.target {
+            target.bucket_routing = BTreeMap::from([("*".to_string(), bucket.to_string())]);
+        }
+        writer
+    }
+
+    // Serializes a rejected batch into a new file and returns its path.
+    pub fn write(&self, points: &[DataPoint]) -> io::Result<PathBuf> {
+        let body = self.serialize(points);
+
+        // Room is made first, so that the new file is never the one pruned
+        if let Err(e) = self.prune(body.len() as u64) {
+            warn!("Failed to prune dead-letter directory: {}", e);
+        }
+
+        let (path, mut file) = self.create_file()?;
+        file.write_all(body.as_bytes())?;
+        debug!("Wrote {} points to {}", points.len(), path.display());
+        Ok(path)
+    }
+
+    // Renders the points, grouped by bucket under their headers when there is a target.
+    fn serialize(&self, points: &[DataPoint]) -> String {
+        let lines = points.iter().map(render);
+        let Some(target) = &self.target else {
+            return lines.map(|line| line + "\n").collect();
+        };
+
+        let mut by_bucket: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for line in lines {
+            let measurement = measurement_of_line(&line);
+            let bucket = route(&target.bucket_routing, &target.bucket, &measurement);
+            by_bucket.entry(bucket).or_default().push(line);
+        }
+
+        let mut body = format!("{}{}\n", ORG_HEADER, target.org);
+        for (bucket, lines) in by_bucket {
+            body.push_str(&format!("{}{}\n", BUCKET_HEADER, bucket));
+            for line in lines {
+                body.push_str(&line);
+                body.push('\n');
+            }
+        }
+        body
+    }
+
+    // Creates a new file named after the current time, never replacing an existing one.
+    fn create_file(&self) -> io::Result<(PathBuf, fs::File)> {
+        let now = Utc::now().format("%Y%m%dT%H%M%S%.6fZ").to_string();
+        loop {
+            let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+            let name = format!("{}{}-{:06}.{}", FILE_PREFIX, now, seq, FILE_EXTENSION);
+            let path = self.directory.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Lists the dead-letter files, oldest first (names sort chronologically).
+    pub fn list(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut files = fs::read_dir(&self.directory)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let is_dead_letter = name.starts_with(FILE_PREFIX)
+                    && Path::new(&name)
+                        .extension()
+                        .is_some_and(|e| e == FILE_EXTENSION);
+                let size = entry.metadata().ok()?.len();
+                is_dead_letter.then_some((name, size))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files)
+    }
+
+    // Reads back the line protocol of a dead-letter file. Only bare file names from `list`
+    // are accepted so callers cannot escape the directory.
+    pub fn read(&self, name: &str) -> io::Result<String> {
+        fs::read_to_string(self.resolve(name)?)
+    }
+
+    // Reads back a dead-letter file split by the bucket its points were headed for.
+    pub fn read_batches(&self, name: &str) -> io::Result<Vec<DeadLetterBatch>> {
+        Ok(parse(&self.read(name)?))
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.resolve(name)?)
+    }
+
+    fn resolve(&self, name: &str) -> io::Result<PathBuf> {
+        let known = self.list()?.into_iter().any(|(file, _)| file == name);
+        if !known {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown dead-letter file '{}'", name),
+            ));
+        }
+        Ok(self.directory.join(name))
+    }
+
+    // Deletes the oldest files until the directory fits in its size budget, leaving room for
+    // `incoming` more bytes.
+    fn prune(&self, incoming: u64) -> io::Result<()> {
+        let files = self.list()?;
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum::<u64>() + incoming;
+
+        for (name, size) in files {
+            if total <= self.max_total_bytes {
+                break;
+            }
+            warn!("Dead-letter directory over budget, removing {}", name);
+            fs::remove_file(self.directory.join(&name))?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+// Splits the body of a dead-letter file at its bucket headers.
+fn parse(body: &str) -> Vec<DeadLetterBatch> {
+    let mut org = None;
+    let mut batches: Vec<DeadLetterBatch> = Vec::new();
+    for line in body.lines() {
+        if let Some(value) = line.strip_prefix(ORG_HEADER) {
+            org = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix(BUCKET_HEADER) {
+            batches.push(DeadLetterBatch {
+                org: org.clone(),
+                bucket: Some(value.to_string()),
+                lines: String::new(),
+            });
+        } else if !line.is_empty() {
+            if batches.is_empty() {
+                batches.push(DeadLetterBatch {
+                    org: org.clone(),
+                    bucket: None,
+                    lines: String::new(),
+                });
+            }
+            let batch = batches.last_mut().expect("a batch was just pushed");
+            batch.lines.push_str(line);
+            batch.lines.push('\n');
+        }
+    }
+    batches.retain(|batch| !batch.lines.is_empty());
+    batches
+}
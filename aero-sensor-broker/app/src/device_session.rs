@@ -0,0 +1,103 @@
+// device_session.rs
+//
+// Interactive access to the device of a source over the `/ws/device` WebSocket, for provisioning tools:
+// text messages from the client are written to the serial port as commands, and every line read
+// from the port is streamed back, including the frames the data pipeline consumes. Only one
+// session may be open at a time, and a session without client messages for `idle_timeout` is
+// closed.
+
+use crate::source::SensorSource;
+use futures::{SinkExt, StreamExt};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep_until, Duration, Instant};
+use warp::ws::{Message, WebSocket};
+
+#[derive(Clone, Default)]
+pub struct DeviceSessions {
+    open: Arc<AtomicBool>,
+}
+
+// Holds the single session slot until dropped, including when the upgrade never completes.
+pub struct SessionSlot {
+    open: Arc<AtomicBool>,
+}
+
+impl DeviceSessions {
+    // Takes the session slot, or returns `None` while another session holds it.
+    pub fn acquire(&self) -> Option<SessionSlot> {
+        self.open
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| SessionSlot {
+                open: self.open.clone(),
+            })
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.open.store(false, Ordering::Release);
+    }
+}
+
+// Relays between the socket and the device until either side closes or the session idles.
+pub async fn run(
+    socket: WebSocket,
+    device: Arc<dyn SensorSource>,
+    idle_timeout: Duration,
+    _slot: SessionSlot,
+) {
+    info!("Device session opened");
+    let (mut to_client, mut from_client) = socket.split();
+    let mut lines = device.subscribe_raw_lines();
+    let mut idle_deadline = Instant::now() + idle_timeout;
+
+    loop {
+        tokio::select! {
+            message = from_client.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(e)) => {
+                        warn!("Device session failed: {}", e);
+                        break;
+                    }
+                    None => break,
+                };
+                if message.is_close() {
+                    break;
+                }
+                idle_deadline = Instant::now() + idle_timeout;
+                let Ok(command) = message.to_str() else {
+                    continue;
+                };
+                info!("Device session command: {}", command.trim_end());
+                if let Err(e) = device.write_command(command).await {
+                    let notice = format!("error: failed to send command: {}", e);
+                    if to_client.send(Message::text(notice)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            line = lines.recv() => {
+                let text = match line {
+                    Ok(line) => line,
+                    Err(RecvError::Lagged(dropped)) => format!("dropped {} lines", dropped),
+                    Err(RecvError::Closed) => break,
+                };
+                if to_client.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            _ = sleep_until(idle_deadline) => {
+                info!("Device session idle for {:?}, closing it", idle_timeout);
+                break;
+            }
+        }
+    }
+
+    let _ = to_client.send(Message::close()).await;
+    info!("Device session closed");
+}
@@ -0,0 +1,88 @@
+// errors.rs
+//
+// The error type of the device, parsing, and InfluxDB code. Its variants tell the caller what to
+// do about a failure: a serial error calls for reopening the port, an invalid configuration for
+// stopping, a write InfluxDB rejected for the dead-letter directory. The sinks report their own
+// `SinkError`, which the flush path already knows how to handle.
+
+use crate::influxdb::{is_retryable, WriteError};
+use influxdb2::RequestError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AppError {
+    // Reading from or writing to the serial port failed, e.g. the device was unplugged.
+    #[error("serial I/O failed: {0}")]
+    Serial(#[from] std::io::Error),
+    // The serial ports could not be listed or the port could not be opened.
+    #[error("serial port error: {0}")]
+    SerialPort(#[from] serialport::Error),
+    // No serial port reports the configured product name.
+    #[error("no serial port matches device '{0}'")]
+    DeviceNotFound(String),
+    // The device answered, but not as expected.
+    #[error("{0}")]
+    Device(String),
+    // A frame could not be turned into points.
+    #[error("invalid frame '{raw_frame}': {reason}")]
+    Parse { reason: String, raw_frame: String },
+    // Writing to InfluxDB failed; `retryable` tells whether writing the same data again may
+    // succeed.
+    #[error("InfluxDB write failed: {source}")]
+    InfluxWrite { retryable: bool, source: WriteError },
+    // Any other request to InfluxDB failed: bucket validation or a query.
+    #[error("InfluxDB request failed: {0}")]
+    InfluxRequest(#[from] RequestError),
+    // InfluxDB did not pass its health check.
+    #[error("InfluxDB unavailable: {0}")]
+    InfluxUnavailable(String),
+    // A point cannot be serialized as line protocol, e.g. it has a field that is not a finite
+    // number.
+    #[error("invalid point: {0}")]
+    LineProtocol(String),
+    // The settings are invalid or inconsistent, e.g. a secret cannot be read.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    // The broker cannot run or keep running, e.g. its HTTP port is taken or a background task
+    // keeps failing.
+    #[error("{0}")]
+    Runtime(String),
+}
+
+impl AppError {
+    // Whether the same operation may succeed later without anyone changing the configuration
+    // or the data: a reconnect, a retried write, or a later health check.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::Serial(_)
+            | AppError::SerialPort(_)
+            | AppError::DeviceNotFound(_)
+            | AppError::Device(_)
+            | AppError::InfluxUnavailable(_) => true,
+            AppError::InfluxWrite { retryable, .. } => *retryable,
+            AppError::InfluxRequest(e) => is_retryable(e),
+            AppError::Parse { .. }
+            | AppError::LineProtocol(_)
+            | AppError::Config(_)
+            | AppError::Runtime(_) => false,
+        }
+    }
+}
+
+impl From<WriteError> for AppError {
+    fn from(error: WriteError) -> Self {
+        AppError::InfluxWrite {
+            retryable: error.is_retryable(),
+            source: error,
+        }
+    }
+}
+
+impl From<std::string::FromUtf8Error> for AppError {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        AppError::Parse {
+            reason: format!("frame is not valid UTF-8: {}", error.utf8_error()),
+            raw_frame: String::from_utf8_lossy(error.as_bytes()).into_owned(),
+        }
+    }
+}
@@ -0,0 +1,393 @@
+// file_sink.rs
+//
+// Appends aggregated points to daily CSV files, for air-gapped deployments that collect data
+// from the SD card by hand instead of writing to a database. Each row holds the timestamp, the
+// measurement, one column per tag, and one column per field. When a point brings tags or fields
+// the current file has no column for, a new segment of the day's file is started with the
+// widened header rather than rewriting what is already on disk. Once the day changes, the
+// previous day's segments can be gzip-compressed. Points of a day before the current one, e.g. a
+// batch retried after midnight, go to a segment of their own of that day, leaving the segment
+// being appended to alone.
+
+use crate::config::FileSinkConfig;
+use crate::line_protocol::{decode, DecodedPoint};
+use crate::sink::{DataSink, SinkError};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use influxdb2::models::{DataPoint, FieldValue};
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const FILE_EXTENSION: &str = "csv";
+
+#[derive(Clone)]
+pub struct FileSink {
+    directory: PathBuf,
+    prefix: String,
+    gzip_rotated: bool,
+    state: Arc<Mutex<SegmentState>>,
+}
+
+// The file currently being appended to.
+#[derive(Default)]
+struct SegmentState {
+    day: Option<NaiveDate>,
+    path: Option<PathBuf>,
+    tags: BTreeSet<String>,
+    fields: BTreeSet<String>,
+}
+
+impl FileSink {
+    pub fn new(config: &FileSinkConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        info!("Writing CSV files to {}", config.directory);
+        Ok(Self {
+            directory: PathBuf::from(&config.directory),
+            prefix: config.file_prefix.clone(),
+            gzip_rotated: config.gzip_rotated,
+            state: Arc::new(Mutex::new(SegmentState::default())),
+        })
+    }
+
+    // Appends the points to the file of their day, rotating files as needed.
+    fn append(&self, points: &[DecodedPoint]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut writer: Option<BufWriter<File>> = None;
+        let mut earlier_days: BTreeMap<NaiveDate, Vec<&DecodedPoint>> = BTreeMap::new();
+
+        for point in points {
+            let day = datetime_of(point).date_naive();
+
+            if state.day.is_some_and(|current| day < current) {
+                earlier_days.entry(day).or_default().push(point);
+                continue;
+            }
+            if state.day != Some(day) {
+                if let Some(writer) = writer.take() {
+                    writer.into_inner()?.sync_data()?;
+                }
+                if let Some(previous) = state.day.replace(day) {
+                    if self.gzip_rotated {
+                        self.compress_day(previous);
+                    }
+                }
+                state.path = None;
+            }
+
+            let new_tags = point.tags.keys().any(|tag| !state.tags.contains(tag));
+            let new_fields = point
+                .fields
+                .keys()
+                .any(|field| !state.fields.contains(field));
+            if state.path.is_none() || new_tags || new_fields {
+                if let Some(writer) = writer.take() {
+                    writer.into_inner()?.sync_data()?;
+                }
+                if state.path.is_none() {
+                    state.tags.clear();
+                    state.fields.clear();
+                }
+                state.tags.extend(point.tags.keys().cloned());
+                state.fields.extend(point.fields.keys().cloned());
+
+                let path = self.next_segment(day);
+                let mut file = BufWriter::new(File::create(&path)?);
+                write_header(&mut file, &state)?;
+                info!("Started CSV segment {}", path.display());
+                state.path = Some(path);
+                writer = Some(file);
+            }
+
+            if writer.is_none() {
+                if let Some(path) = &state.path {
+                    let file = OpenOptions::new().append(true).open(path)?;
+                    writer = Some(BufWriter::new(file));
+                }
+            }
+            if let Some(writer) = writer.as_mut() {
+                write_row(writer, &state, point)?;
+            }
+        }
+
+        if let Some(writer) = writer {
+            writer.into_inner()?.sync_data()?;
+        }
+        for (day, points) in earlier_days {
+            self.append_to_earlier_day(day, &points)?;
+        }
+        Ok(())
+    }
+
+    // Writes points of a day before the current one to a new segment of their day, with the
+    // columns they need, and compresses it right away when finished days are compressed.
+    fn append_to_earlier_day(&self, day: NaiveDate, points: &[&DecodedPoint]) -> io::Result<()> {
+        let mut columns = SegmentState::default();
+        for point in points {
+            columns.tags.extend(point.tags.keys().cloned());
+            columns.fields.extend(point.fields.keys().cloned());
+        }
+
+        let path = self.next_segment(day);
+        let mut file = BufWriter::new(File::create(&path)?);
+        write_header(&mut file, &columns)?;
+        for point in points {
+            write_row(&mut file, &columns, point)?;
+        }
+        file.into_inner()?.sync_data()?;
+        info!(
+            "Wrote {} point(s) of {} to the CSV segment {}",
+            points.len(),
+            day,
+            path.display()
+        );
+
+        if self.gzip_rotated {
+            if let Err(e) = compress(&path) {
+                warn!("Failed to compress {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    // First segment name of the day that is not taken yet, also counting compressed files,
+    // so a restart never appends to a file whose header it does not know.
+    fn next_segment(&self, day: NaiveDate) -> PathBuf {
+        (0..)
+            .map(|segment| self.segment_path(day, segment))
+            .find(|path| !path.exists() && !gzip_path(path).exists())
+            .unwrap_or_default()
+    }
+
+    fn segment_path(&self, day: NaiveDate, segment: u32) -> PathBuf {
+        let name = match segment {
+            0 => format!("{}-{}.{}", self.prefix, day, FILE_EXTENSION),
+            n => format!("{}-{}.{}.{}", self.prefix, day, n, FILE_EXTENSION),
+        };
+        self.directory.join(name)
+    }
+
+    // Compresses every segment of a finished day. Failures are logged; the plain files stay.
+    fn compress_day(&self, day: NaiveDate) {
+        let mut segment = 0;
+        loop {
+            let path = self.segment_path(day, segment);
+            if !path.exists() {
+                if gzip_path(&path).exists() {
+                    segment += 1;
+                    continue;
+                }
+                break;
+            }
+            match compress(&path) {
+                Ok(()) => info!("Compressed {}", path.display()),
+                Err(e) => warn!("Failed to compress {}: {}", path.display(), e),
+            }
+            segment += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl DataSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let decoded: Vec<DecodedPoint> = points.iter().filter_map(decode).collect();
+        if decoded.len() < points.len() {
+            warn!(
+                "Skipping {} points that could not be decoded for the CSV files",
+                points.len() - decoded.len()
+            );
+        }
+
+        let sink = self.clone();
+        tokio::task::spawn_blocking(move || sink.append(&decoded))
+            .await
+            .map_err(|e| SinkError::Unavailable(e.to_string()))?
+            .map_err(|e| SinkError::Unavailable(format!("CSV write failed: {}", e)))
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        match fs::metadata(&self.directory) {
+            Ok(metadata) if metadata.is_dir() && !metadata.permissions().readonly() => Ok(()),
+            Ok(_) => Err(SinkError::Unavailable(format!(
+                "{} is not a writable directory",
+                self.directory.display()
+            ))),
+            Err(e) => Err(SinkError::Unavailable(e.to_string())),
+        }
+    }
+}
+
+fn datetime_of(point: &DecodedPoint) -> DateTime<Utc> {
+    point
+        .timestamp
+        .map(DateTime::from_timestamp_nanos)
+        .unwrap_or_else(Utc::now)
+}
+
+fn write_header(writer: &mut impl Write, state: &SegmentState) -> io::Result<()> {
+    let columns = ["timestamp", "measurement"]
+        .into_iter()
+        .chain(state.tags.iter().map(String::as_str))
+        .chain(state.fields.iter().map(String::as_str))
+        .map(escape)
+        .collect::<Vec<_>>();
+    writeln!(writer, "{}", columns.join(","))
+}
+
+fn write_row(
+    writer: &mut impl Write,
+    state: &SegmentState,
+    point: &DecodedPoint,
+) -> io::Result<()> {
+    let mut columns = vec![
+        datetime_of(point).to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        escape(&point.measurement),
+    ];
+    columns.extend(
+        state
+            .tags
+            .iter()
+            .map(|tag| point.tags.get(tag).map(|v| escape(v)).unwrap_or_default()),
+    );
+    columns.extend(
+        state
+            .fields
+            .iter()
+            .map(|field| match point.fields.get(field) {
+                Some(FieldValue::Bool(value)) => value.to_string(),
+                Some(FieldValue::I64(value)) => value.to_string(),
+                Some(FieldValue::F64(value)) => value.to_string(),
+                Some(FieldValue::String(value)) => escape(value),
+                None => String::new(),
+            }),
+    );
+    writeln!(writer, "{}", columns.join(","))
+}
+
+// Quotes a CSV value when it contains a separator, a quote, or a line break.
+fn escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn gzip_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+// Writes `<file>.gz` next to the file and removes the original once the copy is complete.
+fn compress(path: &Path) -> io::Result<()> {
+    let target = gzip_path(path);
+    let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-11-14T22:13:20Z, and a day later.
+    const DAY_1: i64 = 1_700_000_000_000_000_000;
+    const DAY_2: i64 = DAY_1 + 86_400_000_000_000;
+
+    fn sink(name: &str, gzip_rotated: bool) -> FileSink {
+        let directory =
+            std::env::temp_dir().join(format!("aero-file-sink-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        FileSink::new(&FileSinkConfig {
+            directory: directory.display().to_string(),
+            file_prefix: "sensors".to_string(),
+            gzip_rotated,
+        })
+        .unwrap()
+    }
+
+    fn point(value: f64, timestamp: i64) -> DecodedPoint {
+        DecodedPoint {
+            measurement: "temperature".to_string(),
+            tags: BTreeMap::new(),
+            fields: BTreeMap::from([("value".to_string(), FieldValue::F64(value))]),
+            timestamp: Some(timestamp),
+        }
+    }
+
+    // The files of the directory, with the contents of those not compressed.
+    fn files(sink: &FileSink) -> BTreeMap<String, String> {
+        let files = fs::read_dir(&sink.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, fs::read_to_string(&path).unwrap_or_default())
+            })
+            .collect();
+        let _ = fs::remove_dir_all(&sink.directory);
+        files
+    }
+
+    #[test]
+    fn a_point_of_an_earlier_day_leaves_the_current_segment_alone() {
+        let sink = sink("earlier-day", true);
+
+        sink.append(&[point(20.0, DAY_1)]).unwrap();
+        sink.append(&[point(21.0, DAY_2)]).unwrap();
+        // A batch retried after midnight
+        sink.append(&[point(20.5, DAY_1 + 1), point(21.5, DAY_2 + 1)])
+            .unwrap();
+
+        let files = files(&sink);
+        let names: Vec<&str> = files.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            [
+                "sensors-2023-11-14.1.csv.gz",
+                "sensors-2023-11-14.csv.gz",
+                "sensors-2023-11-15.csv",
+            ]
+        );
+        assert_eq!(
+            files["sensors-2023-11-15.csv"],
+            "timestamp,measurement,value\n\
+             2023-11-15T22:13:20Z,temperature,21\n\
+             2023-11-15T22:13:20.000000001Z,temperature,21.5\n"
+        );
+    }
+
+    #[test]
+    fn earlier_points_are_written_to_their_own_segment() {
+        let sink = sink("earlier-segment", false);
+
+        sink.append(&[point(21.0, DAY_2)]).unwrap();
+        sink.append(&[point(20.0, DAY_1), point(20.5, DAY_1 + 1)])
+            .unwrap();
+
+        let files = files(&sink);
+        assert_eq!(
+            files["sensors-2023-11-14.csv"],
+            "timestamp,measurement,value\n\
+             2023-11-14T22:13:20Z,temperature,20\n\
+             2023-11-14T22:13:20.000000001Z,temperature,20.5\n"
+        );
+        assert_eq!(
+            files["sensors-2023-11-15.csv"],
+            "timestamp,measurement,value\n2023-11-15T22:13:20Z,temperature,21\n"
+        );
+    }
+}
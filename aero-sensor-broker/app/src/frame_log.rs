@@ -0,0 +1,79 @@
+// frame_log.rs
+//
+// The last lines each source received, valid frames or not, kept in memory for `/admin/raw`:
+// "what exactly did the Arduino send in the last minute?" is the first question of every
+// support call. The log is a bounded ring filled by `read_data`. Recording only takes a
+// short, uncontended lock to push an entry allocated beforehand, so the read loop is not slowed
+// down; the snapshot copies the entries out under the same lock.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[derive(Clone)]
+pub struct FrameLog {
+    entries: Arc<Mutex<VecDeque<Frame>>>,
+    capacity: usize,
+}
+
+#[derive(Clone)]
+pub struct Frame {
+    pub received_at: DateTime<Utc>,
+    pub line: String,
+    pub valid: bool,
+}
+
+impl FrameLog {
+    // Keeps the last `capacity` lines; none when it is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, line: &str, valid: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        let frame = Frame {
+            received_at: Utc::now(),
+            line: line.to_string(),
+            valid,
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(frame);
+    }
+
+    // The lines kept and the most that are, for `/stats`.
+    pub fn usage(&self) -> (usize, usize) {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        (entries.len(), self.capacity)
+    }
+
+    // The lines kept, newest first, only the invalid ones if asked.
+    pub fn snapshot(&self, invalid_only: bool) -> Vec<Frame> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries
+            .iter()
+            .rev()
+            .filter(|frame| !invalid_only || !frame.valid)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Frame {
+    pub fn to_json(&self, source: &str) -> Value {
+        json!({
+            "source": source,
+            "received_at": self.received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "line": self.line,
+            "valid": self.valid,
+        })
+    }
+}
@@ -0,0 +1,232 @@
+// freshness.rs
+//
+// Watches how long ago each source last sent a frame that parsed, so that a wedged sensor (the
+// port open, the health check answered, but no data) does not look healthy. A source whose last
+// parsed frame is older than `stale_after_secs` is marked stale, which makes `/readyz` report the
+// broker degraded, and comes back once a frame parses again. Both transitions are logged once and
+// written as a `sensor_stale` point, tagged like the readings of the source, with how long the
+// source had been silent. While ingestion is paused, the frames discarded count as parsed: the
+// sensor is not wedged.
+
+use crate::cache::Cache;
+use crate::clock::{self, Clock};
+use crate::source::Source;
+
+use influxdb2::models::DataPoint;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+const STALE_MEASUREMENT: &str = "sensor_stale";
+
+// How often the sources are checked, at most.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// When a source last parsed a frame, shared by its read loop and the health route.
+pub struct Freshness {
+    clock: Arc<dyn Clock>,
+    // Unix time in milliseconds of the last frame parsed, or of the startup before the first.
+    last_parsed_ms: AtomicI64,
+    stale: AtomicBool,
+    // The last frame parsed before the source became stale, in Unix milliseconds.
+    silent_since_ms: AtomicI64,
+}
+
+impl Default for Freshness {
+    fn default() -> Self {
+        Self::new(clock::system())
+    }
+}
+
+impl Freshness {
+    // Ages the frames by `clock`, starting now.
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            last_parsed_ms: AtomicI64::new(clock.now_utc().timestamp_millis()),
+            clock,
+            stale: AtomicBool::new(false),
+            silent_since_ms: AtomicI64::new(0),
+        }
+    }
+
+    pub fn parsed(&self) {
+        self.last_parsed_ms
+            .store(self.clock.now_utc().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    // Time since the last frame parsed, or since the startup before the first.
+    pub fn age(&self) -> Duration {
+        let now_ms = self.clock.now_utc().timestamp_millis();
+        let age_ms = now_ms - self.last_parsed_ms.load(Ordering::Relaxed);
+        Duration::from_millis(age_ms.max(0) as u64)
+    }
+
+    // Whether the watchdog found the source stale when it last looked.
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+}
+
+// Checks the sources until `shutdown` is cancelled, caching a point at every transition.
+pub async fn watch_freshness(
+    sources: Arc<Vec<Source>>,
+    cache: Cache,
+    stale_after: Duration,
+    shutdown: CancellationToken,
+) {
+    let interval = (stale_after / 4).clamp(Duration::from_secs(1), MAX_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        let mut points = Vec::new();
+        for source in sources.iter() {
+            let freshness = source.freshness();
+            let last_parsed_ms = freshness.last_parsed_ms.load(Ordering::Relaxed);
+            let age = freshness.age();
+            let stale = age > stale_after;
+            if freshness.stale.swap(stale, Ordering::Relaxed) == stale {
+                continue;
+            }
+            let silent_for = match stale {
+                true => {
+                    freshness
+                        .silent_since_ms
+                        .store(last_parsed_ms, Ordering::Relaxed);
+                    warn!(
+                        "Source {} parsed no frame for {}s, marking it stale",
+                        source.name(),
+                        age.as_secs()
+                    );
+                    age
+                }
+                // The silence lasted until the frame that parsed again
+                false => {
+                    let since_ms = freshness.silent_since_ms.load(Ordering::Relaxed);
+                    let silent_for =
+                        Duration::from_millis((last_parsed_ms - since_ms).max(0) as u64);
+                    info!(
+                        "Source {} is parsing frames again after {}s",
+                        source.name(),
+                        silent_for.as_secs()
+                    );
+                    silent_for
+                }
+            };
+            match stale_point(source, stale, silent_for) {
+                Ok(point) => points.push(point),
+                Err(e) => warn!(
+                    "Failed to build the staleness point of source {}: {}",
+                    source.name(),
+                    e
+                ),
+            }
+        }
+        if !points.is_empty() {
+            cache.add(points).await;
+        }
+    }
+}
+
+// `silent_for` is how long the source had parsed nothing: so far when it became stale, in all
+// when it recovered.
+fn stale_point(source: &Source, stale: bool, silent_for: Duration) -> Result<DataPoint, String> {
+    let builder = DataPoint::builder(STALE_MEASUREMENT)
+        .field("stale", stale)
+        .field("silent_secs", silent_for.as_secs() as i64)
+        .timestamp(
+            source
+                .freshness()
+                .clock
+                .now_utc()
+                .timestamp_nanos_opt()
+                .unwrap_or_default(),
+        );
+    source
+        .tags()
+        .iter()
+        .fold(builder, |builder, (key, value)| builder.tag(key, value))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::SourceConfig;
+    use crate::line_protocol::render;
+    use crate::metrics::Metrics;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    const STALE_AFTER: Duration = Duration::from_secs(60);
+
+    fn source(clock: &Arc<MockClock>) -> Source {
+        let config: SourceConfig =
+            serde_json::from_value(json!({"name": "bench", "kind": "simulated"})).unwrap();
+        Source::new(&config, &BTreeMap::new(), Arc::new(Metrics::default()), 0)
+            .with_clock(clock.clone())
+    }
+
+    #[test]
+    fn the_age_goes_by_the_clock_of_the_source() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let freshness = Freshness::new(clock.clone());
+
+        clock.advance(Duration::from_secs(42));
+        assert_eq!(freshness.age(), Duration::from_secs(42));
+
+        freshness.parsed();
+        assert_eq!(freshness.age(), Duration::ZERO);
+    }
+
+    // The watchdog sleeps on the paused tokio clock, the sources age on the mock one.
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_source_turns_stale_and_recovers_once_it_parses_again() {
+        // 2023-11-14T22:13:20Z
+        let clock = Arc::new(MockClock::new(Utc.timestamp_opt(1_700_000_000, 0).unwrap()));
+        let sources = Arc::new(vec![source(&clock)]);
+        let cache = Cache::new(100, Arc::new(Metrics::default()));
+        let shutdown = CancellationToken::new();
+        tokio::spawn(watch_freshness(
+            sources.clone(),
+            cache.clone(),
+            STALE_AFTER,
+            shutdown.clone(),
+        ));
+
+        clock.advance(STALE_AFTER);
+        sleep(MAX_CHECK_INTERVAL).await;
+        assert!(!sources[0].freshness().is_stale());
+
+        clock.advance(Duration::from_secs(1));
+        sleep(MAX_CHECK_INTERVAL).await;
+        assert!(sources[0].freshness().is_stale());
+
+        clock.advance(Duration::from_secs(19));
+        sources[0].freshness().parsed();
+        sleep(MAX_CHECK_INTERVAL).await;
+        assert!(!sources[0].freshness().is_stale());
+        shutdown.cancel();
+
+        let lines: Vec<String> = cache
+            .retrieve_and_clear()
+            .await
+            .iter()
+            .map(render)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "sensor_stale,source=bench silent_secs=61i,stale=t 1700000061000000000",
+                "sensor_stale,source=bench silent_secs=80i,stale=f 1700000080000000000",
+            ]
+        );
+    }
+}
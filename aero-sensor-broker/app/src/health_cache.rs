@@ -0,0 +1,182 @@
+// health_cache.rs
+//
+// Caches the outcome of a health check so frequent probes (load balancers poll `/healthz` every
+// few seconds) do not each trigger a round trip to the device or the database. A result younger
+// than the TTL is returned as is; an older one is returned too while a refresh runs in the
+// background, so callers never wait on a slow dependency. Only the very first check, when
+// nothing is known yet, is awaited, and then only for `health_first_check_wait_ms`.
+
+use crate::clock::{self, Clock};
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::time::{timeout, Duration, Instant};
+
+#[derive(Clone)]
+pub struct CachedHealth {
+    ttl: Duration,
+    // Longest time a caller waits for the first check to complete.
+    max_wait: Duration,
+    state: Arc<Mutex<State>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct State {
+    last: Option<(Result<(), String>, Instant)>,
+    refreshing: bool,
+}
+
+impl CachedHealth {
+    pub fn new(ttl: Duration, max_wait: Duration) -> Self {
+        Self {
+            ttl,
+            max_wait,
+            state: Arc::new(Mutex::new(State::default())),
+            clock: clock::system(),
+        }
+    }
+
+    // Ages the results by `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn age_of(&self, at: Instant) -> Duration {
+        self.clock.now_monotonic().saturating_duration_since(at)
+    }
+
+    // Time since the cached result was produced, if there is one.
+    pub fn age(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.last.as_ref().map(|(_, at)| self.age_of(*at))
+    }
+
+    // Returns the cached result, starting a refresh with `check` when it is older than the TTL.
+    // A check that runs longer than the TTL counts as failed.
+    pub async fn get<F, Fut>(&self, check: F) -> Result<(), String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let (stale, refresh) = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            match &state.last {
+                Some((result, at)) if self.age_of(*at) < self.ttl => return result.clone(),
+                last => {
+                    let stale = last.as_ref().map(|(result, _)| result.clone());
+                    let refresh = (!state.refreshing).then(|| {
+                        state.refreshing = true;
+                        tokio::spawn(self.clone().refresh(check()))
+                    });
+                    (stale, refresh)
+                }
+            }
+        };
+
+        match (stale, refresh) {
+            (Some(result), _) => result,
+            (None, Some(refresh)) => match timeout(self.max_wait, refresh).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => Err(format!("health check failed to run: {}", e)),
+                Err(_) => Err("health check still in progress".to_string()),
+            },
+            (None, None) => Err("health check still in progress".to_string()),
+        }
+    }
+
+    async fn refresh<Fut>(self, check: Fut) -> Result<(), String>
+    where
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let result = timeout(self.ttl, check)
+            .await
+            .unwrap_or_else(|_| Err(format!("health check timed out after {:?}", self.ttl)));
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.last = Some((result.clone(), self.clock.now_monotonic()));
+        state.refreshing = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use chrono::Utc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TTL: Duration = Duration::from_secs(30);
+    const MAX_WAIT: Duration = Duration::from_secs(2);
+
+    // A health cache on a mock clock, and a check counting its runs and failing from the second.
+    fn cached() -> (CachedHealth, Arc<MockClock>, Arc<AtomicUsize>) {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let health = CachedHealth::new(TTL, MAX_WAIT).with_clock(clock.clone());
+        (health, clock, Arc::default())
+    }
+
+    async fn check(health: &CachedHealth, runs: &Arc<AtomicUsize>) -> Result<(), String> {
+        let runs = runs.clone();
+        health
+            .get(move || async move {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    0 => Ok(()),
+                    _ => Err("down".to_string()),
+                }
+            })
+            .await
+    }
+
+    // Lets the refresh spawned in the background complete.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_result_is_reused_until_the_ttl_passed() {
+        let (health, clock, runs) = cached();
+
+        assert_eq!(check(&health, &runs).await, Ok(()));
+        clock.advance(TTL - Duration::from_millis(1));
+        assert_eq!(check(&health, &runs).await, Ok(()));
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(health.age(), Some(TTL - Duration::from_millis(1)));
+    }
+
+    #[tokio::test]
+    async fn an_expired_result_is_returned_while_it_is_refreshed() {
+        let (health, clock, runs) = cached();
+        assert_eq!(check(&health, &runs).await, Ok(()));
+
+        clock.advance(TTL);
+        // The stale result, the refresh runs in the background
+        assert_eq!(check(&health, &runs).await, Ok(()));
+        settle().await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(check(&health, &runs).await, Err("down".to_string()));
+        assert_eq!(health.age(), Some(Duration::ZERO));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_first_check_is_awaited_for_at_most_the_maximum_wait() {
+        let (health, _, _) = cached();
+
+        let result = health
+            .get(|| async {
+                tokio::time::sleep(MAX_WAIT * 2).await;
+                Ok(())
+            })
+            .await;
+
+        assert_eq!(result, Err("health check still in progress".to_string()));
+        assert_eq!(health.age(), None);
+    }
+}
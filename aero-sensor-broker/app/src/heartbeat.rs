@@ -0,0 +1,105 @@
+// heartbeat.rs
+//
+// A `broker_heartbeat` point per source, added to the cache before every periodic flush, so that
+// a silent sensor can be told from a dead broker on the dashboards: the heartbeat keeps coming
+// as long as the broker flushes, whatever the state of the serial side. Each point carries the
+// uptime of the broker, the frames the source received since the previous heartbeat and those it
+// missed according to the sequence numbers of the device, the cache length, and how the last
+// flush went.
+
+use crate::clock::{self, Clock};
+use crate::source::Source;
+
+use influxdb2::models::DataPoint;
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::time::{Duration, Instant};
+
+const HEARTBEAT_MEASUREMENT: &str = "broker_heartbeat";
+
+pub struct Heartbeat {
+    sources: Arc<Vec<Source>>,
+    started: Instant,
+    // Frames each source had received and missed at the previous heartbeat.
+    frames_seen: Mutex<BTreeMap<String, (u64, u64)>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Heartbeat {
+    pub fn new(sources: Arc<Vec<Source>>) -> Self {
+        Self {
+            sources,
+            started: Instant::now(),
+            frames_seen: Mutex::new(BTreeMap::new()),
+            clock: clock::system(),
+        }
+    }
+
+    // Times the uptime, from now, and stamps the heartbeats with `clock` instead of the system
+    // clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.started = clock.now_monotonic();
+        self.clock = clock;
+        self
+    }
+
+    // The heartbeat of every source, given the state of the cache before the flush.
+    pub fn points(&self, cache_len: usize, last_flush: Option<(Duration, bool)>) -> Vec<DataPoint> {
+        let uptime = self
+            .clock
+            .now_monotonic()
+            .saturating_duration_since(self.started);
+        let uptime_secs = uptime.as_secs();
+        let timestamp = match clock::now_nanos(self.clock.as_ref()) {
+            Ok(timestamp) => timestamp,
+            Err(e) => {
+                warn!("Failed to stamp the heartbeats: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut frames_seen = self
+            .frames_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut points = Vec::new();
+        for source in self.sources.iter() {
+            let metrics = source.device().source_metrics();
+            let frames = metrics.frames_received.load(Ordering::Relaxed);
+            let missed = metrics.frames_missed.load(Ordering::Relaxed);
+            let (previous_frames, previous_missed) = frames_seen
+                .insert(source.name().to_string(), (frames, missed))
+                .unwrap_or_default();
+            let mut builder = DataPoint::builder(HEARTBEAT_MEASUREMENT)
+                .field("uptime_secs", uptime_secs as i64)
+                .field("frames", frames.saturating_sub(previous_frames) as i64)
+                .field(
+                    "frames_missed",
+                    missed.saturating_sub(previous_missed) as i64,
+                )
+                .field("cache_len", cache_len as i64)
+                .timestamp(timestamp);
+            if let Some((age, succeeded)) = last_flush {
+                builder = builder
+                    .field("last_flush_ok", succeeded)
+                    .field("last_flush_secs_ago", age.as_secs() as i64);
+            }
+            let point = source
+                .tags()
+                .iter()
+                .fold(builder, |builder, (key, value)| builder.tag(key, value))
+                .build();
+            match point {
+                Ok(point) => points.push(point),
+                Err(e) => warn!(
+                    "Failed to build the heartbeat of source {}: {}",
+                    source.name(),
+                    e
+                ),
+            }
+        }
+        points
+    }
+}
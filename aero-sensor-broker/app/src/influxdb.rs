@@ -3,68 +3,1537 @@
 // Handles interactions with InfluxDB. This module encapsulates all the logic needed to initialize
 // connections, perform health checks, and write data to InfluxDB. It's designed to abstract the
 // complexities of database operations from the main application logic.
+//
+// The startup validation also checks that the token may write every bucket, by reading its
+// authorizations or by writing a trial point, so that a read-only token fails the startup with
+// the bucket it lacks instead of the first flush with an opaque 403.
+//
+// Writes and health checks go through our own reqwest client rather than the influxdb2 one: the
+// latter can neither compress request bodies nor be given custom TLS settings (internal CA,
+// client certificates).
 
-use crate::config::InfluxDBConfig;
+use crate::config::{InfluxDBConfig, Preflight, RetryConfig};
+use crate::errors::AppError;
+use crate::health_cache::CachedHealth;
+use crate::line_protocol::{measurement_of, measurement_of_line};
+use crate::metrics::Metrics;
+use crate::sink::{DataSink, SinkError};
+use crate::stats::{FailureKind, WriteStats};
 
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use influxdb2::{
-    models::{health::Status, DataPoint},
-    Client,
+    models::{health::Status, DataPoint, HealthCheck, WriteDataPoint},
+    RequestError,
 };
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Certificate, Identity, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::{field, info_span, Instrument, Span};
+
+use log::{debug, error, info, warn};
 
-use log::{debug, error, info};
+// Measurement the trial writes of the startup preflight go to.
+const PREFLIGHT_MEASUREMENT: &str = "aero_preflight";
 
 #[derive(Clone)]
 pub struct InfluxDBManager {
-    pub client: Client,
+    bucket: String,
+    bucket_routing: BTreeMap<String, String>,
+    retry: RetryConfig,
+    write_timeout: Duration,
+    health_timeout: Duration,
+    validation_timeout: Duration,
+    health: CachedHealth,
+    create_bucket_if_missing: bool,
+    bucket_retention_secs: u64,
+    preflight: Preflight,
+    preflight_outcome: Arc<Mutex<PreflightOutcome>>,
+    http: reqwest::Client,
+    // The primary endpoint first, then the fallbacks in configuration order.
+    endpoints: Arc<Vec<Endpoint>>,
+    failover: Arc<Mutex<FailoverState>>,
+    stickiness: Duration,
+    gzip_min_bytes: Option<usize>,
+    stats: Arc<WriteStats>,
+    metrics: Arc<Metrics>,
+}
+
+// An InfluxDB instance writes can be sent to.
+struct Endpoint {
+    url: String,
+    org: String,
+    bucket: String,
+    auth_token: String,
+}
+
+impl Endpoint {
+    // Maps a bucket of the primary to this endpoint: the default bucket is replaced, routed
+    // buckets are used as is.
+    fn bucket<'a>(&'a self, bucket: &'a str, primary_bucket: &str) -> &'a str {
+        if bucket == primary_bucket {
+            &self.bucket
+        } else {
+            bucket
+        }
+    }
+
+    // Maps an org of the primary the same way, defaulting to the org of this endpoint.
+    fn org<'a>(&'a self, org: Option<&'a str>, primary_org: &str) -> &'a str {
+        match org {
+            Some(org) if org != primary_org => org,
+            _ => &self.org,
+        }
+    }
+}
+
+struct FailoverState {
+    // Endpoint writes currently go to first, and since when.
+    active: usize,
+    since: Instant,
+    // Endpoint of the last successful write, and when it happened.
+    last_success: Option<usize>,
+    last_success_at: Option<Instant>,
 }
 
 impl InfluxDBManager {
     // Establishes a new client for communicating with InfluxDB using provided configuration settings.
-    pub fn new(config: &InfluxDBConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let client = Client::new(&config.url, &config.org, &config.auth_token);
+    pub fn new(config: &InfluxDBConfig, metrics: Arc<Metrics>) -> Result<Self, AppError> {
+        let auth_token = config.auth_token().map_err(AppError::Config)?;
+        let http = build_http_client(config).map_err(AppError::Config)?;
         info!("New InfluxDB client created for URL: {}", &config.url);
-        Ok(Self { client })
+
+        let mut endpoints = vec![Endpoint {
+            url: config.url.trim_end_matches('/').to_string(),
+            org: config.org.clone(),
+            bucket: config.bucket.clone(),
+            auth_token,
+        }];
+        for fallback in &config.fallbacks {
+            info!("Fallback InfluxDB endpoint configured: {}", &fallback.url);
+            endpoints.push(Endpoint {
+                url: fallback.url.trim_end_matches('/').to_string(),
+                org: fallback.org.clone(),
+                bucket: fallback.bucket.clone(),
+                auth_token: fallback.auth_token().map_err(AppError::Config)?,
+            });
+        }
+
+        Ok(Self {
+            bucket: config.bucket.clone(),
+            bucket_routing: config.bucket_routing.clone(),
+            retry: config.retry.clone(),
+            write_timeout: Duration::from_secs(config.write_timeout_secs),
+            health_timeout: Duration::from_secs(config.health_timeout_secs),
+            validation_timeout: Duration::from_secs(config.validation_timeout_secs),
+            health: CachedHealth::new(
+                Duration::from_secs(config.health_cache_ttl_secs),
+                Duration::from_millis(config.health_first_check_wait_ms),
+            ),
+            create_bucket_if_missing: config.create_bucket_if_missing,
+            bucket_retention_secs: config.bucket_retention_secs,
+            preflight: config.preflight,
+            preflight_outcome: Arc::new(Mutex::new(PreflightOutcome::Pending)),
+            http,
+            endpoints: Arc::new(endpoints),
+            failover: Arc::new(Mutex::new(FailoverState {
+                active: 0,
+                since: Instant::now(),
+                last_success: None,
+                last_success_at: None,
+            })),
+            stickiness: Duration::from_secs(config.failover_stickiness_secs),
+            gzip_min_bytes: config.gzip.then_some(config.gzip_min_bytes),
+            stats: Arc::new(WriteStats::default()),
+            metrics,
+        })
     }
 
-    // Checks the health of the InfluxDB connection and handles any connectivity issues.
-    pub async fn check_health(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        match self.client.health().await {
+    // Confirms at startup that the org and every bucket we write to exist, creating missing
+    // buckets when configured to, and that the token may write them. A typo is reported right
+    // away instead of at the first flush.
+    // When InfluxDB cannot be reached the check is skipped and writes rely on their retries.
+    pub async fn validate(&self) -> Result<(), AppError> {
+        let outcome = match timeout(self.validation_timeout, self.validate_buckets()).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(ValidationError::Invalid(message))) => return Err(AppError::Config(message)),
+            Ok(Err(ValidationError::Unreachable(e))) => {
+                warn!(
+                    "Could not validate InfluxDB buckets, InfluxDB unreachable: {}",
+                    e
+                );
+                PreflightOutcome::Skipped(format!("InfluxDB unreachable: {}", e))
+            }
+            Err(_) => {
+                warn!("Could not validate InfluxDB buckets, InfluxDB did not answer in time");
+                PreflightOutcome::Skipped(format!(
+                    "InfluxDB did not answer within {:?}",
+                    self.validation_timeout
+                ))
+            }
+        };
+        *self
+            .preflight_outcome
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = outcome;
+        Ok(())
+    }
+
+    async fn validate_buckets(&self) -> Result<PreflightOutcome, ValidationError> {
+        let org = &self.endpoints[0].org;
+        let orgs = self.get_json("/api/v2/orgs", &[("org", org)]).await?;
+        let org_id = orgs["orgs"]
+            .as_array()
+            .and_then(|orgs| orgs.iter().find(|o| o["name"] == org.as_str()))
+            .and_then(|o| o["id"].as_str())
+            .ok_or_else(|| {
+                ValidationError::Invalid(format!(
+                    "InfluxDB organization '{}' does not exist or the token cannot access it",
+                    org
+                ))
+            })?
+            .to_string();
+
+        // The name and ID of every bucket
+        let mut bucket_ids = Vec::new();
+        for bucket in self.buckets() {
+            let found = self
+                .get_json("/api/v2/buckets", &[("orgID", &org_id), ("name", bucket)])
+                .await?;
+            let existing = found["buckets"]
+                .as_array()
+                .and_then(|buckets| buckets.iter().find(|b| b["name"] == bucket))
+                .map(|b| b["id"].as_str().unwrap_or_default().to_string());
+
+            if let Some(id) = existing {
+                debug!("InfluxDB bucket '{}' found", bucket);
+                bucket_ids.push((bucket, id));
+            } else if self.create_bucket_if_missing {
+                let id = self.create_bucket(&org_id, bucket).await?;
+                info!("Created missing InfluxDB bucket '{}'", bucket);
+                bucket_ids.push((bucket, id));
+            } else {
+                return Err(ValidationError::Invalid(format!(
+                    "InfluxDB bucket '{}' does not exist in organization '{}'",
+                    bucket, org
+                )));
+            }
+        }
+        info!("InfluxDB organization and buckets validated");
+
+        match self.preflight {
+            Preflight::Authorizations => self.check_authorization(&org_id, &bucket_ids).await,
+            Preflight::TrialWrite => self.check_trial_writes(&bucket_ids).await,
+            Preflight::Off => Ok(PreflightOutcome::Off),
+        }
+    }
+
+    // Checks that the authorization of the token is active and grants writing every bucket.
+    // A token that cannot read its authorizations is not checked.
+    async fn check_authorization(
+        &self,
+        org_id: &str,
+        buckets: &[(&str, String)],
+    ) -> Result<PreflightOutcome, ValidationError> {
+        let primary = &self.endpoints[0];
+        let found = match self
+            .get_json("/api/v2/authorizations", &[("token", &primary.auth_token)])
+            .await
+        {
+            Ok(found) => found,
+            Err(RequestError::Http { status, .. }) if status == StatusCode::FORBIDDEN => {
+                warn!(
+                    "The InfluxDB token cannot read its authorizations, its write permissions \
+                     were not checked: set influxdb.preflight to \"trial_write\" or \"off\""
+                );
+                return Ok(PreflightOutcome::Skipped(
+                    "the token cannot read its authorizations".to_string(),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Older servers ignore the `token` filter and list every authorization the token sees
+        let authorizations = found["authorizations"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let authorization = match authorizations
+            .iter()
+            .find(|authorization| authorization["token"] == primary.auth_token.as_str())
+        {
+            Some(authorization) => authorization,
+            None if authorizations.len() == 1 => &authorizations[0],
+            None => {
+                warn!(
+                    "The authorization of the InfluxDB token was not found, its write \
+                     permissions were not checked"
+                );
+                return Ok(PreflightOutcome::Skipped(
+                    "the authorization of the token was not found".to_string(),
+                ));
+            }
+        };
+        if authorization["status"] == "inactive" {
+            return Err(ValidationError::Invalid(
+                "the InfluxDB token is inactive".to_string(),
+            ));
+        }
+
+        let permissions = authorization["permissions"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for (bucket, id) in buckets {
+            let granted = permissions.iter().any(|permission| {
+                let resource = &permission["resource"];
+                permission["action"] == "write"
+                    && resource["type"] == "buckets"
+                    && (resource["id"].is_null() || resource["id"] == id.as_str())
+                    && (resource["orgID"].is_null() || resource["orgID"] == org_id)
+            });
+            if !granted {
+                return Err(self.lacks_write_permission(bucket));
+            }
+        }
+        info!("InfluxDB token may write every bucket");
+        Ok(PreflightOutcome::Passed)
+    }
+
+    // Writes a point to the `PREFLIGHT_MEASUREMENT` of every bucket, for tokens that cannot
+    // read their authorizations.
+    async fn check_trial_writes(
+        &self,
+        buckets: &[(&str, String)],
+    ) -> Result<PreflightOutcome, ValidationError> {
+        let primary = &self.endpoints[0];
+        for (bucket, _) in buckets {
+            let line = format!("{} ok=true", PREFLIGHT_MEASUREMENT);
+            match self
+                .post_write(primary, &primary.org, bucket, line.into_bytes(), false)
+                .await
+            {
+                Ok(()) => debug!("Trial write to InfluxDB bucket '{}' accepted", bucket),
+                Err(WriteError::Request(RequestError::Http { status, .. }))
+                    if matches!(status.as_u16(), 401 | 403) =>
+                {
+                    return Err(self.lacks_write_permission(bucket));
+                }
+                Err(WriteError::Request(e) | WriteError::Throttled(e, _)) => return Err(e.into()),
+                Err(e) => {
+                    return Err(ValidationError::Invalid(format!(
+                        "trial write to bucket '{}' failed: {}",
+                        bucket, e
+                    )))
+                }
+            }
+        }
+        info!("InfluxDB token may write every bucket, trial writes accepted");
+        Ok(PreflightOutcome::Passed)
+    }
+
+    fn lacks_write_permission(&self, bucket: &str) -> ValidationError {
+        ValidationError::Invalid(format!(
+            "token lacks write permission on bucket '{}' in org '{}'",
+            bucket, self.endpoints[0].org
+        ))
+    }
+
+    // Creates a bucket. Returns its ID.
+    async fn create_bucket(&self, org_id: &str, bucket: &str) -> Result<String, ValidationError> {
+        let retention_rules = match self.bucket_retention_secs {
+            0 => json!([]),
+            seconds => json!([{ "type": "expire", "everySeconds": seconds }]),
+        };
+        let primary = &self.endpoints[0];
+        let response = self
+            .http
+            .post(format!("{}/api/v2/buckets", primary.url))
+            .header("Authorization", format!("Token {}", primary.auth_token))
+            .timeout(self.health_timeout)
+            .json(&json!({
+                "orgID": org_id,
+                "name": bucket,
+                "retentionRules": retention_rules,
+            }))
+            .send()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })?;
+        let created: Value = error_for_status(response)
+            .await?
+            .json()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })?;
+        Ok(created["id"].as_str().unwrap_or_default().to_string())
+    }
+
+    async fn get_json(&self, path: &str, query: &[(&str, &str)]) -> Result<Value, RequestError> {
+        let primary = &self.endpoints[0];
+        let response = self
+            .http
+            .get(format!("{}{}", primary.url, path))
+            .query(query)
+            .header("Authorization", format!("Token {}", primary.auth_token))
+            .timeout(self.health_timeout)
+            .send()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })?;
+        error_for_status(response)
+            .await?
+            .json()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })
+    }
+
+    // Reports the cached health of InfluxDB, refreshing it in the background once it is older
+    // than the configured TTL.
+    pub async fn check_health(&self) -> Result<(), AppError> {
+        let manager = self.clone();
+        self.health
+            .get(|| async move { manager.probe_health().await })
+            .await
+            .map_err(AppError::InfluxUnavailable)
+    }
+
+    // Checks the health of the InfluxDB connection and handles any connectivity issues. Unlike
+    // `check_health`, always asks InfluxDB.
+    pub async fn probe_health(&self) -> Result<(), String> {
+        match self.fetch_health().await {
             Ok(health) if health.status == Status::Pass => {
                 info!("InfluxDB health check successful");
                 Ok(())
             }
             Ok(health) => {
                 error!("InfluxDB health check failed: {:?}", health);
-                Err("InfluxDB health check failed".into())
+                Err(format!("health check status {:?}", health.status))
             }
             Err(e) => {
                 error!("Error performing InfluxDB health check: {}", e);
-                Err(e.into())
+                Err(e.to_string())
             }
         }
     }
 
     // Writes sensor data to InfluxDB. It ensures that data points are correctly formatted and sent to the database.
-    pub async fn write_data(
+    // Transient failures are retried according to the configured policy; the whole call is
+    // bounded by the retry deadline so the flush task can never hang on it.
+    pub async fn write_data(&self, bucket: &str, points: Vec<DataPoint>) -> Result<(), WriteError> {
+        let deadline = Duration::from_secs(self.retry.deadline_secs);
+        let started = Instant::now();
+        let count = points.len();
+        let span = info_span!(
+            "influxdb_write",
+            bucket,
+            points = count,
+            attempts = field::Empty,
+            outcome = field::Empty
+        );
+
+        let write = self
+            .write_with_retries(bucket, points)
+            .instrument(span.clone());
+        let result = match timeout(deadline, write).await {
+            Ok(result) => result,
+            Err(_) => {
+                error!(
+                    "Writing to InfluxDB did not complete within {:?}, giving up",
+                    deadline
+                );
+                Err(WriteError::DeadlineExceeded(deadline))
+            }
+        };
+
+        span.record("outcome", if result.is_ok() { "ok" } else { "error" });
+        let latency = started.elapsed();
+        let failure = result.as_ref().err().map(WriteError::kind);
+        self.stats.record(count, latency, failure);
+        self.metrics.observe_write_latency(latency);
+        if result.is_ok() {
+            self.metrics
+                .points_written
+                .fetch_add(count as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    // Counters of the writes made so far, plus the endpoint that took the last one.
+    pub fn stats(&self) -> Value {
+        json!({
+            "writes": self.stats.snapshot(),
+            "last_write_endpoint": self.last_write_endpoint(),
+        })
+    }
+
+    // Writes raw line protocol, e.g. a dead-letter file being re-submitted, to the given org or
+    // else the configured one.
+    pub async fn write_line_protocol(
+        &self,
+        org: Option<&str>,
+        bucket: &str,
+        body: String,
+    ) -> Result<(), AppError> {
+        self.write_once(org, bucket, body.as_bytes(), false)
+            .await
+            .map_err(|e| {
+                error!("Failed to write line protocol to InfluxDB: {}", e);
+                e.into()
+            })
+    }
+
+    // Fetches the most recent value of every field and series of a measurement written within
+    // `window`, using a Flux `last()` query.
+    pub async fn query_latest(
+        &self,
+        bucket: &str,
+        measurement: &str,
+        window: Duration,
+    ) -> Result<Vec<LatestValue>, AppError> {
+        let flux = format!(
+            "from(bucket: \"{}\")\n  |> range(start: -{}s)\n  |> filter(fn: (r) => r._measurement == \"{}\")\n  |> last()",
+            flux_string(bucket),
+            window.as_secs().max(1),
+            flux_string(measurement)
+        );
+        self.query(&flux).await
+    }
+
+    // Averages one field of a measurement over `every`-long windows, restricted to the points
+    // tagged with this broker's location. Series differing in other tags are merged.
+    pub async fn query_history(
+        &self,
+        bucket: &str,
+        query: &HistoryQuery,
+    ) -> Result<Vec<HistoryValue>, AppError> {
+        let flux = format!(
+            "from(bucket: \"{}\")\n  |> range(start: {}, stop: {})\n  |> filter(fn: (r) => r._measurement == \"{}\" and r._field == \"{}\" and r.location == \"{}\")\n  |> group()\n  |> aggregateWindow(every: {}s, fn: mean, createEmpty: false)",
+            flux_string(bucket),
+            query.start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            query.stop.to_rfc3339_opts(SecondsFormat::Secs, true),
+            flux_string(&query.measurement),
+            flux_string(&query.field),
+            flux_string(&query.location),
+            query.every.as_secs().max(1),
+        );
+        let values = self.query(&flux).await?;
+        Ok(values
+            .into_iter()
+            .map(|value| HistoryValue {
+                time: value.timestamp,
+                value: value.value,
+            })
+            .collect())
+    }
+
+    // Runs a Flux query against the primary endpoint. Queries use their own requests, bounded by
+    // the health timeout, and never touch the write path.
+    async fn query(&self, flux: &str) -> Result<Vec<LatestValue>, AppError> {
+        debug!("Running Flux query: {}", flux);
+
+        let primary = &self.endpoints[0];
+        let response = self
+            .http
+            .post(format!("{}/api/v2/query", primary.url))
+            .query(&[("org", primary.org.as_str())])
+            .header("Authorization", format!("Token {}", primary.auth_token))
+            .header("Accept", "application/csv")
+            .timeout(self.health_timeout)
+            .json(&json!({
+                "query": flux,
+                "type": "flux",
+                "dialect": { "header": true, "annotations": [] },
+            }))
+            .send()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })?;
+
+        let body = error_for_status(response)
+            .await?
+            .text()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })?;
+        Ok(parse_query_result(&body))
+    }
+
+    // URL of the endpoint that took the last successful write.
+    pub fn last_write_endpoint(&self) -> Option<String> {
+        let state = self.failover.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .last_success
+            .map(|index| self.endpoints[index].url.clone())
+    }
+
+    async fn write_with_retries(
         &self,
         bucket: &str,
         points: Vec<DataPoint>,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        // Attempt to write data points to InfluxDB
-        match self
-            .client
-            .write(bucket, futures::stream::iter(points))
+    ) -> Result<(), WriteError> {
+        let max_attempts = self.retry.max_attempts.max(1);
+        let mut attempt = 1;
+        let (body, gzip) = self.encode(&points).map_err(WriteError::Serializing)?;
+
+        loop {
+            // Attempt to write data points to InfluxDB, failing over between endpoints
+            Span::current().record("attempts", attempt);
+            let span = info_span!("write_attempt", attempt, outcome = field::Empty);
+            let result = self
+                .write_once(None, bucket, &body, gzip)
+                .instrument(span.clone())
+                .await;
+            span.record(
+                "outcome",
+                match &result {
+                    Ok(_) => "ok",
+                    Err(e) if e.is_retryable() => "retryable",
+                    Err(_) => "permanent",
+                },
+            );
+            match result {
+                Ok(_) => {
+                    debug!(
+                        "Data written to InfluxDB successfully (attempt {}/{})",
+                        attempt, max_attempts
+                    );
+                    return Ok(());
+                }
+                Err(e) if e.is_retryable() && attempt < max_attempts => {
+                    // A throttled request waits at least as long as InfluxDB asked
+                    let backoff = match &e {
+                        WriteError::Throttled(_, retry_after) => {
+                            backoff(&self.retry, attempt).max(*retry_after)
+                        }
+                        _ => backoff(&self.retry, attempt),
+                    };
+                    warn!(
+                        "Write attempt {}/{} to InfluxDB failed: {}; retrying in {:?}",
+                        attempt, max_attempts, e, backoff
+                    );
+                    sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to write data to InfluxDB after {} attempt(s) ({}): {}",
+                        attempt,
+                        if e.is_retryable() {
+                            "retries exhausted"
+                        } else {
+                            "permanent error"
+                        },
+                        e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Sends the body to the endpoints in failover order until one accepts it. Transient errors
+    // move on to the next endpoint; the error of the last endpoint tried is returned.
+    async fn write_once(
+        &self,
+        org: Option<&str>,
+        bucket: &str,
+        body: &[u8],
+        gzip: bool,
+    ) -> Result<(), WriteError> {
+        let mut outcome = Ok(());
+        for index in self.endpoint_order() {
+            let endpoint = &self.endpoints[index];
+            let bucket = endpoint.bucket(bucket, &self.bucket);
+            let org = endpoint.org(org, &self.endpoints[0].org);
+
+            // Each request is bounded by the write timeout
+            let request = self.post_write(endpoint, org, bucket, body.to_vec(), gzip);
+            outcome = match timeout(self.write_timeout, request).await {
+                Ok(result) => result,
+                Err(_) => Err(WriteError::TimedOut(self.write_timeout)),
+            };
+
+            match &outcome {
+                Ok(()) => {
+                    self.record_success(index);
+                    break;
+                }
+                Err(e) if e.is_retryable() && self.endpoints.len() > 1 => {
+                    warn!("Write to InfluxDB at {} failed: {}", endpoint.url, e);
+                }
+                Err(_) => break,
+            }
+        }
+        outcome
+    }
+
+    // The endpoint writes are sticking to comes first. Once the stickiness period is over the
+    // primary is tried first again.
+    fn endpoint_order(&self) -> Vec<usize> {
+        let state = self.failover.lock().unwrap_or_else(PoisonError::into_inner);
+        let first = if state.since.elapsed() < self.stickiness {
+            state.active
+        } else {
+            0
+        };
+        std::iter::once(first)
+            .chain((0..self.endpoints.len()).filter(|&index| index != first))
+            .collect()
+    }
+
+    fn record_success(&self, index: usize) {
+        let mut state = self.failover.lock().unwrap_or_else(PoisonError::into_inner);
+        // Stay on a fallback for the stickiness period, counted from the switch to it or from
+        // the latest failed probe of the primary, not from every success: the primary has to
+        // be tried again even while the fallback stays healthy.
+        let probed_primary = state.since.elapsed() >= self.stickiness;
+        if index != 0 && (index != state.active || probed_primary) {
+            state.since = Instant::now();
+        }
+        if index != state.active {
+            info!("InfluxDB writes now go to {}", self.endpoints[index].url);
+            state.active = index;
+        }
+        state.last_success = Some(index);
+        state.last_success_at = Some(Instant::now());
+    }
+
+    // Renders the batch as line protocol, gzipped when compression is enabled and the body is
+    // large enough to be worth it. The flag tells whether the body was compressed.
+    fn encode(&self, points: &[DataPoint]) -> io::Result<(Vec<u8>, bool)> {
+        let mut body = Vec::new();
+        for point in points {
+            point.write_data_point_to(&mut body)?;
+        }
+        match self.gzip_min_bytes {
+            Some(min_bytes) if body.len() >= min_bytes => {}
+            _ => return Ok((body, false)),
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        let compressed = encoder.finish()?;
+        debug!(
+            "Compressed write body from {} to {} bytes",
+            body.len(),
+            compressed.len()
+        );
+        Ok((compressed, true))
+    }
+
+    // Sends a line protocol body to the v2 write endpoint. Errors are reported as the influxdb2
+    // client reports them.
+    async fn post_write(
+        &self,
+        endpoint: &Endpoint,
+        org: &str,
+        bucket: &str,
+        body: Vec<u8>,
+        gzip: bool,
+    ) -> Result<(), WriteError> {
+        let mut request = self
+            .http
+            .post(format!("{}/api/v2/write", endpoint.url))
+            .query(&[("org", org), ("bucket", bucket), ("precision", "ns")])
+            .header("Authorization", format!("Token {}", endpoint.auth_token))
+            .header("Content-Type", "text/plain; charset=utf-8");
+        if gzip {
+            request = request.header("Content-Encoding", "gzip");
+        }
+        let response =
+            request.body(body).send().await.map_err(|source| {
+                WriteError::Request(RequestError::ReqwestProcessing { source })
+            })?;
+
+        let retry_after = retry_after(&response);
+        error_for_status(response)
             .await
-        {
-            Ok(_) => {
-                debug!("Data written to InfluxDB successfully");
-                Ok(())
+            .map_err(|e| match retry_after {
+                Some(delay) => WriteError::Throttled(e, delay),
+                None => WriteError::Request(e),
+            })?;
+        Ok(())
+    }
+
+    // Asks the endpoint writes currently go to.
+    async fn fetch_health(&self) -> Result<HealthCheck, RequestError> {
+        let endpoint = &self.endpoints[self.endpoint_order()[0]];
+        let response = self
+            .http
+            .get(format!("{}/health", endpoint.url))
+            .timeout(self.health_timeout)
+            .send()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })?;
+
+        // InfluxDB answers 503 with a regular health document when it is not ready
+        let response = match response.status() {
+            StatusCode::SERVICE_UNAVAILABLE => response,
+            _ => error_for_status(response).await?,
+        };
+        response
+            .json()
+            .await
+            .map_err(|source| RequestError::ReqwestProcessing { source })
+    }
+
+    // A manager writing every point to `bucket`, e.g. the raw samples, through the client, the
+    // endpoints, and the counters of this one.
+    pub fn for_bucket(&self, bucket: &str) -> Self {
+        let mut manager = self.clone();
+        manager.bucket_routing = BTreeMap::from([("*".to_string(), bucket.to_string())]);
+        manager
+    }
+
+    // Target bucket of a measurement: its own route, else the "*" route, else the default bucket.
+    pub fn bucket_for(&self, measurement: &str) -> &str {
+        route(&self.bucket_routing, &self.bucket, measurement)
+    }
+
+    // The default bucket and every bucket of `bucket_routing`, sorted and without duplicates.
+    pub fn buckets(&self) -> Vec<&str> {
+        let mut buckets: Vec<&str> = self.bucket_routing.values().map(String::as_str).collect();
+        buckets.push(&self.bucket);
+        buckets.sort_unstable();
+        buckets.dedup();
+        buckets
+    }
+}
+
+// Exponential backoff for the given attempt, between half and all of the base delay so
+// brokers failing together do not retry in step.
+fn backoff(retry: &RetryConfig, attempt: u32) -> Duration {
+    let base = retry
+        .initial_backoff_ms
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(retry.max_backoff_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(base / 2..=base))
+}
+
+#[async_trait]
+impl DataSink for InfluxDBManager {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    // Groups the points by target bucket and issues one write per bucket, so a failing bucket
+    // does not prevent the others from being written.
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let mut by_bucket: BTreeMap<&str, Vec<DataPoint>> = BTreeMap::new();
+        for point in points {
+            let bucket = match self.bucket_routing.is_empty() {
+                true => &self.bucket,
+                false => self.bucket_for(&measurement_of(&point)),
+            };
+            by_bucket.entry(bucket).or_default().push(point);
+        }
+
+        let single_bucket = by_bucket.len() == 1;
+        let mut partially_written = false;
+        let mut failures = Vec::new();
+        for (bucket, points) in by_bucket {
+            debug!("Writing {} points to bucket {}", points.len(), bucket);
+            if let Err(e) = self.write_data(bucket, points.clone()).await {
+                let message = format!("bucket {}: {}", bucket, e);
+
+                // When InfluxDB kept part of the batch, only the rejected measurements failed
+                if let Some(rejected) = e.rejected_measurements() {
+                    let (dropped, written): (Vec<_>, Vec<_>) = points
+                        .iter()
+                        .cloned()
+                        .partition(|point| rejected.contains(&measurement_of(point)));
+                    if !dropped.is_empty() && !written.is_empty() {
+                        warn!(
+                            "InfluxDB rejected {} points of {:?} in bucket {}, {} other points were written",
+                            dropped.len(),
+                            rejected,
+                            bucket,
+                            written.len()
+                        );
+                        failures.push((SinkError::Rejected(message), dropped));
+                        partially_written = true;
+                        continue;
+                    }
+                }
+
+                let error = match e {
+                    WriteError::TimedOut(_) | WriteError::DeadlineExceeded(_) => {
+                        SinkError::TimedOut(message)
+                    }
+                    e if !e.is_retryable() => SinkError::Rejected(message),
+                    _ => SinkError::Unavailable(message),
+                };
+                failures.push((error, points));
             }
-            Err(e) => {
-                error!("Failed to write data to InfluxDB: {}", e);
-                Err(e.into())
+        }
+
+        match failures.len() {
+            0 => Ok(()),
+            1 if single_bucket && !partially_written => Err(failures.remove(0).0),
+            _ => Err(SinkError::Partial(failures)),
+        }
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        InfluxDBManager::check_health(self)
+            .await
+            .map_err(|e| SinkError::Unavailable(e.to_string()))
+    }
+
+    fn health_age(&self) -> Option<Duration> {
+        self.health.age()
+    }
+
+    fn status(&self) -> Value {
+        let state = self.failover.lock().unwrap_or_else(PoisonError::into_inner);
+        let preflight = self
+            .preflight_outcome
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        json!({
+            "last_write_endpoint": state.last_success.map(|index| &self.endpoints[index].url),
+            "last_successful_write_secs_ago": state.last_success_at.map(|at| at.elapsed().as_secs()),
+            "preflight": preflight,
+        })
+    }
+}
+
+// Builds the HTTP client used for InfluxDB, applying the TLS settings of the configuration.
+fn build_http_client(config: &InfluxDBConfig) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder().use_rustls_tls();
+
+    if let Some(path) = &config.ca_cert_path {
+        let pem =
+            fs::read(path).map_err(|e| format!("cannot read CA certificate {}: {}", path, e))?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| format!("invalid CA certificate {}: {}", path, e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    match (&config.client_cert_path, &config.client_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let mut pem = fs::read(cert_path)
+                .map_err(|e| format!("cannot read client certificate {}: {}", cert_path, e))?;
+            pem.extend(
+                fs::read(key_path)
+                    .map_err(|e| format!("cannot read client key {}: {}", key_path, e))?,
+            );
+            let identity = Identity::from_pem(&pem)
+                .map_err(|e| format!("invalid client certificate or key: {}", e))?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err("client_cert_path and client_key_path must be set together".to_string()),
+    }
+
+    if config.accept_invalid_certs {
+        warn!(
+            "!!! TLS certificate verification for InfluxDB is DISABLED (accept_invalid_certs) !!!"
+        );
+        warn!("!!! The connection is open to man-in-the-middle attacks; do not use this in production !!!");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("cannot build the HTTP client: {}", e))
+}
+
+// Parameters of `query_history`.
+pub struct HistoryQuery {
+    pub measurement: String,
+    pub field: String,
+    pub location: String,
+    pub start: DateTime<Utc>,
+    pub stop: DateTime<Utc>,
+    pub every: Duration,
+}
+
+// One window of `query_history`: its end time and the mean of the field over it.
+#[derive(Debug, Serialize)]
+pub struct HistoryValue {
+    pub time: String,
+    pub value: Value,
+}
+
+// One field of one series as returned by `query_latest`.
+#[derive(Debug, Serialize)]
+pub struct LatestValue {
+    pub tags: BTreeMap<String, String>,
+    pub field: String,
+    pub value: Value,
+    pub timestamp: String,
+}
+
+// Escapes a value for use inside a Flux string literal.
+fn flux_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+}
+
+// Parses the CSV of a Flux query. Each table starts with a header row; tables are separated
+// by empty lines. Annotation rows (`#datatype`, `#group`, `#default`) are not asked for, and
+// skipped should a server send them anyway. Columns not starting with an underscore (other
+// than `result` and `table`) are tags.
+fn parse_query_result(body: &str) -> Vec<LatestValue> {
+    let mut values = Vec::new();
+    let mut header: Option<Vec<String>> = None;
+
+    for line in body.lines().map(|line| line.trim_end_matches('\r')) {
+        if line.is_empty() {
+            header = None;
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let row = parse_csv_line(line);
+        let Some(columns) = &header else {
+            header = Some(row);
+            continue;
+        };
+
+        let mut value = LatestValue {
+            tags: BTreeMap::new(),
+            field: String::new(),
+            value: Value::Null,
+            timestamp: String::new(),
+        };
+        for (column, cell) in columns.iter().zip(row) {
+            match column.as_str() {
+                "_time" => value.timestamp = cell,
+                "_field" => value.field = cell,
+                "_value" => {
+                    value.value = cell
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|number| serde_json::Number::from_f64(number).map(Value::Number))
+                        .unwrap_or(Value::String(cell))
+                }
+                "" | "result" | "table" => {}
+                name if name.starts_with('_') => {}
+                name => {
+                    value.tags.insert(name.to_string(), cell);
+                }
             }
         }
+        values.push(value);
+    }
+    values
+}
+
+// Splits a CSV line, honoring double-quoted cells with `""` escapes.
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                cell.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => cells.push(std::mem::take(&mut cell)),
+            (c, _) => cell.push(c),
+        }
+    }
+    cells.push(cell);
+    cells
+}
+
+// Picks the bucket of a measurement from `bucket_routing`: its own route, else the "*" route, else
+// the default bucket.
+pub fn route<'a>(
+    bucket_routing: &'a BTreeMap<String, String>,
+    bucket: &'a str,
+    measurement: &str,
+) -> &'a str {
+    bucket_routing
+        .get(measurement)
+        .or_else(|| bucket_routing.get("*"))
+        .map_or(bucket, String::as_str)
+}
+
+// Delay requested by a 429 or 503 response through its Retry-After header.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    if !matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+// Reads a Retry-After value, either delta-seconds or an HTTP date. A date in the past asks for
+// no delay at all.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+// Turns an error status into a `RequestError::Http` carrying the response body.
+async fn error_for_status(response: reqwest::Response) -> Result<reqwest::Response, RequestError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let text = response
+        .text()
+        .await
+        .map_err(|source| RequestError::ReqwestProcessing { source })?;
+    Err(RequestError::Http { status, text })
+}
+
+// Outcome of a failed startup validation: either the configuration is wrong, or InfluxDB could
+// not tell us.
+enum ValidationError {
+    Invalid(String),
+    Unreachable(RequestError),
+}
+
+impl From<RequestError> for ValidationError {
+    // Authentication failures and 404s mean the configuration is wrong; anything else may be
+    // a temporary outage.
+    fn from(error: RequestError) -> Self {
+        match &error {
+            RequestError::Http { status, text }
+                if matches!(status.as_u16(), 400 | 401 | 403 | 404) =>
+            {
+                ValidationError::Invalid(format!(
+                    "InfluxDB rejected the startup validation ({}): {}",
+                    status, text
+                ))
+            }
+            _ => ValidationError::Unreachable(error),
+        }
+    }
+}
+
+// How the startup validation of the buckets and the token went, as reported in `/health`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+enum PreflightOutcome {
+    // The validation has not run yet.
+    Pending,
+    Passed,
+    // The validation could not tell whether the token may write the buckets, and why.
+    Skipped(String),
+    // `influxdb.preflight` is "off": the buckets were validated, the permissions were not.
+    Off,
+}
+
+// Why a write to InfluxDB failed. Timeouts are kept apart from request errors so a server that
+// hangs can be told from one that refuses connections.
+#[derive(Debug)]
+pub enum WriteError {
+    // The request failed or InfluxDB answered with an error status.
+    Request(RequestError),
+    // InfluxDB answered 429 or 503 and asked us to retry after the given delay.
+    Throttled(RequestError, Duration),
+    // The batch could not be turned into a request body.
+    Serializing(io::Error),
+    // A single request took longer than the write timeout.
+    TimedOut(Duration),
+    // All attempts together took longer than the retry deadline.
+    DeadlineExceeded(Duration),
+}
+
+impl WriteError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WriteError::Request(e) => is_retryable(e),
+            WriteError::Throttled(..) => true,
+            WriteError::Serializing(_) => false,
+            WriteError::TimedOut(_) | WriteError::DeadlineExceeded(_) => true,
+        }
+    }
+
+    // Measurements InfluxDB refused when it reports a partial write (the other lines of the
+    // batch were stored). Returns `None` for any other error, or when the message does not
+    // name the rejected measurements.
+    fn rejected_measurements(&self) -> Option<BTreeSet<String>> {
+        let WriteError::Request(RequestError::Http { status, text }) = self else {
+            return None;
+        };
+        if !matches!(status.as_u16(), 400 | 422) {
+            return None;
+        }
+
+        // The body is JSON with the details in `message`; fall back to the raw text
+        let message = serde_json::from_str::<Value>(text)
+            .ok()
+            .and_then(|body| body["message"].as_str().map(str::to_string))
+            .unwrap_or_else(|| text.clone());
+        if !message.contains("partial write") {
+            return None;
+        }
+
+        let mut rejected = BTreeSet::new();
+        // Field type conflicts: `... on measurement "temperature" is type ...`
+        for part in message.split("on measurement \"").skip(1) {
+            if let Some(end) = part.find('"') {
+                rejected.insert(part[..end].to_string());
+            }
+        }
+        // Parse errors: `unable to parse '<line>': <reason>`
+        for part in message.split("unable to parse '").skip(1) {
+            if let Some(end) = part.find("': ") {
+                rejected.insert(measurement_of_line(&part[..end]));
+            }
+        }
+        (!rejected.is_empty()).then_some(rejected)
+    }
+
+    fn kind(&self) -> FailureKind {
+        match self {
+            WriteError::TimedOut(_) | WriteError::DeadlineExceeded(_) => FailureKind::Timeout,
+            WriteError::Throttled(RequestError::Http { status, .. }, _)
+                if status.is_server_error() =>
+            {
+                FailureKind::ServerError
+            }
+            WriteError::Throttled(..) => FailureKind::ClientError,
+            WriteError::Request(RequestError::ReqwestProcessing { source })
+                if source.is_timeout() =>
+            {
+                FailureKind::Timeout
+            }
+            WriteError::Request(RequestError::ReqwestProcessing { .. }) => FailureKind::Connection,
+            WriteError::Request(RequestError::Http { status, .. }) if status.is_client_error() => {
+                FailureKind::ClientError
+            }
+            WriteError::Request(RequestError::Http { status, .. }) if status.is_server_error() => {
+                FailureKind::ServerError
+            }
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Request(e) => write!(f, "{}", e),
+            WriteError::Throttled(e, delay) => write!(f, "{} (retry after {:?})", e, delay),
+            WriteError::Serializing(e) => write!(f, "failed to serialize points: {}", e),
+            WriteError::TimedOut(limit) => write!(f, "request timed out after {:?}", limit),
+            WriteError::DeadlineExceeded(limit) => {
+                write!(f, "write deadline of {:?} exceeded", limit)
+            }
+        }
+    }
+}
+
+impl Error for WriteError {}
+
+// Timeouts, connection errors, 429 and 5xx responses are transient; anything else (400 bad
+// line protocol, 401/403) will fail the same way again.
+pub fn is_retryable(error: &RequestError) -> bool {
+    match error {
+        RequestError::ReqwestProcessing { .. } => true,
+        RequestError::Http { status, .. } => status.as_u16() == 429 || status.is_server_error(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The JSON body of an InfluxDB error answer.
+    fn body(code: &str, message: &str) -> String {
+        json!({ "code": code, "message": message }).to_string()
+    }
+
+    // The measurements rejected by a write InfluxDB answered with `status` and `body`.
+    fn rejected(status: u16, body: &str) -> Option<Vec<String>> {
+        let error = WriteError::Request(RequestError::Http {
+            status: StatusCode::from_u16(status).unwrap(),
+            text: body.to_string(),
+        });
+        Some(error.rejected_measurements()?.into_iter().collect())
+    }
+
+    #[test]
+    fn a_field_type_conflict_names_its_measurement() {
+        let message = "failure writing points to database: partial write: field type conflict: \
+                       input field \"value\" on measurement \"humidity\" is type float, already \
+                       exists as type integer dropped=2";
+        let body = body("unprocessable entity", message);
+        assert_eq!(rejected(422, &body), Some(vec!["humidity".to_string()]));
+    }
+
+    #[test]
+    fn every_conflicting_measurement_is_named() {
+        let message = "partial write: field type conflict: input field \"value\" on measurement \
+                       \"humidity\" is type float, already exists as type integer dropped=1; \
+                       field type conflict: input field \"state\" on measurement \"door\" is \
+                       type string, already exists as type boolean dropped=1";
+        let body = body("unprocessable entity", message);
+        let expected = vec!["door".to_string(), "humidity".to_string()];
+        assert_eq!(rejected(422, &body), Some(expected));
+    }
+
+    #[test]
+    fn unparsable_lines_name_their_measurement() {
+        let message = "partial write has occurred, errors encountered on line(s): line 2: unable \
+                       to parse 'humidity,sensor=bme280 value=': missing field value; line 3: \
+                       unable to parse 'air\\ quality value=1 x': bad timestamp";
+        let body = body("invalid", message);
+        let expected = vec!["air quality".to_string(), "humidity".to_string()];
+        assert_eq!(rejected(400, &body), Some(expected));
+    }
+
+    #[test]
+    fn a_plain_text_body_is_read_as_the_message() {
+        let body = "partial write: field type conflict: input field \"value\" on measurement \
+                    \"temperature\" is type integer, already exists as type float dropped=1";
+        assert_eq!(rejected(422, body), Some(vec!["temperature".to_string()]));
+    }
+
+    #[test]
+    fn errors_that_are_not_partial_writes_reject_the_whole_batch() {
+        let unparsable = body(
+            "invalid",
+            "unable to parse 'humidity value=': missing field value",
+        );
+        assert_eq!(rejected(400, &unparsable), None);
+        let not_found = body("not found", "bucket \"climate\" not found");
+        assert_eq!(rejected(404, &not_found), None);
+        // A server error is retried whatever its message says
+        let server = body(
+            "internal error",
+            "partial write: on measurement \"humidity\"",
+        );
+        assert_eq!(rejected(500, &server), None);
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_as_a_date() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        // A date already past needs no wait
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("-5", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    fn http(status: u16) -> RequestError {
+        RequestError::Http {
+            status: StatusCode::from_u16(status).unwrap(),
+            text: String::new(),
+        }
+    }
+
+    #[test]
+    fn throttling_server_errors_and_timeouts_are_retried() {
+        for status in [429, 500, 502, 503] {
+            assert!(is_retryable(&http(status)), "{}", status);
+        }
+        for status in [400, 401, 403, 404, 413, 422] {
+            assert!(!is_retryable(&http(status)), "{}", status);
+        }
+        assert!(WriteError::Throttled(http(503), Duration::from_secs(1)).is_retryable());
+        assert!(WriteError::TimedOut(Duration::from_secs(5)).is_retryable());
+        assert!(WriteError::DeadlineExceeded(Duration::from_secs(30)).is_retryable());
+        assert!(!WriteError::Request(http(401)).is_retryable());
+        let unwritable = io::Error::other("disk full");
+        assert!(!WriteError::Serializing(unwritable).is_retryable());
+    }
+
+    #[test]
+    fn the_backoff_doubles_up_to_its_maximum_with_at_most_half_taken_off() {
+        let retry = RetryConfig {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+            ..RetryConfig::default()
+        };
+        for (attempt, base) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1_000),
+            (40, 1_000),
+        ] {
+            for _ in 0..100 {
+                let delay = backoff(&retry, attempt);
+                let bounds = Duration::from_millis(base / 2)..=Duration::from_millis(base);
+                assert!(bounds.contains(&delay), "attempt {}: {:?}", attempt, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn a_zero_backoff_does_not_wait() {
+        let retry = RetryConfig {
+            initial_backoff_ms: 0,
+            ..RetryConfig::default()
+        };
+        assert_eq!(backoff(&retry, 3), Duration::ZERO);
+    }
+
+    // A manager for InfluxDB at `url`, checking the token by its authorizations.
+    fn manager(url: &str) -> InfluxDBManager {
+        let config: InfluxDBConfig = serde_json::from_value(json!({
+            "url": url,
+            "org": "site",
+            "bucket": "sensors",
+            "auth_token": "token",
+            "health_timeout_secs": 5,
+            "validation_timeout_secs": 1,
+        }))
+        .unwrap();
+        InfluxDBManager::new(&config, Arc::new(Metrics::default())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_validation_that_runs_out_of_time_reports_the_preflight_skipped() {
+        // Accepts connections and never answers them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let manager = manager(&format!("http://{}", listener.local_addr().unwrap()));
+        assert_eq!(
+            manager.status()["preflight"],
+            json!({ "outcome": "pending" })
+        );
+
+        manager.validate().await.unwrap();
+
+        let expected = json!({
+            "outcome": "skipped",
+            "reason": "InfluxDB did not answer within 1s",
+        });
+        assert_eq!(manager.status()["preflight"], expected);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_influxdb_reports_the_preflight_skipped() {
+        // Nothing listens on the port once the listener is dropped
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let manager = manager(&format!("http://{}", address));
+
+        manager.validate().await.unwrap();
+
+        let preflight = manager.status()["preflight"].clone();
+        assert_eq!(preflight["outcome"], "skipped");
+        let reason = preflight["reason"].as_str().unwrap();
+        assert!(reason.starts_with("InfluxDB unreachable: "), "{}", reason);
+    }
+
+    #[test]
+    fn flux_strings_escape_quotes_backslashes_and_interpolation() {
+        assert_eq!(flux_string("sensors"), "sensors");
+        assert_eq!(flux_string(r#"a"b"#), r#"a\"b"#);
+        assert_eq!(flux_string(r"C:\data"), r"C:\\data");
+        assert_eq!(flux_string("${token}"), r"\${token}");
+        // Without the escapes the quote would end the literal
+        assert_eq!(
+            flux_string(r#"x") |> drop(columns: ["_value"]) //"#),
+            r#"x\") |> drop(columns: [\"_value\"]) //"#
+        );
+    }
+
+    #[test]
+    fn csv_cells_may_be_quoted() {
+        assert_eq!(parse_csv_line("a,b,,c"), ["a", "b", "", "c"]);
+        assert_eq!(
+            parse_csv_line(r#","room ""A"", east",21.5"#),
+            ["", r#"room "A", east"#, "21.5"]
+        );
+        assert_eq!(parse_csv_line(r#""""#), [""]);
+        assert_eq!(parse_csv_line(""), [""]);
+    }
+
+    #[test]
+    fn every_table_of_a_query_result_is_read() {
+        let body = ",result,table,_start,_stop,_time,_value,_field,_measurement,room\r\n\
+                    ,_result,0,2023-11-14T22:00:00Z,2023-11-14T23:00:00Z,2023-11-14T22:13:20Z,21.5,value,temperature,\"kitchen, east\"\r\n\
+                    \r\n\
+                    ,result,table,_time,_value,_field,_measurement\r\n\
+                    ,_result,1,2023-11-14T22:13:30Z,on,state,temperature\r\n";
+
+        let values = parse_query_result(body);
+
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].field, "value");
+        assert_eq!(values[0].value, json!(21.5));
+        assert_eq!(values[0].timestamp, "2023-11-14T22:13:20Z");
+        assert_eq!(
+            values[0].tags,
+            BTreeMap::from([("room".to_string(), "kitchen, east".to_string())])
+        );
+        assert_eq!(values[1].field, "state");
+        assert_eq!(values[1].value, json!("on"));
+        assert!(values[1].tags.is_empty());
+    }
+
+    #[test]
+    fn annotation_rows_are_skipped() {
+        let body = "#datatype,string,long,dateTime:RFC3339,double,string\n\
+                    #group,false,false,false,false,true\n\
+                    #default,_result,,,,\n\
+                    ,result,table,_time,_value,_field\n\
+                    ,,0,2023-11-14T22:13:20Z,48.25,value\n";
+
+        let values = parse_query_result(body);
+
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].value, json!(48.25));
+        assert_eq!(values[0].timestamp, "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn an_empty_result_has_no_values() {
+        assert!(parse_query_result("").is_empty());
+        assert!(parse_query_result("\r\n").is_empty());
+        // A table with its header only
+        assert!(parse_query_result(",result,table,_time,_value,_field\r\n\r\n").is_empty());
+    }
+
+    #[test]
+    fn a_partial_write_naming_no_measurement_rejects_the_whole_batch() {
+        let body = body(
+            "unprocessable entity",
+            "partial write: points beyond retention policy dropped=3",
+        );
+        assert_eq!(rejected(422, &body), None);
     }
 }
@@ -0,0 +1,182 @@
+// ingest_limit.rs
+//
+// Per-source ingest limits, so that a board gone haywire (a firmware bug once made one send 500
+// frames a second) cannot keep a core busy parsing. The frames of a source are counted in
+// one-second windows: once `frames_per_second` were admitted in a window, the rest are dropped
+// unparsed, or one in `sample_one_in` of them is parsed. After parsing, the points of each
+// measurement can be capped the same way. What is dropped is counted in the stats of the source,
+// and a warning is logged when a source first hits its limit and again when a whole window
+// passes without anything dropped.
+//
+// The windows go by a coarse clock, a counter of seconds advanced by a task of its own, so that
+// admitting a frame costs an atomic load rather than a clock read.
+
+use crate::config::{IngestLimitConfig, IngestOverflow};
+use crate::data_manipulation::MyDataPoint;
+use crate::metrics::SourceMetrics;
+
+use log::warn;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+// Seconds since the clock was started, shared by every limiter.
+#[derive(Clone, Default)]
+pub struct CoarseClock {
+    seconds: Arc<AtomicU64>,
+}
+
+impl CoarseClock {
+    // Starts the task advancing the clock, which runs until `shutdown` is cancelled.
+    pub fn start(shutdown: CancellationToken) -> Self {
+        let clock = Self::default();
+        let seconds = clock.seconds.clone();
+        tokio::spawn(async move {
+            let mut ticks = interval(Duration::from_secs(1));
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            // The first tick completes right away
+            ticks.tick().await;
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {
+                        seconds.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ = shutdown.cancelled() => return,
+                }
+            }
+        });
+        clock
+    }
+
+    fn now(&self) -> u64 {
+        self.seconds.load(Ordering::Relaxed)
+    }
+}
+
+pub struct IngestLimiter<'a> {
+    source: String,
+    config: IngestLimitConfig,
+    clock: CoarseClock,
+    metrics: &'a SourceMetrics,
+    window: u64,
+    frames: u32,
+    // Frames beyond the cap since the last one sampled, one in `sample_one_in` being parsed.
+    overflow: u32,
+    points: HashMap<String, u32>,
+    // What was dropped in the current window, and since the limit engaged.
+    dropped_in_window: u64,
+    dropped_while_engaged: u64,
+    engaged: bool,
+}
+
+impl<'a> IngestLimiter<'a> {
+    pub fn new(
+        source: &str,
+        config: &IngestLimitConfig,
+        clock: CoarseClock,
+        metrics: &'a SourceMetrics,
+    ) -> Self {
+        let window = clock.now();
+        Self {
+            source: source.to_string(),
+            config: config.clone(),
+            clock,
+            metrics,
+            window,
+            frames: 0,
+            overflow: 0,
+            points: HashMap::new(),
+            dropped_in_window: 0,
+            dropped_while_engaged: 0,
+            engaged: false,
+        }
+    }
+
+    // Whether the frame just read is to be parsed.
+    pub fn admit_frame(&mut self) -> bool {
+        self.roll_window();
+        if self.frames < self.config.frames_per_second {
+            self.frames += 1;
+            return true;
+        }
+        self.overflow += 1;
+        if self.config.overflow == IngestOverflow::Sample
+            && self.overflow >= self.config.sample_one_in
+        {
+            self.overflow = 0;
+            return true;
+        }
+        self.metrics.frames_limited.fetch_add(1, Ordering::Relaxed);
+        self.dropped(1);
+        false
+    }
+
+    // The points within the cap of their measurement, all of them when there is none.
+    pub fn admit_points(&mut self, points: Vec<MyDataPoint>) -> Vec<MyDataPoint> {
+        let cap = self.config.points_per_measurement_per_second;
+        if cap == 0 {
+            return points;
+        }
+        self.roll_window();
+        let before = points.len();
+        let admitted: Vec<MyDataPoint> = points
+            .into_iter()
+            .filter(|point| {
+                let count = match self.points.get_mut(point.get_measurement()) {
+                    Some(count) => count,
+                    None => self
+                        .points
+                        .entry(point.get_measurement().to_string())
+                        .or_default(),
+                };
+                *count += 1;
+                *count <= cap
+            })
+            .collect();
+        let dropped = (before - admitted.len()) as u64;
+        if dropped > 0 {
+            self.metrics
+                .points_limited
+                .fetch_add(dropped, Ordering::Relaxed);
+            self.dropped(dropped);
+        }
+        admitted
+    }
+
+    fn dropped(&mut self, count: u64) {
+        self.dropped_in_window += count;
+        self.dropped_while_engaged += count;
+        if !self.engaged {
+            self.engaged = true;
+            warn!(
+                "Source {} exceeds its ingest limit of {} frames per second, limiting it",
+                self.source, self.config.frames_per_second
+            );
+        }
+    }
+
+    // Starts a new window once the clock moved on, releasing the limit after a window (or
+    // more) in which nothing was dropped.
+    fn roll_window(&mut self) {
+        let now = self.clock.now();
+        if now == self.window {
+            return;
+        }
+        let quiet_window = self.dropped_in_window == 0 || now > self.window + 1;
+        if self.engaged && quiet_window {
+            warn!(
+                "Source {} is back within its ingest limit, {} frames or points were dropped",
+                self.source, self.dropped_while_engaged
+            );
+            self.engaged = false;
+            self.dropped_while_engaged = 0;
+        }
+        self.window = now;
+        self.frames = 0;
+        self.overflow = 0;
+        self.points.clear();
+        self.dropped_in_window = 0;
+    }
+}
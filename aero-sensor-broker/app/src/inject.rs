@@ -0,0 +1,166 @@
+// inject.rs
+//
+// Synthetic points pushed through the real pipeline by `POST /admin/inject`, to check dashboards
+// and alert rules end to end. An injected point is added to the cache exactly like the points of
+// the sources, so it is written by the next flush with everything else, but it is always tagged
+// with `http.inject_tag` set to "true", whatever the request says, so that production queries
+// can filter it out. The global tags are added to it as well; the tags of the request override
+// them. The measurement and the tags are held to the rules of the configured tags.
+
+use crate::cache::Cache;
+use crate::config::valid_tag;
+use crate::line_protocol::render;
+
+use chrono::Utc;
+use influxdb2::models::DataPoint;
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// The body of `POST /admin/inject`; `timestamp` is in seconds since the epoch, now when unset.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InjectRequest {
+    pub measurement: String,
+    pub value: f64,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub timestamp: Option<f64>,
+}
+
+#[derive(Clone)]
+pub struct Injector {
+    cache: Cache,
+    // The global tags.
+    tags: BTreeMap<String, String>,
+    // Tag set to "true" on every injected point.
+    tag_name: String,
+}
+
+impl Injector {
+    pub fn new(cache: Cache, tags: BTreeMap<String, String>, tag_name: &str) -> Self {
+        Self {
+            cache,
+            tags,
+            tag_name: tag_name.to_string(),
+        }
+    }
+
+    // Adds the point of the request to the cache. Returns it as it will be written, in line
+    // protocol.
+    pub async fn inject(&self, request: InjectRequest) -> Result<String, String> {
+        let point = self.point(request)?;
+        let line = render(&point);
+        info!("Injected point {}", line);
+        self.cache.add(vec![point]).await;
+        Ok(line)
+    }
+
+    fn point(&self, request: InjectRequest) -> Result<DataPoint, String> {
+        let measurement = request.measurement.trim();
+        if !valid_tag(measurement) {
+            return Err(format!(
+                "measurement '{}' must be non-empty text without control characters",
+                measurement.escape_debug()
+            ));
+        }
+        if !request.value.is_finite() {
+            return Err("value must be a finite number".to_string());
+        }
+        if let Some((key, value)) = request.tags.iter().find(|(key, value)| {
+            [key, value]
+                .iter()
+                .any(|text| text.trim().is_empty() || !valid_tag(text))
+        }) {
+            return Err(format!(
+                "tag '{}'='{}' must have a key and a value without control characters",
+                key.escape_debug(),
+                value.escape_debug()
+            ));
+        }
+        let timestamp = match request.timestamp {
+            None => Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            // Past 2262 the nanoseconds no longer fit
+            Some(seconds) if (0.0..i64::MAX as f64 / 1e9).contains(&seconds) => {
+                (seconds * 1e9) as i64
+            }
+            Some(seconds) => return Err(format!("invalid timestamp {}", seconds)),
+        };
+
+        let mut tags = self.tags.clone();
+        tags.extend(request.tags);
+        tags.insert(self.tag_name.clone(), "true".to_string());
+        let builder = DataPoint::builder(measurement)
+            .field("value", request.value)
+            .timestamp(timestamp);
+        tags.iter()
+            .fold(builder, |builder, (key, value)| builder.tag(key, value))
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use std::sync::Arc;
+
+    fn injector() -> Injector {
+        let cache = Cache::new(100, Arc::new(Metrics::default()));
+        let tags = BTreeMap::from([("location".to_string(), "hangar".to_string())]);
+        Injector::new(cache, tags, "injected")
+    }
+
+    fn request(body: &str) -> InjectRequest {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn an_injected_point_is_tagged_and_cached() {
+        let injector = injector();
+
+        let line = injector
+            .inject(request(
+                r#"{"measurement": " temperature ", "value": 99.5, "tags": {"location": "lab"}, "timestamp": 1700000000}"#,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            line,
+            "temperature,injected=true,location=lab value=99.5 1700000000000000000"
+        );
+        assert_eq!(injector.cache.len().await, 1);
+    }
+
+    #[test]
+    fn a_measurement_or_tag_with_a_line_break_is_refused() {
+        let injector = injector();
+
+        for body in [
+            r#"{"measurement": "", "value": 1}"#,
+            r#"{"measurement": "temp\nerature", "value": 1}"#,
+            r#"{"measurement": "temperature", "value": 1, "tags": {"room": " "}}"#,
+            r#"{"measurement": "temperature", "value": 1, "tags": {"ro\nom": "lab"}}"#,
+            r#"{"measurement": "temperature", "value": 1, "tags": {"room": "lab\r"}}"#,
+        ] {
+            assert!(injector.point(request(body)).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn a_timestamp_out_of_the_nanosecond_range_is_refused() {
+        let injector = injector();
+
+        for timestamp in ["-1", "1e12", "9300000000"] {
+            let body = format!(
+                r#"{{"measurement": "temperature", "value": 1, "timestamp": {}}}"#,
+                timestamp
+            );
+            assert!(injector.point(request(&body)).is_err(), "{}", timestamp);
+        }
+        let body = r#"{"measurement": "temperature", "value": 1, "timestamp": 9200000000}"#;
+        assert!(injector.point(request(body)).is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+// latest.rs
+//
+// Keeps the most recent value of every series (measurement and tags) as points are parsed, for
+// the `/api/latest` endpoints. The map lives next to the read loop instead of being derived from
+// the flush cache, so the current values are still known right after a flush empties the cache.
+//
+// The map holds at most `max_series` series: beyond that, the series updated least recently is
+// evicted, so an explosion of tag values cannot grow it without bound.
+
+use crate::cap::Cap;
+use crate::data_manipulation::MyDataPoint;
+use influxdb2::models::FieldValue;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+type SeriesKey = (String, BTreeMap<String, String>);
+
+#[derive(Clone)]
+pub struct LatestValues {
+    series: Arc<Mutex<Series>>,
+    stale_after: Duration,
+    cap: Arc<Cap>,
+}
+
+#[derive(Default)]
+struct Series {
+    readings: BTreeMap<SeriesKey, Reading>,
+    // The series by the number of their last update, least recent first.
+    recency: BTreeMap<u64, SeriesKey>,
+    updates: u64,
+}
+
+struct Reading {
+    fields: BTreeMap<String, FieldValue>,
+    timestamp: Option<i64>,
+    received: Instant,
+    // Number of the last update, the key of the series in `recency`.
+    update: u64,
+}
+
+impl LatestValues {
+    // Entries not updated for `stale_after` are reported with `"stale": true`. Keeps up to
+    // `max_series` series.
+    pub fn new(stale_after: Duration, max_series: usize) -> Self {
+        Self {
+            series: Arc::new(Mutex::new(Series::default())),
+            stale_after,
+            cap: Arc::new(Cap::new("Latest values series", max_series)),
+        }
+    }
+
+    // Records the values of freshly parsed points, replacing the previous ones of their series.
+    pub fn update(&self, points: &[MyDataPoint]) {
+        let received = Instant::now();
+        let mut series = self.series.lock().unwrap_or_else(PoisonError::into_inner);
+        let series = &mut *series;
+        for point in points.iter().filter(|point| !point.get_fields().is_empty()) {
+            let key = (
+                point.get_measurement().to_string(),
+                point.get_tags().clone(),
+            );
+            series.updates += 1;
+            let update = series.updates;
+            match series.readings.get_mut(&key) {
+                Some(reading) => {
+                    series.recency.remove(&reading.update);
+                    reading.fields.extend(point.get_fields().clone());
+                    reading.timestamp = point.get_timestamp();
+                    reading.received = received;
+                    reading.update = update;
+                }
+                None => {
+                    // The least recently updated series makes room for the new one
+                    while series.readings.len() >= self.cap.limit() {
+                        let Some((_, evicted)) = series.recency.pop_first() else {
+                            break;
+                        };
+                        series.readings.remove(&evicted);
+                        self.cap.hit();
+                    }
+                    series.readings.insert(
+                        key.clone(),
+                        Reading {
+                            fields: point.get_fields().clone(),
+                            timestamp: point.get_timestamp(),
+                            received,
+                            update,
+                        },
+                    );
+                    self.cap.observe(series.readings.len());
+                }
+            }
+            series.recency.insert(update, key);
+        }
+    }
+
+    // The number of series kept and the limit, for `/stats`.
+    pub fn stats(&self) -> Value {
+        let series = self.series.lock().unwrap_or_else(PoisonError::into_inner);
+        self.cap.to_json(series.readings.len())
+    }
+
+    // Every series, or only those of `measurement`, as JSON objects.
+    pub fn snapshot(&self, measurement: Option<&str>) -> Vec<Value> {
+        let series = self.series.lock().unwrap_or_else(PoisonError::into_inner);
+        series
+            .readings
+            .iter()
+            .filter(|((name, _), _)| measurement.is_none() || measurement == Some(name.as_str()))
+            .map(|((name, tags), reading)| {
+                let age = reading.received.elapsed();
+                json!({
+                    "measurement": name,
+                    "tags": tags,
+                    "fields": fields_to_json(&reading.fields),
+                    "timestamp": reading.timestamp,
+                    "age_secs": age.as_secs(),
+                    "stale": age > self.stale_after,
+                })
+            })
+            .collect()
+    }
+}
+
+fn fields_to_json(fields: &BTreeMap<String, FieldValue>) -> Value {
+    fields
+        .iter()
+        .map(|(name, value)| (name.clone(), field_to_json(value)))
+        .collect()
+}
+
+pub fn field_to_json(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Bool(value) => json!(value),
+        FieldValue::I64(value) => json!(value),
+        FieldValue::F64(value) => json!(value),
+        FieldValue::String(value) => json!(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_manipulation::Reading;
+
+    const MAX_SERIES: usize = 1000;
+
+    // A reading of the temperature of one of many racks.
+    fn reading(rack: usize) -> MyDataPoint {
+        let tags = BTreeMap::from([("rack".to_string(), rack.to_string())]);
+        MyDataPoint::from_reading("temperature".into(), tags, Reading::Value(21.5), 0)
+    }
+
+    fn racks(latest: &LatestValues) -> Vec<usize> {
+        let mut racks: Vec<usize> = latest
+            .snapshot(None)
+            .iter()
+            .map(|series| series["tags"]["rack"].as_str().unwrap().parse().unwrap())
+            .collect();
+        racks.sort_unstable();
+        racks
+    }
+
+    #[test]
+    fn an_explosion_of_series_keeps_the_most_recent_ones() {
+        let latest = LatestValues::new(Duration::from_secs(60), MAX_SERIES);
+
+        for batch in (0..20_000).collect::<Vec<usize>>().chunks(100) {
+            let points: Vec<MyDataPoint> = batch.iter().map(|&rack| reading(rack)).collect();
+            latest.update(&points);
+        }
+
+        assert_eq!(racks(&latest), (19_000..20_000).collect::<Vec<_>>());
+        let stats = latest.stats();
+        assert_eq!(stats["used"], MAX_SERIES);
+        assert_eq!(stats["hits"], 19_000);
+        assert_eq!(stats["reached"], true);
+    }
+
+    #[test]
+    fn the_series_updated_least_recently_is_evicted_first() {
+        let latest = LatestValues::new(Duration::from_secs(60), MAX_SERIES);
+        let points: Vec<MyDataPoint> = (0..MAX_SERIES).map(reading).collect();
+        latest.update(&points);
+
+        // The oldest series is updated again, the next oldest makes room
+        latest.update(&[reading(0)]);
+        latest.update(&[reading(MAX_SERIES)]);
+
+        let racks = racks(&latest);
+        assert_eq!(racks.len(), MAX_SERIES);
+        assert!(racks.contains(&0));
+        assert!(!racks.contains(&1));
+        assert!(racks.contains(&MAX_SERIES));
+    }
+}
@@ -0,0 +1,1587 @@
+// lib.rs
+//
+// The Aero Sensor Flow broker as a library: the modules reading the devices, parsing and
+// aggregating their frames, and writing the points to the sinks, and `run`, which wires them
+// into the running broker until it is shut down. The binary only parses the command line,
+// loads the settings, and calls `run`; integration tests build pipelines from the same modules
+// with their own sources and sinks.
+
+mod access_log;
+pub mod arduino;
+pub mod broker_stats;
+pub mod buffered_fan_out;
+pub mod build_info;
+pub mod cache;
+mod cap;
+pub mod clock;
+pub mod clock_skew;
+mod coalesce;
+pub mod config;
+pub mod crash;
+pub mod data_manipulation;
+pub mod dead_letter;
+mod device_session;
+pub mod errors;
+mod file_sink;
+pub mod frame_log;
+pub mod freshness;
+mod health_cache;
+mod heartbeat;
+pub mod influxdb;
+mod ingest_limit;
+mod inject;
+mod latest;
+pub mod line_protocol;
+mod live;
+pub mod liveness;
+pub mod logging;
+mod measurements;
+pub mod metrics;
+mod mqtt;
+pub mod pacing;
+pub mod pause;
+mod rate_limit;
+mod raw;
+mod read_now;
+mod recorder;
+pub mod reload;
+pub mod replay;
+pub mod routes;
+mod run_state;
+pub mod selftest;
+mod sensors;
+pub mod sequence;
+pub mod shutdown;
+mod simulator;
+pub mod sink;
+pub mod source;
+pub mod startup;
+mod stats;
+mod supervisor;
+pub mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tls;
+mod window_clock;
+
+use access_log::AccessLog;
+use broker_stats::BrokerStats;
+use buffered_fan_out::BufferedFanOutSink;
+use build_info::BuildInfo;
+use cache::Cache;
+use clock::Clock;
+use clock_skew::ClockSkewCorrector;
+use config::{ConfigSettings, SourceKind, TimeJumpAction};
+use data_manipulation::{
+    parse_sensor_data, Aggregator, StaticFields, TIMESTAMP_SOURCE_DEVICE, TIMESTAMP_SOURCE_HOST,
+    TIMESTAMP_SOURCE_TAG,
+};
+use dead_letter::DeadLetterWriter;
+use errors::AppError;
+use file_sink::FileSink;
+use futures::future::join_all;
+use futures::FutureExt;
+use heartbeat::Heartbeat;
+use influxdb::InfluxDBManager;
+use influxdb2::models::DataPoint;
+use ingest_limit::{CoarseClock, IngestLimiter};
+use inject::Injector;
+use latest::LatestValues;
+use live::LiveFeed;
+use liveness::Liveness;
+use measurements::MeasurementControl;
+use metrics::Metrics;
+use mqtt::MqttSink;
+use pause::IngestionControl;
+use rate_limit::RateLimitedSink;
+use raw::{RawSampler, RawTier};
+use recorder::Recorder;
+use reload::{Reloader, Tunables};
+use replay::{CountingSink, ReplaySummary, Replayer};
+use routes::{
+    cors, create_config_route, create_dead_letter_routes, create_device_session_route,
+    create_health_route, create_history_route, create_inject_route, create_latest_route,
+    create_latest_values_route, create_log_level_route, create_measurement_routes,
+    create_metrics_route, create_pause_routes, create_ports_route, create_raw_frames_route,
+    create_read_now_route, create_reload_routes, create_sensors_route, create_stats_route,
+    create_stream_route, create_version_route, handle_rejection, with_auth, HealthPolicy,
+};
+use run_state::RunStateFile;
+use sensors::SensorRegistry;
+use sequence::SequenceTracker;
+use shutdown::ShutdownCoordinator;
+use sink::{DataSink, DryRunSink, FanOutSink};
+use source::{SensorSource, Source};
+use startup::Startup;
+use supervisor::Supervisor;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use warp::{Filter, Reply};
+use window_clock::WindowClock;
+
+use log::{debug, error, info, warn};
+
+// How long each shutdown stage may take before it is abandoned and the next one starts. The
+// HTTP stage is the drain period of the requests still in flight.
+const STOP_READING_TIMEOUT: Duration = Duration::from_secs(5);
+const CLOSE_WINDOWS_TIMEOUT: Duration = Duration::from_secs(5);
+const FINAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+const HTTP_DRAIN_PERIOD: Duration = Duration::from_secs(5);
+const CLOSE_SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
+const STOP_RECORDING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Name of the InfluxDB validation among the components brought up at startup, which is also
+// the name of the InfluxDB sink.
+const INFLUXDB_COMPONENT: &str = "influxdb";
+
+// Longest delay between two attempts to reopen a failing device.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// How often a read loop waiting for a frame checks whether its window is over; the clock of the
+// source may be moved by other means than time passing.
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Stand-ins for components the settings describe, e.g. the mocks of the integration tests.
+#[derive(Default)]
+pub struct Overrides {
+    // Devices read by the sources of these names instead of their own.
+    pub devices: BTreeMap<String, Arc<dyn SensorSource>>,
+    // Sinks the aggregated points are written to instead of those of `sinks`.
+    pub sinks: Option<Vec<Arc<dyn DataSink>>>,
+    // Clock of every source instead of the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+// Runs the broker until `shutdown` is cancelled, or until a source or a background task fails
+// for good, then shuts it down in order. Errors are logged where they happen; the one returned
+// tells whether the configuration or the run failed.
+pub async fn run(
+    settings: ConfigSettings,
+    reloader: Reloader,
+    shutdown: CancellationToken,
+) -> Result<(), AppError> {
+    run_with(settings, reloader, Overrides::default(), shutdown).await
+}
+
+// `run`, with some of the components replaced by `overrides`.
+pub async fn run_with(
+    settings: ConfigSettings,
+    reloader: Reloader,
+    mut overrides: Overrides,
+    shutdown: CancellationToken,
+) -> Result<(), AppError> {
+    let build_info = BuildInfo::current();
+    info!(
+        "aero-sensor-broker {} (commit {}, built {}, {})",
+        build_info.version, build_info.commit, build_info.built_at, build_info.rustc
+    );
+
+    // Pipeline metrics shared by every component
+    let metrics = Arc::new(Metrics::default());
+
+    // The clock every source reads and every point not read from a source is stamped with
+    let clock = overrides.clock.clone().unwrap_or_else(clock::system);
+
+    // Create the device of every source, tagging its points with the global tags, its own tags,
+    // and its name. The devices are opened in the background once the HTTP server is listening.
+    let tags = global_tags(&settings, &build_info);
+    let mut sources = Vec::new();
+    for config in &settings.sources {
+        let source = match overrides.devices.remove(&config.name) {
+            Some(device) => Source::with_device(config, &tags, device),
+            None => Source::new(config, &tags, metrics.clone(), settings.http.recent_frames),
+        };
+        let source = source.with_clock(clock.clone());
+        let tag_list: Vec<String> = source
+            .tags()
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        info!(
+            "Source {} tags points with {}",
+            source.name(),
+            tag_list.join(", ")
+        );
+        sources.push(source);
+    }
+    let sources = Arc::new(sources);
+
+    // Components brought up in the background, which the probes report as starting until they
+    // are up
+    let startup = Startup::default();
+    for source in sources.iter() {
+        startup.register(source.name());
+    }
+
+    // Initialize Cache
+    let cache = Cache::new(settings.cache.max_size, metrics.clone())
+        .with_clock(clock.clone())
+        .with_coalesce(settings.cache.coalesce)
+        .with_flush_seq(settings.cache.flush_seq)
+        .with_pacing(settings.cache.pacing.as_ref());
+
+    // The raw samples, if enabled, are cached apart from the averages; the length and evictions
+    // of their cache are not mixed with those the metrics report
+    let static_fields = StaticFields::new(&settings.fields);
+    let raw = settings.raw.enabled.then(|| {
+        let raw_cache = Cache::new(settings.cache.max_size, Arc::new(Metrics::default()));
+        RawTier::new(&settings.raw, raw_cache, static_fields.clone())
+    });
+
+    // Most recent value of every series, served by `/api/latest`
+    let latest = LatestValues::new(
+        Duration::from_secs(settings.http.latest_stale_secs),
+        settings.http.latest_max_series,
+    );
+
+    // Units and names of the measurements, served with the latest values
+    let sensors = SensorRegistry::new(&settings.sensors, settings.unit_tag);
+
+    // Parsed readings streamed to `/api/stream` subscribers
+    let live = LiveFeed::new(
+        settings.http.stream_buffer,
+        settings.http.max_stream_subscribers,
+    );
+
+    // Lets the admin routes pause ingestion during sensor maintenance
+    let control = IngestionControl::default();
+
+    // Lets the admin routes stop recording single measurements
+    let measurements = MeasurementControl::new(
+        &settings.disabled_measurements,
+        settings.http.max_measurements,
+    );
+
+    // Setup InfluxDBManager with settings from the config
+    let influxdb_manager = InfluxDBManager::new(&settings.influxdb, metrics.clone())
+        .inspect_err(|e| error!("Failed to initialize InfluxDBManager: {}", e))?;
+    let validate_influxdb = !settings.dry_run
+        && overrides.sinks.is_none()
+        && settings.sinks.iter().any(|sink| sink == INFLUXDB_COMPONENT);
+    if validate_influxdb {
+        startup.register(INFLUXDB_COMPONENT);
+    }
+
+    // Setup the sinks aggregated points are written to
+    let mut sinks = match overrides.sinks.take() {
+        Some(sinks) => sinks,
+        None => build_sinks(&settings, &influxdb_manager, &metrics)
+            .inspect_err(|e| error!("Failed to initialize sinks: {}", e))?,
+    };
+
+    // The raw samples are written to their bucket with the retries of the averages
+    let raw_influxdb = raw
+        .is_some()
+        .then(|| influxdb_manager.for_bucket(&settings.raw.bucket));
+    let raw_sink = raw_influxdb.clone().map(|manager| -> Arc<dyn DataSink> {
+        match settings.dry_run {
+            true => Arc::new(DryRunSink),
+            false => Arc::new(manager),
+        }
+    });
+
+    // Setup the dead-letter writer for batches InfluxDB permanently rejects, if configured
+    let dead_letter = settings
+        .dead_letter
+        .as_ref()
+        .map(|config| {
+            DeadLetterWriter::new(config)
+                .map(|writer| writer.with_target(&settings.influxdb))
+                .map_err(|e| {
+                    error!("Failed to initialize dead-letter directory: {}", e);
+                    AppError::Runtime(format!("cannot create {}: {}", config.directory, e))
+                })
+        })
+        .transpose()?;
+
+    // Several sinks are written independently, each from a queue of its own
+    let sink: Arc<dyn DataSink> = match sinks.len() {
+        1 => sinks.remove(0),
+        _ => Arc::new(BufferedFanOutSink::start(
+            sinks,
+            settings.cache.max_size,
+            metrics.clone(),
+            reloader.flush_interval(),
+            dead_letter.clone(),
+        )),
+    };
+
+    // Record the raw lines of every source, if configured
+    let recorder = settings
+        .record
+        .as_ref()
+        .and_then(|config| Recorder::start(config, &sources, shutdown.clone()));
+
+    // Reloadable settings are re-read on SIGHUP
+    reload::reload_on_sighup(reloader.clone());
+
+    // Background tasks are restarted when they fail, or shut the broker down when they cannot
+    // be
+    let liveness = Liveness::default();
+    let supervisor = Supervisor::new(liveness.clone(), shutdown.clone());
+
+    // Initialize the HTTP server for health checks, stats, and dead-letter administration,
+    // unless the broker runs headless
+    let mut http_server = None;
+    let stop_http = CancellationToken::new();
+    if settings.http.enabled {
+        let invalid_http = |e: String| {
+            error!("Invalid HTTP configuration: {}", e);
+            AppError::Config(e)
+        };
+        let http_addr = settings.http.socket_addr().map_err(invalid_http)?;
+        let auth_token = settings.http.auth_token().map_err(invalid_http)?;
+        if auth_token.is_none() {
+            warn!("No http.auth_token configured, the admin routes are unauthenticated");
+        }
+
+        let health_route = create_health_route(
+            sources.clone(),
+            sink.clone(),
+            cache.clone(),
+            control.clone(),
+            liveness.clone(),
+            startup.clone(),
+            build_info.clone(),
+            HealthPolicy::new(
+                &settings.health,
+                Duration::from_millis(settings.http.health_check_timeout_ms),
+            ),
+        );
+        let stats_route = create_stats_route(
+            influxdb_manager.clone(),
+            sink.clone(),
+            sources.clone(),
+            live.clone(),
+            latest.clone(),
+            sensors.clone(),
+            measurements.clone(),
+        );
+        let latest_route = create_latest_route(influxdb_manager.clone());
+        let metrics_route = create_metrics_route(metrics.clone());
+        let latest_values_route = create_latest_values_route(latest.clone(), sensors.clone());
+        let sensors_route = create_sensors_route(sensors.clone());
+        let stream_route = create_stream_route(live.clone());
+        let pause_routes = create_pause_routes(control.clone());
+        let measurement_routes = create_measurement_routes(measurements.clone());
+        let log_level_route = create_log_level_route();
+        let read_now_route = create_read_now_route(
+            sources.clone(),
+            control.clone(),
+            Duration::from_secs(settings.http.read_now_timeout_secs),
+        );
+        let raw_frames_route = create_raw_frames_route(sources.clone());
+        let ports_route = create_ports_route(
+            settings
+                .sources
+                .iter()
+                .filter(|source| source.kind == SourceKind::Serial)
+                .map(|source| (source.name.clone(), source.serial.device_name.clone()))
+                .collect(),
+        );
+        let inject_route = create_inject_route(Injector::new(
+            cache.clone(),
+            tags.clone(),
+            &settings.http.inject_tag,
+        ));
+        let config_route = create_config_route(reloader.running());
+        let reload_routes = create_reload_routes(reloader.clone());
+        let version_route =
+            create_version_route(build_info.clone(), settings.active_profile.clone());
+        let device_session_route = create_device_session_route(
+            sources.clone(),
+            Duration::from_secs(settings.http.device_session_idle_secs),
+        );
+        let history_route = create_history_route(
+            influxdb_manager.clone(),
+            settings.location().to_string(),
+            Duration::from_secs(settings.http.history_max_span_secs),
+        );
+        let dead_letter_routes = create_dead_letter_routes(
+            dead_letter.clone(),
+            influxdb_manager.clone(),
+            settings.influxdb.bucket.clone(),
+        );
+        // Everything but the probes requires the bearer token, when one is configured
+        let protected_routes = stats_route
+            .or(metrics_route)
+            .or(latest_route)
+            .or(latest_values_route)
+            .or(sensors_route)
+            .or(stream_route)
+            .or(pause_routes)
+            .or(measurement_routes)
+            .or(log_level_route)
+            .or(read_now_route)
+            .or(raw_frames_route)
+            .or(ports_route)
+            .or(inject_route)
+            .or(config_route)
+            .or(reload_routes)
+            .or(version_route)
+            .or(device_session_route)
+            .or(history_route)
+            .or(dead_letter_routes);
+        let access_log = AccessLog {
+            enabled: settings.http.access_log,
+            log_probes: settings.http.access_log_probes,
+        };
+        let cors = settings
+            .http
+            .cors
+            .as_ref()
+            .map(|config| cors(config, auth_token.is_some()))
+            .transpose()
+            .map_err(invalid_http)?;
+        let api = health_route.or(with_auth(auth_token).and(protected_routes));
+        // Preflight requests are answered by the CORS filter without reaching the handlers
+        let api = match cors {
+            Some(cors) => api.with(cors).map(Reply::into_response).boxed(),
+            None => api.map(Reply::into_response).boxed(),
+        };
+        // Each request runs in a span, the parent of what its handler traces
+        let routes = access_log
+            .wrap(api.recover(handle_rejection))
+            .with(warp::trace::request());
+
+        // The server stops after the final flush, so that the probes keep answering until then
+        let stopped = stop_http.clone().cancelled_owned();
+        let bound = match &settings.http.tls {
+            Some(config) => {
+                let presented =
+                    tls::check(config).map_err(|e| invalid_http(format!("http.tls: {}", e)))?;
+                info!("HTTP server serving TLS with {}", presented);
+                tokio::spawn(tls::watch(config.clone(), shutdown.clone()));
+                let server = warp::serve(routes)
+                    .tls()
+                    .cert_path(&config.cert_path)
+                    .key_path(&config.key_path);
+                let server = match &config.client_ca_path {
+                    Some(path) => server.client_auth_required_path(path),
+                    None => server,
+                };
+                server
+                    .try_bind_with_graceful_shutdown(http_addr, stopped)
+                    .map(|(addr, server)| (addr, server.boxed()))
+            }
+            None => warp::serve(routes)
+                .try_bind_with_graceful_shutdown(http_addr, stopped)
+                .map(|(addr, server)| (addr, server.boxed())),
+        };
+        let (bound_addr, server) = bound.map_err(|e| {
+            error!("Failed to start the HTTP server on {}: {}", http_addr, e);
+            AppError::Runtime(format!("cannot listen on {}: {}", http_addr, e))
+        })?;
+        info!("HTTP server listening on {}", bound_addr);
+
+        // The listener cannot be bound again, so the server is not restarted
+        http_server = Some(supervisor.spawn_critical("http_server", server));
+    } else {
+        info!("HTTP server disabled, running headless");
+    }
+
+    // Bring InfluxDB up in the background now that the probes answer, the devices are opened by
+    // their read loops. The broker gives up when they are not all up by the deadline, and
+    // InfluxDB, when it is required at startup, within its grace period.
+    if validate_influxdb {
+        tokio::spawn(bring_up_influxdb(
+            [Some(influxdb_manager), raw_influxdb]
+                .into_iter()
+                .flatten()
+                .collect(),
+            // The averages go to the other sinks meanwhile, if there are any
+            [
+                (settings.sinks.len() == 1).then(|| cache.clone()),
+                raw.as_ref().map(|raw| raw.cache().clone()),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            Duration::from_secs(settings.influxdb.startup_grace_secs),
+            settings.influxdb.require_at_startup,
+            startup.clone(),
+            shutdown.clone(),
+        ));
+    }
+    let startup_deadline = match settings.startup_deadline_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let startup_task = tokio::spawn(await_startup(
+        startup.clone(),
+        startup_deadline,
+        shutdown.clone(),
+    ));
+
+    // Tell the downtime since the previous run, then keep the state of this one
+    let run_state = RunStateFile::new(
+        &settings.state_file,
+        metrics.clone(),
+        cache.clone(),
+        sources.clone(),
+        measurements.clone(),
+    );
+    let run_state = Arc::new(run_state.with_clock(clock.clone()));
+    if let Some(previous) = run_state.previous() {
+        if !previous.disabled_measurements.is_empty() {
+            let names: Vec<&str> = previous
+                .disabled_measurements
+                .keys()
+                .map(String::as_str)
+                .collect();
+            info!(
+                "Measurements still disabled since the previous run: {}",
+                names.join(", ")
+            );
+            measurements.restore(previous.disabled_measurements.clone());
+        }
+        match previous.restart_point(clock.now_utc(), &tags) {
+            Ok(point) => cache.add(vec![point]).await,
+            Err(e) => warn!("Failed to build the restart point: {}", e),
+        }
+    }
+    tokio::spawn(run_state.clone().keep(shutdown.clone()));
+    tokio::spawn(sensors.clone().warn_unmatched(shutdown.clone()));
+
+    // Write the panics of this run, and the crash of the previous one, to the sinks
+    tokio::spawn(crash::write_crash_points(
+        sink.clone(),
+        tags.clone(),
+        shutdown.clone(),
+    ));
+
+    // Watch for sources that stopped parsing frames, if enabled
+    if settings.health.stale_after_secs > 0 {
+        supervisor.spawn_restartable("freshness_watchdog", {
+            let sources = sources.clone();
+            let cache = cache.clone();
+            let stale_after = Duration::from_secs(settings.health.stale_after_secs);
+            let shutdown = shutdown.clone();
+            move || {
+                freshness::watch_freshness(
+                    sources.clone(),
+                    cache.clone(),
+                    stale_after,
+                    shutdown.clone(),
+                )
+            }
+        });
+    }
+
+    // Spawn a task for periodic cache flush to the sink, along with the heartbeat of every
+    // source and the counters of the broker if enabled, restarted if it panics
+    let flush_interval = reloader.flush_interval();
+    let heartbeat = settings
+        .cache
+        .heartbeat
+        .then(|| Arc::new(Heartbeat::new(sources.clone()).with_clock(clock.clone())));
+    let broker_stats = settings.cache.broker_stats.then(|| {
+        let stats = BrokerStats::new(metrics.clone(), tags.clone());
+        Arc::new(stats.with_clock(clock.clone()))
+    });
+    let flush_task = spawn_flush(
+        &supervisor,
+        "flush_task",
+        cache.clone(),
+        sink.clone(),
+        flush_interval.clone(),
+        dead_letter.clone(),
+        heartbeat,
+        broker_stats,
+        shutdown.clone(),
+    );
+    // The raw samples are flushed alongside, by a task of their own
+    let raw_flush_task = raw.as_ref().zip(raw_sink.clone()).map(|(raw, raw_sink)| {
+        spawn_flush(
+            &supervisor,
+            "raw_flush_task",
+            raw.cache().clone(),
+            raw_sink,
+            flush_interval,
+            dead_letter
+                .as_ref()
+                .map(|writer| writer.for_bucket(&settings.raw.bucket)),
+            None,
+            None,
+            shutdown.clone(),
+        )
+    });
+
+    // The ingest limits of the sources go by a clock of their own, started only when needed
+    let ingest_clock = sources
+        .iter()
+        .any(|source| source.ingest_limit().is_some())
+        .then(|| CoarseClock::start(shutdown.clone()));
+
+    // Process data from every source and write to Cache in a loop, until shutdown. A source
+    // that fails for good shuts the broker down. The aggregator of each source outlives its
+    // loop, so that the window open when it stopped can still be closed, even when the loop
+    // did not stop in time.
+    let aggregation = reloader.tunables().borrow().aggregation.clone();
+    let aggregators: Vec<Mutex<Aggregator>> = sources
+        .iter()
+        .map(|source| {
+            let aggregator = Aggregator::new(&aggregation)
+                .with_clock(source.clock().clone())
+                .with_static_fields(static_fields.clone());
+            Mutex::new(aggregator)
+        })
+        .collect();
+    let read_loops = join_all(
+        sources
+            .iter()
+            .zip(&aggregators)
+            .map(|(source, aggregator)| {
+                let alive = liveness.track(format!("read_loop.{}", source.name()));
+                let (cache, tunables) = (cache.clone(), reloader.tunables());
+                let sampler = raw.as_ref().map(RawTier::sampler);
+                let (latest, live, sensors) = (&latest, &live, &sensors);
+                let (control, measurements) = (&control, &measurements);
+                let (ingest_clock, dead_letter) = (ingest_clock.as_ref(), dead_letter.as_ref());
+                let (metrics, startup, shutdown) = (&metrics, &startup, &shutdown);
+                async move {
+                    let _alive = alive;
+                    let result = run_serial_to_influx_loop(
+                        source,
+                        aggregator,
+                        cache,
+                        latest,
+                        live,
+                        sensors,
+                        control,
+                        measurements,
+                        tunables,
+                        sampler,
+                        ingest_clock,
+                        dead_letter,
+                        metrics,
+                        startup,
+                        shutdown,
+                    )
+                    .await;
+                    match &result {
+                        Ok(_) => {}
+                        Err(e @ AppError::Parse { .. }) => error!(
+                            "Source {} keeps sending frames that cannot be parsed, giving up: {}",
+                            source.name(),
+                            e
+                        ),
+                        Err(e) if e.is_retryable() => {
+                            error!("Source {} keeps failing, giving up: {}", source.name(), e);
+                        }
+                        Err(e) => error!(
+                            "Error in serial to InfluxDB loop of source {}: {}",
+                            source.name(),
+                            e
+                        ),
+                    }
+                    shutdown.cancel();
+                    result
+                }
+            }),
+    );
+    tokio::pin!(read_loops);
+    let stopped = tokio::select! {
+        results = &mut read_loops => Some(results),
+        _ = shutdown.cancelled() => None,
+    };
+
+    // Tear the pipeline down in order, so that everything read so far reaches the sink
+    let mut coordinator = ShutdownCoordinator::default();
+    let results = match stopped {
+        Some(results) => Some(results),
+        None => {
+            let read_loops = async { Ok(read_loops.await) };
+            coordinator
+                .stage("stop_reading", STOP_READING_TIMEOUT, read_loops)
+                .await
+        }
+    };
+    // A failed source fails the run, so that the broker gets restarted rather than idling. Its
+    // open window is closed all the same, as are those of the loops that did not stop in time.
+    let source_error = results
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(Result::err);
+
+    let close_windows = async {
+        for (source, aggregator) in sources.iter().zip(&aggregators) {
+            let window_points =
+                close_current_window(&mut lock(aggregator), source, dead_letter.as_ref());
+            cache.add(window_points).await;
+        }
+        Ok(())
+    };
+    coordinator
+        .stage("close_windows", CLOSE_WINDOWS_TIMEOUT, close_windows)
+        .await;
+
+    // The periodic flush stops first, so that the final flush does not race it. The raw samples
+    // are flushed meanwhile.
+    let final_raw_flush = async {
+        let (Some(raw), Some(raw_sink), Some(raw_flush_task)) = (&raw, &raw_sink, raw_flush_task)
+        else {
+            return Ok(());
+        };
+        let _ = raw_flush_task.await;
+        raw.cache()
+            .shutdown(raw_sink.as_ref(), FINAL_FLUSH_TIMEOUT, dead_letter.as_ref())
+            .await
+            .map_err(|e| format!("raw samples: {}", e))
+    };
+    let final_flush = async {
+        let _ = flush_task.await;
+        let flushed = cache
+            .shutdown(sink.as_ref(), FINAL_FLUSH_TIMEOUT, dead_letter.as_ref())
+            .await;
+        let closed = sink.close().await.map_err(|e| e.to_string());
+        flushed.and(closed)
+    };
+    let final_flush = async {
+        let (flushed, raw_flushed) = tokio::join!(final_flush, final_raw_flush);
+        flushed.and(raw_flushed)
+    };
+    coordinator
+        .stage("flush_cache", FINAL_FLUSH_TIMEOUT, final_flush)
+        .await;
+
+    // The state is clean only once everything read was flushed
+    if !coordinator.failed() {
+        run_state.save(true);
+    }
+
+    if let Some(server) = http_server {
+        stop_http.cancel();
+        let stop_server = async {
+            server
+                .await
+                .map_err(|e| format!("HTTP server task failed: {}", e))
+        };
+        coordinator
+            .stage("stop_http", HTTP_DRAIN_PERIOD, stop_server)
+            .await;
+    }
+
+    let close_serial = async {
+        let closed = join_all(sources.iter().map(|source| source.device().shutdown())).await;
+        let failures: Vec<String> = sources
+            .iter()
+            .zip(closed)
+            .filter_map(|(source, result)| {
+                result
+                    .err()
+                    .map(|e| format!("source {}: {}", source.name(), e))
+            })
+            .collect();
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(failures.join("; ")),
+        }
+    };
+    coordinator
+        .stage("close_serial", CLOSE_SERIAL_TIMEOUT, close_serial)
+        .await;
+
+    if let Some(recorder) = recorder {
+        coordinator
+            .stage(
+                "stop_recording",
+                STOP_RECORDING_TIMEOUT,
+                recorder.shutdown(),
+            )
+            .await;
+    }
+
+    if let Ok(Err(e)) = startup_task.await {
+        return Err(e);
+    }
+    if let Some(e) = source_error {
+        return Err(e);
+    }
+    if supervisor.failed() {
+        return Err(AppError::Runtime(
+            "a background task failed for good".to_string(),
+        ));
+    }
+    if coordinator.failed() {
+        return Err(AppError::Runtime(
+            "the shutdown did not complete".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// The tags of every point: `[tags]`, then the build and profile tags when enabled.
+fn global_tags(settings: &ConfigSettings, build_info: &BuildInfo) -> BTreeMap<String, String> {
+    let mut tags = settings.tags.clone();
+    if settings.build_tags {
+        tags.extend(build_info.tags());
+    }
+    if let (true, Some(profile)) = (settings.profile_tag, &settings.active_profile) {
+        tags.insert("profile".to_string(), profile.clone());
+    }
+    tags
+}
+
+// Feeds the recording through the pipeline of a source as if its device had sent the lines,
+// without the HTTP server, then flushes everything. Stops early when `shutdown` is cancelled,
+// which also happens at the end of the recording. Errors are logged where they happen.
+pub async fn replay(
+    settings: ConfigSettings,
+    reloader: Reloader,
+    recording: &Path,
+    source_name: Option<&str>,
+    speed: f64,
+    shutdown: CancellationToken,
+) -> Result<ReplaySummary, AppError> {
+    let config = match source_name {
+        Some(name) => settings.sources.iter().find(|source| source.name == name),
+        None => settings.sources.first(),
+    };
+    let Some(config) = config else {
+        let e = format!("no source named {}", source_name.unwrap_or_default());
+        error!("Failed to replay: {}", e);
+        return Err(AppError::Config(e));
+    };
+    if !speed.is_finite() || speed < 0.0 {
+        let e = format!("the speed must be 0 or a positive factor, not {}", speed);
+        error!("Failed to replay: {}", e);
+        return Err(AppError::Config(e));
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let influxdb_manager = InfluxDBManager::new(&settings.influxdb, metrics.clone())
+        .inspect_err(|e| error!("Failed to initialize InfluxDBManager: {}", e))?;
+    let sink = build_sink(&settings, &influxdb_manager, &metrics)
+        .inspect_err(|e| error!("Failed to initialize sinks: {}", e))?;
+    let sink = Arc::new(CountingSink::new(sink));
+
+    let replayer = Replayer::open(
+        recording,
+        &config.name,
+        speed,
+        shutdown.clone(),
+        metrics.clone(),
+    )
+    .inspect_err(|e| error!("Failed to replay: {}", e))?;
+    let replayer = Arc::new(replayer);
+    // Every line of the recording is replayed, however many are rejected in a row
+    let mut config = config.clone();
+    config.serial.max_consecutive_errors = 0;
+    let tags = global_tags(&settings, &BuildInfo::current());
+    let source = Source::with_device(&config, &tags, replayer.clone());
+
+    let dead_letter = settings.dead_letter.as_ref().and_then(|config| {
+        DeadLetterWriter::new(config)
+            .map(|writer| writer.with_target(&settings.influxdb))
+            .inspect_err(|e| error!("Failed to initialize dead-letter directory: {}", e))
+            .ok()
+    });
+
+    // Points are only written once the whole recording was replayed, or on SIGINT/SIGTERM
+    let cache = Cache::new(settings.cache.max_size, metrics.clone())
+        .with_coalesce(settings.cache.coalesce)
+        .with_flush_seq(settings.cache.flush_seq)
+        .with_pacing(settings.cache.pacing.as_ref());
+    let aggregator = Aggregator::new(&settings.aggregation)
+        .with_clock(source.clock().clone())
+        .with_static_fields(StaticFields::new(&settings.fields));
+    let aggregator = Mutex::new(aggregator);
+    let read_loop = run_serial_to_influx_loop(
+        &source,
+        &aggregator,
+        cache.clone(),
+        &LatestValues::new(
+            Duration::from_secs(settings.http.latest_stale_secs),
+            settings.http.latest_max_series,
+        ),
+        &LiveFeed::default(),
+        &SensorRegistry::new(&settings.sensors, settings.unit_tag),
+        &IngestionControl::default(),
+        &MeasurementControl::new(
+            &settings.disabled_measurements,
+            settings.http.max_measurements,
+        ),
+        reloader.tunables(),
+        None,
+        None,
+        dead_letter.as_ref(),
+        &metrics,
+        &Startup::default(),
+        &shutdown,
+    )
+    .await;
+    let mut failed = false;
+    if let Err(e) = read_loop {
+        error!("Replay stopped: {}", e);
+        failed = true;
+    }
+    let window_points = close_current_window(&mut lock(&aggregator), &source, dead_letter.as_ref());
+    cache.add(window_points).await;
+    let flush = cache.shutdown(sink.as_ref(), FINAL_FLUSH_TIMEOUT, dead_letter.as_ref());
+    if let Err(e) = flush.await {
+        error!("Failed to write the replayed points: {}", e);
+        failed = true;
+    }
+
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+    Ok(ReplaySummary {
+        recording: recording.to_path_buf(),
+        frames_read: load(&metrics.frames_received),
+        frames_invalid: load(&metrics.frames_invalid),
+        frames_rejected: load(&metrics.frames_rejected),
+        points_parsed: load(&metrics.points_parsed),
+        points_written: sink.written(),
+        unreadable_lines: replayer.errors(),
+        failed,
+    })
+}
+
+// Flushes the cache to the sink periodically, restarted if it panics.
+#[allow(clippy::too_many_arguments)]
+fn spawn_flush(
+    supervisor: &Supervisor,
+    name: &'static str,
+    cache: Cache,
+    sink: Arc<dyn DataSink>,
+    flush_interval: watch::Receiver<Duration>,
+    dead_letter: Option<DeadLetterWriter>,
+    heartbeat: Option<Arc<Heartbeat>>,
+    broker_stats: Option<Arc<BrokerStats>>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    supervisor.spawn_restartable(name, move || {
+        let cache_to_flush = cache.clone();
+        let sink_to_flush = sink.clone();
+        let flush_interval = flush_interval.clone();
+        let dead_letter = dead_letter.clone();
+        let heartbeat = heartbeat.clone();
+        let broker_stats = broker_stats.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            cache_to_flush
+                .periodic_flush(
+                    sink_to_flush,
+                    flush_interval,
+                    dead_letter,
+                    heartbeat,
+                    broker_stats,
+                    shutdown,
+                )
+                .await;
+        }
+    })
+}
+
+// Builds the sinks listed in `sinks`, each behind the rate limit when one is configured.
+fn build_sinks(
+    settings: &ConfigSettings,
+    influxdb_manager: &InfluxDBManager,
+    metrics: &Arc<Metrics>,
+) -> Result<Vec<Arc<dyn DataSink>>, AppError> {
+    if settings.dry_run {
+        warn!("Dry-run mode: points are logged, nothing is written");
+        return Ok(vec![Arc::new(DryRunSink)]);
+    }
+
+    let mut sinks: Vec<Arc<dyn DataSink>> = Vec::new();
+    for name in &settings.sinks {
+        let sink: Arc<dyn DataSink> = match name.as_str() {
+            "influxdb" => Arc::new(influxdb_manager.clone()),
+            "mqtt" => {
+                let config = settings.mqtt.as_ref().ok_or_else(|| {
+                    AppError::Config("the mqtt sink requires an [mqtt] section".to_string())
+                })?;
+                Arc::new(MqttSink::new(config, metrics.clone())?)
+            }
+            "file" => {
+                let config = settings.file.as_ref().ok_or_else(|| {
+                    AppError::Config("the file sink requires a [file] section".to_string())
+                })?;
+                let file_sink = FileSink::new(config).map_err(|e| {
+                    AppError::Config(format!("cannot create {}: {}", config.directory, e))
+                })?;
+                Arc::new(file_sink)
+            }
+            other => return Err(AppError::Config(format!("unknown sink '{}'", other))),
+        };
+        // Each sink waits on a rate limit of its own
+        match &settings.rate_limit {
+            Some(config) => sinks.push(Arc::new(RateLimitedSink::new(sink, config))),
+            None => sinks.push(sink),
+        }
+    }
+
+    match sinks.is_empty() {
+        true => Err(AppError::Config("no sink configured".to_string())),
+        false => Ok(sinks),
+    }
+}
+
+// The configured sinks written all at once, for a replay which only writes at the end.
+fn build_sink(
+    settings: &ConfigSettings,
+    influxdb_manager: &InfluxDBManager,
+    metrics: &Arc<Metrics>,
+) -> Result<Arc<dyn DataSink>, AppError> {
+    let mut sinks = build_sinks(settings, influxdb_manager, metrics)?;
+    match sinks.len() {
+        1 => Ok(sinks.remove(0)),
+        _ => Ok(Arc::new(FanOutSink::new(sinks))),
+    }
+}
+
+// Probes the health of InfluxDB, then validates the configuration of every manager, the
+// averages' and the raw samples', retrying with backoff while InfluxDB cannot be reached, until
+// it is valid or `shutdown` is cancelled. An invalid configuration fails the startup. When
+// InfluxDB does not answer within `grace`, the startup fails too if it is `required`; otherwise
+// the broker goes on offline, holding back the flushes of `caches`, which only write to
+// InfluxDB, and they are flushed right away once InfluxDB answers.
+async fn bring_up_influxdb(
+    influxdb_managers: Vec<InfluxDBManager>,
+    caches: Vec<Cache>,
+    grace: Duration,
+    required: bool,
+    startup: Startup,
+    shutdown: CancellationToken,
+) {
+    let started = Instant::now();
+    let mut attempts = 0;
+    // When the broker went offline and the evictions of the caches by then
+    let mut offline: Option<(Instant, u64)> = None;
+    loop {
+        let validated = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => return,
+            validated = probe_and_validate(&influxdb_managers) => validated,
+        };
+        let e = match validated {
+            Ok(()) => break,
+            Err(AppError::Config(e)) => {
+                error!("Invalid InfluxDB configuration: {}", e);
+                return startup.failed(INFLUXDB_COMPONENT, e);
+            }
+            Err(e) => e.to_string(),
+        };
+        attempts += 1;
+        match (offline.is_some(), started.elapsed() < grace) {
+            (true, _) => {}
+            (false, true) => {
+                warn!(
+                    "InfluxDB not reachable at startup, attempt {}, {:.0}s of the {}s grace \
+                     period: {}",
+                    attempts,
+                    started.elapsed().as_secs_f64(),
+                    grace.as_secs(),
+                    e
+                );
+                startup.attempt_failed(INFLUXDB_COMPONENT, e);
+            }
+            (false, false) if required => {
+                error!(
+                    "InfluxDB not reachable within the {}s grace period, giving up",
+                    grace.as_secs()
+                );
+                let error = format!("not reachable within {}s: {}", grace.as_secs(), e);
+                return startup.failed(INFLUXDB_COMPONENT, error);
+            }
+            (false, false) => {
+                warn!(
+                    "InfluxDB not reachable within the {}s grace period, starting offline: \
+                     points are cached until it is reachable",
+                    grace.as_secs()
+                );
+                let evicted = caches.iter().map(Cache::evicted).sum();
+                offline = Some((Instant::now(), evicted));
+                caches.iter().for_each(|cache| cache.hold(true));
+                startup.go_offline(INFLUXDB_COMPONENT, e);
+            }
+        }
+        tokio::select! {
+            _ = sleep(reconnect_backoff(attempts)) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+
+    // The points cached while offline are written without waiting for the next flush
+    if let Some((since, evicted)) = offline {
+        let mut backlog = 0;
+        for cache in &caches {
+            backlog += cache.len().await;
+            cache.hold(false);
+            cache.flush_soon();
+        }
+        let evicted = caches.iter().map(Cache::evicted).sum::<u64>() - evicted;
+        info!(
+            "InfluxDB reachable after {:.0}s offline, flushing the {} point(s) cached meanwhile \
+             ({} evicted from the full cache)",
+            since.elapsed().as_secs_f64(),
+            backlog,
+            evicted
+        );
+    }
+    startup.ready(INFLUXDB_COMPONENT);
+}
+
+async fn probe_and_validate(influxdb_managers: &[InfluxDBManager]) -> Result<(), AppError> {
+    if let Some(influxdb_manager) = influxdb_managers.first() {
+        influxdb_manager
+            .probe_health()
+            .await
+            .map_err(AppError::InfluxUnavailable)?;
+    }
+    for influxdb_manager in influxdb_managers {
+        influxdb_manager.validate().await?;
+    }
+    Ok(())
+}
+
+// Waits for every component to come up, within the deadline when there is one, and shuts the
+// broker down when they do not.
+async fn await_startup(
+    startup: Startup,
+    deadline: Option<Duration>,
+    shutdown: CancellationToken,
+) -> Result<(), AppError> {
+    let complete = async {
+        match deadline {
+            Some(deadline) => timeout(deadline, startup.complete()).await.ok(),
+            None => Some(startup.complete().await),
+        }
+    };
+    let completed = tokio::select! {
+        completed = complete => completed,
+        _ = shutdown.cancelled() => return Ok(()),
+    };
+    let deadline_secs = deadline.unwrap_or_default().as_secs();
+    let error = match completed {
+        Some(Ok(elapsed)) => {
+            match startup.offline().as_slice() {
+                [] => info!("Startup complete in {:.1}s, ready", elapsed.as_secs_f64()),
+                offline => info!(
+                    "Startup complete in {:.1}s, ready but offline: {}",
+                    elapsed.as_secs_f64(),
+                    offline.join(", ")
+                ),
+            }
+            return Ok(());
+        }
+        // Only an invalid configuration, or InfluxDB unreachable when it is required at startup,
+        // keeps a component from coming up for good
+        Some(Err(e)) => {
+            error!("Startup failed, {}", e);
+            AppError::Config(e)
+        }
+        None => {
+            let pending = startup.pending().join(", ");
+            error!(
+                "Startup did not complete within {}s, still starting: {}",
+                deadline_secs, pending
+            );
+            AppError::Runtime(format!("{} did not come up", pending))
+        }
+    };
+    shutdown.cancel();
+    Err(error)
+}
+
+// Opens the device of the source, retrying with backoff until it is up or `shutdown` is
+// cancelled.
+async fn connect(source: &Source, startup: &Startup, shutdown: &CancellationToken) {
+    let mut attempts = 0;
+    loop {
+        let connected = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => return,
+            connected = source.device().connect() => connected,
+        };
+        let e = match connected {
+            Ok(()) => return startup.ready(source.name()),
+            Err(e) => e,
+        };
+        match e {
+            AppError::DeviceNotFound(_) => warn!(
+                "Failed to open source {}, retrying: {}; `list-ports` shows the devices found",
+                source.name(),
+                e
+            ),
+            _ => warn!("Failed to open source {}, retrying: {}", source.name(), e),
+        }
+        attempts += 1;
+        startup.attempt_failed(source.name(), e.to_string());
+        tokio::select! {
+            _ = sleep(reconnect_backoff(attempts)) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+// Delay before reopening the device after the given number of errors in a row.
+fn reconnect_backoff(consecutive_errors: u32) -> Duration {
+    Duration::from_secs(1 << consecutive_errors.saturating_sub(1).min(5)).min(RECONNECT_MAX_BACKOFF)
+}
+
+// Averages the points of the window, which is over, with the clock skew point of the source.
+fn close_window(
+    aggregator: &mut Aggregator,
+    skew: &mut ClockSkewCorrector,
+    source: &Source,
+    dead_letter: Option<&DeadLetterWriter>,
+) -> Vec<DataPoint> {
+    let mut window_points = aggregator.close_window();
+    window_points.extend(skew.window_point(source.tags()));
+    account_window(aggregator, source, dead_letter);
+    window_points
+}
+
+// Averages the points of the window a read loop left open when it stopped, although it is not
+// over yet.
+fn close_current_window(
+    aggregator: &mut Aggregator,
+    source: &Source,
+    dead_letter: Option<&DeadLetterWriter>,
+) -> Vec<DataPoint> {
+    let pending = aggregator.pending();
+    let window_points = aggregator.close_current_window();
+    if pending > 0 {
+        info!(
+            "Closed the open window of source {}: {} points averaged into {}",
+            source.name(),
+            pending,
+            window_points.len()
+        );
+    }
+    account_window(aggregator, source, dead_letter);
+    window_points
+}
+
+// Counts the series the aggregator left out of a window, and those it could not weigh by time.
+// The points whose averages do not fit their field type go to the dead-letter directory, if
+// there is one.
+fn account_window(
+    aggregator: &mut Aggregator,
+    source: &Source,
+    dead_letter: Option<&DeadLetterWriter>,
+) {
+    source
+        .device()
+        .source_metrics()
+        .points_suppressed
+        .fetch_add(aggregator.take_suppressed(), Ordering::Relaxed);
+    source
+        .device()
+        .source_metrics()
+        .count_missing(aggregator.take_missing());
+
+    let rejected = aggregator.take_rejected();
+    match dead_letter {
+        _ if rejected.is_empty() => {}
+        Some(dead_letter) => match dead_letter.write(&rejected) {
+            Ok(path) => warn!(
+                "{} points of source {} not fitting their field type saved to {}",
+                rejected.len(),
+                source.name(),
+                path.display()
+            ),
+            Err(e) => error!("Failed to write dead-letter file: {}", e),
+        },
+        None => warn!(
+            "{} points of source {} not fitting their field type dropped, no dead-letter \
+             directory is configured",
+            rejected.len(),
+            source.name()
+        ),
+    }
+}
+
+// The aggregator of a read loop, which also closes its window at shutdown. It is only locked
+// between two awaits.
+fn lock(aggregator: &Mutex<Aggregator>) -> MutexGuard<'_, Aggregator> {
+    aggregator.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Reads frames into the window of `aggregator`, applying reloaded settings as they come in,
+// until `shutdown` is cancelled. Rejected frames are skipped and read errors reopen the device;
+// only when the source keeps failing does the loop return an error. Either way, the points of
+// the window open when the loop stopped are left in `aggregator`.
+#[allow(clippy::too_many_arguments)]
+async fn run_serial_to_influx_loop(
+    source: &Source,
+    aggregator: &Mutex<Aggregator>,
+    cache: Cache,
+    latest: &LatestValues,
+    live: &LiveFeed,
+    sensors: &SensorRegistry,
+    control: &IngestionControl,
+    measurements: &MeasurementControl,
+    mut tunables: watch::Receiver<Tunables>,
+    mut raw: Option<RawSampler>,
+    ingest_clock: Option<&CoarseClock>,
+    dead_letter: Option<&DeadLetterWriter>,
+    metrics: &Metrics,
+    startup: &Startup,
+    shutdown: &CancellationToken,
+) -> Result<(), AppError> {
+    let device = source.device();
+    let source_metrics = device.source_metrics();
+    let tags = source.tags();
+    let mut settings = tunables.borrow_and_update().clone();
+    let clock = source.clock();
+    lock(aggregator).reconfigure(&settings.aggregation);
+    let mut skew =
+        ClockSkewCorrector::new(source.parser(&settings.parser)).with_clock(clock.clone());
+    let mut sequence = SequenceTracker::new();
+    let mut window = WindowClock::new(&settings.aggregation, clock.now_monotonic());
+    let mut consecutive_errors = 0;
+    let mut paused = false;
+    let mut limiter = source
+        .ingest_limit()
+        .zip(ingest_clock)
+        .map(|(config, clock)| {
+            IngestLimiter::new(source.name(), config, clock.clone(), source_metrics)
+        });
+
+    // The device is opened here rather than at startup, so that a missing one does not keep the
+    // broker from starting; the loop ends right away when the broker shuts down meanwhile
+    connect(source, startup, shutdown).await;
+
+    'reading: loop {
+        // The window is closed on time even while no frame arrives, e.g. when a loose wire
+        // silences the device, so that its series are counted as having gone quiet
+        let read = device.read_data();
+        tokio::pin!(read);
+        let read = loop {
+            let wait = window.remaining(clock.now_monotonic());
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break 'reading,
+                read = &mut read => break read,
+                _ = sleep(wait.min(WINDOW_CHECK_INTERVAL)) => {}
+            }
+            let now = clock.now_monotonic();
+            if window.is_over(now) {
+                window.restart(now);
+                // Nothing is recorded while paused but the points read before the pause, not
+                // even the series going quiet
+                if lock(aggregator).pending() > 0 || !control.is_paused() {
+                    let window_points =
+                        close_window(&mut lock(aggregator), &mut skew, source, dead_letter);
+                    cache.add(window_points).await;
+                }
+            }
+        };
+        let data = match read {
+            Ok(data) => data,
+            // A frame that is not even text is skipped like any other rejected frame
+            Err(e @ AppError::Parse { .. }) => {
+                error!("Failed to read data from source {}: {}", source.name(), e);
+                consecutive_errors += 1;
+                if source.should_give_up(consecutive_errors) {
+                    return Err(e);
+                }
+                continue;
+            }
+            Err(e) if e.is_retryable() => {
+                error!("Failed to read data from source {}: {}", source.name(), e);
+                consecutive_errors += 1;
+                if source.should_give_up(consecutive_errors) {
+                    return Err(e);
+                }
+                tokio::select! {
+                    _ = sleep(reconnect_backoff(consecutive_errors)) => {}
+                    _ = shutdown.cancelled() => break 'reading,
+                }
+                if let Err(e) = device.reconnect().await {
+                    warn!("Failed to reconnect source {}: {}", source.name(), e);
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        if tunables.has_changed().unwrap_or(false) {
+            settings = tunables.borrow_and_update().clone();
+            lock(aggregator).reconfigure(&settings.aggregation);
+            window.reconfigure(&settings.aggregation);
+            skew.reconfigure(source.parser(&settings.parser));
+        }
+
+        // A jump of the wall clock ends the window open before it, which is not averaged with
+        // the points read after
+        let (wall_ms, now) = (clock.now_utc().timestamp_millis(), clock.now_monotonic());
+        if let Some(jump_ms) = window.observe(wall_ms, now) {
+            let action = settings.aggregation.on_time_jump;
+            warn!(
+                "Wall clock jumped by {}s while reading source {}, {} the {} points of the open \
+                 window",
+                jump_ms / 1000,
+                source.name(),
+                match action {
+                    TimeJumpAction::Close => "closing",
+                    TimeJumpAction::Discard => "discarding",
+                },
+                lock(aggregator).pending()
+            );
+            if action == TimeJumpAction::Close && lock(aggregator).pending() > 0 {
+                let window_points =
+                    close_window(&mut lock(aggregator), &mut skew, source, dead_letter);
+                cache.add(window_points).await;
+            } else {
+                lock(aggregator).discard_window();
+            }
+            window.restart(now);
+        }
+
+        // Keep draining the serial port while paused, as fast as it is read, but record
+        // nothing. The window open when the pause began is closed, so that its points are not
+        // averaged with those read after the pause.
+        let was_paused = std::mem::replace(&mut paused, control.is_paused());
+        if paused {
+            if !was_paused && lock(aggregator).pending() > 0 {
+                let window_points =
+                    close_window(&mut lock(aggregator), &mut skew, source, dead_letter);
+                cache.add(window_points).await;
+            }
+            window.restart(clock.now_monotonic());
+            debug!("Ingestion paused, frame discarded.");
+            source.freshness().parsed();
+            sequence.reset();
+            continue;
+        }
+
+        // Frames beyond the ingest limit are not even parsed
+        if let Some(limiter) = &mut limiter {
+            if !limiter.admit_frame() {
+                debug!(
+                    "Ingest limit of source {} reached, frame dropped.",
+                    source.name()
+                );
+                sequence.reset();
+                continue;
+            }
+        }
+
+        let parser = source.parser(&settings.parser);
+        let protocol = device.protocol();
+        let mut new_points =
+            match parse_sensor_data(data, tags, parser, protocol, &mut skew, clock.as_ref()) {
+                Ok(points) => {
+                    // Before the points of disabled measurements are dropped, with their number
+                    sequence.observe_frame(source.name(), &points, source_metrics);
+                    match (measurements.admit(points), &mut limiter) {
+                        (points, Some(limiter)) => limiter.admit_points(points),
+                        (points, None) => points,
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to parse sensor data: {}", e);
+                    source.read_now().deliver(Err(&e));
+                    metrics.frames_rejected.fetch_add(1, Ordering::Relaxed);
+                    source_metrics
+                        .frames_rejected
+                        .fetch_add(1, Ordering::Relaxed);
+                    consecutive_errors += 1;
+                    if source.should_give_up(consecutive_errors) {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+        consecutive_errors = 0;
+        source.freshness().parsed();
+        metrics
+            .points_parsed
+            .fetch_add(new_points.len() as u64, Ordering::Relaxed);
+        source_metrics
+            .points_parsed
+            .fetch_add(new_points.len() as u64, Ordering::Relaxed);
+        for point in &new_points {
+            let counter = match point.get_tag(TIMESTAMP_SOURCE_TAG) {
+                Some(TIMESTAMP_SOURCE_DEVICE) => &source_metrics.device_timestamps,
+                Some(TIMESTAMP_SOURCE_HOST) => &source_metrics.host_timestamps,
+                _ => continue,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        sensors.observe(&mut new_points);
+        source.read_now().deliver(Ok(&new_points));
+        latest.update(&new_points);
+        live.publish(&new_points);
+        if let Some(raw) = &mut raw {
+            raw.add(&new_points).await;
+        }
+
+        lock(aggregator).push(new_points);
+
+        let now = clock.now_monotonic();
+        if window.is_over(now) {
+            window.restart(now);
+            let window_points = close_window(&mut lock(aggregator), &mut skew, source, dead_letter);
+            cache.add(window_points).await;
+        }
+
+        debug!("Data processed successfully.");
+    }
+
+    // The clock skew seen during the open window is summarized right away, its points are
+    // averaged by whoever stopped the loop
+    cache
+        .add(skew.window_point(tags).into_iter().collect())
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AggregationConfig, DeadLetterConfig, SourceConfig};
+    use crate::data_manipulation::{MyDataPoint, Reading};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn an_average_not_fitting_its_field_type_goes_to_the_dead_letters() {
+        let directory = std::env::temp_dir().join(format!(
+            "aero-sensor-broker-field-types-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        let dead_letter = DeadLetterWriter::new(&DeadLetterConfig {
+            directory: directory.display().to_string(),
+            max_total_bytes: 1024 * 1024,
+        })
+        .unwrap();
+        let config: SourceConfig =
+            serde_json::from_value(json!({"name": "bench", "kind": "simulated"})).unwrap();
+        let source = Source::new(&config, &BTreeMap::new(), Arc::default(), 0);
+        let config: AggregationConfig =
+            serde_json::from_value(json!({"field_types": {"door": "bool"}})).unwrap();
+        let mut aggregator = Aggregator::new(&config);
+        // 2023-11-14T22:13:20Z, and 20 seconds later
+        let door = |value: f64, timestamp: i64| {
+            let tags = BTreeMap::from([("source".to_string(), "bench".to_string())]);
+            MyDataPoint::from_reading("door".into(), tags, Reading::Value(value), timestamp)
+        };
+        let points = vec![
+            door(0.0, 1_700_000_000_000_000_000),
+            door(1.0, 1_700_000_020_000_000_000),
+        ];
+
+        assert!(aggregator.aggregate(points).is_empty());
+        account_window(&mut aggregator, &source, Some(&dead_letter));
+
+        let files = dead_letter.list().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            dead_letter.read(&files[0].0).unwrap().trim_end(),
+            "door,source=bench value=0.5 1700000010000000000"
+        );
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,395 @@
+// line_protocol.rs
+//
+// Helpers around the InfluxDB line protocol. `DataPoint` keeps its measurement, tags, and fields
+// private, so the parts of a point are recovered by rendering it with the influxdb2 client's own
+// serializer and parsing the resulting line back; the measurement alone is read by stopping the
+// serializer at its end. Sinks that are not InfluxDB (MQTT, files) and the bucket routing rely
+// on this to look inside the points they receive.
+//
+// `to_line` and `to_lines` serialize points ourselves, at a given timestamp precision, with the
+// escaping rules of the line protocol: commas and spaces in the measurement; commas, equals signs,
+// and spaces in tag keys, tag values, and field keys; double quotes and backslashes in string
+// field values. What the line protocol cannot carry (no field, an empty tag value, a newline
+// outside a string field, a float that is not finite) is an error rather than a corrupt line.
+
+use crate::errors::AppError;
+
+use influxdb2::models::{DataPoint, FieldValue, WriteDataPoint};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+
+// Unit of the timestamps of a line protocol payload; points carry theirs in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    // The value of the `precision` parameter of the write endpoints.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Precision::Nanoseconds => "ns",
+            Precision::Microseconds => "us",
+            Precision::Milliseconds => "ms",
+            Precision::Seconds => "s",
+        }
+    }
+
+    // Converts a timestamp in nanoseconds, rounding down.
+    pub fn from_nanos(self, nanos: i64) -> i64 {
+        let per_unit = match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        };
+        nanos.div_euclid(per_unit)
+    }
+}
+
+// The parts of a DataPoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedPoint {
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, FieldValue>,
+    pub timestamp: Option<i64>,
+}
+
+// Renders a point as a single line of line protocol, without the trailing newline.
+pub fn render(point: &DataPoint) -> String {
+    let mut line = Vec::new();
+    // Writing into a Vec cannot fail.
+    let _ = point.write_data_point_to(&mut line);
+    String::from_utf8_lossy(&line).trim_end().to_string()
+}
+
+// Serializes a point as a single line of line protocol, without the trailing newline.
+// `DataPoint` only shows its parts through the writer of the influxdb2 client, so the line it
+// writes is rewritten with our escaping in a single pass, section by section, rather than
+// decoded into a `DecodedPoint` and encoded again.
+pub fn to_line(point: &DataPoint, precision: Precision) -> Result<String, AppError> {
+    let rendered = render(point);
+    let invalid = |problem: &str| {
+        let measurement = measurement_of_line(&rendered);
+        AppError::LineProtocol(format!("measurement '{}': {}", measurement, problem))
+    };
+    let sections = split_unescaped(&rendered, &[' '], true);
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => {
+            return Err(AppError::LineProtocol(format!(
+                "cannot serialize the point '{}'",
+                rendered
+            )))
+        }
+    };
+
+    let mut line = String::with_capacity(rendered.len() + 8);
+    let mut series = split_unescaped(series, &[','], false).into_iter();
+    let measurement = series.next().unwrap_or_default();
+    if measurement.is_empty() {
+        return Err(invalid("the measurement name is empty"));
+    }
+    reescape(&mut line, measurement, &[',', ' '], false).map_err(|e| invalid(&e))?;
+    for tag in series {
+        let (key, value) = split_key_value(tag).unwrap_or((tag, ""));
+        if key.is_empty() || value.is_empty() {
+            let key = unescape(key);
+            return Err(invalid(&format!("tag '{}' has an empty key or value", key)));
+        }
+        line.push(',');
+        reescape(&mut line, key, &[',', '=', ' '], false).map_err(|e| invalid(&e))?;
+        line.push('=');
+        reescape(&mut line, value, &[',', '=', ' '], false).map_err(|e| invalid(&e))?;
+    }
+
+    for (index, field) in split_unescaped(fields, &[','], true)
+        .into_iter()
+        .enumerate()
+    {
+        let (key, value) = split_key_value(field).unwrap_or((field, ""));
+        if key.is_empty() {
+            return Err(invalid("a field has an empty key"));
+        }
+        line.push(if index == 0 { ' ' } else { ',' });
+        reescape(&mut line, key, &[',', '=', ' '], false).map_err(|e| invalid(&e))?;
+        line.push('=');
+        match value {
+            "t" => line.push_str("true"),
+            "f" => line.push_str("false"),
+            _ if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') => {
+                line.push('"');
+                // The client escapes the quotes of a string but not its backslashes
+                let _ = reescape(&mut line, &value[1..value.len() - 1], &['"'], true);
+                line.push('"');
+            }
+            _ if value.ends_with('i') => line.push_str(value),
+            _ => match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => line.push_str(value),
+                _ => {
+                    let key = unescape(key);
+                    return Err(invalid(&format!("field '{}' is not a finite number", key)));
+                }
+            },
+        }
+    }
+
+    if let Some(timestamp) = timestamp {
+        let nanos: i64 = timestamp
+            .parse()
+            .map_err(|_| invalid(&format!("invalid timestamp '{}'", timestamp)))?;
+        let _ = write!(line, " {}", precision.from_nanos(nanos));
+    }
+    Ok(line)
+}
+
+// Serializes points as a line protocol payload, one line per point, each ending with a newline.
+pub fn to_lines(points: &[DataPoint], precision: Precision) -> Result<String, AppError> {
+    let mut payload = String::new();
+    for point in points {
+        payload.push_str(&to_line(point, precision)?);
+        payload.push('\n');
+    }
+    Ok(payload)
+}
+
+// Serializes the parts of a point as a single line of line protocol.
+pub fn decoded_to_line(point: &DecodedPoint, precision: Precision) -> Result<String, AppError> {
+    let invalid = |problem: &str| {
+        AppError::LineProtocol(format!("measurement '{}': {}", point.measurement, problem))
+    };
+    if point.measurement.is_empty() {
+        return Err(invalid("the measurement name is empty"));
+    }
+    if point.fields.is_empty() {
+        return Err(invalid("a point needs at least one field"));
+    }
+
+    let mut line = escape(&point.measurement, &[',', ' ']).map_err(|e| invalid(&e))?;
+    for (key, value) in &point.tags {
+        if key.is_empty() || value.is_empty() {
+            return Err(invalid(&format!("tag '{}' has an empty key or value", key)));
+        }
+        let key = escape(key, &[',', '=', ' ']).map_err(|e| invalid(&e))?;
+        let value = escape(value, &[',', '=', ' ']).map_err(|e| invalid(&e))?;
+        let _ = write!(line, ",{}={}", key, value);
+    }
+
+    for (index, (key, value)) in point.fields.iter().enumerate() {
+        if key.is_empty() {
+            return Err(invalid("a field has an empty key"));
+        }
+        let key = escape(key, &[',', '=', ' ']).map_err(|e| invalid(&e))?;
+        line.push(if index == 0 { ' ' } else { ',' });
+        // Writing into a String cannot fail
+        let _ = match value {
+            FieldValue::F64(value) if !value.is_finite() => {
+                return Err(invalid(&format!("field '{}' is not a finite number", key)));
+            }
+            FieldValue::F64(value) => write!(line, "{}={}", key, value),
+            FieldValue::I64(value) => write!(line, "{}={}i", key, value),
+            FieldValue::Bool(value) => write!(line, "{}={}", key, value),
+            FieldValue::String(value) => {
+                let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+                write!(line, "{}=\"{}\"", key, value)
+            }
+        };
+    }
+
+    if let Some(timestamp) = point.timestamp {
+        let _ = write!(line, " {}", precision.from_nanos(timestamp));
+    }
+    Ok(line)
+}
+
+// Rewrites text as the influxdb2 client escapes it, the `special` characters but not the
+// backslashes, with our escaping of both. Newlines cannot be escaped, except in strings.
+fn reescape(
+    line: &mut String,
+    escaped: &str,
+    special: &[char],
+    newlines: bool,
+) -> Result<(), String> {
+    let mut chars = escaped.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | '\r' if !newlines => {
+                let text = unescape(escaped);
+                return Err(format!("'{}' contains a newline", text.escape_debug()));
+            }
+            '\\' if chars.peek().is_some_and(|next| special.contains(next)) => {
+                line.push('\\');
+                line.extend(chars.next());
+            }
+            '\\' => line.push_str("\\\\"),
+            c => line.push(c),
+        }
+    }
+    Ok(())
+}
+
+// Escapes `special` characters and backslashes with a backslash. Newlines cannot be escaped.
+fn escape(input: &str, special: &[char]) -> Result<String, String> {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '\n' | '\r' => return Err(format!("'{}' contains a newline", input.escape_debug())),
+            c if c == '\\' || special.contains(&c) => {
+                output.push('\\');
+                output.push(c);
+            }
+            c => output.push(c),
+        }
+    }
+    Ok(output)
+}
+
+// Extracts the measurement name of a point, rendering it only up to the end of its
+// measurement.
+pub fn measurement_of(point: &DataPoint) -> String {
+    let mut head = MeasurementWriter::default();
+    // The writer stops the rendering with an error once it holds the measurement
+    let _ = point.write_data_point_to(&mut head);
+    unescape(&String::from_utf8_lossy(&head.measurement))
+}
+
+// Keeps the escaped measurement at the start of a rendered line and refuses what follows it.
+#[derive(Default)]
+struct MeasurementWriter {
+    measurement: Vec<u8>,
+    escaped: bool,
+    complete: bool,
+}
+
+impl io::Write for MeasurementWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.complete {
+            return Err(io::Error::other("the measurement is complete"));
+        }
+        for &byte in buf {
+            match byte {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                b',' | b' ' | b'\n' => {
+                    self.complete = true;
+                    break;
+                }
+                _ => {}
+            }
+            self.measurement.push(byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Extracts the measurement name of a line, even when the rest of the line is malformed.
+pub fn measurement_of_line(line: &str) -> String {
+    let end = split_unescaped(line, &[',', ' '], false)
+        .first()
+        .map_or(0, |part| part.len());
+    unescape(&line[..end])
+}
+
+// Recovers the measurement, tags, fields, and timestamp of a point.
+pub fn decode(point: &DataPoint) -> Option<DecodedPoint> {
+    decode_line(&render(point))
+}
+
+// Parses one line of line protocol.
+pub fn decode_line(line: &str) -> Option<DecodedPoint> {
+    let sections = split_unescaped(line, &[' '], true);
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(timestamp.parse().ok()?)),
+        _ => return None,
+    };
+
+    let mut series = split_unescaped(series, &[','], false).into_iter();
+    let measurement = unescape(series.next()?);
+    let tags = series
+        .map(|tag| {
+            let (key, value) = split_key_value(tag)?;
+            Some((unescape(key), unescape(value)))
+        })
+        .collect::<Option<BTreeMap<_, _>>>()?;
+
+    let fields = split_unescaped(fields, &[','], true)
+        .into_iter()
+        .map(|field| {
+            let (key, value) = split_key_value(field)?;
+            Some((unescape(key), parse_field_value(value)?))
+        })
+        .collect::<Option<BTreeMap<_, _>>>()?;
+
+    Some(DecodedPoint {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    })
+}
+
+// Splits `input` on any of `separators`, ignoring escaped characters and, when `quotes` is set,
+// anything inside double-quoted string field values.
+fn split_unescaped<'a>(input: &'a str, separators: &[char], quotes: bool) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (index, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            c if !quoted && separators.contains(&c) => {
+                parts.push(&input[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+fn split_key_value(pair: &str) -> Option<(&str, &str)> {
+    let key = split_unescaped(pair, &['='], false).into_iter().next()?;
+    let value = pair.get(key.len() + 1..)?;
+    Some((key, value))
+}
+
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => output.extend(chars.next()),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+fn parse_field_value(value: &str) -> Option<FieldValue> {
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Some(FieldValue::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => Some(FieldValue::Bool(false)),
+        _ if value.starts_with('"') && value.ends_with('"') && value.len() >= 2 => {
+            Some(FieldValue::String(unescape(&value[1..value.len() - 1])))
+        }
+        _ if value.ends_with('i') => value[..value.len() - 1].parse().ok().map(FieldValue::I64),
+        _ => value.parse().ok().map(FieldValue::F64),
+    }
+}
@@ -0,0 +1,245 @@
+// live.rs
+//
+// Publishes every parsed (pre-aggregation) reading to the subscribers of `/api/stream`. Readings
+// go through a `tokio::sync::broadcast` channel: publishing never waits, and a subscriber that
+// falls more than `buffer` events behind loses the oldest ones and is told how many it missed
+// instead of slowing down the read loop. Every subscriber is listed with the events it lost, and
+// a subscriber falling behind or catching up again is logged once.
+//
+// The channel holds at most `buffer` events whatever the number of subscribers, and beyond
+// `max_subscribers` new subscribers are refused, so streaming cannot grow the broker unbounded.
+
+use crate::cap::Cap;
+use crate::data_manipulation::MyDataPoint;
+use crate::latest::field_to_json;
+use futures::stream::{self, Stream};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::sse::Event;
+
+// Events buffered per subscriber before the oldest are dropped, and subscribers at a time, when
+// not configured.
+const DEFAULT_BUFFER: usize = 256;
+const DEFAULT_MAX_SUBSCRIBERS: usize = 16;
+
+#[derive(Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<Arc<Reading>>,
+    buffer: usize,
+    subscribers: Arc<Mutex<BTreeMap<u64, Arc<Subscriber>>>>,
+    next_id: Arc<AtomicU64>,
+    cap: Arc<Cap>,
+}
+
+struct Reading {
+    measurement: String,
+    json: String,
+}
+
+// What is known of a subscriber, for `/stats`.
+struct Subscriber {
+    since: Instant,
+    measurement: Option<String>,
+    dropped: AtomicU64,
+    lagging: AtomicBool,
+}
+
+// Removes the subscriber from the list once its stream is dropped.
+struct Registration {
+    id: u64,
+    subscribers: Arc<Mutex<BTreeMap<u64, Arc<Subscriber>>>>,
+    cap: Arc<Cap>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        subscribers.remove(&self.id);
+        self.cap.observe(subscribers.len());
+    }
+}
+
+impl Default for LiveFeed {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUFFER, DEFAULT_MAX_SUBSCRIBERS)
+    }
+}
+
+impl LiveFeed {
+    // Buffers up to `buffer` events, and serves up to `max_subscribers` subscribers at a time.
+    pub fn new(buffer: usize, max_subscribers: usize) -> Self {
+        let (sender, _) = broadcast::channel(buffer.max(1));
+        Self {
+            sender,
+            buffer,
+            subscribers: Arc::new(Mutex::new(BTreeMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            cap: Arc::new(Cap::new("Stream subscribers", max_subscribers)),
+        }
+    }
+
+    // Publishes one event per field of every point. Nothing is serialized while nobody listens.
+    pub fn publish(&self, points: &[MyDataPoint]) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        for point in points {
+            for (field, value) in point.get_fields() {
+                let json = json!({
+                    "measurement": point.get_measurement(),
+                    "tags": point.get_tags(),
+                    "field": field,
+                    "value": field_to_json(value),
+                    "timestamp": point.get_timestamp(),
+                });
+                // Sending only fails when every subscriber has gone away in the meantime.
+                let _ = self.sender.send(Arc::new(Reading {
+                    measurement: point.get_measurement().to_string(),
+                    json: json.to_string(),
+                }));
+            }
+        }
+    }
+
+    // A stream of SSE events for a new subscriber, optionally limited to one measurement.
+    // `None` when the broker already serves as many subscribers as it may.
+    pub fn subscribe(
+        &self,
+        measurement: Option<String>,
+    ) -> Option<impl Stream<Item = Result<Event, Infallible>> + Send + 'static> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let subscriber = Arc::new(Subscriber {
+            since: Instant::now(),
+            measurement: measurement.clone(),
+            dropped: AtomicU64::new(0),
+            lagging: AtomicBool::new(false),
+        });
+        {
+            let mut subscribers = self
+                .subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if subscribers.len() >= self.cap.limit() {
+                self.cap.hit();
+                return None;
+            }
+            subscribers.insert(id, subscriber.clone());
+            self.cap.observe(subscribers.len());
+        }
+        let registration = Registration {
+            id,
+            subscribers: self.subscribers.clone(),
+            cap: self.cap.clone(),
+        };
+
+        let receiver = self.sender.subscribe();
+        let stream = stream::unfold(
+            (receiver, registration),
+            move |(mut receiver, registration)| {
+                let measurement = measurement.clone();
+                let subscriber = subscriber.clone();
+                async move {
+                    loop {
+                        let event = match receiver.recv().await {
+                            Ok(reading) => {
+                                if receiver.is_empty()
+                                    && subscriber.lagging.swap(false, Ordering::Relaxed)
+                                {
+                                    info!(
+                                        "Stream subscriber {} caught up, {} events dropped so far",
+                                        registration.id,
+                                        subscriber.dropped.load(Ordering::Relaxed)
+                                    );
+                                }
+                                if measurement
+                                    .as_ref()
+                                    .is_some_and(|wanted| *wanted != reading.measurement)
+                                {
+                                    continue;
+                                }
+                                Event::default().data(reading.json.as_str())
+                            }
+                            Err(RecvError::Lagged(dropped)) => {
+                                subscriber.dropped.fetch_add(dropped, Ordering::Relaxed);
+                                if !subscriber.lagging.swap(true, Ordering::Relaxed) {
+                                    warn!(
+                                        "Stream subscriber {} falls behind, dropping events",
+                                        registration.id
+                                    );
+                                }
+                                Event::default().comment(format!("dropped {} events", dropped))
+                            }
+                            Err(RecvError::Closed) => return None,
+                        };
+                        return Some((Ok(event), (receiver, registration)));
+                    }
+                }
+            },
+        );
+        Some(stream)
+    }
+
+    // The subscribers and the limits of the feed, for `/stats`.
+    pub fn stats(&self) -> Value {
+        let subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let list: Vec<Value> = subscribers
+            .iter()
+            .map(|(id, subscriber)| {
+                json!({
+                    "id": id,
+                    "connected_secs": subscriber.since.elapsed().as_secs(),
+                    "measurement": subscriber.measurement,
+                    "dropped_events": subscriber.dropped.load(Ordering::Relaxed),
+                    "lagging": subscriber.lagging.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        json!({
+            "buffer": self.buffer,
+            "queued": self.sender.len(),
+            "subscribers": self.cap.to_json(subscribers.len()),
+            "subscriber_list": list,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SUBSCRIBERS: usize = 100;
+
+    #[tokio::test]
+    async fn subscribers_beyond_the_limit_are_refused_until_others_leave() {
+        let feed = LiveFeed::new(16, MAX_SUBSCRIBERS);
+
+        let subscribers: Vec<_> = (0..5000).map(|_| feed.subscribe(None)).collect();
+
+        let served = subscribers.iter().filter(|stream| stream.is_some()).count();
+        assert_eq!(served, MAX_SUBSCRIBERS);
+        // The first ones come in, the others are refused
+        assert!(subscribers[..MAX_SUBSCRIBERS].iter().all(Option::is_some));
+        let stats = feed.stats();
+        assert_eq!(stats["subscribers"]["used"], MAX_SUBSCRIBERS);
+        assert_eq!(stats["subscribers"]["hits"], 5000 - MAX_SUBSCRIBERS);
+        assert_eq!(stats["subscribers"]["reached"], true);
+
+        drop(subscribers);
+        let stats = feed.stats();
+        assert_eq!(stats["subscribers"]["used"], 0);
+        assert_eq!(stats["subscribers"]["reached"], false);
+        assert!(feed.subscribe(None).is_some());
+    }
+}
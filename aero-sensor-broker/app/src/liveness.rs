@@ -0,0 +1,71 @@
+// liveness.rs
+//
+// Tracks whether the long-running tasks of the process (read loop of each source, flush task,
+// HTTP server) are still running, for the `/livez` probe. Each task holds a guard for as long
+// as it runs; the guard marks the task as stopped when it is dropped, including when the task
+// panics or returns early. The supervisor records how often a task was restarted and why it
+// last stopped.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[derive(Clone, Default)]
+pub struct Liveness {
+    tasks: Arc<Mutex<BTreeMap<String, TaskState>>>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct TaskState {
+    pub running: bool,
+    pub restarts: u32,
+    // How the task last stopped on its own, e.g. the message of a panic.
+    pub last_failure: Option<String>,
+}
+
+pub struct TaskGuard {
+    liveness: Liveness,
+    name: String,
+}
+
+impl Liveness {
+    // Marks the task as running until the returned guard is dropped.
+    pub fn track(&self, name: impl Into<String>) -> TaskGuard {
+        let name = name.into();
+        self.update(&name, |state| state.running = true);
+        TaskGuard {
+            liveness: self.clone(),
+            name,
+        }
+    }
+
+    // Records that the task stopped on its own, and whether it is being restarted.
+    pub fn record_failure(&self, name: &str, failure: &str, restarting: bool) {
+        self.update(name, |state| {
+            state.last_failure = Some(failure.to_string());
+            if restarting {
+                state.restarts += 1;
+            }
+        });
+    }
+
+    // State of every task tracked so far.
+    pub fn tasks(&self) -> BTreeMap<String, TaskState> {
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn update(&self, name: &str, change: impl FnOnce(&mut TaskState)) {
+        let mut tasks = self.tasks.lock().unwrap_or_else(PoisonError::into_inner);
+        change(tasks.entry(name.to_string()).or_default());
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.liveness
+            .update(&self.name, |state| state.running = false);
+    }
+}
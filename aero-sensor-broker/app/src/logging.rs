@@ -0,0 +1,278 @@
+// logging.rs
+//
+// The logger is set up twice: from RUST_LOG and `--log-level` before the settings are loaded, so
+// that loading problems are reported, then from the `[logging]` section once they are. Reloading
+// the settings rebuilds it, which changes the levels without a restart. `PUT /admin/log-level`
+// changes them too, without touching the configuration, e.g. to turn on debug logging while the
+// state to observe is still there; they stay changed until the `[logging]` section is reloaded
+// with other levels, or the broker restarts. The JSON format writes one object per line with the
+// timestamp, level, target, message, and the key-value pairs of the record under `fields`. Records
+// are also recorded in the current span when traces are exported.
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::telemetry;
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, Write};
+use std::sync::{OnceLock, PoisonError, RwLock};
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+// An env_logger logger that can be replaced while the broker runs.
+struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+    // `--log-level`, which overrides the configured level.
+    cli_level: Option<LevelFilter>,
+    // What the logger was built from.
+    current: RwLock<Current>,
+}
+
+struct Current {
+    levels: Levels,
+    format: LogFormat,
+    // Whether `--log-level` still overrides `levels.level`, until the level is changed at
+    // runtime.
+    cli_level: Option<LevelFilter>,
+}
+
+// The levels of the logger: the default level, or RUST_LOG-style directives when it comes from
+// RUST_LOG, and the levels of single modules on top.
+#[derive(Serialize, Clone, Debug)]
+pub struct Levels {
+    pub level: String,
+    pub modules: BTreeMap<String, String>,
+}
+
+// The body of `PUT /admin/log-level`: the default level, kept when unset, and module levels
+// set on top of the current ones.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LevelChange {
+    pub level: Option<String>,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let inner = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        if inner.matches(record) {
+            inner.log(record);
+            telemetry::record_log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush()
+    }
+}
+
+// Installs the logger, configured from the environment and the command line only.
+pub fn init(cli_level: Option<LevelFilter>) {
+    let logger = LOGGER.get_or_init(|| {
+        let current = Current {
+            levels: configured_levels(&LoggingConfig::default()),
+            format: LogFormat::default(),
+            cli_level,
+        };
+        ReloadableLogger {
+            inner: RwLock::new(current.build()),
+            cli_level,
+            current: RwLock::new(current),
+        }
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(
+            logger
+                .inner
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .filter(),
+        );
+    }
+}
+
+// Replaces the logger with one configured by the `[logging]` section.
+pub fn configure(config: &LoggingConfig) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    logger.replace(Current {
+        levels: configured_levels(config),
+        format: config.format,
+        cli_level: logger.cli_level,
+    });
+}
+
+// Changes the levels of the logger at runtime. Returns the levels before and after the change,
+// or why the change is invalid, in which case nothing changed.
+pub fn change_levels(change: LevelChange) -> Result<(Levels, Levels), String> {
+    if let Some(level) = &change.level {
+        parse_directives(level).map_err(|e| format!("level: {}", e))?;
+    }
+    for (module, level) in &change.modules {
+        if module.is_empty() || module.contains(|c: char| c.is_whitespace() || "=,/".contains(c)) {
+            return Err(format!("modules: invalid module name '{}'", module));
+        }
+        parse_level(level).map_err(|e| format!("modules.{}: {}", module, e))?;
+    }
+    let logger = LOGGER.get().ok_or("the logger is not installed")?;
+    let mut current = logger
+        .current
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    let previous = current.effective_levels();
+    let mut levels = current.levels.clone();
+    let cli_level = match change.level {
+        Some(level) => {
+            levels.level = level;
+            None
+        }
+        None => current.cli_level,
+    };
+    levels.modules.extend(change.modules);
+    *current = Current {
+        levels,
+        format: current.format,
+        cli_level,
+    };
+    let inner = current.build();
+    log::set_max_level(inner.filter());
+    *logger.inner.write().unwrap_or_else(PoisonError::into_inner) = inner;
+    Ok((previous, current.effective_levels()))
+}
+
+// The levels in effect, and the most verbose of them, for `/admin/status`.
+pub fn status() -> Value {
+    let levels = LOGGER.get().map(|logger| {
+        logger
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .effective_levels()
+    });
+    json!({
+        "level": levels.as_ref().map(|levels| &levels.level),
+        "modules": levels.as_ref().map(|levels| &levels.modules),
+        "max_level": log::max_level().as_str().to_lowercase(),
+    })
+}
+
+impl ReloadableLogger {
+    fn replace(&self, current: Current) {
+        let inner = current.build();
+        log::set_max_level(inner.filter());
+        *self.inner.write().unwrap_or_else(PoisonError::into_inner) = inner;
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = current;
+    }
+}
+
+impl Current {
+    // `--log-level` wins over the level, module levels apply on top.
+    fn build(&self) -> env_logger::Logger {
+        let mut builder = env_logger::Builder::new();
+        builder.parse_filters(&self.levels.level);
+        if let Ok(style) = env::var("RUST_LOG_STYLE") {
+            builder.parse_write_style(&style);
+        }
+        if let Some(level) = self.cli_level {
+            builder.filter_level(level);
+        }
+        for (module, level) in &self.levels.modules {
+            builder.parse_filters(&format!("{}={}", module, level));
+        }
+        if self.format == LogFormat::Json {
+            builder.format(write_json);
+        }
+        builder.build()
+    }
+
+    fn effective_levels(&self) -> Levels {
+        let mut levels = self.levels.clone();
+        if let Some(level) = self.cli_level {
+            levels.level = level.as_str().to_lowercase();
+        }
+        levels
+    }
+}
+
+// The configured level wins over RUST_LOG, which only applies when no level is configured.
+// Without either, only errors are logged.
+fn configured_levels(config: &LoggingConfig) -> Levels {
+    let level = match &config.level {
+        Some(level) => level.clone(),
+        None => env::var("RUST_LOG").unwrap_or_else(|_| LevelFilter::Error.as_str().to_lowercase()),
+    };
+    Levels {
+        level,
+        modules: config.modules.clone(),
+    }
+}
+
+// Checks RUST_LOG-style directives, e.g. "info,aero_sensor_broker::arduino=trace", which
+// env_logger would otherwise apply as far as it understands them.
+fn parse_directives(directives: &str) -> Result<(), String> {
+    if directives.trim().is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    for directive in directives.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            Some((module, _)) if module.is_empty() || module.contains(char::is_whitespace) => {
+                return Err(format!("invalid module name in '{}'", directive))
+            }
+            Some((_, level)) => parse_level(level)?,
+            None => parse_level(directive)?,
+        }
+    }
+    Ok(())
+}
+
+fn parse_level(level: &str) -> Result<(), String> {
+    level.parse::<LevelFilter>().map(|_| ()).map_err(|_| {
+        format!(
+            "'{}' is not one of off, error, warn, info, debug, or trace",
+            level
+        )
+    })
+}
+
+fn write_json(buf: &mut env_logger::fmt::Formatter, record: &Record) -> io::Result<()> {
+    let mut fields = Fields::default();
+    // Collecting into a map cannot fail.
+    let _ = record.key_values().visit(&mut fields);
+    let line = json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "fields": fields.0,
+    });
+    writeln!(buf, "{}", line)
+}
+
+// The key-value pairs of a record, rendered as strings.
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .insert(key.to_string(), Value::from(value.to_string()));
+        Ok(())
+    }
+}
@@ -1,118 +1,211 @@
 // main.rs
 //
 // This is the entry point of the Aero Sensor Flow application. It initializes the logging,
-// loads settings from configuration, and manages the lifecycle of the application components
-// including the ArduinoManager for handling Arduino device interactions and the InfluxDBManager
-// for database operations. The application also establishes an HTTP server for health checks.
-
-mod arduino;
-mod cache;
-mod config;
-mod data_manipulation;
-mod influxdb;
-mod routes;
-
-use arduino::ArduinoManager;
-use cache::Cache;
-use chrono::Utc;
-use config::load_settings;
-use data_manipulation::{calculate_average, parse_sensor_data};
-use influxdb::InfluxDBManager;
-use routes::create_health_route;
-
-use std::env;
-use std::error::Error;
-use tokio::time::{sleep, Duration};
-
-use log::{debug, error};
+// loads settings from configuration, and runs the broker, or one of the subcommands that help
+// preparing a deployment.
+
+mod cli;
+
+use aero_sensor_broker::arduino::list_candidate_ports;
+use aero_sensor_broker::config::{load_settings, ConfigSettings, SourceKind};
+use aero_sensor_broker::crash;
+use aero_sensor_broker::errors::AppError;
+use aero_sensor_broker::logging;
+use aero_sensor_broker::reload::Reloader;
+use aero_sensor_broker::routes::cors;
+use aero_sensor_broker::selftest;
+use aero_sensor_broker::shutdown;
+use aero_sensor_broker::telemetry::Telemetry;
+use clap::Parser;
+use cli::{Cli, Command, EXIT_CONFIG_ERROR, EXIT_RUNTIME_FAILURE};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use log::{error, warn};
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let cli = Cli::parse();
 
-    // Load settings from the configuration file
-    let settings = load_settings().unwrap_or_else(|e| {
-        error!("Failed to load settings: {}", e);
-        std::process::exit(1);
-    });
-
-    // Setup ArduinoManager with settings from the config
-    let arduino_manager = ArduinoManager::new(&settings.arduino).unwrap_or_else(|e| {
-        error!("Failed to initialize ArduinoManager: {}", e);
-        std::process::exit(1);
-    });
+    logging::init(cli.log_level);
 
-    // Initialize Cache
-    let cache = Cache::new(1000);
+    let command = cli.command.clone().unwrap_or(Command::Run);
+    if command == Command::ListPorts {
+        list_ports(&cli);
+        return;
+    }
 
-    // Setup InfluxDBManager with settings from the config
-    let influxdb_manager = InfluxDBManager::new(&settings.influxdb).unwrap_or_else(|e| {
-        error!("Failed to initialize InfluxDBManager: {}", e);
-        std::process::exit(1);
+    // Load settings from the configuration files
+    let source = cli.config_source();
+    let mut settings = load_settings(&source).unwrap_or_else(|e| {
+        error!("Failed to load settings: {}", e);
+        std::process::exit(EXIT_CONFIG_ERROR);
     });
+    settings.dry_run |= cli.dry_run;
+    logging::configure(&settings.logging);
 
-    // Initialize the HTTP server for health checks
-    let health_route = create_health_route(arduino_manager.clone(), influxdb_manager.clone());
-    tokio::spawn(async move {
-        warp::serve(health_route).run(([0, 0, 0, 0], 3030)).await;
-    });
+    // Report a crash of the previous run, then keep a record of the panics of this one
+    crash::report_previous_crash(Path::new(&settings.crash_marker));
+    crash::install_panic_hook(PathBuf::from(&settings.crash_marker));
 
-    // Spawn a task for periodic cache flush to InfluxDB
-    tokio::spawn({
-        let cache_to_flush = cache.clone();
-        let influxdb_manager_to_flush = influxdb_manager.clone();
-        async move {
-            cache_to_flush
-                .periodic_flush(
-                    influxdb_manager_to_flush,
-                    &settings.influxdb.bucket,
-                    Duration::from_secs(60),
-                )
-                .await;
-        }
+    if command == Command::CheckConfig {
+        check_config(&settings);
+        return;
+    }
+    if let Command::Selftest {
+        source,
+        frame_timeout,
+        write,
+        json,
+    } = &command
+    {
+        let frame_timeout = Duration::from_secs(*frame_timeout);
+        selftest(&settings, source.as_deref(), frame_timeout, *write, *json).await;
+        return;
+    }
+    let reloader = Reloader::new(source, cli.dry_run, &settings);
+    // Traces are optional: the broker runs without them when the exporter cannot be set up
+    let telemetry = settings.tracing.as_ref().and_then(|config| {
+        Telemetry::init(config)
+            .inspect_err(|e| warn!("Traces are not exported: {}", e))
+            .ok()
     });
 
-    // Process data from Arduino and write to Cache in a loop
-    if let Err(e) = run_serial_to_influx_loop(arduino_manager, cache).await {
-        error!("Error in serial to InfluxDB loop: {}", e);
+    // Cancelled on SIGINT/SIGTERM, and by a replay at the end of its recording
+    let shutdown = CancellationToken::new();
+    shutdown::cancel_on_signal(shutdown.clone());
+
+    let result = match command {
+        Command::Replay {
+            recording,
+            source,
+            speed,
+        } => aero_sensor_broker::replay(
+            settings,
+            reloader,
+            &recording,
+            source.as_deref(),
+            speed,
+            shutdown,
+        )
+        .await
+        .map(|summary| {
+            println!("{}", summary);
+            summary.failed
+        }),
+        _ => aero_sensor_broker::run(settings, reloader, shutdown)
+            .await
+            .map(|()| false),
+    };
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown().await;
+    }
+    // A fatal panic left its marker before getting here
+    if result.is_ok() {
+        crash::clear_marker();
+    }
+    // Errors were logged where they happened
+    match result {
+        Ok(false) => {}
+        Ok(true) | Err(AppError::Runtime(_)) => std::process::exit(EXIT_RUNTIME_FAILURE),
+        Err(AppError::Config(_)) => std::process::exit(EXIT_CONFIG_ERROR),
+        Err(_) => std::process::exit(EXIT_RUNTIME_FAILURE),
     }
 }
 
-async fn run_serial_to_influx_loop(
-    arduino_manager: ArduinoManager,
-    cache: Cache,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Retrieve the environment variable `CLUSTER_DISPLAY_NAME` and use it as a location
-    let location = env::var("CLUSTER_DISPLAY_NAME").unwrap_or_else(|e| {
-        println!("Couldn't read CLUSTER_DISPLAY_NAME: {}", e);
-        String::from("Default")
-    });
-
-    let mut previous_timestamp = Utc::now().timestamp();
-    let mut points = Vec::new();
-
-    loop {
-        let data = arduino_manager.read_data().await.map_err(|e| {
-            error!("Failed to read data from Arduino: {}", e);
-            e
-        })?;
-
-        let new_points = parse_sensor_data(data, &location).map_err(|e| {
-            error!("Failed to parse sensor data: {}", e);
-            e
-        })?;
+// Prints the settings, already validated by `load_settings`, with secrets redacted. Exits with
+// `EXIT_CONFIG_ERROR` when the checks done at startup (CORS) fail.
+fn check_config(settings: &ConfigSettings) {
+    let mut problems = Vec::new();
+    if let Some(Err(e)) = settings
+        .http
+        .cors
+        .as_ref()
+        .map(|config| cors(config, matches!(settings.http.auth_token(), Ok(Some(_)))))
+    {
+        problems.push(e);
+    }
 
-        points.extend(new_points);
+    match serde_json::to_string_pretty(&settings.redacted()) {
+        Ok(json) => println!("{}", json),
+        Err(e) => problems.push(format!("failed to render the settings: {}", e)),
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        std::process::exit(EXIT_CONFIG_ERROR);
+    }
+    eprintln!("Configuration is valid");
+}
 
-        let timestamp = Utc::now().timestamp();
+// Runs the self-test and prints its report. Exits with `EXIT_RUNTIME_FAILURE` when a stage
+// failed, and with `EXIT_CONFIG_ERROR` when there is no such source.
+async fn selftest(
+    settings: &ConfigSettings,
+    source: Option<&str>,
+    frame_timeout: Duration,
+    write: bool,
+    json: bool,
+) {
+    let report = selftest::run(settings, source, frame_timeout, write)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to run the self-test: {}", e);
+            std::process::exit(match e {
+                AppError::Config(_) => EXIT_CONFIG_ERROR,
+                _ => EXIT_RUNTIME_FAILURE,
+            });
+        });
+    match json {
+        true => println!("{:#}", report.to_json()),
+        false => println!("{}", report),
+    }
+    if !report.passed() {
+        std::process::exit(EXIT_RUNTIME_FAILURE);
+    }
+}
 
-        if (timestamp - previous_timestamp) > 60 {
-            previous_timestamp = Utc::now().timestamp();
-            cache.add(calculate_average(points)).await;
-            points = Vec::new();
+// Prints the serial ports with what they report and, when the settings can be loaded, whether
+// each one matches each serial source, or why not.
+fn list_ports(cli: &Cli) {
+    let sources = match load_settings(&cli.config_source()) {
+        Ok(settings) => settings.sources,
+        Err(e) => {
+            warn!("Failed to load settings, ports are not matched: {}", e);
+            Vec::new()
         }
+    };
+    let ports = list_candidate_ports().unwrap_or_else(|e| {
+        error!("Failed to list serial ports: {}", e);
+        std::process::exit(EXIT_RUNTIME_FAILURE);
+    });
 
-        debug!("Data processed successfully.");
-        sleep(Duration::from_millis(1000)).await;
+    if ports.is_empty() {
+        println!("No serial ports found");
+    }
+    for port in ports {
+        let usb_id = match (&port.vid, &port.pid) {
+            (Some(vid), Some(pid)) => format!("{}:{}", vid, pid),
+            _ => port.port_type.to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\tserial {}",
+            port.port_name,
+            usb_id,
+            port.manufacturer.as_deref().unwrap_or("-"),
+            port.product.as_deref().unwrap_or("-"),
+            port.serial_number.as_deref().unwrap_or("-")
+        );
+        let serial_sources = sources
+            .iter()
+            .filter(|source| source.kind == SourceKind::Serial);
+        for source in serial_sources {
+            match port.check(&source.serial.device_name) {
+                Ok(()) => println!("  matches source {}", source.name),
+                Err(reason) => println!("  source {}: {}", source.name, reason),
+            }
+        }
     }
 }
@@ -0,0 +1,273 @@
+// measurements.rs
+//
+// Lets operators stop recording single measurements at runtime, e.g. during a noisy experiment,
+// without touching the firmware. The read loops hand every parsed point to `admit`: the points
+// of a disabled measurement are counted and dropped before they are aggregated, streamed, or
+// cached. The frames they came from still count as parsed, so a source whose measurements are
+// all disabled is not reported stale.
+//
+// The disabled measurements are those of `disabled_measurements` in the configuration and those
+// disabled through the admin routes. The latter are kept in the state file, saved as soon as they
+// change, so that a restart does not silently enable them again.
+//
+// The counters are kept for at most `http.max_measurements` measurements, so that a firmware
+// inventing measurement names cannot grow them without bound; the least recently seen make room.
+
+use crate::cap::Cap;
+use crate::data_manipulation::MyDataPoint;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::Notify;
+
+// Who disabled a measurement, and when (RFC 3339).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Disabled {
+    pub by: String,
+    pub at: String,
+    // Disabled by the configuration, which disables it again at every start anyway.
+    #[serde(skip)]
+    configured: bool,
+}
+
+#[derive(Clone)]
+pub struct MeasurementControl {
+    state: Arc<Mutex<State>>,
+    // Notified when a measurement is disabled or enabled, for the state to be saved.
+    changed: Arc<Notify>,
+    // Measurements counted at most.
+    cap: Arc<Cap>,
+}
+
+#[derive(Default)]
+struct State {
+    disabled: BTreeMap<String, Disabled>,
+    counters: BTreeMap<String, Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    // Points recorded, and points dropped while the measurement was disabled.
+    recorded: u64,
+    discarded: u64,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+impl MeasurementControl {
+    // Starts with the measurements of the configuration disabled. Counts the points of up to
+    // `max_measurements` measurements.
+    pub fn new(disabled: &[String], max_measurements: usize) -> Self {
+        let control = Self {
+            state: Arc::default(),
+            changed: Arc::default(),
+            cap: Arc::new(Cap::new("Measurements counted", max_measurements)),
+        };
+        control.restore(
+            disabled
+                .iter()
+                .map(|measurement| {
+                    let disabled = Disabled {
+                        configured: true,
+                        ..disabled_now("config")
+                    };
+                    (measurement.clone(), disabled)
+                })
+                .collect(),
+        );
+        control
+    }
+
+    // Disables the measurements the previous run left disabled, in addition to those of the
+    // configuration.
+    pub fn restore(&self, disabled: BTreeMap<String, Disabled>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for (measurement, by) in disabled {
+            state.disabled.entry(measurement).or_insert(by);
+        }
+    }
+
+    // Disables or enables a measurement. Returns whether its state changed.
+    pub fn set_enabled(&self, measurement: &str, enabled: bool, by: String) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let changed = match enabled {
+            true => state.disabled.remove(measurement).is_some(),
+            false if state.disabled.contains_key(measurement) => false,
+            false => {
+                let disabled = disabled_now(&by);
+                state.disabled.insert(measurement.to_string(), disabled);
+                true
+            }
+        };
+        if changed {
+            self.changed.notify_one();
+        }
+        changed
+    }
+
+    // Waits until a measurement is disabled or enabled.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    // The measurements disabled through the admin routes, as saved in the state file.
+    pub fn disabled(&self) -> BTreeMap<String, Disabled> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .disabled
+            .iter()
+            .filter(|(_, disabled)| !disabled.configured)
+            .map(|(measurement, disabled)| (measurement.clone(), disabled.clone()))
+            .collect()
+    }
+
+    // Counts the points of a frame by measurement, and keeps those of the enabled ones.
+    pub fn admit(&self, mut points: Vec<MyDataPoint>) -> Vec<MyDataPoint> {
+        let now = Utc::now();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        points.retain(|point| {
+            let measurement = point.get_measurement();
+            let enabled = !state.disabled.contains_key(measurement);
+            if !state.counters.contains_key(measurement) {
+                state.make_room(&self.cap);
+            }
+            let counters = match state.counters.get_mut(measurement) {
+                Some(counters) => counters,
+                None => state.counters.entry(measurement.to_string()).or_default(),
+            };
+            match enabled {
+                true => counters.recorded += 1,
+                false => counters.discarded += 1,
+            }
+            counters.last_seen = Some(now);
+            enabled
+        });
+        self.cap.observe(state.counters.len());
+        points
+    }
+
+    // The number of measurements counted and the limit, for `/stats`.
+    pub fn stats(&self) -> Value {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.cap.to_json(state.counters.len())
+    }
+
+    // Every measurement seen or disabled, for `GET /admin/measurements`.
+    pub fn status(&self) -> Value {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let names = state.counters.keys().chain(state.disabled.keys());
+        let measurements: serde_json::Map<String, Value> = names
+            .map(|measurement| (measurement.clone(), state.entry(measurement)))
+            .collect();
+        json!({ "measurements": measurements })
+    }
+
+    // One measurement, as reported by the admin routes.
+    pub fn measurement_status(&self, measurement: &str) -> Value {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut entry = state.entry(measurement);
+        entry["measurement"] = json!(measurement);
+        entry
+    }
+}
+
+impl State {
+    // Forgets the counters of the least recently seen measurements until one more fits.
+    fn make_room(&mut self, cap: &Cap) {
+        while self.counters.len() >= cap.limit() {
+            let oldest = self
+                .counters
+                .iter()
+                .min_by_key(|(_, counters)| counters.last_seen)
+                .map(|(measurement, _)| measurement.clone());
+            let Some(oldest) = oldest else {
+                break;
+            };
+            self.counters.remove(&oldest);
+            cap.hit();
+        }
+    }
+
+    fn entry(&self, measurement: &str) -> Value {
+        let counters = self.counters.get(measurement);
+        let disabled = self.disabled.get(measurement);
+        json!({
+            "enabled": disabled.is_none(),
+            "disabled_by": disabled.map(|disabled| &disabled.by),
+            "disabled_at": disabled.map(|disabled| &disabled.at),
+            "points_recorded": counters.map_or(0, |counters| counters.recorded),
+            "points_discarded": counters.map_or(0, |counters| counters.discarded),
+            "last_seen": counters.and_then(|counters| counters.last_seen).map(rfc3339),
+        })
+    }
+}
+
+fn disabled_now(by: &str) -> Disabled {
+    Disabled {
+        by: by.to_string(),
+        at: rfc3339(Utc::now()),
+        configured: false,
+    }
+}
+
+fn rfc3339(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_manipulation::Reading;
+
+    fn points(measurements: &[&str]) -> Vec<MyDataPoint> {
+        measurements
+            .iter()
+            .map(|measurement| {
+                let reading = Reading::Value(1.0);
+                MyDataPoint::from_reading(measurement.to_string(), BTreeMap::new(), reading, 0)
+            })
+            .collect()
+    }
+
+    fn counted(control: &MeasurementControl) -> Vec<String> {
+        let status = control.status();
+        status["measurements"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn the_least_recently_seen_measurement_makes_room_for_a_new_one() {
+        let control = MeasurementControl::new(&[], 2);
+        control.admit(points(&["humidity"]));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        control.admit(points(&["temperature"]));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        control.admit(points(&["humidity", "pressure"]));
+
+        assert_eq!(counted(&control), ["humidity", "pressure"]);
+        let stats = control.stats();
+        assert_eq!(stats["used"], 2);
+        assert_eq!(stats["limit"], 2);
+        assert_eq!(stats["reached"], true);
+        assert_eq!(stats["hits"], 1);
+    }
+
+    #[test]
+    fn a_disabled_measurement_stays_disabled_once_its_counters_are_forgotten() {
+        let control = MeasurementControl::new(&["door".to_string()], 1);
+        control.admit(points(&["door"]));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let admitted = control.admit(points(&["temperature", "door"]));
+
+        assert_eq!(admitted.len(), 1);
+        assert_eq!(control.measurement_status("door")["enabled"], false);
+    }
+}
@@ -0,0 +1,360 @@
+// metrics.rs
+//
+// Pipeline counters exposed in the Prometheus text format on `GET /metrics`. A single `Metrics`
+// value is shared by the serial reader, the parser loop, the cache, and the sinks; every value
+// is a plain atomic so recording costs next to nothing.
+//
+// Exposed metrics (names and labels are stable, dashboards depend on them):
+//
+//   aero_build_info{version}                    gauge, always 1
+//   aero_frames_received_total                  counter, frames read from the serial port
+//   aero_frames_invalid_total                   counter, frames with an unknown framing
+//   aero_points_parsed_total                    counter, points produced by the parser
+//   aero_frames_rejected_total                  counter, frames the parser rejected
+//   aero_cache_length                           gauge, points waiting to be flushed
+//   aero_cache_evictions_total                  counter, points dropped because the cache was full
+//   aero_cache_points_unstamped_total           counter, points flushed without the flush_seq
+//                                               field, which could not be decoded to add it
+//   aero_flushes_total{result}                  counter, flushes by result: success, failure
+//   aero_points_written_total                   counter, points InfluxDB accepted
+//   aero_write_latency_seconds                  histogram, duration of InfluxDB writes
+//   aero_reconnects_total{component}            counter, reconnections: serial, mqtt
+//   aero_source_frames_received_total{source}   counter, frames read from each source
+//   aero_source_frames_invalid_total{source}    counter, frames with an unknown framing
+//   aero_source_points_parsed_total{source}     counter, points produced from each source
+//   aero_source_frames_rejected_total{source}   counter, frames of each source the parser rejected
+//   aero_source_device_timestamps_total{source} counter, points timestamped by the device clock
+//   aero_source_host_timestamps_total{source}   counter, points whose unset device clock was
+//                                               replaced by the host clock
+//   aero_source_frames_limited_total{source}    counter, frames dropped unparsed by the ingest limit
+//   aero_source_points_limited_total{source}    counter, points dropped by the per-measurement
+//                                               ingest limit
+//   aero_source_points_suppressed_total{source} counter, averaged points left out because they
+//                                               stayed within their deadband
+//   aero_source_frames_oversized_total{source}  counter, lines discarded for being longer than
+//                                               max_frame_bytes
+//   aero_source_frames_missed_total{source}     counter, frames missing from the sequence numbers
+//                                               of protocol 3 devices
+//   aero_source_readings_missing_total{source}  counter, readings the probes could not take (NaN,
+//                                               infinity, or the sentinel value)
+//   aero_source_lines_dropped_total{source}     counter, lines read from the port while the queue
+//                                               of the read loop was full
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+// Upper bounds of the write latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    pub frames_received: AtomicU64,
+    pub frames_invalid: AtomicU64,
+    pub points_parsed: AtomicU64,
+    pub frames_rejected: AtomicU64,
+    pub cache_length: AtomicU64,
+    pub cache_evictions: AtomicU64,
+    pub points_unstamped: AtomicU64,
+    pub flush_successes: AtomicU64,
+    pub flush_failures: AtomicU64,
+    pub points_written: AtomicU64,
+    pub serial_reconnects: AtomicU64,
+    pub mqtt_reconnects: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_us: AtomicU64,
+    sources: Mutex<BTreeMap<String, Arc<SourceMetrics>>>,
+}
+
+// The counters of one source, recorded alongside the totals.
+#[derive(Default)]
+pub struct SourceMetrics {
+    pub frames_received: AtomicU64,
+    pub frames_invalid: AtomicU64,
+    pub points_parsed: AtomicU64,
+    pub frames_rejected: AtomicU64,
+    pub device_timestamps: AtomicU64,
+    pub host_timestamps: AtomicU64,
+    pub frames_limited: AtomicU64,
+    pub points_limited: AtomicU64,
+    pub points_suppressed: AtomicU64,
+    pub frames_oversized: AtomicU64,
+    pub frames_missed: AtomicU64,
+    pub readings_missing: AtomicU64,
+    pub lines_dropped: AtomicU64,
+    // The readings missing, per measurement.
+    pub readings_missing_by_measurement: Mutex<BTreeMap<String, u64>>,
+}
+
+// Names and help texts of the per-source counters, in the order of `SourceMetrics::values`.
+const SOURCE_COUNTERS: [(&str, &str); 13] = [
+    (
+        "aero_source_frames_received_total",
+        "Frames read from each source.",
+    ),
+    (
+        "aero_source_frames_invalid_total",
+        "Frames of each source with an unknown framing.",
+    ),
+    (
+        "aero_source_points_parsed_total",
+        "Points produced from each source.",
+    ),
+    (
+        "aero_source_frames_rejected_total",
+        "Frames of each source the parser rejected.",
+    ),
+    (
+        "aero_source_device_timestamps_total",
+        "Points of each source timestamped by the device clock.",
+    ),
+    (
+        "aero_source_host_timestamps_total",
+        "Points of each source whose unset device clock was replaced by the host clock.",
+    ),
+    (
+        "aero_source_frames_limited_total",
+        "Frames of each source dropped unparsed by its ingest limit.",
+    ),
+    (
+        "aero_source_points_limited_total",
+        "Points of each source dropped by its per-measurement ingest limit.",
+    ),
+    (
+        "aero_source_points_suppressed_total",
+        "Averaged points of each source left out because they stayed within their deadband.",
+    ),
+    (
+        "aero_source_frames_oversized_total",
+        "Lines of each source discarded for being longer than max_frame_bytes.",
+    ),
+    (
+        "aero_source_frames_missed_total",
+        "Frames of each source missing from the sequence numbers of the device.",
+    ),
+    (
+        "aero_source_readings_missing_total",
+        "Readings of each source the probes could not take.",
+    ),
+    (
+        "aero_source_lines_dropped_total",
+        "Lines of each source dropped while the queue of the read loop was full.",
+    ),
+];
+
+impl SourceMetrics {
+    fn values(&self) -> [&AtomicU64; 13] {
+        [
+            &self.frames_received,
+            &self.frames_invalid,
+            &self.points_parsed,
+            &self.frames_rejected,
+            &self.device_timestamps,
+            &self.host_timestamps,
+            &self.frames_limited,
+            &self.points_limited,
+            &self.points_suppressed,
+            &self.frames_oversized,
+            &self.frames_missed,
+            &self.readings_missing,
+            &self.lines_dropped,
+        ]
+    }
+
+    // Counts the readings missing from a window, per measurement.
+    pub fn count_missing(&self, missing: BTreeMap<String, u64>) {
+        let mut by_measurement = self
+            .readings_missing_by_measurement
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (measurement, count) in missing {
+            self.readings_missing.fetch_add(count, Ordering::Relaxed);
+            *by_measurement.entry(measurement).or_insert(0) += count;
+        }
+    }
+}
+
+impl Metrics {
+    // The counters of the named source, registered on first use.
+    pub fn source(&self, name: &str) -> Arc<SourceMetrics> {
+        self.sources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn observe_write_latency(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        header(&mut out, "aero_build_info", "gauge", "Build information.");
+        let _ = writeln!(
+            out,
+            "aero_build_info{{version=\"{}\"}} 1",
+            env!("CARGO_PKG_VERSION")
+        );
+
+        let simple = [
+            (
+                "aero_frames_received_total",
+                "counter",
+                "Frames read from the serial port.",
+                &self.frames_received,
+            ),
+            (
+                "aero_frames_invalid_total",
+                "counter",
+                "Frames with an unknown framing.",
+                &self.frames_invalid,
+            ),
+            (
+                "aero_points_parsed_total",
+                "counter",
+                "Points produced by the parser.",
+                &self.points_parsed,
+            ),
+            (
+                "aero_frames_rejected_total",
+                "counter",
+                "Frames the parser rejected.",
+                &self.frames_rejected,
+            ),
+            (
+                "aero_cache_length",
+                "gauge",
+                "Points waiting to be flushed.",
+                &self.cache_length,
+            ),
+            (
+                "aero_cache_evictions_total",
+                "counter",
+                "Points dropped because the cache was full.",
+                &self.cache_evictions,
+            ),
+            (
+                "aero_cache_points_unstamped_total",
+                "counter",
+                "Points flushed without the flush_seq field, which could not be decoded to add it.",
+                &self.points_unstamped,
+            ),
+            (
+                "aero_points_written_total",
+                "counter",
+                "Points InfluxDB accepted.",
+                &self.points_written,
+            ),
+        ];
+        for (name, kind, help, value) in simple {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, load(value));
+        }
+
+        header(
+            &mut out,
+            "aero_flushes_total",
+            "counter",
+            "Cache flushes by result.",
+        );
+        let _ = writeln!(
+            out,
+            "aero_flushes_total{{result=\"success\"}} {}",
+            load(&self.flush_successes)
+        );
+        let _ = writeln!(
+            out,
+            "aero_flushes_total{{result=\"failure\"}} {}",
+            load(&self.flush_failures)
+        );
+
+        header(
+            &mut out,
+            "aero_reconnects_total",
+            "counter",
+            "Reconnections by component.",
+        );
+        let _ = writeln!(
+            out,
+            "aero_reconnects_total{{component=\"serial\"}} {}",
+            load(&self.serial_reconnects)
+        );
+        let _ = writeln!(
+            out,
+            "aero_reconnects_total{{component=\"mqtt\"}} {}",
+            load(&self.mqtt_reconnects)
+        );
+
+        header(
+            &mut out,
+            "aero_write_latency_seconds",
+            "histogram",
+            "Duration of InfluxDB writes.",
+        );
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            let _ = writeln!(
+                out,
+                "aero_write_latency_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                load(bucket)
+            );
+        }
+        let count = load(&self.latency_count);
+        let _ = writeln!(
+            out,
+            "aero_write_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "aero_write_latency_seconds_sum {}",
+            load(&self.latency_sum_us) as f64 / 1e6
+        );
+        let _ = writeln!(out, "aero_write_latency_seconds_count {}", count);
+
+        let sources = self
+            .sources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for (index, (name, help)) in SOURCE_COUNTERS.iter().enumerate() {
+            header(&mut out, name, "counter", help);
+            for (source, counters) in &sources {
+                let _ = writeln!(
+                    out,
+                    "{}{{source=\"{}\"}} {}",
+                    name,
+                    escape_label(source),
+                    load(counters.values()[index])
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
@@ -0,0 +1,298 @@
+// mqtt.rs
+//
+// Publishes aggregated points to an MQTT broker, for building-management systems that do not
+// talk to InfluxDB. Each point is sent as a small JSON document on a topic rendered from a
+// template such as `sensors/{location}/{measurement}`, the MQTT separator and wildcards in the
+// tag values replaced so that each value stays one topic level. The rumqttc event loop runs in its own
+// task and reconnects on its own; messages are queued in a bounded buffer while disconnected
+// and dropped (with a warning) once that buffer is full.
+
+use crate::config::MqttConfig;
+use crate::errors::AppError;
+use crate::line_protocol::{decode, DecodedPoint};
+use crate::metrics::Metrics;
+use crate::sink::{DataSink, SinkError};
+
+use async_trait::async_trait;
+use influxdb2::models::{DataPoint, FieldValue};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, Transport};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+use log::{debug, error, info, warn};
+
+// Delay before polling the event loop again after a connection error, which reconnects.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub struct MqttSink {
+    client: AsyncClient,
+    topic_template: String,
+    qos: QoS,
+    connected: Arc<AtomicBool>,
+}
+
+impl MqttSink {
+    // Creates the MQTT client and spawns the task driving its connection.
+    pub fn new(config: &MqttConfig, metrics: Arc<Metrics>) -> Result<Self, AppError> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+
+        if let Some(username) = &config.username {
+            let password = config.password().map_err(AppError::Config)?;
+            options.set_credentials(username, password.unwrap_or_default());
+        }
+
+        if config.tls {
+            let transport = match &config.ca_cert_path {
+                Some(path) => {
+                    let ca = fs::read(path).map_err(|e| {
+                        AppError::Config(format!("cannot read MQTT CA certificate {}: {}", path, e))
+                    })?;
+                    Transport::tls(ca, None, None)
+                }
+                None => Transport::tls_with_default_config(),
+            };
+            options.set_transport(transport);
+        }
+
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            other => return Err(AppError::Config(format!("invalid MQTT QoS {}", other))),
+        };
+
+        let (client, event_loop) = AsyncClient::new(options, config.max_buffered_messages);
+        let connected = Arc::new(AtomicBool::new(false));
+        tokio::spawn(drive_event_loop(event_loop, connected.clone(), metrics));
+
+        info!(
+            "New MQTT client created for {}:{}",
+            &config.host, config.port
+        );
+        Ok(Self {
+            client,
+            topic_template: config.topic_template.clone(),
+            qos,
+            connected,
+        })
+    }
+}
+
+#[async_trait]
+impl DataSink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    // Queues one message per point. Points that do not fit in the buffer are dropped.
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let mut dropped = 0;
+
+        for point in &points {
+            let Some(decoded) = decode(point) else {
+                warn!("Skipping point that could not be decoded for MQTT");
+                continue;
+            };
+            let topic = render_topic(&self.topic_template, &decoded);
+            let payload = to_json(&decoded).to_string();
+
+            if let Err(e) = self.client.try_publish(&topic, self.qos, false, payload) {
+                debug!("Failed to queue MQTT message for {}: {}", topic, e);
+                dropped += 1;
+            }
+        }
+
+        if dropped > 0 {
+            warn!(
+                "MQTT buffer full, dropped {} of {} messages",
+                dropped,
+                points.len()
+            );
+            return Err(SinkError::Unavailable(format!(
+                "MQTT buffer full, dropped {} messages",
+                dropped
+            )));
+        }
+        Ok(())
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        if self.connected.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(SinkError::Unavailable(
+                "not connected to the MQTT broker".into(),
+            ))
+        }
+    }
+}
+
+// Polls the event loop forever. Polling again after an error makes rumqttc reconnect.
+async fn drive_event_loop(
+    mut event_loop: EventLoop,
+    connected: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+) {
+    let mut connected_before = false;
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker");
+                connected.store(true, Ordering::Relaxed);
+                if connected_before {
+                    metrics.mqtt_reconnects.fetch_add(1, Ordering::Relaxed);
+                }
+                connected_before = true;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if connected.swap(false, Ordering::Relaxed) {
+                    error!("MQTT connection lost: {}", e);
+                } else {
+                    debug!("MQTT connection attempt failed: {}", e);
+                }
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// Characters with a meaning in MQTT topics: the level separator and the wildcards.
+const TOPIC_RESERVED: [char; 3] = ['/', '+', '#'];
+
+// Replaces `{measurement}` and `{<tag name>}` placeholders; unknown placeholders become "unknown".
+// The reserved characters of a value become '_'.
+fn render_topic(template: &str, point: &DecodedPoint) -> String {
+    let mut topic = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        topic.push_str(&rest[..start]);
+        let name = &rest[start + 1..start + end];
+        let value = match name {
+            "measurement" => Some(&point.measurement),
+            tag => point.tags.get(tag),
+        };
+        topic.push_str(
+            &value
+                .map_or("unknown", String::as_str)
+                .replace(TOPIC_RESERVED, "_"),
+        );
+        rest = &rest[start + end + 1..];
+    }
+    topic.push_str(rest);
+    topic
+}
+
+fn to_json(point: &DecodedPoint) -> Value {
+    let fields: Map<String, Value> = point
+        .fields
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                FieldValue::Bool(value) => json!(value),
+                FieldValue::I64(value) => json!(value),
+                FieldValue::F64(value) => json!(value),
+                FieldValue::String(value) => json!(value),
+            };
+            (name.clone(), value)
+        })
+        .collect();
+
+    json!({
+        "measurement": point.measurement,
+        "tags": point.tags,
+        "fields": fields,
+        "timestamp": point.timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(measurement: &str, tags: &[(&str, &str)]) -> DataPoint {
+        tags.iter()
+            .fold(DataPoint::builder(measurement), |builder, (key, value)| {
+                builder.tag(*key, *value)
+            })
+            .field("value", 21.5)
+            .timestamp(1_700_000_000_000_000_000)
+            .build()
+            .unwrap()
+    }
+
+    fn topic(template: &str, point: &DataPoint) -> String {
+        render_topic(template, &decode(point).unwrap())
+    }
+
+    #[test]
+    fn the_placeholders_are_replaced_by_the_measurement_and_tags() {
+        let point = point("temperature", &[("location", "hangar-2")]);
+
+        assert_eq!(
+            topic("sensors/{location}/{measurement}", &point),
+            "sensors/hangar-2/temperature"
+        );
+        assert_eq!(
+            topic("sensors/{room}/{measurement}/raw", &point),
+            "sensors/unknown/temperature/raw"
+        );
+        // Not a placeholder without its closing brace
+        assert_eq!(topic("sensors/{location", &point), "sensors/{location");
+    }
+
+    #[test]
+    fn a_tag_value_cannot_add_levels_or_wildcards_to_the_topic() {
+        let point = point("temperature", &[("location", "hangar/2+#")]);
+
+        assert_eq!(
+            topic("sensors/{location}/{measurement}", &point),
+            "sensors/hangar_2__/temperature"
+        );
+    }
+
+    fn sink(capacity: usize) -> (MqttSink, EventLoop) {
+        // The event loop is never polled, so nothing leaves the buffer
+        let (client, event_loop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), capacity);
+        let sink = MqttSink {
+            client,
+            topic_template: "sensors/{measurement}".to_string(),
+            qos: QoS::AtLeastOnce,
+            connected: Arc::new(AtomicBool::new(false)),
+        };
+        (sink, event_loop)
+    }
+
+    #[tokio::test]
+    async fn a_full_buffer_fails_the_write_as_unavailable() {
+        let (sink, _event_loop) = sink(2);
+
+        let points = vec![point("temperature", &[]); 3];
+        let error = sink.write(points).await.unwrap_err();
+
+        assert!(!error.is_permanent());
+        assert!(
+            error.to_string().contains("dropped 1 messages"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn a_disconnected_sink_is_unhealthy() {
+        let (sink, _event_loop) = sink(2);
+
+        assert!(sink.check_health().await.is_err());
+        sink.connected.store(true, Ordering::Relaxed);
+        assert!(sink.check_health().await.is_ok());
+    }
+}
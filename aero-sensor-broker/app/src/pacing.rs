@@ -0,0 +1,119 @@
+// pacing.rs
+//
+// Spreads a large flush over part of the flush interval, configured by `[cache.pacing]`. After an
+// outage the cache holds thousands of points, and writing them back to back makes a CPU and
+// network spike that holds up the read loops of a single-core gateway. A paced flush writes them
+// in batches of `batch_size` instead, evenly spaced over `spread` of the interval: one batch
+// every `interval * spread / batches`. A backlog below `min_points` is written at once, as
+// without pacing.
+//
+// The batches still have to be written within the deadline of the flush, which a wait is cut
+// short to fit. Once the broker shuts down the waits are skipped, so that the remaining batches
+// are written right away rather than holding up the shutdown.
+//
+// The time left before the deadline is read from the clock of the cache. The waits themselves
+// are timers of tokio, which a `MockClock` does not move: the tests pause the time of tokio
+// instead, which moves the monotonic clock of `SystemClock` along with the timers.
+
+use crate::clock::Clock;
+use crate::config::PacingConfig;
+use crate::sink::{DataSink, SinkError};
+
+use influxdb2::models::DataPoint;
+use log::debug;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone, Debug)]
+pub struct Pacing {
+    batch_size: usize,
+    spread: f64,
+    min_points: usize,
+}
+
+impl Pacing {
+    pub fn new(config: &PacingConfig) -> Self {
+        Self {
+            batch_size: config.batch_size.max(1),
+            spread: config.spread,
+            min_points: config.min_points,
+        }
+    }
+
+    // The time between the batches of a flush of `points` every `interval`, or `None` when the
+    // backlog is small enough to be written at once.
+    pub fn delay(&self, points: usize, interval: Duration) -> Option<Duration> {
+        if points < self.min_points || points <= self.batch_size {
+            return None;
+        }
+        let batches = points.div_ceil(self.batch_size);
+        Some(interval.mul_f64(self.spread) / batches as u32)
+    }
+
+    // Writes `points` to `sink` in batches `delay` apart, within `deadline` on `clock`. A batch
+    // that fails for a reason worth retrying ends the flush: the batches after it are not written
+    // either, and are reported with it to be cached again. The batches the sink rejects are
+    // reported and the others written all the same.
+    pub async fn write(
+        &self,
+        sink: &dyn DataSink,
+        points: Vec<DataPoint>,
+        delay: Duration,
+        deadline: Duration,
+        clock: &dyn Clock,
+        shutdown: &CancellationToken,
+    ) -> Result<(), SinkError> {
+        let started = clock.now_monotonic();
+        let left =
+            || deadline.saturating_sub(clock.now_monotonic().saturating_duration_since(started));
+        let batches = points.len().div_ceil(self.batch_size);
+        debug!(
+            "Pacing the flush of {} points in {} batches, {:?} apart",
+            points.len(),
+            batches,
+            delay
+        );
+
+        let mut failures = Vec::new();
+        let mut remaining = points.into_iter();
+        for index in 0..batches {
+            let batch: Vec<DataPoint> = remaining.by_ref().take(self.batch_size).collect();
+            if index > 0 && !shutdown.is_cancelled() {
+                tokio::select! {
+                    _ = sleep(delay.min(left())) => {}
+                    _ = shutdown.cancelled() => debug!("Shutting down, pacing stopped"),
+                }
+            }
+
+            let result = match timeout(left(), sink.write(batch.clone())).await {
+                Ok(result) => result,
+                Err(_) => Err(SinkError::TimedOut(format!(
+                    "paced flush did not complete within {:?}",
+                    deadline
+                ))),
+            };
+            let Err(error) = result else {
+                continue;
+            };
+            let permanent = error.is_permanent();
+            failures.extend(error.into_failures(batch));
+            if !permanent {
+                let unwritten: Vec<DataPoint> = remaining.collect();
+                if !unwritten.is_empty() {
+                    let error = SinkError::Unavailable(format!(
+                        "not written, batch {} of {} failed",
+                        index + 1,
+                        batches
+                    ));
+                    failures.push((error, unwritten));
+                }
+                break;
+            }
+        }
+
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(SinkError::Partial(failures)),
+        }
+    }
+}
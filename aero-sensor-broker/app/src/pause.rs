@@ -0,0 +1,52 @@
+// pause.rs
+//
+// Lets operators suspend recording during sensor maintenance without restarting the broker.
+// While paused, the read loop keeps draining the serial port but discards every frame; the flush
+// task keeps writing whatever was cached before the pause.
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[derive(Clone, Default)]
+pub struct IngestionControl {
+    paused: Arc<AtomicBool>,
+    last_change: Arc<Mutex<Option<Change>>>,
+}
+
+// Who toggled the state last, and when.
+struct Change {
+    by: String,
+    at: DateTime<Utc>,
+}
+
+impl IngestionControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    // Pauses or resumes ingestion. Returns whether the state changed.
+    pub fn set_paused(&self, paused: bool, by: String) -> bool {
+        let changed = self.paused.swap(paused, Ordering::Relaxed) != paused;
+        if changed {
+            *self
+                .last_change
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(Change { by, at: Utc::now() });
+        }
+        changed
+    }
+
+    pub fn status(&self) -> Value {
+        let last_change = self
+            .last_change
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        json!({
+            "paused": self.is_paused(),
+            "changed_by": last_change.as_ref().map(|change| &change.by),
+            "changed_at": last_change.as_ref().map(|change| change.at.to_rfc3339()),
+        })
+    }
+}
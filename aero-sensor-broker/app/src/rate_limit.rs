@@ -0,0 +1,228 @@
+// rate_limit.rs
+//
+// Client-side rate limiting of writes. A shared InfluxDB instance may cap the write rate per
+// token, and after an outage the backlog would otherwise be sent in one burst and answered
+// with 429s. `RateLimitedSink` wraps another sink, splits each batch into chunks no larger than
+// the burst size, and waits on a token bucket before writing each chunk. Only the flush task
+// writes to sinks, so the waiting never holds up the serial loop or the HTTP server.
+
+use crate::config::{RateLimitConfig, MIN_POINTS_PER_SECOND};
+use crate::sink::{DataSink, SinkError};
+
+use async_trait::async_trait;
+use influxdb2::models::DataPoint;
+use log::debug;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+// Longest a single chunk is held back, whatever the configured rate. With a rate above
+// `MIN_POINTS_PER_SECOND` and a burst of thousands of points a chunk can still add up to hours,
+// which would stall the flush task past any deadline.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+// Token bucket refilled at `rate` points per second, holding at most `burst` points. Taking more
+// tokens than there are leaves the bucket in debt, repaid by the next refills.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+
+    // Takes `points` tokens and returns how long to wait before they are all there.
+    fn take(&mut self, points: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= points as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64(-self.tokens / self.rate)
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT)
+    }
+}
+
+pub struct RateLimitedSink {
+    inner: Arc<dyn DataSink>,
+    bucket: Mutex<TokenBucket>,
+    burst: usize,
+    throttled_us: AtomicU64,
+}
+
+impl RateLimitedSink {
+    pub fn new(inner: Arc<dyn DataSink>, config: &RateLimitConfig) -> Self {
+        let burst = config.burst.max(1);
+        Self {
+            inner,
+            bucket: Mutex::new(TokenBucket {
+                rate: config.points_per_second.max(MIN_POINTS_PER_SECOND),
+                burst: burst as f64,
+                tokens: burst as f64,
+                refilled: Instant::now(),
+            }),
+            burst,
+            throttled_us: AtomicU64::new(0),
+        }
+    }
+
+    // Waits until `points` tokens are available and takes them. The lock is held while
+    // waiting, so that the chunks go out in order.
+    async fn acquire(&self, points: usize) {
+        let mut bucket = self.bucket.lock().await;
+        let wait = bucket.take(points, Instant::now());
+        if !wait.is_zero() {
+            debug!(
+                "Rate limit reached, waiting {:?} before writing {} points",
+                wait, points
+            );
+            sleep(wait).await;
+            self.throttled_us
+                .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    // Total time writes were held back so far.
+    pub fn throttled(&self) -> Duration {
+        Duration::from_micros(self.throttled_us.load(Ordering::Relaxed))
+    }
+}
+
+#[async_trait]
+impl DataSink for RateLimitedSink {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let chunks: Vec<Vec<DataPoint>> = points.chunks(self.burst).map(<[_]>::to_vec).collect();
+        let single_chunk = chunks.len() == 1;
+
+        let mut failures = Vec::new();
+        for chunk in chunks {
+            self.acquire(chunk.len()).await;
+            if let Err(e) = self.inner.write(chunk.clone()).await {
+                failures.extend(e.into_failures(chunk));
+            }
+        }
+
+        match failures.len() {
+            0 => Ok(()),
+            1 if single_chunk => Err(failures.remove(0).0),
+            _ => Err(SinkError::Partial(failures)),
+        }
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        self.inner.check_health().await
+    }
+
+    fn health_age(&self) -> Option<Duration> {
+        self.inner.health_age()
+    }
+
+    fn parts(&self) -> Vec<Arc<dyn DataSink>> {
+        self.inner.parts()
+    }
+
+    async fn close(&self) -> Result<(), SinkError> {
+        self.inner.close().await
+    }
+
+    fn status(&self) -> Value {
+        json!({
+            "throttled_secs": self.throttled().as_secs_f64(),
+            "sink": self.inner.status(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::DryRunSink;
+
+    fn full_bucket(rate: f64, burst: usize) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn a_full_bucket_lets_a_burst_through_at_once() {
+        let mut bucket = full_bucket(10.0, 100);
+        let now = Instant::now();
+        assert_eq!(bucket.take(60, now), Duration::ZERO);
+        assert_eq!(bucket.take(40, now), Duration::ZERO);
+        // The next point has to wait for a tenth of a second of refill
+        assert_eq!(bucket.take(1, now), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn the_bucket_refills_at_the_rate_up_to_the_burst() {
+        let mut bucket = full_bucket(10.0, 100);
+        let start = Instant::now();
+        bucket.take(100, start);
+
+        assert_eq!(
+            bucket.take(20, start + Duration::from_secs(2)),
+            Duration::ZERO
+        );
+        // An hour refills no more than the burst
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(bucket.take(100, later), Duration::ZERO);
+        assert_eq!(bucket.take(10, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_wait_is_capped() {
+        let mut bucket = full_bucket(MIN_POINTS_PER_SECOND, 10_000);
+        let now = Instant::now();
+        bucket.take(10_000, now);
+        assert_eq!(bucket.take(10_000, now), MAX_WAIT);
+
+        // Even where the division overflows
+        let mut bucket = full_bucket(f64::MIN_POSITIVE, 1);
+        assert_eq!(bucket.take(2, now), MAX_WAIT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_time_held_back_is_accounted() {
+        let config = RateLimitConfig {
+            points_per_second: 100.0,
+            burst: 50,
+        };
+        let sink = RateLimitedSink::new(Arc::new(DryRunSink), &config);
+        let points = |count| {
+            (0..count)
+                .map(|i| {
+                    DataPoint::builder("temperature")
+                        .field("value", i as f64)
+                        .build()
+                        .unwrap()
+                })
+                .collect()
+        };
+
+        sink.write(points(50)).await.unwrap();
+        assert_eq!(sink.throttled(), Duration::ZERO);
+
+        // Two chunks of 50, each waiting half a second for its tokens
+        let started = Instant::now();
+        sink.write(points(100)).await.unwrap();
+        assert_eq!(sink.throttled(), Duration::from_secs(1));
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+}
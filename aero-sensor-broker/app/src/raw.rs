@@ -0,0 +1,130 @@
+// raw.rs
+//
+// The raw tier: the samples of every source, as parsed and at their own timestamps, written to
+// a bucket of their own next to the averages, so that an incident can be looked at sample by
+// sample while the averages are kept for the long run. The samples go through a cache and a
+// periodic flush of their own, with the same retries and dead-lettering as the averages.
+//
+// `max_rate_hz` caps the samples of each series: one is kept only once 1/max_rate_hz passed
+// since the previous one kept, going by the timestamps of the samples rather than the time they
+// were read at, so that a burst of buffered samples is thinned out like a live stream.
+
+use crate::cache::Cache;
+use crate::config::RawConfig;
+use crate::data_manipulation::{MyDataPoint, StaticFields};
+
+use influxdb2::models::DataPoint;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone)]
+pub struct RawTier {
+    cache: Cache,
+    // Nanoseconds between two samples kept of a series, `None` when every sample is kept.
+    min_interval_ns: Option<i64>,
+    static_fields: StaticFields,
+}
+
+impl RawTier {
+    pub fn new(config: &RawConfig, cache: Cache, static_fields: StaticFields) -> Self {
+        let min_interval_ns =
+            (config.max_rate_hz > 0.0).then(|| (1e9 / config.max_rate_hz).round() as i64);
+        Self {
+            cache,
+            min_interval_ns,
+            static_fields,
+        }
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    // The sampler of a read loop, which keeps track of the series of its source.
+    pub fn sampler(&self) -> RawSampler {
+        RawSampler {
+            tier: self.clone(),
+            last_kept: HashMap::new(),
+        }
+    }
+}
+
+pub struct RawSampler {
+    tier: RawTier,
+    // Timestamp of the last sample kept, per series.
+    last_kept: HashMap<(String, BTreeMap<String, String>), i64>,
+}
+
+impl RawSampler {
+    // Caches the samples the rate cap lets through.
+    pub async fn add(&mut self, points: &[MyDataPoint]) {
+        // A sample whose readings are all missing has nothing to write
+        let static_fields = self.tier.static_fields.clone();
+        let raw_points: Vec<DataPoint> = points
+            .iter()
+            .filter(|point| !point.get_fields().is_empty() && self.keep(point))
+            .filter_map(|point| match raw_point(point, &static_fields) {
+                Ok(raw_point) => Some(raw_point),
+                Err(e) => {
+                    warn!(
+                        "Failed to build the raw point of {}: {}",
+                        point.get_measurement(),
+                        e
+                    );
+                    None
+                }
+            })
+            .collect();
+        if !raw_points.is_empty() {
+            self.tier.cache.add(raw_points).await;
+        }
+    }
+
+    fn keep(&mut self, point: &MyDataPoint) -> bool {
+        let Some(timestamp) = point.get_timestamp() else {
+            return false;
+        };
+        let Some(min_interval_ns) = self.tier.min_interval_ns else {
+            return true;
+        };
+        let series = (
+            point.get_measurement().to_string(),
+            point.get_tags().clone(),
+        );
+        match self.last_kept.get(&series) {
+            // A clock stepping back starts the series over
+            Some(&last) if (0..min_interval_ns).contains(&(timestamp - last)) => false,
+            _ => {
+                self.last_kept.insert(series, timestamp);
+                true
+            }
+        }
+    }
+}
+
+// The sample as a point of its own: the fields and tags it was parsed with, at its timestamp,
+// plus the static fields it does not have.
+pub(crate) fn raw_point(
+    point: &MyDataPoint,
+    static_fields: &StaticFields,
+) -> Result<DataPoint, String> {
+    let mut builder = DataPoint::builder(point.get_measurement());
+    if let Some(timestamp) = point.get_timestamp() {
+        builder = builder.timestamp(timestamp);
+    }
+    let builder = point
+        .get_fields()
+        .iter()
+        .fold(builder, |builder, (name, value)| {
+            builder.field(name, value.clone())
+        });
+    let builder = static_fields.add_to(point.get_measurement(), builder, |name| {
+        point.get_fields().contains_key(name)
+    });
+    point
+        .get_tags()
+        .iter()
+        .fold(builder, |builder, (key, value)| builder.tag(key, value))
+        .build()
+        .map_err(|e| e.to_string())
+}
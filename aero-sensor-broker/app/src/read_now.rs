@@ -0,0 +1,122 @@
+// read_now.rs
+//
+// `POST /admin/read-now` asks the device of a source for a reading outside of its cadence with
+// the `READ_NOW` command of the firmware, e.g. to check a probe right after installing it. The
+// read loop stays the only reader of the device: the command goes through the command queue,
+// and the frame it triggers is read, parsed, and recorded by the read loop like any other, which
+// then hands the parsed points to the requests waiting for a frame. The next frame parsed
+// answers them, be it the one triggered by the command or a regular one read in the meantime.
+
+use crate::data_manipulation::MyDataPoint;
+use crate::errors::AppError;
+use crate::latest::field_to_json;
+use crate::source::SensorSource;
+
+use serde_json::{json, Value};
+use std::sync::{Mutex, PoisonError};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+
+pub const COMMAND: &str = "READ_NOW";
+
+// Lines read from the device that a response reports at most.
+const MAX_LINES: usize = 32;
+
+// The readings of the next frame parsed, or why it was rejected.
+type Outcome = Result<Vec<Value>, String>;
+
+#[derive(Default)]
+pub struct ReadNow {
+    waiting: Mutex<Vec<oneshot::Sender<Outcome>>>,
+}
+
+pub enum ReadNowError {
+    // The command could not be sent, or the device refused it.
+    Command(String),
+    // The next frame was rejected by the parser.
+    Rejected { error: String, lines: Vec<String> },
+    // No frame was parsed in time.
+    TimedOut { lines: Vec<String> },
+}
+
+impl ReadNow {
+    // Hands the points parsed from a frame, or the error rejecting it, to the waiting requests.
+    pub fn deliver(&self, parsed: Result<&[MyDataPoint], &AppError>) {
+        let waiting = {
+            let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+            if waiting.is_empty() {
+                return;
+            }
+            std::mem::take(&mut *waiting)
+        };
+        let outcome: Outcome = parsed
+            .map(|points| points.iter().map(reading).collect())
+            .map_err(|e| e.to_string());
+        for waiter in waiting {
+            // Sending only fails when the request timed out in the meantime.
+            let _ = waiter.send(outcome.clone());
+        }
+    }
+
+    // Sends `READ_NOW` to `device` and waits up to `deadline` for the next frame the read loop
+    // parses. Returns its readings and the lines read from the device meanwhile.
+    pub async fn request(
+        &self,
+        device: &dyn SensorSource,
+        deadline: Duration,
+    ) -> Result<Value, ReadNowError> {
+        // Subscribed and waiting before the command is sent, not to miss a fast answer
+        let mut lines = device.subscribe_raw_lines();
+        let (waiter, mut parsed) = oneshot::channel();
+        {
+            let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+            waiting.retain(|waiter| !waiter.is_closed());
+            waiting.push(waiter);
+        }
+        device
+            .write_command(COMMAND)
+            .await
+            .map_err(|e| ReadNowError::Command(e.to_string()))?;
+
+        let mut seen = Vec::new();
+        let wait = async {
+            loop {
+                tokio::select! {
+                    outcome = &mut parsed => return outcome.ok(),
+                    line = lines.recv() => match line {
+                        Ok(line) if seen.len() < MAX_LINES => seen.push(line),
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return (&mut parsed).await.ok(),
+                    },
+                }
+            }
+        };
+        let parsed = timeout(deadline, wait).await;
+        // The frame itself was broadcast before it was parsed
+        while let Ok(line) = lines.try_recv() {
+            if seen.len() < MAX_LINES {
+                seen.push(line);
+            }
+        }
+        match parsed {
+            Ok(Some(Ok(readings))) => Ok(json!({ "readings": readings, "lines": seen })),
+            Ok(Some(Err(error))) => Err(ReadNowError::Rejected { error, lines: seen }),
+            Ok(None) | Err(_) => Err(ReadNowError::TimedOut { lines: seen }),
+        }
+    }
+}
+
+fn reading(point: &MyDataPoint) -> Value {
+    let fields: serde_json::Map<String, Value> = point
+        .get_fields()
+        .iter()
+        .map(|(field, value)| (field.clone(), field_to_json(value)))
+        .collect();
+    json!({
+        "measurement": point.get_measurement(),
+        "tags": point.get_tags(),
+        "fields": fields,
+        "timestamp": point.get_timestamp(),
+    })
+}
@@ -0,0 +1,263 @@
+// recorder.rs
+//
+// Records every raw line the sources read, valid frame or not, so that a site's data can be
+// replayed later with the `replay` subcommand. Each source subscribes to the raw lines of its
+// device and writes them to files of its own in the configured directory, in the recording
+// format of `replay`: a header line, then each raw line prefixed with the milliseconds elapsed
+// since the file was started. A file is closed and a new one started once it reaches its size
+// or age limit, and the oldest files are pruned when the directory outgrows its budget.
+//
+// Recording must never get in the way of the pipeline: lines are received from a broadcast
+// channel, so a slow disk only makes the recorder skip lines, and the first write error (e.g.
+// a full disk) is logged once and stops recording altogether.
+
+use crate::config::RecordConfig;
+use crate::replay::recording_header;
+use crate::source::Source;
+
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+const FILE_PREFIX: &str = "raw-";
+const FILE_EXTENSION: &str = "rec";
+
+pub struct Recorder {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+// What the recording tasks of all sources share.
+struct RecordingDirectory {
+    config: RecordConfig,
+    directory: PathBuf,
+    // Names of the files being written, which pruning leaves alone.
+    open_files: Mutex<BTreeSet<String>>,
+    // Set by the first write error, which stops every recording task.
+    disabled: AtomicBool,
+}
+
+// The file a source is currently recording to.
+struct RecordingFile {
+    name: String,
+    writer: BufWriter<File>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Recorder {
+    // Starts recording the raw lines of every source until `shutdown` is cancelled. Returns
+    // `None`, after logging why, when the directory cannot be created.
+    pub fn start(
+        config: &RecordConfig,
+        sources: &[Source],
+        shutdown: CancellationToken,
+    ) -> Option<Self> {
+        if let Err(e) = fs::create_dir_all(&config.path) {
+            error!(
+                "Failed to create recording directory {}, raw lines are not recorded: {}",
+                config.path, e
+            );
+            return None;
+        }
+        info!("Recording raw lines to {}", config.path);
+        let directory = Arc::new(RecordingDirectory {
+            config: config.clone(),
+            directory: PathBuf::from(&config.path),
+            open_files: Mutex::new(BTreeSet::new()),
+            disabled: AtomicBool::new(false),
+        });
+        let tasks = sources
+            .iter()
+            .map(|source| {
+                let lines = source.device().subscribe_raw_lines();
+                tokio::spawn(record(
+                    source.name().to_string(),
+                    lines,
+                    directory.clone(),
+                    shutdown.clone(),
+                ))
+            })
+            .collect();
+        Some(Self { tasks })
+    }
+
+    // Waits for every source to write the lines already received and close its file.
+    pub async fn shutdown(self) -> Result<(), String> {
+        for task in self.tasks {
+            task.await
+                .map_err(|e| format!("recording task failed: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+// Writes the lines of the source `name` until shutdown, or until recording is disabled.
+async fn record(
+    name: String,
+    mut lines: Receiver<String>,
+    directory: Arc<RecordingDirectory>,
+    shutdown: CancellationToken,
+) {
+    let mut file = None;
+    loop {
+        let line = tokio::select! {
+            line = lines.recv() => line,
+            // Lines received before the shutdown are still written
+            _ = shutdown.cancelled() => match lines.try_recv() {
+                Ok(line) => Ok(line),
+                Err(_) => break,
+            },
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Recording of source {} fell behind, {} lines were not recorded",
+                    name, skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if directory.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = directory.write(&name, &mut file, &line) {
+            if !directory.disabled.swap(true, Ordering::Relaxed) {
+                error!(
+                    "Failed to record raw lines to {}, recording is disabled: {}",
+                    directory.directory.display(),
+                    e
+                );
+            }
+            return;
+        }
+    }
+    if let Some(file) = file {
+        if let Err(e) = directory.close(file) {
+            warn!("Failed to close the recording of source {}: {}", name, e);
+        }
+    }
+}
+
+impl RecordingDirectory {
+    // Appends the line to the current file of the source, starting a new file first when there
+    // is none yet or the current one is full or too old.
+    fn write(&self, source: &str, file: &mut Option<RecordingFile>, line: &str) -> io::Result<()> {
+        let full = file.as_ref().is_some_and(|file| {
+            file.bytes >= self.config.max_file_bytes
+                || file.started.elapsed() >= Duration::from_secs(self.config.max_file_secs)
+        });
+        if full {
+            if let Some(full) = file.take() {
+                self.close(full)?;
+            }
+        }
+        let file = match file {
+            Some(file) => file,
+            None => file.insert(self.open(source)?),
+        };
+
+        let record = format!("{} {}\n", file.started.elapsed().as_millis(), line);
+        file.writer.write_all(record.as_bytes())?;
+        file.bytes += record.len() as u64;
+        Ok(())
+    }
+
+    fn open(&self, source: &str) -> io::Result<RecordingFile> {
+        let start = Utc::now();
+        let name = format!(
+            "{}{}-{}.{}",
+            FILE_PREFIX,
+            start.format("%Y%m%dT%H%M%S%.3fZ"),
+            file_safe(source),
+            FILE_EXTENSION
+        );
+        let mut writer = BufWriter::new(File::create(self.directory.join(&name))?);
+        let header = format!("{}\n", recording_header(start));
+        writer.write_all(header.as_bytes())?;
+        debug!("Recording source {} to {}", source, name);
+        self.open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.clone());
+        Ok(RecordingFile {
+            name,
+            writer,
+            started: Instant::now(),
+            bytes: header.len() as u64,
+        })
+    }
+
+    // Writes out what is buffered, then prunes the directory now that the file is complete.
+    fn close(&self, mut file: RecordingFile) -> io::Result<()> {
+        self.open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&file.name);
+        file.writer.flush()?;
+        if let Err(e) = self.prune() {
+            warn!("Failed to prune recording directory: {}", e);
+        }
+        Ok(())
+    }
+
+    // Deletes the oldest files, except those being written, until the directory fits in its
+    // size budget. File names sort chronologically.
+    fn prune(&self) -> io::Result<()> {
+        let mut files = fs::read_dir(&self.directory)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let is_recording = name.starts_with(FILE_PREFIX)
+                    && Path::new(&name)
+                        .extension()
+                        .is_some_and(|e| e == FILE_EXTENSION);
+                let size = entry.metadata().ok()?.len();
+                is_recording.then_some((name, size))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum();
+
+        let open_files = self
+            .open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for (name, size) in files {
+            if total <= self.config.max_total_bytes {
+                break;
+            }
+            if open_files.contains(&name) {
+                continue;
+            }
+            info!("Recording directory over budget, removing {}", name);
+            fs::remove_file(self.directory.join(&name))?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+// The source name with anything but letters, digits, `-`, and `_` replaced, for file names.
+fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
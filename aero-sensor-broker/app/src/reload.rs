@@ -0,0 +1,238 @@
+// reload.rs
+//
+// Reloads the configuration files without a restart, on SIGHUP or `POST /admin/reload`. The new
+// settings are validated and compared with the running ones: changes to the aggregation, parser,
+// and logging settings and to the flush interval are applied to the running components through watch
+// channels, every other change is only logged as requiring a restart. A reload that fails
+// validation leaves the running settings untouched. Inline secrets are compared in their
+// redacted form, so changing one goes unreported.
+
+use crate::config::{load_settings, AggregationConfig, ConfigSettings, ConfigSource, ParserConfig};
+use crate::logging;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+// Settings applied without a restart, as dotted keys; a key covers everything below it.
+const RELOADABLE: [&str; 4] = [
+    "aggregation",
+    "parser",
+    "cache.flush_interval_secs",
+    "logging",
+];
+
+// The reloadable settings the read loop works with.
+#[derive(Clone)]
+pub struct Tunables {
+    pub aggregation: AggregationConfig,
+    pub parser: ParserConfig,
+}
+
+// What the last reload did.
+#[derive(Serialize, Clone)]
+pub struct ReloadOutcome {
+    pub at: DateTime<Utc>,
+    pub trigger: String,
+    pub succeeded: bool,
+    // Keys whose new value is in effect.
+    pub applied: Vec<String>,
+    // Keys that changed but only take effect after a restart.
+    pub requires_restart: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Reloader {
+    source: Arc<ConfigSource>,
+    // `--dry-run` applies on top of whatever the files say.
+    dry_run: bool,
+    // The running settings, reloadable sections updated by every successful reload. Serialized,
+    // so inline secrets are already redacted.
+    running: Arc<watch::Sender<Value>>,
+    tunables: Arc<watch::Sender<Tunables>>,
+    flush_interval: Arc<watch::Sender<Duration>>,
+    last_outcome: Arc<Mutex<Option<ReloadOutcome>>>,
+}
+
+impl Reloader {
+    pub fn new(source: ConfigSource, dry_run: bool, settings: &ConfigSettings) -> Self {
+        let (tunables, _) = watch::channel(Tunables {
+            aggregation: settings.aggregation.clone(),
+            parser: settings.parser.clone(),
+        });
+        let (flush_interval, _) =
+            watch::channel(Duration::from_secs(settings.cache.flush_interval_secs));
+        let (running, _) = watch::channel(settings.redacted());
+        Self {
+            source: Arc::new(source),
+            dry_run,
+            running: Arc::new(running),
+            tunables: Arc::new(tunables),
+            flush_interval: Arc::new(flush_interval),
+            last_outcome: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn tunables(&self) -> watch::Receiver<Tunables> {
+        self.tunables.subscribe()
+    }
+
+    pub fn flush_interval(&self) -> watch::Receiver<Duration> {
+        self.flush_interval.subscribe()
+    }
+
+    // The settings in effect, secrets redacted, as of the last successful reload.
+    pub fn running(&self) -> watch::Receiver<Value> {
+        self.running.subscribe()
+    }
+
+    pub fn last_outcome(&self) -> Option<ReloadOutcome> {
+        self.last_outcome
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Loads the configuration files again and applies what can be applied. `trigger` says what
+    // asked for the reload, for the logs and the admin route.
+    pub fn reload(&self, trigger: &str) -> ReloadOutcome {
+        info!("Reloading the configuration ({})", trigger);
+        let mut outcome = ReloadOutcome {
+            at: Utc::now(),
+            trigger: trigger.to_string(),
+            succeeded: false,
+            applied: Vec::new(),
+            requires_restart: Vec::new(),
+            error: None,
+        };
+
+        match load_settings(&self.source) {
+            Ok(mut settings) => {
+                settings.dry_run |= self.dry_run;
+                self.apply(settings, &mut outcome);
+                outcome.succeeded = true;
+            }
+            Err(e) => {
+                error!(
+                    "Configuration reload failed, keeping the running settings: {}",
+                    e
+                );
+                outcome.error = Some(e.to_string());
+            }
+        }
+
+        *self
+            .last_outcome
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(outcome.clone());
+        outcome
+    }
+
+    fn apply(&self, settings: ConfigSettings, outcome: &mut ReloadOutcome) {
+        let new = settings.redacted();
+        let mut running = self.running.borrow().clone();
+
+        let mut changed = Vec::new();
+        changed_keys(&running, &new, "", &mut changed);
+        let (applied, requires_restart): (Vec<String>, Vec<String>) =
+            changed.into_iter().partition(|key| is_reloadable(key));
+
+        for key in &requires_restart {
+            warn!("{} changed, this requires a restart", key);
+        }
+        if applied.is_empty() {
+            info!("Configuration reloaded, no reloadable setting changed");
+        } else {
+            info!("Configuration reloaded, applied: {}", applied.join(", "));
+        }
+
+        for key in RELOADABLE {
+            let pointer = format!("/{}", key.replace('.', "/"));
+            if let (Some(slot), Some(value)) =
+                (running.pointer_mut(&pointer), new.pointer(&pointer))
+            {
+                *slot = value.clone();
+            }
+        }
+        self.running.send_replace(running);
+        if applied
+            .iter()
+            .any(|key| key.starts_with("aggregation.") || key.starts_with("parser."))
+        {
+            self.tunables.send_replace(Tunables {
+                aggregation: settings.aggregation,
+                parser: settings.parser,
+            });
+        }
+        if applied.iter().any(|key| key.starts_with("logging.")) {
+            logging::configure(&settings.logging);
+        }
+        self.flush_interval.send_if_modified(|interval| {
+            let new_interval = Duration::from_secs(settings.cache.flush_interval_secs);
+            let modified = *interval != new_interval;
+            *interval = new_interval;
+            modified
+        });
+
+        outcome.applied = applied;
+        outcome.requires_restart = requires_restart;
+    }
+}
+
+fn is_reloadable(key: &str) -> bool {
+    RELOADABLE.iter().any(|reloadable| {
+        key == *reloadable
+            || key
+                .strip_prefix(reloadable)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+// Collects the dotted keys whose values differ. Objects are compared field by field, anything
+// else as a whole. Only keys are reported, so secrets never end up in the logs.
+fn changed_keys(old: &Value, new: &Value, prefix: &str, changed: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let mut names: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let key = match prefix {
+                    "" => name.clone(),
+                    _ => format!("{}.{}", prefix, name),
+                };
+                let old_field = old_fields.get(name).unwrap_or(&Value::Null);
+                let new_field = new_fields.get(name).unwrap_or(&Value::Null);
+                changed_keys(old_field, new_field, &key, changed);
+            }
+        }
+        _ if old != new => changed.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+// Reloads the configuration on every SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_sighup(reloader: Reloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while sighup.recv().await.is_some() {
+            reloader.reload("SIGHUP");
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_reloader: Reloader) {}
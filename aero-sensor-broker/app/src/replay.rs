@@ -0,0 +1,370 @@
+// replay.rs
+//
+// Replays a recording of the raw lines of a device through the production pipeline, to
+// reproduce locally what a site saw. A recording is a text file starting with a header line
+// that names the format version and when the recording started, followed by one line per raw
+// line read from the device, prefixed with the milliseconds elapsed since the start:
+//
+//   # sensorflow-recording v1 start=2026-10-16T13:27:43.123Z
+//   0 [{"type": "temperature", "value": 21.5}]
+//   1013 <21.6|40.1|87>
+//
+// The `Replayer` is the device of the replayed source: it hands out the recorded lines with
+// their recorded spacing, scaled by the replay speed, and cancels its token once the recording
+// is exhausted, which shuts the pipeline down like a signal would. The answer to `PROTOCOL?`
+// recorded when the device was opened sets the protocol the frames after it are parsed with.
+
+use crate::arduino::is_valid_frame;
+use crate::data_manipulation::Protocol;
+use crate::errors::AppError;
+use crate::frame_log::FrameLog;
+use crate::metrics::{Metrics, SourceMetrics};
+use crate::sink::{DataSink, SinkError};
+use crate::source::SensorSource;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb2::models::DataPoint;
+use serde_json::Value;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{sleep_until, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use log::{debug, info, warn};
+
+// Version of the recording format, in the header line.
+const RECORDING_VERSION: u32 = 1;
+
+const HEADER_PREFIX: &str = "# sensorflow-recording";
+
+// Raw lines buffered per subscriber before the oldest are dropped.
+const RAW_LINES_CAPACITY: usize = 256;
+
+// The header line of a recording started at `start`.
+pub fn recording_header(start: DateTime<Utc>) -> String {
+    format!(
+        "{} v{} start={}",
+        HEADER_PREFIX,
+        RECORDING_VERSION,
+        start.to_rfc3339_opts(SecondsFormat::Millis, true)
+    )
+}
+
+// The start of the recording, from its header line.
+fn parse_header(line: &str) -> Result<DateTime<Utc>, String> {
+    let fields = line.strip_prefix(HEADER_PREFIX).ok_or_else(|| {
+        format!(
+            "not a recording, the header must start with '{}'",
+            HEADER_PREFIX
+        )
+    })?;
+    let mut fields = fields.split_whitespace();
+    match fields.next() {
+        Some(version) if version == format!("v{}", RECORDING_VERSION) => {}
+        Some(version) => return Err(format!("unsupported recording version '{}'", version)),
+        None => return Err("header without a version".to_string()),
+    }
+    let start = fields
+        .find_map(|field| field.strip_prefix("start="))
+        .ok_or_else(|| "header without a start time".to_string())?;
+    DateTime::parse_from_rfc3339(start)
+        .map(|start| start.with_timezone(&Utc))
+        .map_err(|e| format!("invalid start time '{}': {}", start, e))
+}
+
+// The offset in milliseconds and the raw line of a recorded line.
+fn parse_line(line: &str) -> Result<(u64, &str), String> {
+    let (offset, raw_line) = line
+        .split_once(' ')
+        .ok_or_else(|| "line without an offset".to_string())?;
+    let offset = offset
+        .parse()
+        .map_err(|e| format!("invalid offset '{}': {}", offset, e))?;
+    Ok((offset, raw_line))
+}
+
+pub struct Replayer {
+    path: PathBuf,
+    state: Mutex<ReplayState>,
+    // Replay speed relative to the recording; 0 replays without waiting.
+    speed: f64,
+    // Cancelled once every line was replayed.
+    finished: CancellationToken,
+    // Recorded lines that could not be read.
+    errors: AtomicU64,
+    // Unix time in milliseconds of the last valid frame, 0 before the first one.
+    last_frame_ms: AtomicU64,
+    // Number of the protocol the recorded device answered, 0 until an answer was replayed.
+    protocol: AtomicU8,
+    metrics: Arc<Metrics>,
+    source_metrics: Arc<SourceMetrics>,
+    raw_lines: broadcast::Sender<String>,
+    // A replay runs without the HTTP server, it keeps no lines.
+    frames: FrameLog,
+}
+
+struct ReplayState {
+    lines: Lines<BufReader<File>>,
+    // Line number of the last line read, for error messages.
+    line_number: usize,
+    started: Instant,
+}
+
+impl Replayer {
+    // Opens the recording and checks its header. Frames are counted for the source `name`.
+    pub fn open(
+        path: &Path,
+        name: &str,
+        speed: f64,
+        finished: CancellationToken,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, AppError> {
+        let file = File::open(path).map_err(|e| {
+            AppError::Config(format!("cannot open recording {}: {}", path.display(), e))
+        })?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let start = parse_header(&header).map_err(|e| {
+            AppError::Config(format!("invalid recording {}: {}", path.display(), e))
+        })?;
+        info!(
+            "Replaying {} recorded at {} as source {}",
+            path.display(),
+            start,
+            name
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(ReplayState {
+                lines,
+                line_number: 1,
+                started: Instant::now(),
+            }),
+            speed,
+            finished,
+            errors: AtomicU64::new(0),
+            last_frame_ms: AtomicU64::new(0),
+            protocol: AtomicU8::new(0),
+            source_metrics: metrics.source(name),
+            metrics,
+            raw_lines: broadcast::channel(RAW_LINES_CAPACITY).0,
+            frames: FrameLog::new(0),
+        })
+    }
+
+    // Recorded lines skipped because they could not be read.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    // The next recorded raw line, once its time came, or `None` at the end of the recording.
+    async fn next_line(&self) -> Result<Option<String>, AppError> {
+        let mut state = self.state.lock().await;
+        loop {
+            let Some(line) = state.lines.next().transpose()? else {
+                return Ok(None);
+            };
+            state.line_number += 1;
+            let (offset, raw_line) = match parse_line(&line) {
+                Ok(recorded) => recorded,
+                Err(e) => {
+                    warn!(
+                        "Skipping line {} of {}: {}",
+                        state.line_number,
+                        self.path.display(),
+                        e
+                    );
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            if self.speed > 0.0 {
+                let delay = Duration::from_millis(offset).div_f64(self.speed);
+                sleep_until(state.started + delay).await;
+            }
+            return Ok(Some(raw_line.trim().to_string()));
+        }
+    }
+}
+
+#[async_trait]
+impl SensorSource for Replayer {
+    async fn read_data(&self) -> Result<String, AppError> {
+        loop {
+            let Some(line) = self.next_line().await? else {
+                info!("End of recording {}", self.path.display());
+                self.finished.cancel();
+                // The read loop stops on the cancelled token
+                return std::future::pending().await;
+            };
+            // Sending only fails when there is no subscriber.
+            let _ = self.raw_lines.send(line.clone());
+            if let Some(protocol) = Protocol::from_answer(&line) {
+                debug!("Replayed device speaks protocol {}", protocol);
+                self.protocol.store(protocol.number(), Ordering::Relaxed);
+                continue;
+            }
+            self.metrics.frames_received.fetch_add(1, Ordering::Relaxed);
+            self.source_metrics
+                .frames_received
+                .fetch_add(1, Ordering::Relaxed);
+            if !is_valid_frame(&line) {
+                warn!("Invalid data format: '{}'", line);
+                self.metrics.frames_invalid.fetch_add(1, Ordering::Relaxed);
+                self.source_metrics
+                    .frames_invalid
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            debug!("Replayed frame: '{}'", line);
+            self.last_frame_ms
+                .store(Utc::now().timestamp_millis() as u64, Ordering::Relaxed);
+            return Ok(line);
+        }
+    }
+
+    // There is nothing to reopen.
+    async fn reconnect(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn write_command(&self, _command: &str) -> Result<(), AppError> {
+        Err(AppError::Device(
+            "a replayed source does not accept commands".to_string(),
+        ))
+    }
+
+    fn subscribe_raw_lines(&self) -> broadcast::Receiver<String> {
+        self.raw_lines.subscribe()
+    }
+
+    async fn check_health(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn health_age(&self) -> Option<Duration> {
+        None
+    }
+
+    fn source_metrics(&self) -> &SourceMetrics {
+        &self.source_metrics
+    }
+
+    fn port_name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn recent_frames(&self) -> &FrameLog {
+        &self.frames
+    }
+
+    fn protocol(&self) -> Option<Protocol> {
+        Protocol::from_number(self.protocol.load(Ordering::Relaxed))
+    }
+
+    fn last_frame_age(&self) -> Option<Duration> {
+        match self.last_frame_ms.load(Ordering::Relaxed) {
+            0 => None,
+            at => {
+                let now = Utc::now().timestamp_millis() as u64;
+                Some(Duration::from_millis(now.saturating_sub(at)))
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
+
+// What a replay did, printed once it is over.
+pub struct ReplaySummary {
+    pub recording: PathBuf,
+    pub frames_read: u64,
+    pub frames_invalid: u64,
+    pub frames_rejected: u64,
+    pub points_parsed: u64,
+    pub points_written: u64,
+    pub unreadable_lines: u64,
+    // Whether the replay stopped early or points could not be written.
+    pub failed: bool,
+}
+
+impl fmt::Display for ReplaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Replayed {}", self.recording.display())?;
+        writeln!(f, "  frames read:      {}", self.frames_read)?;
+        writeln!(f, "  frames invalid:   {}", self.frames_invalid)?;
+        writeln!(f, "  frames rejected:  {}", self.frames_rejected)?;
+        writeln!(f, "  points parsed:    {}", self.points_parsed)?;
+        writeln!(f, "  points written:   {}", self.points_written)?;
+        write!(f, "  unreadable lines: {}", self.unreadable_lines)
+    }
+}
+
+// Counts the points the wrapped sink accepted, for the summary of the replay.
+pub struct CountingSink {
+    inner: Arc<dyn DataSink>,
+    written: AtomicU64,
+}
+
+impl CountingSink {
+    pub fn new(inner: Arc<dyn DataSink>) -> Self {
+        Self {
+            inner,
+            written: AtomicU64::new(0),
+        }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl DataSink for CountingSink {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let total = points.len();
+        let result = self.inner.write(points).await;
+        let failed = match &result {
+            Ok(()) => 0,
+            Err(SinkError::Partial(failures)) => {
+                failures.iter().map(|(_, points)| points.len()).sum()
+            }
+            Err(_) => total,
+        };
+        self.written
+            .fetch_add(total.saturating_sub(failed) as u64, Ordering::Relaxed);
+        result
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        self.inner.check_health().await
+    }
+
+    fn health_age(&self) -> Option<Duration> {
+        self.inner.health_age()
+    }
+
+    fn parts(&self) -> Vec<Arc<dyn DataSink>> {
+        self.inner.parts()
+    }
+
+    async fn close(&self) -> Result<(), SinkError> {
+        self.inner.close().await
+    }
+
+    fn status(&self) -> Value {
+        self.inner.status()
+    }
+}
@@ -1,30 +1,265 @@
 // routes.rs
 //
 // This module defines the HTTP routes for the application, particularly for health checks
-// that verify the status of the Arduino connection and the InfluxDB connection.
+// that verify the status of the Arduino connection and the InfluxDB connection, write statistics,
+// and the admin routes used to inspect and re-submit dead-letter files.
 
-use crate::arduino::ArduinoManager;
-use crate::influxdb::InfluxDBManager;
+use crate::access_log::{request_id, with_request_id};
+use crate::arduino::list_candidate_ports;
+use crate::build_info::BuildInfo;
+use crate::cache::Cache;
+use crate::config::{CorsConfig, HealthConfig};
+use crate::data_manipulation::Protocol;
+use crate::dead_letter::DeadLetterWriter;
+use crate::device_session::{self, DeviceSessions};
+use crate::influxdb::{HistoryQuery, InfluxDBManager};
+use crate::inject::{InjectRequest, Injector};
+use crate::latest::LatestValues;
+use crate::live::LiveFeed;
+use crate::liveness::Liveness;
+use crate::logging::{self, LevelChange};
+use crate::measurements::MeasurementControl;
+use crate::metrics::Metrics;
+use crate::pause::IngestionControl;
+use crate::read_now::ReadNowError;
+use crate::reload::Reloader;
+use crate::sensors::SensorRegistry;
+use crate::sink::DataSink;
+use crate::source::Source;
+use crate::startup::{ComponentState, Startup};
 
-use serde_json::json;
-use warp::{reply, Filter};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
+use warp::cors::CorsForbidden;
+use warp::http::header::HeaderName;
+use warp::http::uri::Authority;
+use warp::http::{Method, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reject::{MethodNotAllowed, PayloadTooLarge, UnsupportedMediaType};
+use warp::reply::Response;
+use warp::{reply, Filter, Reply};
 
-// Creates an HTTP route for health checks.
+// Rejection of a request without the expected bearer token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// Requires `Authorization: Bearer <token>` when a token is configured and lets every request
+// through otherwise. The scheme is matched whatever its case, as RFC 7235 has it. The probes are
+// mounted outside of this filter.
+pub fn with_auth(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let token: Option<Arc<str>> = token.map(Arc::from);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let Some(token) = token else {
+                    return Ok(());
+                };
+                let presented = header
+                    .as_deref()
+                    .and_then(|header| header.split_once(' '))
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+                    .map(|(_, presented)| presented.trim_start());
+                match presented {
+                    Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Compares every byte regardless of where the first difference is, so the response time does
+// not reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Builds the CORS filter, validating the configuration first since warp panics on invalid
+// origins, methods, or headers. Any origin along with authentication is already rejected by
+// `ConfigSettings::validate`, and again here for settings that did not go through it.
+pub fn cors(config: &CorsConfig, auth_enabled: bool) -> Result<warp::cors::Builder, String> {
+    let mut cors = warp::cors()
+        .max_age(Duration::from_secs(config.max_age_secs))
+        .allow_credentials(auth_enabled);
+
+    for method in &config.allowed_methods {
+        Method::from_bytes(method.as_bytes())
+            .map_err(|_| format!("invalid method '{}' in http.cors", method))?;
+    }
+    cors = cors.allow_methods(config.allowed_methods.iter().map(String::as_str));
+
+    for header in &config.allowed_headers {
+        HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| format!("invalid header '{}' in http.cors", header))?;
+    }
+    cors = cors.allow_headers(config.allowed_headers.iter().map(String::as_str));
+
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        if auth_enabled {
+            return Err(
+                "http.cors cannot allow any origin ('*') while http.auth_token is set".to_string(),
+            );
+        }
+        return Ok(cors.allow_any_origin());
+    }
+    for origin in &config.allowed_origins {
+        let valid = origin.split_once("://").is_some_and(|(scheme, authority)| {
+            matches!(scheme, "http" | "https") && authority.parse::<Authority>().is_ok()
+        });
+        if !valid {
+            return Err(format!("invalid origin '{}' in http.cors", origin));
+        }
+    }
+    Ok(cors.allow_origins(config.allowed_origins.iter().map(String::as_str)))
+}
+
+// Turns every rejection into a JSON error response, so that requests no route accepted still
+// go through the access log: authentication failures become a 401, the rest keep the status
+// warp would have answered with.
+pub async fn handle_rejection(rejection: warp::Rejection) -> Result<Response, Infallible> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let body = reply::json(&json!({"error": "missing or invalid bearer token"}));
+        return Ok(reply::with_header(
+            reply::with_status(body, StatusCode::UNAUTHORIZED),
+            "www-authenticate",
+            "Bearer",
+        )
+        .into_response());
+    }
+
+    let (code, error) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = rejection.find::<CorsForbidden>() {
+        (StatusCode::FORBIDDEN, e.to_string())
+    } else if let Some(e) = rejection.find::<MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else if let Some(e) = rejection.find::<PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    } else if let Some(e) = rejection.find::<UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("invalid request: {:?}", rejection),
+        )
+    };
+    Ok(reply::with_status(reply::json(&json!({"error": error})), code).into_response())
+}
+
+// Version of the `/readyz` payload.
+const HEALTH_SCHEMA: u32 = 6;
+
+// How `/readyz` judges the components, resolved from the `[health]` settings.
+#[derive(Clone)]
+pub struct HealthPolicy {
+    required: Option<Vec<String>>,
+    source_timeout: Duration,
+    sink_timeout: Duration,
+    stale_frame_age: Duration,
+    stale_flush_age: Duration,
+    first_frame_grace: Duration,
+}
+
+impl HealthPolicy {
+    // `default_timeout` applies to the checks without a timeout of their own.
+    pub fn new(config: &HealthConfig, default_timeout: Duration) -> Self {
+        let timeout = |ms: Option<u64>| ms.map_or(default_timeout, Duration::from_millis);
+        Self {
+            required: config.required.clone(),
+            source_timeout: timeout(config.arduino_timeout_ms),
+            sink_timeout: timeout(config.sink_timeout_ms),
+            stale_frame_age: Duration::from_secs(config.stale_frame_secs),
+            stale_flush_age: Duration::from_secs(config.stale_flush_secs),
+            first_frame_grace: Duration::from_secs(config.first_frame_grace_secs),
+        }
+    }
+
+    fn is_required(&self, component: &str) -> bool {
+        match &self.required {
+            Some(required) => required.iter().any(|name| name == component),
+            None => true,
+        }
+    }
+}
+
+// Creates the health routes: `/livez` answers 200 while the core tasks are running, `/readyz`
+// answers 200 only once every component is up and while the required ones are healthy, 503
+// otherwise. `/healthz` is an alias of `/readyz`.
+#[allow(clippy::too_many_arguments)]
 pub fn create_health_route(
-    arduino_manager: ArduinoManager,
-    influxdb_manager: InfluxDBManager,
+    sources: Arc<Vec<Source>>,
+    sink: Arc<dyn DataSink>,
+    cache: Cache,
+    control: IngestionControl,
+    liveness: Liveness,
+    startup: Startup,
+    build_info: BuildInfo,
+    policy: HealthPolicy,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("healthz")
+    let readyz_liveness = liveness.clone();
+    let livez = warp::path!("livez")
         .and(warp::get())
-        .and(with_arduino_manager(arduino_manager))
-        .and(with_influxdb_manager(influxdb_manager))
-        .and_then(handle_health)
+        .map(move || handle_liveness(&liveness));
+
+    let readyz = warp::path!("readyz")
+        .or(warp::path!("healthz"))
+        .unify()
+        .and(warp::get())
+        .and(with_sources(sources))
+        .and(with_sink(sink))
+        .and(warp::any().map(move || cache.clone()))
+        .and(warp::any().map(move || control.clone()))
+        .and(warp::any().map(move || readyz_liveness.clone()))
+        .and(warp::any().map(move || startup.clone()))
+        .and(warp::any().map(move || build_info.clone()))
+        .and(warp::any().map(move || policy.clone()))
+        .and_then(handle_health);
+
+    livez.or(readyz)
+}
+
+fn handle_liveness(liveness: &Liveness) -> reply::WithStatus<reply::Json> {
+    let tasks = liveness.tasks();
+    let (status, code) = if tasks.values().all(|task| task.running) {
+        ("alive", StatusCode::OK)
+    } else {
+        ("dead", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    reply::with_status(
+        reply::json(&json!({"status": status, "tasks": tasks})),
+        code,
+    )
 }
 
-fn with_arduino_manager(
-    arduino_manager: ArduinoManager,
-) -> impl Filter<Extract = (ArduinoManager,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || arduino_manager.clone())
+fn with_sources(
+    sources: Arc<Vec<Source>>,
+) -> impl Filter<Extract = (Arc<Vec<Source>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || sources.clone())
+}
+
+fn with_sink(
+    sink: Arc<dyn DataSink>,
+) -> impl Filter<Extract = (Arc<dyn DataSink>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || sink.clone())
 }
 
 fn with_influxdb_manager(
@@ -33,17 +268,1240 @@ fn with_influxdb_manager(
     warp::any().map(move || influxdb_manager.clone())
 }
 
+// Outcome of one dependency check.
+enum Check {
+    Ok,
+    Failed(String),
+    TimedOut,
+    // Still being brought up at startup, so not checked.
+    Starting {
+        attempts: u32,
+        last_error: Option<String>,
+    },
+}
+
+impl<E: fmt::Display> From<Result<Result<(), E>, Elapsed>> for Check {
+    fn from(result: Result<Result<(), E>, Elapsed>) -> Self {
+        match result {
+            Ok(Ok(())) => Check::Ok,
+            Ok(Err(e)) => Check::Failed(e.to_string()),
+            Err(_) => Check::TimedOut,
+        }
+    }
+}
+
+// Checks a component within `limit`, unless it is still being brought up at startup.
+async fn check_component<E: fmt::Display>(
+    startup: &Startup,
+    name: &str,
+    limit: Duration,
+    check: impl Future<Output = Result<(), E>>,
+) -> Check {
+    match startup.state(name) {
+        Some(ComponentState::Starting {
+            attempts,
+            last_error,
+        }) => Check::Starting {
+            attempts,
+            last_error,
+        },
+        _ => timeout(limit, check).await.into(),
+    }
+}
+
+// Reports every component of the pipeline: each source and each sink. The broker is starting (503)
+// until every component brought up in the background is up, e.g. while the Arduino is missing, and
+// until every source sent its first frame or `first_frame_grace_secs` passed. It is unhealthy (503)
+// when a required component fails its health check or does not answer within its timeout, and
+// degraded (still 200) when only optional components fail, a source sent no frame recently or was
+// found stale by the freshness watchdog, a background task is being restarted, or the last flush
+// failed or is too old. While ingestion is paused on purpose it reports "paused" (200) whatever the
+// checks say. All checks run concurrently, so the probe is answered within the longest timeout even
+// when the serial port is busy. Bump `schema` whenever the payload shape changes.
+#[allow(clippy::too_many_arguments)]
 async fn handle_health(
-    arduino_manager: ArduinoManager,
+    sources: Arc<Vec<Source>>,
+    sink: Arc<dyn DataSink>,
+    cache: Cache,
+    control: IngestionControl,
+    liveness: Liveness,
+    startup: Startup,
+    build_info: BuildInfo,
+    policy: HealthPolicy,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut sinks = sink.parts();
+    if sinks.is_empty() {
+        sinks.push(sink);
+    }
+    let (source_healths, sink_healths) = tokio::join!(
+        join_all(sources.iter().map(|source| check_component(
+            &startup,
+            source.name(),
+            policy.source_timeout,
+            source.device().check_health()
+        ))),
+        join_all(sinks.iter().map(|sink| check_component(
+            &startup,
+            sink.name(),
+            policy.sink_timeout,
+            sink.check_health()
+        ))),
+    );
+    let last_flush = cache.last_flush();
+
+    let components = sources
+        .iter()
+        .map(|source| source.name())
+        .zip(&source_healths)
+        .chain(sinks.iter().map(|sink| sink.name()).zip(&sink_healths));
+    let (mut required_failed, mut optional_failed) = (false, false);
+    for (name, check) in components {
+        match (check, policy.is_required(name)) {
+            (Check::Ok | Check::Starting { .. }, _) => {}
+            (_, true) => required_failed = true,
+            (_, false) => optional_failed = true,
+        }
+    }
+    // A source that sent no frame yet is only late once the grace period is over
+    let in_grace = startup.elapsed() < policy.first_frame_grace;
+    let awaiting_frame = in_grace
+        && sources
+            .iter()
+            .any(|source| source.device().last_frame_age().is_none());
+    let stalled = sources.iter().any(|source| {
+        source.freshness().is_stale()
+            || match source.device().last_frame_age() {
+                Some(age) => age > policy.stale_frame_age,
+                None => !in_grace,
+            }
+    });
+    let tasks = liveness.tasks();
+    let task_down = tasks.values().any(|task| !task.running);
+    let flush_lagging =
+        matches!(last_flush, Some((age, succeeded)) if !succeeded || age > policy.stale_flush_age);
+    // A pause is on purpose: the checks failing meanwhile, e.g. a sensor unplugged for
+    // maintenance, must not get the broker restarted
+    let (status, code) = if control.is_paused() {
+        ("paused", StatusCode::OK)
+    } else if required_failed {
+        ("unhealthy", StatusCode::SERVICE_UNAVAILABLE)
+    } else if !startup.is_complete() || awaiting_frame {
+        ("starting", StatusCode::SERVICE_UNAVAILABLE)
+    } else if optional_failed || stalled || task_down || flush_lagging {
+        ("degraded", StatusCode::OK)
+    } else {
+        ("healthy", StatusCode::OK)
+    };
+
+    let mut sources_json = Map::new();
+    for (source, health) in sources.iter().zip(source_healths) {
+        let device = source.device();
+        let mut source_json = component_health(health, device.health_age());
+        source_json["port"] = json!(device.port_name());
+        source_json["protocol"] = json!(device.protocol().map(Protocol::number));
+        source_json["last_frame_secs_ago"] =
+            json!(device.last_frame_age().map(|age| age.as_secs()));
+        source_json["last_parsed_secs_ago"] = json!(source.freshness().age().as_secs());
+        source_json["stale"] = json!(source.freshness().is_stale());
+        source_json["required"] = json!(policy.is_required(source.name()));
+        sources_json.insert(source.name().to_string(), source_json);
+    }
+
+    let mut body = json!({
+        "schema": HEALTH_SCHEMA,
+        "status": status,
+        "build": build_info,
+        "sources": sources_json,
+        "tasks": tasks,
+        "cache": {
+            "len": cache.len().await,
+            "evicted": cache.evicted(),
+            "last_flush_secs_ago": last_flush.map(|(age, _)| age.as_secs()),
+            "last_flush_ok": last_flush.map(|(_, succeeded)| succeeded),
+        },
+    });
+    for (sink, health) in sinks.iter().zip(sink_healths) {
+        let mut sink_json = component_health(health, sink.health_age());
+        sink_json["required"] = json!(policy.is_required(sink.name()));
+        if let (Value::Object(component), Value::Object(details)) = (&mut sink_json, sink.status())
+        {
+            component.extend(details);
+        }
+        body[sink.name()] = sink_json;
+    }
+    Ok(reply::with_status(reply::json(&body), code))
+}
+
+// The cached status of one component and how old it is.
+fn component_health(check: Check, age: Option<Duration>) -> Value {
+    let checked_secs_ago = age.map(|age| age.as_secs());
+    match check {
+        Check::Ok => json!({"status": "ok", "checked_secs_ago": checked_secs_ago}),
+        Check::Failed(e) => {
+            json!({"status": "error", "checked_secs_ago": checked_secs_ago, "error": e})
+        }
+        Check::TimedOut => json!({"status": "timeout", "checked_secs_ago": checked_secs_ago}),
+        Check::Starting {
+            attempts,
+            last_error,
+        } => json!({"status": "starting", "attempts": attempts, "error": last_error}),
+    }
+}
+
+// Creates the route exposing pipeline metrics in the Prometheus text format.
+pub fn create_metrics_route(
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(move || {
+        reply::with_header(
+            metrics.render(),
+            "Content-Type",
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+    })
+}
+
+// Creates the route reporting write statistics, the counters of each source, and the usage of
+// the stream, latest values, and measurement counters against their limits, and the
+// `[sensors]` entries that never matched a measurement.
+pub fn create_stats_route(
+    influxdb_manager: InfluxDBManager,
+    sink: Arc<dyn DataSink>,
+    sources: Arc<Vec<Source>>,
+    live: LiveFeed,
+    latest: LatestValues,
+    sensors: SensorRegistry,
+    measurements: MeasurementControl,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("stats")
+        .and(warp::get())
+        .and(with_influxdb_manager(influxdb_manager))
+        .and(with_sink(sink))
+        .and(with_sources(sources))
+        .map(
+            move |influxdb_manager: InfluxDBManager,
+                  sink: Arc<dyn DataSink>,
+                  sources: Arc<Vec<Source>>| {
+                let sources: Map<String, Value> = sources
+                    .iter()
+                    .map(|source| (source.name().to_string(), source.stats()))
+                    .collect();
+                reply::json(&json!({
+                    "influxdb": influxdb_manager.stats(),
+                    "sink": sink.status(),
+                    "sources": sources,
+                    "stream": live.stats(),
+                    "latest_series": latest.stats(),
+                    "measurements": measurements.stats(),
+                    "sensors": sensors.stats(),
+                }))
+            },
+        )
+}
+
+// Creates the admin route returning the latest values InfluxDB stored for a measurement, so
+// operators can compare them with what the broker wrote. The bucket defaults to the one the
+// measurement is routed to, and may only be one the broker writes to; the window defaults to
+// one hour.
+pub fn create_latest_route(
+    influxdb_manager: InfluxDBManager,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "latest" / String)
+        .and(warp::get())
+        .and(warp::query::<LatestQuery>())
+        .and(with_influxdb_manager(influxdb_manager))
+        .and(request_id())
+        .and_then(handle_latest)
+}
+
+#[derive(Deserialize)]
+struct LatestQuery {
+    bucket: Option<String>,
+    #[serde(default = "default_latest_window_secs")]
+    window_secs: u64,
+}
+
+fn default_latest_window_secs() -> u64 {
+    3600
+}
+
+async fn handle_latest(
+    measurement: String,
+    query: LatestQuery,
+    influxdb_manager: InfluxDBManager,
+    request_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let bucket = query
+        .bucket
+        .unwrap_or_else(|| influxdb_manager.bucket_for(&measurement).to_string());
+    if !influxdb_manager.buckets().contains(&bucket.as_str()) {
+        let error = format!(
+            "bucket '{}' is not one the broker writes to: {}",
+            bucket,
+            influxdb_manager.buckets().join(", ")
+        );
+        let reply = reply::with_status(
+            reply::json(&json!({"measurement": measurement, "error": error})),
+            StatusCode::BAD_REQUEST,
+        );
+        return Ok(with_request_id(reply, &request_id));
+    }
+    let window = Duration::from_secs(query.window_secs);
+
+    let reply = match influxdb_manager
+        .query_latest(&bucket, &measurement, window)
+        .await
+    {
+        Ok(values) => reply::with_status(
+            reply::json(&json!({
+                "measurement": measurement,
+                "bucket": bucket,
+                "values": values,
+            })),
+            StatusCode::OK,
+        ),
+        Err(e) => {
+            warn!(
+                "request_id={} Failed to query the latest {} values: {}",
+                request_id, measurement, e
+            );
+            reply::with_status(
+                reply::json(&json!({"measurement": measurement, "error": e.to_string()})),
+                StatusCode::BAD_GATEWAY,
+            )
+        }
+    };
+    Ok(with_request_id(reply, &request_id))
+}
+
+// Creates the routes serving the most recent value of every series, `/api/latest`, or of the
+// series of one measurement, `/api/latest/{measurement}`, each with the metadata of its
+// measurement under `sensor`.
+pub fn create_latest_values_route(
+    latest: LatestValues,
+    sensors: SensorRegistry,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let snapshot = move |measurement: Option<&str>| -> Vec<Value> {
+        let mut series = latest.snapshot(measurement);
+        for entry in &mut series {
+            let metadata = sensors.metadata_json(entry["measurement"].as_str().unwrap_or_default());
+            entry["sensor"] = metadata;
+        }
+        series
+    };
+    let all = warp::path!("api" / "latest").and(warp::get()).map({
+        let snapshot = snapshot.clone();
+        move || reply::json(&json!({"series": snapshot(None)}))
+    });
+    let one =
+        warp::path!("api" / "latest" / String)
+            .and(warp::get())
+            .map(move |measurement: String| {
+                let series = snapshot(Some(&measurement));
+                let code = if series.is_empty() {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::OK
+                };
+                reply::with_status(
+                    reply::json(&json!({"measurement": measurement, "series": series})),
+                    code,
+                )
+            });
+
+    all.or(one)
+}
+
+// Creates the route serving the metadata of the measurements, `/api/sensors`.
+pub fn create_sensors_route(
+    sensors: SensorRegistry,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "sensors")
+        .and(warp::get())
+        .map(move || reply::json(&sensors.to_json()))
+}
+
+// Creates the Server-Sent Events route streaming every parsed reading as it arrives,
+// `/api/stream?measurement=` to follow a single measurement.
+pub fn create_stream_route(
+    live: LiveFeed,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "stream")
+        .and(warp::get())
+        .and(warp::query::<StreamQuery>())
+        .map(
+            move |query: StreamQuery| match live.subscribe(query.measurement) {
+                Some(events) => {
+                    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+                }
+                None => reply::with_status(
+                    reply::json(&json!({"error": "too many stream subscribers"})),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                )
+                .into_response(),
+            },
+        )
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    measurement: Option<String>,
+}
+
+// Creates the admin route returning the last lines every source received, newest first,
+// `/admin/raw?invalid_only=true` for the lines that were not valid frames only.
+pub fn create_raw_frames_route(
+    sources: Arc<Vec<Source>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "raw")
+        .and(warp::get())
+        .and(warp::query::<RawFramesQuery>())
+        .map(move |query: RawFramesQuery| {
+            let mut frames: Vec<(DateTime<Utc>, Value)> = sources
+                .iter()
+                .flat_map(|source| {
+                    source
+                        .device()
+                        .recent_frames()
+                        .snapshot(query.invalid_only)
+                        .into_iter()
+                        .map(|frame| (frame.received_at, frame.to_json(source.name())))
+                })
+                .collect();
+            frames.sort_by(|(a, _), (b, _)| b.cmp(a));
+            let frames: Vec<Value> = frames.into_iter().map(|(_, frame)| frame).collect();
+            reply::json(&json!({ "frames": frames }))
+        })
+}
+
+#[derive(Deserialize)]
+struct RawFramesQuery {
+    #[serde(default)]
+    invalid_only: bool,
+}
+
+// Creates the admin route listing the serial ports, `GET /admin/ports`, with what each reports
+// and, for each serial source given as its name and device name, whether the port matches it
+// or why not.
+pub fn create_ports_route(
+    devices: Vec<(String, String)>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "ports")
+        .and(warp::get())
+        .map(move || {
+            let ports = match list_candidate_ports() {
+                Ok(ports) => ports,
+                Err(e) => {
+                    return reply::with_status(
+                        reply::json(&json!({"error": e.to_string()})),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                }
+            };
+            let ports: Vec<Value> = ports
+                .iter()
+                .map(|port| {
+                    let sources: Map<String, Value> = devices
+                        .iter()
+                        .map(|(source, device_name)| {
+                            let result = match port.check(device_name) {
+                                Ok(()) => json!({"matches": true}),
+                                Err(reason) => json!({"matches": false, "reason": reason}),
+                            };
+                            (source.clone(), result)
+                        })
+                        .collect();
+                    let mut port = serde_json::to_value(port).unwrap_or_default();
+                    port["sources"] = Value::Object(sources);
+                    port
+                })
+                .collect();
+            reply::with_status(reply::json(&json!({ "ports": ports })), StatusCode::OK)
+        })
+}
+
+// Creates the admin route adding a synthetic point to the cache, `POST /admin/inject`, with a
+// JSON body such as `{"measurement": "temperature", "value": 99.9, "tags": {"room": "lab"}}`.
+// Answers with the point as it will be written, or 400 and why the body was refused.
+pub fn create_inject_route(
+    injector: Injector,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "inject")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(INJECT_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || injector.clone()))
+        .and_then(handle_inject)
+}
+
+// Largest body `/admin/inject` accepts.
+const INJECT_BODY_LIMIT: u64 = 16 * 1024;
+
+async fn handle_inject(
+    body: Bytes,
+    injector: Injector,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let injected = match serde_json::from_slice::<InjectRequest>(&body) {
+        Ok(request) => injector.inject(request).await,
+        Err(e) => Err(format!("invalid body: {}", e)),
+    };
+    let reply = match injected {
+        Ok(line) => reply::with_status(reply::json(&json!({ "point": line })), StatusCode::OK),
+        Err(e) => reply::with_status(reply::json(&json!({ "error": e })), StatusCode::BAD_REQUEST),
+    };
+    Ok(reply)
+}
+
+// Creates the admin route changing the log levels at runtime, `PUT /admin/log-level` with a
+// body like `{"level": "debug"}` or `{"modules": {"aero_sensor_broker::arduino": "trace"}}`.
+// It answers with the levels before and after the change; an invalid body changes nothing and
+// is refused with 400. Like pausing, the operator can name themselves with `?by=`.
+pub fn create_log_level_route(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "log-level")
+        .and(warp::put())
+        .and(warp::query::<PauseQuery>())
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(LOG_LEVEL_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .map(
+            |query: PauseQuery, remote: Option<SocketAddr>, body: Bytes| {
+                let changed = serde_json::from_slice::<LevelChange>(&body)
+                    .map_err(|e| format!("invalid body: {}", e))
+                    .and_then(logging::change_levels);
+                match changed {
+                    Ok((previous, current)) => {
+                        let body = json!({ "previous": previous, "current": current });
+                        info!(
+                            "Log levels changed by {}: {} (were {})",
+                            operator(query, remote),
+                            body["current"],
+                            body["previous"]
+                        );
+                        reply::with_status(reply::json(&body), StatusCode::OK)
+                    }
+                    Err(e) => reply::with_status(
+                        reply::json(&json!({ "error": e })),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                }
+            },
+        )
+}
+
+// Largest body `/admin/log-level` accepts.
+const LOG_LEVEL_BODY_LIMIT: u64 = 4 * 1024;
+
+// Creates the admin routes pausing and resuming ingestion, `POST /admin/pause` and
+// `POST /admin/resume`, and `GET /admin/status` reporting the current state along with the log
+// levels. The operator can name themselves with `?by=`; the remote address is recorded
+// otherwise.
+pub fn create_pause_routes(
+    control: IngestionControl,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let toggle = warp::path!("admin" / "pause")
+        .map(|| true)
+        .or(warp::path!("admin" / "resume").map(|| false))
+        .unify()
+        .and(warp::post())
+        .and(warp::query::<PauseQuery>())
+        .and(warp::addr::remote())
+        .and(with_control(control.clone()))
+        .and_then(handle_pause);
+
+    let status = warp::path!("admin" / "status")
+        .and(warp::get())
+        .and(with_control(control))
+        .map(|control: IngestionControl| {
+            let mut status = control.status();
+            status["logging"] = logging::status();
+            reply::json(&status)
+        });
+
+    toggle.or(status)
+}
+
+#[derive(Deserialize)]
+struct PauseQuery {
+    by: Option<String>,
+}
+
+fn with_control(
+    control: IngestionControl,
+) -> impl Filter<Extract = (IngestionControl,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || control.clone())
+}
+
+async fn handle_pause(
+    paused: bool,
+    query: PauseQuery,
+    remote: Option<SocketAddr>,
+    control: IngestionControl,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let by = operator(query, remote);
+    if control.set_paused(paused, by.clone()) {
+        info!(
+            "Ingestion {} by {}",
+            if paused { "paused" } else { "resumed" },
+            by
+        );
+    }
+    Ok(reply::json(&control.status()))
+}
+
+// Creates the admin routes disabling and enabling the recording of a measurement,
+// `POST /admin/measurements/{name}/disable` and `.../enable`, and `GET /admin/measurements`
+// listing every measurement seen or disabled with its counters. Like pausing, the operator can
+// name themselves with `?by=`.
+pub fn create_measurement_routes(
+    measurements: MeasurementControl,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let toggle = warp::path!("admin" / "measurements" / String / "disable")
+        .map(|measurement| (measurement, false))
+        .or(warp::path!("admin" / "measurements" / String / "enable")
+            .map(|measurement| (measurement, true)))
+        .unify()
+        .and(warp::post())
+        .and(warp::query::<PauseQuery>())
+        .and(warp::addr::remote())
+        .and(with_measurements(measurements.clone()))
+        .map(
+            |(measurement, enabled): (String, bool),
+             query: PauseQuery,
+             remote: Option<SocketAddr>,
+             measurements: MeasurementControl| {
+                let by = operator(query, remote);
+                if measurements.set_enabled(&measurement, enabled, by.clone()) {
+                    info!(
+                        "Measurement {} {} by {}",
+                        measurement,
+                        if enabled { "enabled" } else { "disabled" },
+                        by
+                    );
+                }
+                reply::json(&measurements.measurement_status(&measurement))
+            },
+        );
+
+    let list = warp::path!("admin" / "measurements")
+        .and(warp::get())
+        .and(with_measurements(measurements))
+        .map(|measurements: MeasurementControl| reply::json(&measurements.status()));
+
+    toggle.or(list)
+}
+
+fn with_measurements(
+    measurements: MeasurementControl,
+) -> impl Filter<Extract = (MeasurementControl,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || measurements.clone())
+}
+
+// The name the operator gave with `?by=`, or their address.
+fn operator(query: PauseQuery, remote: Option<SocketAddr>) -> String {
+    query
+        .by
+        .or_else(|| remote.map(|addr| addr.ip().to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Creates the admin route asking the device of a source for a reading right away,
+// `POST /admin/read-now?source=<name>`, the first source by default. It answers with the
+// readings of the next frame parsed, which are recorded like any other, or 504 with the lines
+// read from the device when none is parsed within `deadline`.
+pub fn create_read_now_route(
+    sources: Arc<Vec<Source>>,
+    control: IngestionControl,
+    deadline: Duration,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "read-now")
+        .and(warp::post())
+        .and(warp::query::<ReadNowQuery>())
+        .and(with_sources(sources))
+        .and(with_control(control))
+        .and_then(move |query, sources, control| handle_read_now(query, sources, control, deadline))
+}
+
+#[derive(Deserialize)]
+struct ReadNowQuery {
+    source: Option<String>,
+}
+
+async fn handle_read_now(
+    query: ReadNowQuery,
+    sources: Arc<Vec<Source>>,
+    control: IngestionControl,
+    deadline: Duration,
+) -> Result<Response, warp::Rejection> {
+    let source = match &query.source {
+        Some(name) => sources.iter().find(|source| source.name() == name),
+        None => sources.first(),
+    };
+    let Some(source) = source else {
+        let body = json!({"error": "unknown source"});
+        return Ok(reply::with_status(reply::json(&body), StatusCode::NOT_FOUND).into_response());
+    };
+    // The read loop discards the frames unparsed while paused
+    if control.is_paused() {
+        let body = json!({"error": "ingestion is paused", "source": source.name()});
+        return Ok(reply::with_status(reply::json(&body), StatusCode::CONFLICT).into_response());
+    }
+
+    let (status, body) = match source
+        .read_now()
+        .request(source.device().as_ref(), deadline)
+        .await
+    {
+        Ok(mut body) => {
+            info!("Reading on demand from source {}", source.name());
+            body["source"] = json!(source.name());
+            (StatusCode::OK, body)
+        }
+        Err(ReadNowError::Command(error)) => (
+            StatusCode::BAD_GATEWAY,
+            json!({"source": source.name(), "error": error, "command_sent": false}),
+        ),
+        Err(ReadNowError::Rejected { error, lines }) => (
+            StatusCode::BAD_GATEWAY,
+            json!({
+                "source": source.name(),
+                "error": format!("frame rejected: {}", error),
+                "command_sent": true,
+                "lines": lines,
+            }),
+        ),
+        Err(ReadNowError::TimedOut { lines }) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            json!({
+                "source": source.name(),
+                "error": format!("no frame parsed within {:?}", deadline),
+                "command_sent": true,
+                "lines": lines,
+            }),
+        ),
+    };
+    Ok(reply::with_status(reply::json(&body), status).into_response())
+}
+
+// Creates the route returning the version and build details of the running broker, and the
+// configuration profile it runs with.
+pub fn create_version_route(
+    build_info: BuildInfo,
+    profile: Option<String>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let mut version = serde_json::to_value(&build_info).unwrap_or_default();
+    version["profile"] = json!(profile);
+    warp::path!("version")
+        .and(warp::get())
+        .map(move || reply::json(&version))
+}
+
+// Creates the `/ws/device/<source>` WebSocket route giving interactive access to the device of
+// a source; `/ws/device` opens the first source. While a session is open, further sessions are
+// refused with 409.
+pub fn create_device_session_route(
+    sources: Arc<Vec<Source>>,
+    idle_timeout: Duration,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let sessions = DeviceSessions::default();
+    let source_name = warp::path!("ws" / "device")
+        .map(|| None)
+        .or(warp::path!("ws" / "device" / String).map(Some))
+        .unify();
+    source_name.and(warp::ws()).and(with_sources(sources)).map(
+        move |name: Option<String>, ws: warp::ws::Ws, sources: Arc<Vec<Source>>| {
+            let source = match &name {
+                Some(name) => sources.iter().find(|source| source.name() == name),
+                None => sources.first(),
+            };
+            let Some(source) = source else {
+                return reply::with_status(
+                    reply::json(&json!({"error": "unknown source"})),
+                    StatusCode::NOT_FOUND,
+                )
+                .into_response();
+            };
+            match sessions.acquire() {
+                Some(slot) => {
+                    let device = source.device().clone();
+                    ws.on_upgrade(move |socket| {
+                        device_session::run(socket, device, idle_timeout, slot)
+                    })
+                    .into_response()
+                }
+                None => reply::with_status(
+                    reply::json(&json!({"error": "a device session is already open"})),
+                    StatusCode::CONFLICT,
+                )
+                .into_response(),
+            }
+        },
+    )
+}
+
+// Creates the admin route returning the effective configuration, secrets redacted, as the last
+// reload left it.
+pub fn create_config_route(
+    settings: watch::Receiver<Value>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "config")
+        .and(warp::get())
+        .map(move || reply::json(&*settings.borrow()))
+}
+
+// Creates the admin routes reloading the configuration files, `POST /admin/reload`, and
+// reporting what the last reload did, `GET /admin/reload`. A reload that fails validation is
+// answered with 422 and the problems found.
+pub fn create_reload_routes(
+    reloader: Reloader,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let reload = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .and(with_reloader(reloader.clone()))
+        .map(|reloader: Reloader| {
+            let outcome = reloader.reload("admin route");
+            let status = match outcome.succeeded {
+                true => StatusCode::OK,
+                false => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            reply::with_status(reply::json(&outcome), status)
+        });
+
+    let last = warp::path!("admin" / "reload")
+        .and(warp::get())
+        .and(with_reloader(reloader))
+        .map(|reloader: Reloader| reply::json(&json!({ "last_reload": reloader.last_outcome() })));
+
+    reload.or(last)
+}
+
+fn with_reloader(
+    reloader: Reloader,
+) -> impl Filter<Extract = (Reloader,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || reloader.clone())
+}
+
+// Creates the route returning the history of one field of a measurement at this broker's
+// location, averaged over windows: `/api/history/{measurement}?start=-6h&stop=&every=5m&field=`.
+// `start` and `stop` are RFC 3339 times or offsets from now such as `-6h`; `stop` defaults to
+// now, `every` to a window giving about 500 values, `field` to "value".
+pub fn create_history_route(
+    influxdb_manager: InfluxDBManager,
+    location: String,
+    max_span: Duration,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "history" / String)
+        .and(warp::get())
+        .and(warp::query::<HistoryParams>())
+        .and(with_influxdb_manager(influxdb_manager))
+        .and(warp::any().map(move || location.clone()))
+        .and(warp::any().map(move || max_span))
+        .and(request_id())
+        .and_then(handle_history)
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    start: String,
+    stop: Option<String>,
+    every: Option<String>,
+    field: Option<String>,
+}
+
+// Windows a single history query may return at most.
+const MAX_HISTORY_WINDOWS: u64 = 10_000;
+
+// Windows of a history query without an explicit `every`.
+const DEFAULT_HISTORY_WINDOWS: u64 = 500;
+
+async fn handle_history(
+    measurement: String,
+    params: HistoryParams,
+    influxdb_manager: InfluxDBManager,
+    location: String,
+    max_span: Duration,
+    request_id: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let query = match history_query(measurement, params, location, max_span) {
+        Ok(query) => query,
+        Err(e) => {
+            let reply =
+                reply::with_status(reply::json(&json!({"error": e})), StatusCode::BAD_REQUEST);
+            return Ok(with_request_id(reply, &request_id));
+        }
+    };
+
+    let bucket = influxdb_manager.bucket_for(&query.measurement).to_string();
+    let reply = match influxdb_manager.query_history(&bucket, &query).await {
+        Ok(values) => reply::with_status(reply::json(&values), StatusCode::OK),
+        Err(e) => {
+            warn!(
+                "request_id={} Failed to query the history of {}: {}",
+                request_id, query.measurement, e
+            );
+            reply::with_status(
+                reply::json(&json!({"error": e.to_string()})),
+                StatusCode::BAD_GATEWAY,
+            )
+        }
+    };
+    Ok(with_request_id(reply, &request_id))
+}
+
+// Validates the parameters of a history request.
+fn history_query(
+    measurement: String,
+    params: HistoryParams,
+    location: String,
+    max_span: Duration,
+) -> Result<HistoryQuery, String> {
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    };
+    let field = params.field.unwrap_or_else(|| "value".to_string());
+    if !valid_name(&measurement) || !valid_name(&field) {
+        return Err(
+            "measurement and field names may only contain letters, digits, '_', '-', and '.'"
+                .to_string(),
+        );
+    }
+
+    let now = Utc::now();
+    let start = parse_time(&params.start, now).ok_or("invalid start")?;
+    let stop = match &params.stop {
+        Some(stop) => parse_time(stop, now).ok_or("invalid stop")?,
+        None => now,
+    };
+    let span = (stop - start)
+        .to_std()
+        .ok()
+        .filter(|span| !span.is_zero())
+        .ok_or("start must be before stop")?;
+    if span > max_span {
+        return Err(format!(
+            "the range may span at most {}s",
+            max_span.as_secs()
+        ));
+    }
+
+    let every = match &params.every {
+        Some(every) => parse_duration(every).ok_or("invalid every")?,
+        None => Duration::from_secs((span.as_secs() / DEFAULT_HISTORY_WINDOWS).max(1)),
+    };
+    if every.is_zero() || span.as_secs() / every.as_secs().max(1) > MAX_HISTORY_WINDOWS {
+        return Err(format!(
+            "every is too small, the range would return more than {} values",
+            MAX_HISTORY_WINDOWS
+        ));
+    }
+
+    Ok(HistoryQuery {
+        measurement,
+        field,
+        location,
+        start,
+        stop,
+        every,
+    })
+}
+
+// Parses an RFC 3339 time or an offset from now such as `-6h`.
+fn parse_time(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if let Some(offset) = value.strip_prefix('-') {
+        return now.checked_sub_signed(chrono::Duration::from_std(parse_duration(offset)?).ok()?);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+// Parses a duration such as `90s`, `5m`, `6h`, `7d`, or `2w`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let unit_at = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: u64 = value[..unit_at].parse().ok()?;
+    let unit = match &value[unit_at..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(unit)?))
+}
+
+// Creates the admin routes listing dead-letter files and re-submitting one of them to InfluxDB.
+// Both answer 404 when no dead-letter directory is configured.
+pub fn create_dead_letter_routes(
+    dead_letter: Option<DeadLetterWriter>,
+    influxdb_manager: InfluxDBManager,
+    bucket: String,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::path!("admin" / "dead-letter")
+        .and(warp::get())
+        .and(with_dead_letter(dead_letter.clone()))
+        .and_then(handle_dead_letter_list);
+
+    let resubmit = warp::path!("admin" / "dead-letter" / String / "resubmit")
+        .and(warp::post())
+        .and(with_dead_letter(dead_letter))
+        .and(with_influxdb_manager(influxdb_manager))
+        .and(warp::any().map(move || bucket.clone()))
+        .and(request_id())
+        .and_then(handle_dead_letter_resubmit);
+
+    list.or(resubmit)
+}
+
+fn with_dead_letter(
+    dead_letter: Option<DeadLetterWriter>,
+) -> impl Filter<Extract = (Option<DeadLetterWriter>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || dead_letter.clone())
+}
+
+fn dead_letter_not_configured() -> reply::WithStatus<reply::Json> {
+    reply::with_status(
+        reply::json(&json!({"error": "dead-letter directory is not configured"})),
+        StatusCode::NOT_FOUND,
+    )
+}
+
+async fn handle_dead_letter_list(
+    dead_letter: Option<DeadLetterWriter>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(dead_letter) = dead_letter else {
+        return Ok(dead_letter_not_configured());
+    };
+
+    let reply = match dead_letter.list() {
+        Ok(files) => {
+            let files: Vec<_> = files
+                .into_iter()
+                .map(|(name, size)| json!({"file": name, "bytes": size}))
+                .collect();
+            reply::with_status(reply::json(&json!({ "files": files })), StatusCode::OK)
+        }
+        Err(e) => reply::with_status(
+            reply::json(&json!({"error": e.to_string()})),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    };
+    Ok(reply)
+}
+
+async fn handle_dead_letter_resubmit(
+    file: String,
+    dead_letter: Option<DeadLetterWriter>,
     influxdb_manager: InfluxDBManager,
+    bucket: String,
+    request_id: String,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let arduino_health = arduino_manager.check_health().await;
-    let influxdb_health = influxdb_manager.check_health().await;
+    let reply = resubmit_dead_letter(file, dead_letter, influxdb_manager, bucket, &request_id);
+    Ok(with_request_id(reply.await, &request_id))
+}
 
-    let status = match (arduino_health, influxdb_health) {
-        (Ok(_), Ok(_)) => "healthy",
-        _ => "unhealthy",
+async fn resubmit_dead_letter(
+    file: String,
+    dead_letter: Option<DeadLetterWriter>,
+    influxdb_manager: InfluxDBManager,
+    bucket: String,
+    request_id: &str,
+) -> reply::WithStatus<reply::Json> {
+    let Some(dead_letter) = dead_letter else {
+        return dead_letter_not_configured();
+    };
+
+    let batches = match dead_letter.read_batches(&file) {
+        Ok(batches) => batches,
+        Err(e) => {
+            return reply::with_status(
+                reply::json(&json!({"error": e.to_string()})),
+                StatusCode::NOT_FOUND,
+            )
+        }
     };
 
-    Ok(reply::json(&json!({"status": status})))
+    // Every batch goes back to the org and bucket it was headed for, the files written before
+    // they were recorded go to the default bucket
+    for batch in batches {
+        let bucket = batch.bucket.as_deref().unwrap_or(&bucket);
+        let written = influxdb_manager
+            .write_line_protocol(batch.org.as_deref(), bucket, batch.lines)
+            .await;
+        if let Err(e) = written {
+            warn!(
+                "request_id={} Failed to re-submit dead-letter file {} to bucket {}: {}",
+                request_id, file, bucket, e
+            );
+            return reply::with_status(
+                reply::json(&json!({"file": file, "resubmitted": false, "error": e.to_string()})),
+                StatusCode::BAD_GATEWAY,
+            );
+        }
+    }
+
+    info!(
+        "request_id={} Re-submitted dead-letter file {}",
+        request_id, file
+    );
+    let removed = dead_letter.remove(&file).is_ok();
+    reply::with_status(
+        reply::json(&json!({"file": file, "resubmitted": true, "removed": removed})),
+        StatusCode::OK,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(86_400);
+
+    fn params(start: &str, stop: Option<&str>, every: Option<&str>) -> HistoryParams {
+        HistoryParams {
+            start: start.to_string(),
+            stop: stop.map(str::to_string),
+            every: every.map(str::to_string),
+            field: None,
+        }
+    }
+
+    fn query(start: &str, stop: Option<&str>, every: Option<&str>) -> Result<HistoryQuery, String> {
+        let params = params(start, stop, every);
+        history_query("temperature".to_string(), params, "lab".to_string(), DAY)
+    }
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn durations_take_a_whole_amount_and_a_unit() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("6h"), Some(Duration::from_secs(21_600)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(604_800)));
+        assert_eq!(parse_duration("2w"), Some(Duration::from_secs(1_209_600)));
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn durations_without_a_known_unit_or_amount_are_refused() {
+        for value in ["", "5", "m", "5y", "5ms", "1.5h", "-5m", "5 m", "5M"] {
+            assert_eq!(parse_duration(value), None, "{:?}", value);
+        }
+        // An amount of seconds that does not fit
+        assert_eq!(parse_duration(&format!("{}w", u64::MAX / 2)), None);
+    }
+
+    #[test]
+    fn times_are_rfc_3339_or_offsets_from_now() {
+        let now = time("2023-11-14T22:13:20Z");
+        assert_eq!(
+            parse_time("2023-11-14T20:00:00+01:00", now),
+            Some(time("2023-11-14T19:00:00Z"))
+        );
+        assert_eq!(parse_time("-6h", now), Some(time("2023-11-14T16:13:20Z")));
+        assert_eq!(parse_time("-0s", now), Some(now));
+        for value in ["yesterday", "2023-11-14", "6h", "+6h", "-6y", "-"] {
+            assert_eq!(parse_time(value, now), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn a_relative_range_is_split_in_about_500_windows() {
+        let query = query("-6h", None, None).unwrap();
+
+        assert_eq!(query.stop - query.start, chrono::Duration::hours(6));
+        assert_eq!(query.every, Duration::from_secs(43));
+        assert_eq!(query.field, "value");
+        assert_eq!(query.location, "lab");
+    }
+
+    #[test]
+    fn an_absolute_range_is_taken_as_is() {
+        let query = query(
+            "2023-11-14T00:00:00Z",
+            Some("2023-11-14T12:00:00Z"),
+            Some("15m"),
+        )
+        .unwrap();
+
+        assert_eq!(query.start, time("2023-11-14T00:00:00Z"));
+        assert_eq!(query.stop, time("2023-11-14T12:00:00Z"));
+        assert_eq!(query.every, Duration::from_secs(900));
+    }
+
+    #[test]
+    fn an_empty_or_reversed_range_is_refused() {
+        let at = "2023-11-14T00:00:00Z";
+        assert_eq!(
+            query(at, Some(at), None).err().as_deref(),
+            Some("start must be before stop")
+        );
+        assert_eq!(
+            query("-1h", Some("-2h"), None).err().as_deref(),
+            Some("start must be before stop")
+        );
+        assert_eq!(
+            query("-0s", None, None).err().as_deref(),
+            Some("start must be before stop")
+        );
+    }
+
+    #[test]
+    fn a_range_longer_than_the_maximum_is_refused() {
+        assert!(query("-1d", None, None).is_ok());
+        assert_eq!(
+            query("-25h", None, None).err().as_deref(),
+            Some("the range may span at most 86400s")
+        );
+    }
+
+    #[test]
+    fn the_windows_of_a_query_are_bounded() {
+        // 86400 windows of a second
+        assert_eq!(
+            query("-1d", None, Some("1s")).err().as_deref(),
+            Some("every is too small, the range would return more than 10000 values")
+        );
+        assert_eq!(
+            query("-1d", None, Some("0s")).err().as_deref(),
+            Some("every is too small, the range would return more than 10000 values")
+        );
+        // Exactly the maximum, and a single window longer than the range
+        assert!(query("-10000s", None, Some("1s")).is_ok());
+        assert!(query("-1h", None, Some("2h")).is_ok());
+    }
+
+    #[test]
+    fn invalid_times_units_and_names_are_refused() {
+        assert_eq!(
+            query("soon", None, None).err().as_deref(),
+            Some("invalid start")
+        );
+        assert_eq!(
+            query("-1h", Some("later"), None).err().as_deref(),
+            Some("invalid stop")
+        );
+        assert_eq!(
+            query("-1h", None, Some("5y")).err().as_deref(),
+            Some("invalid every")
+        );
+        let mut params = params("-1h", None, None);
+        params.field = Some("value\") |> yield()".to_string());
+        let error = history_query("temperature".to_string(), params, "lab".to_string(), DAY)
+            .err()
+            .unwrap();
+        assert!(
+            error.starts_with("measurement and field names"),
+            "{}",
+            error
+        );
+    }
 }
@@ -0,0 +1,251 @@
+// run_state.rs
+//
+// The state of the run, kept in a small JSON file so that the next start can tell what the gap
+// in the data was. The file is saved every `SAVE_INTERVAL` while the broker runs, and once more
+// at a clean shutdown, with the time of the last successful write, the time of the last frame,
+// the counters of the pipeline, and the measurements disabled at runtime, which the next start
+// disables again.
+//
+// On startup, the file of the previous run is read and a single `broker_restart` point is cached
+// with how long the broker was down and whether it was shut down cleanly: a run that was killed
+// or aborted never saves the clean state, and a panic leaves its crash marker. A gap that started
+// well before the last frame of the previous run was rather a sensor outage. The fields:
+//
+//   downtime_secs        time since the previous run last saved its state
+//   clean_shutdown       whether the previous run shut down cleanly
+//   panicked             whether the previous run left a crash marker
+//   last_write_secs_ago  time since the previous run last wrote to the sink, when it did
+//   last_frame_secs_ago  time since the previous run last parsed a frame, when it did
+//
+// The file is written to a temporary file renamed over the previous one, so it is never seen
+// half-written. A file that cannot be read or parsed is reported and ignored.
+
+use crate::cache::Cache;
+use crate::clock::{self, Clock};
+use crate::crash;
+use crate::measurements::{Disabled, MeasurementControl};
+use crate::metrics::Metrics;
+use crate::source::Source;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb2::models::DataPoint;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+const RESTART_MEASUREMENT: &str = "broker_restart";
+
+// How often the state is saved while the broker runs; a crash loses at most this much of it.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// The state of a run, as saved in the file. Times are RFC 3339.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunState {
+    pub saved_at: String,
+    // Set only by the save at a clean shutdown.
+    pub clean: bool,
+    pub last_write_at: Option<String>,
+    pub last_frame_at: Option<String>,
+    pub counters: BTreeMap<String, u64>,
+    // Measurements disabled through the admin routes, disabled again on the next start.
+    #[serde(default)]
+    pub disabled_measurements: BTreeMap<String, Disabled>,
+}
+
+// Keeps the state file of this run up to date.
+pub struct RunStateFile {
+    path: PathBuf,
+    metrics: Arc<Metrics>,
+    cache: Cache,
+    sources: Arc<Vec<Source>>,
+    measurements: MeasurementControl,
+    // The last successful write seen, and the points written when it was seen.
+    last_write: Mutex<(Option<DateTime<Utc>>, u64)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RunStateFile {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        metrics: Arc<Metrics>,
+        cache: Cache,
+        sources: Arc<Vec<Source>>,
+        measurements: MeasurementControl,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            metrics,
+            cache,
+            sources,
+            measurements,
+            last_write: Mutex::new((None, 0)),
+            clock: clock::system(),
+        }
+    }
+
+    // Dates the state with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Reads the state the previous run left, before this run overwrites it.
+    pub fn previous(&self) -> Option<RunState> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("No state file at {}, first run", self.path.display());
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to read state file {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        serde_json::from_str(&contents)
+            .inspect_err(|e| {
+                warn!(
+                    "State file {} is corrupt, ignored: {}",
+                    self.path.display(),
+                    e
+                )
+            })
+            .ok()
+    }
+
+    // Saves the state every `SAVE_INTERVAL`, and as soon as a measurement is disabled or enabled,
+    // until `shutdown` is cancelled; the clean state is saved by the shutdown itself, once
+    // everything was flushed.
+    pub async fn keep(self: Arc<Self>, shutdown: CancellationToken) {
+        let mut ticks = interval(SAVE_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = self.measurements.changed() => {}
+                _ = shutdown.cancelled() => return,
+            }
+            self.save(false);
+        }
+    }
+
+    pub fn save(&self, clean: bool) {
+        let state = self.snapshot(clean);
+        if let Err(e) = write_atomically(&self.path, &state) {
+            warn!("Failed to save state file {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn snapshot(&self, clean: bool) -> RunState {
+        let now = self.clock.now_utc();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        // The last flush is only known until the next one, so a success is remembered here
+        let points_written = load(&self.metrics.points_written);
+        let mut last_write = self
+            .last_write
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some((age, true)) = self.cache.last_flush() {
+            last_write.0 = Some(now - chrono::Duration::from_std(age).unwrap_or_default());
+        } else if points_written > last_write.1 {
+            last_write.0 = Some(now);
+        }
+        last_write.1 = points_written;
+
+        // The freshness of a source dates from the startup until its first frame
+        let last_frame = self
+            .sources
+            .iter()
+            .filter(|source| load(&source.device().source_metrics().frames_received) > 0)
+            .map(|source| source.freshness().age())
+            .min()
+            .map(|age| now - chrono::Duration::from_std(age).unwrap_or_default());
+
+        let counters = [
+            ("frames_received", &self.metrics.frames_received),
+            ("frames_invalid", &self.metrics.frames_invalid),
+            ("points_written", &self.metrics.points_written),
+            ("flush_failures", &self.metrics.flush_failures),
+            ("evictions", &self.metrics.cache_evictions),
+        ]
+        .into_iter()
+        .map(|(name, counter)| (name.to_string(), load(counter)))
+        .collect();
+
+        RunState {
+            saved_at: rfc3339(now),
+            clean,
+            last_write_at: last_write.0.map(rfc3339),
+            last_frame_at: last_frame.map(rfc3339),
+            counters,
+            disabled_measurements: self.measurements.disabled(),
+        }
+    }
+}
+
+impl RunState {
+    // The `broker_restart` point of a start following this state.
+    pub fn restart_point(
+        &self,
+        now: DateTime<Utc>,
+        tags: &BTreeMap<String, String>,
+    ) -> Result<DataPoint, String> {
+        let saved_at = parse(&self.saved_at).ok_or("invalid saved_at")?;
+        let secs_ago = |at: &Option<String>| {
+            at.as_deref()
+                .and_then(parse)
+                .map(|at| (now - at).num_milliseconds() as f64 / 1000.0)
+        };
+        let mut builder = DataPoint::builder(RESTART_MEASUREMENT)
+            .field(
+                "downtime_secs",
+                (now - saved_at).num_milliseconds() as f64 / 1000.0,
+            )
+            .field("clean_shutdown", self.clean)
+            .field("panicked", crash::previous_run_crashed())
+            .timestamp(
+                now.timestamp_nanos_opt()
+                    .ok_or("the wall clock is past the range of timestamps")?,
+            );
+        if let Some(secs) = secs_ago(&self.last_write_at) {
+            builder = builder.field("last_write_secs_ago", secs);
+        }
+        if let Some(secs) = secs_ago(&self.last_frame_at) {
+            builder = builder.field("last_frame_secs_ago", secs);
+        }
+        tags.iter()
+            .fold(builder, |builder, (key, value)| builder.tag(key, value))
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Writes the state to a temporary file next to `path`, then renames it over `path`.
+fn write_atomically(path: &Path, state: &RunState) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let contents = serde_json::to_vec_pretty(state)?;
+    let mut file = File::create(&temporary)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+fn rfc3339(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse(at: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(at)
+        .ok()
+        .map(|at| at.with_timezone(&Utc))
+}
@@ -0,0 +1,297 @@
+// selftest.rs
+//
+// A one-shot check of the whole pipeline for installers, run by the `selftest` subcommand: the
+// device of a source is found and opened, answers a PING, and sends a valid frame, which is
+// parsed into points; InfluxDB answers its health check and, if asked, accepts a `selftest`
+// point. Every stage has a timeout of its own, and a stage whose input is missing is skipped
+// rather than failed, so the report points at the first thing that is wrong.
+
+use crate::build_info::BuildInfo;
+use crate::clock_skew::ClockSkewCorrector;
+use crate::config::{ConfigSettings, SourceKind};
+use crate::data_manipulation::{parse_sensor_data, StaticFields};
+use crate::errors::AppError;
+use crate::influxdb::InfluxDBManager;
+use crate::metrics::Metrics;
+use crate::raw::raw_point;
+use crate::source::Source;
+use crate::{global_tags, INFLUXDB_COMPONENT};
+
+use chrono::Utc;
+use influxdb2::models::DataPoint;
+use log::warn;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration, Instant};
+
+const SELFTEST_MEASUREMENT: &str = "selftest";
+
+// Timeouts of the stages; waiting for a frame takes the one given on the command line.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+const PARSE_TIMEOUT: Duration = Duration::from_secs(1);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub enum Outcome {
+    // Passed, with what was found, e.g. the port opened.
+    Passed(String),
+    Failed(String),
+    // Not run, with the reason.
+    Skipped(String),
+}
+
+pub struct Stage {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+}
+
+pub struct SelfTestReport {
+    pub source: String,
+    pub stages: Vec<Stage>,
+}
+
+impl SelfTestReport {
+    // Whether no stage failed.
+    pub fn passed(&self) -> bool {
+        !self
+            .stages
+            .iter()
+            .any(|stage| matches!(stage.outcome, Outcome::Failed(_)))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let stages: Vec<Value> = self
+            .stages
+            .iter()
+            .map(|stage| {
+                let (result, detail) = stage.outcome.parts();
+                json!({
+                    "stage": stage.name,
+                    "result": result,
+                    "detail": detail,
+                    "elapsed_ms": stage.elapsed.as_millis() as u64,
+                })
+            })
+            .collect();
+        json!({
+            "source": self.source,
+            "passed": self.passed(),
+            "stages": stages,
+        })
+    }
+
+    // Runs `check` as the stage `name`, failing it past `limit`. Returns what it produced when it
+    // passed.
+    async fn run<T>(
+        &mut self,
+        name: &'static str,
+        limit: Duration,
+        check: impl Future<Output = Result<(T, String), String>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let (value, outcome) = match timeout(limit, check).await {
+            Ok(Ok((value, detail))) => (Some(value), Outcome::Passed(detail)),
+            Ok(Err(e)) => (None, Outcome::Failed(e)),
+            Err(_) => (
+                None,
+                Outcome::Failed(format!("timed out after {:?}", limit)),
+            ),
+        };
+        self.stages.push(Stage {
+            name,
+            outcome,
+            elapsed: started.elapsed(),
+        });
+        value
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.stages.push(Stage {
+            name,
+            outcome: Outcome::Skipped(reason.to_string()),
+            elapsed: Duration::ZERO,
+        });
+    }
+}
+
+impl Outcome {
+    fn parts(&self) -> (&'static str, &str) {
+        match self {
+            Outcome::Passed(detail) => ("pass", detail),
+            Outcome::Failed(detail) => ("fail", detail),
+            Outcome::Skipped(detail) => ("skip", detail),
+        }
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Self-test of source {}", self.source)?;
+        for stage in &self.stages {
+            let (result, detail) = stage.outcome.parts();
+            writeln!(
+                f,
+                "  {:<4}  {:<8} {:>7.2}s  {}",
+                result.to_uppercase(),
+                stage.name,
+                stage.elapsed.as_secs_f64(),
+                detail
+            )?;
+        }
+        write!(f, "Result: {}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+// Runs the stages against the named source, the first one by default, waiting up to
+// `frame_timeout` for a frame. A `selftest` point is only written when `write` is set.
+pub async fn run(
+    settings: &ConfigSettings,
+    source_name: Option<&str>,
+    frame_timeout: Duration,
+    write: bool,
+) -> Result<SelfTestReport, AppError> {
+    let config = match source_name {
+        Some(name) => settings.sources.iter().find(|source| source.name == name),
+        None => settings.sources.first(),
+    };
+    let Some(config) = config else {
+        return Err(AppError::Config(format!(
+            "no source named {}",
+            source_name.unwrap_or_default()
+        )));
+    };
+    let metrics = Arc::new(Metrics::default());
+    let tags = global_tags(settings, &BuildInfo::current());
+    let source = Source::new(config, &tags, metrics.clone(), 0);
+    let mut report = SelfTestReport {
+        source: config.name.clone(),
+        stages: Vec::new(),
+    };
+
+    let device = source.device().clone();
+    let opened = report
+        .run("open", OPEN_TIMEOUT, async {
+            device.connect().await.map_err(|e| e.to_string())?;
+            let detail = match config.kind {
+                SourceKind::Serial => format!(
+                    "port {}, protocol {}",
+                    device.port_name(),
+                    device.protocol().unwrap_or_default()
+                ),
+                SourceKind::Simulated => "simulated".to_string(),
+            };
+            Ok(((), detail))
+        })
+        .await
+        .is_some();
+
+    let frame = match opened {
+        true => {
+            report
+                .run("ping", PING_TIMEOUT, async {
+                    device.check_health().await.map_err(|e| e.to_string())?;
+                    Ok(((), "the device answered".to_string()))
+                })
+                .await;
+            report
+                .run("frame", frame_timeout, async {
+                    let frame = device.read_data().await.map_err(|e| e.to_string())?;
+                    Ok((frame.clone(), frame))
+                })
+                .await
+        }
+        false => {
+            report.skip("ping", "the device is not open");
+            report.skip("frame", "the device is not open");
+            None
+        }
+    };
+
+    match frame {
+        Some(frame) => {
+            let parser = source.parser(&settings.parser);
+            let mut skew = ClockSkewCorrector::new(parser).with_clock(source.clock().clone());
+            let static_fields = StaticFields::new(&settings.fields);
+            report
+                .run("parse", PARSE_TIMEOUT, async {
+                    let protocol = device.protocol();
+                    let points = parse_sensor_data(
+                        frame,
+                        source.tags(),
+                        parser,
+                        protocol,
+                        &mut skew,
+                        source.clock().as_ref(),
+                    )
+                    .map_err(|e| e.to_string())?;
+                    let built = points
+                        .iter()
+                        .map(|point| raw_point(point, &static_fields))
+                        .collect::<Result<Vec<DataPoint>, String>>()?;
+                    let measurements: Vec<&str> =
+                        points.iter().map(|point| point.get_measurement()).collect();
+                    Ok((
+                        (),
+                        format!("{} point(s): {}", built.len(), measurements.join(", ")),
+                    ))
+                })
+                .await;
+        }
+        None => report.skip("parse", "no frame was read"),
+    }
+    if let Err(e) = device.shutdown().await {
+        warn!("Failed to close source {}: {}", config.name, e);
+    }
+
+    let influxdb = match settings.sinks.iter().any(|sink| sink == INFLUXDB_COMPONENT) {
+        true => Some(InfluxDBManager::new(&settings.influxdb, metrics)?),
+        false => None,
+    };
+    let Some(influxdb) = influxdb else {
+        report.skip("influxdb", "InfluxDB is not a configured sink");
+        report.skip("write", "InfluxDB is not a configured sink");
+        return Ok(report);
+    };
+    let healthy = report
+        .run("influxdb", HEALTH_TIMEOUT, async {
+            influxdb.probe_health().await?;
+            Ok(((), settings.influxdb.url.clone()))
+        })
+        .await
+        .is_some();
+
+    match (write, healthy) {
+        (false, _) => report.skip("write", "not asked for, see --write"),
+        (true, _) if settings.dry_run => report.skip("write", "dry run"),
+        (true, false) => report.skip("write", "InfluxDB is not healthy"),
+        (true, true) => {
+            let bucket = influxdb.bucket_for(SELFTEST_MEASUREMENT).to_string();
+            report
+                .run("write", WRITE_TIMEOUT, async {
+                    let point = selftest_point(source.tags())?;
+                    influxdb
+                        .write_data(&bucket, vec![point])
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    Ok(((), format!("bucket {}", bucket)))
+                })
+                .await;
+        }
+    }
+    Ok(report)
+}
+
+fn selftest_point(tags: &BTreeMap<String, String>) -> Result<DataPoint, String> {
+    let builder = DataPoint::builder(SELFTEST_MEASUREMENT)
+        .field("passed", true)
+        .timestamp(Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    tags.iter()
+        .fold(builder, |builder, (key, value)| builder.tag(key, value))
+        .build()
+        .map_err(|e| e.to_string())
+}
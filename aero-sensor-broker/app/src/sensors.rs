@@ -0,0 +1,140 @@
+// sensors.rs
+//
+// The metadata of the measurements, from the `[sensors.<measurement>]` tables: the unit, the name
+// to display, a description, and the decimals worth showing. It is defined once here rather than
+// in every frontend: `/api/sensors` serves the whole registry and `/api/latest` adds the entry of
+// each series inline, with nulls for measurements without one. With `unit_tag` set, the points of
+// a measurement with a unit are also tagged `unit` before being aggregated and written.
+//
+// An entry is only of use if its measurement is ever read, but a typo in the name cannot be told
+// at load time. The registry remembers the measurements it saw instead, and once the broker has
+// run for `UNMATCHED_GRACE` the entries still never seen are warned about and listed by `/stats`.
+
+use crate::config::SensorMetadata;
+use crate::data_manipulation::MyDataPoint;
+
+use log::warn;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+pub const UNIT_TAG: &str = "unit";
+
+// How long the entries have to match a measurement before they are reported as unmatched.
+const UNMATCHED_GRACE: Duration = Duration::from_secs(600);
+
+#[derive(Clone)]
+pub struct SensorRegistry {
+    entries: Arc<BTreeMap<String, Entry>>,
+    unit_tag: bool,
+    started: Instant,
+}
+
+struct Entry {
+    metadata: SensorMetadata,
+    // Whether a point of the measurement was seen.
+    matched: AtomicBool,
+}
+
+impl SensorRegistry {
+    pub fn new(sensors: &BTreeMap<String, SensorMetadata>, unit_tag: bool) -> Self {
+        let entries = sensors
+            .iter()
+            .map(|(measurement, metadata)| {
+                let entry = Entry {
+                    metadata: metadata.clone(),
+                    matched: AtomicBool::new(false),
+                };
+                (measurement.clone(), entry)
+            })
+            .collect();
+        Self {
+            entries: Arc::new(entries),
+            unit_tag,
+            started: Instant::now(),
+        }
+    }
+
+    // Marks the entries of the measurements of freshly parsed points as matched, and tags the
+    // points with their unit when asked to.
+    pub fn observe(&self, points: &mut [MyDataPoint]) {
+        for point in points {
+            let Some(entry) = self.entries.get(point.get_measurement()) else {
+                continue;
+            };
+            entry.matched.store(true, Ordering::Relaxed);
+            if let (true, Some(unit)) = (self.unit_tag, &entry.metadata.unit) {
+                point.set_tag(UNIT_TAG, unit);
+            }
+        }
+    }
+
+    // The metadata of a measurement, every key null when it has no entry.
+    pub fn metadata_json(&self, measurement: &str) -> Value {
+        let metadata = self.entries.get(measurement).map(|entry| &entry.metadata);
+        json!({
+            "unit": metadata.and_then(|metadata| metadata.unit.as_ref()),
+            "display_name": metadata.and_then(|metadata| metadata.display_name.as_ref()),
+            "description": metadata.and_then(|metadata| metadata.description.as_ref()),
+            "decimals": metadata.and_then(|metadata| metadata.decimals),
+        })
+    }
+
+    // The whole registry, for `/api/sensors`.
+    pub fn to_json(&self) -> Value {
+        let sensors: serde_json::Map<String, Value> = self
+            .entries
+            .iter()
+            .map(|(measurement, entry)| {
+                let mut metadata = self.metadata_json(measurement);
+                metadata["observed"] = json!(entry.matched.load(Ordering::Relaxed));
+                (measurement.clone(), metadata)
+            })
+            .collect();
+        json!({ "sensors": sensors })
+    }
+
+    // The number of entries and, once the grace period is over, those that never matched, for
+    // `/stats`.
+    pub fn stats(&self) -> Value {
+        let unmatched = match self.started.elapsed() >= UNMATCHED_GRACE {
+            true => json!(self.unmatched()),
+            false => Value::Null,
+        };
+        json!({
+            "entries": self.entries.len(),
+            "unmatched": unmatched,
+        })
+    }
+
+    // Warns about the entries that did not match any measurement once the grace period is over,
+    // unless `shutdown` is cancelled first.
+    pub async fn warn_unmatched(self, shutdown: CancellationToken) {
+        if self.entries.is_empty() {
+            return;
+        }
+        tokio::select! {
+            _ = sleep(UNMATCHED_GRACE) => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let unmatched = self.unmatched();
+        if !unmatched.is_empty() {
+            warn!(
+                "No measurement matched the [sensors] entries of {} after {:?}, check their names",
+                unmatched.join(", "),
+                UNMATCHED_GRACE
+            );
+        }
+    }
+
+    fn unmatched(&self) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.matched.load(Ordering::Relaxed))
+            .map(|(measurement, _)| measurement.as_str())
+            .collect()
+    }
+}
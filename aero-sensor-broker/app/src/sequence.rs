@@ -0,0 +1,96 @@
+// sequence.rs
+//
+// Protocol 3 devices number their frames in `seq`, one more for every frame, so that the frames
+// lost between the device and the broker (serial corruption, an overrun of the device buffer)
+// show up as gaps in the numbers. The read loop of a source keeps the number it saw last, and
+// counts the frames missing between it and the next one in `frames_missed` of the source. A
+// frame the parser rejected is missed as well: its readings never made it.
+//
+// The counter of the firmware is a u32 that wraps around to 0, so numbers are compared modulo
+// 2^32: the frame after 4294967295 is 0. A number that went back (more than half the range
+// ahead) is taken for a device that restarted numbering, not for a gap of billions of frames,
+// and so is the first frame after ingestion was paused or frames were dropped on purpose.
+
+use crate::data_manipulation::MyDataPoint;
+use crate::metrics::SourceMetrics;
+
+use log::{debug, info, warn};
+use std::sync::atomic::Ordering;
+
+// How the number of a frame follows the one seen before it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sequence {
+    // The first number seen, or the first since the tracker was reset.
+    First,
+    // One more than the previous number.
+    InOrder,
+    // Frames are missing, `from` to `to` included.
+    Gap { missed: u32, from: u32, to: u32 },
+    // The same number again, e.g. a frame sent twice.
+    Duplicate,
+    // A number before the previous one: the device numbers its frames anew.
+    Restarted { previous: u32 },
+}
+
+#[derive(Default)]
+pub struct SequenceTracker {
+    last: Option<u32>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Compares the number of a frame with the previous one, and remembers it unless it is a
+    // duplicate.
+    pub fn observe(&mut self, seq: u32) -> Sequence {
+        let Some(last) = self.last else {
+            self.last = Some(seq);
+            return Sequence::First;
+        };
+        let ahead = seq.wrapping_sub(last);
+        let sequence = match ahead {
+            0 => return Sequence::Duplicate,
+            1 => Sequence::InOrder,
+            _ if ahead > u32::MAX / 2 => Sequence::Restarted { previous: last },
+            _ => Sequence::Gap {
+                missed: ahead - 1,
+                from: last.wrapping_add(1),
+                to: seq.wrapping_sub(1),
+            },
+        };
+        self.last = Some(seq);
+        sequence
+    }
+
+    // Forgets the previous number, when frames are skipped on purpose.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    // Follows the number of a parsed frame, which all its points carry, logging the gaps of
+    // `source` and counting the frames missed. Frames without a number are not followed.
+    pub fn observe_frame(&mut self, source: &str, points: &[MyDataPoint], metrics: &SourceMetrics) {
+        let Some(seq) = points.iter().find_map(MyDataPoint::get_seq) else {
+            return;
+        };
+        match self.observe(seq) {
+            Sequence::First | Sequence::InOrder => {}
+            Sequence::Gap { missed, from, to } => {
+                warn!(
+                    "Source {} missed {} frame(s), seq {} to {}",
+                    source, missed, from, to
+                );
+                metrics
+                    .frames_missed
+                    .fetch_add(missed as u64, Ordering::Relaxed);
+            }
+            Sequence::Duplicate => debug!("Source {} sent frame {} again", source, seq),
+            Sequence::Restarted { previous } => info!(
+                "Source {} numbers its frames anew, seq {} after {}",
+                source, seq, previous
+            ),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// shutdown.rs
+//
+// The application lifecycle is driven by a single `CancellationToken`: it is cancelled on
+// SIGINT or SIGTERM, and every long-running component watches it to stop in an orderly way.
+// Once it is cancelled, the `ShutdownCoordinator` tears the components down stage by stage, so
+// that nothing read before the shutdown is lost: reading stops, the open aggregation windows
+// are closed, the cache is flushed, the HTTP server stops, and the serial ports are closed.
+
+use log::{error, info};
+use std::future::Future;
+use tokio::signal;
+use tokio::time::{timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// Runs the shutdown stages one after the other, each within its own timeout. A stage only
+// starts once the previous one finished, failed, or was abandoned.
+#[derive(Default)]
+pub struct ShutdownCoordinator {
+    failed: bool,
+}
+
+impl ShutdownCoordinator {
+    // Runs the stage `name`, giving up on it after `limit`. Returns what the stage produced, or
+    // `None` when it failed or timed out.
+    pub async fn stage<T, Fut>(&mut self, name: &str, limit: Duration, stage: Fut) -> Option<T>
+    where
+        Fut: Future<Output = Result<T, String>>,
+    {
+        let started = Instant::now();
+        match timeout(limit, stage).await {
+            Ok(Ok(value)) => {
+                info!(
+                    "Shutdown stage {} completed in {:?}",
+                    name,
+                    started.elapsed()
+                );
+                Some(value)
+            }
+            Ok(Err(e)) => {
+                error!(
+                    "Shutdown stage {} failed after {:?}: {}",
+                    name,
+                    started.elapsed(),
+                    e
+                );
+                self.failed = true;
+                None
+            }
+            Err(_) => {
+                error!("Shutdown stage {} timed out after {:?}", name, limit);
+                self.failed = true;
+                None
+            }
+        }
+    }
+
+    // Whether any stage failed or timed out.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+}
+
+// Cancels `token` on the first SIGINT or SIGTERM.
+pub fn cancel_on_signal(token: CancellationToken) {
+    tokio::spawn(async move {
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                if let Err(e) = result {
+                    error!("Failed to listen for SIGINT: {}", e);
+                    return;
+                }
+                info!("SIGINT received, shutting down");
+            }
+            _ = terminate() => info!("SIGTERM received, shutting down"),
+        }
+        token.cancel();
+    });
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            error!("Failed to listen for SIGTERM: {}", e);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending::<()>().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use warp::Filter;
+
+    // A server on a free port, stopping gracefully once `token` is cancelled. Its only route
+    // takes `delay` to answer.
+    fn serve(
+        token: &CancellationToken,
+        delay: Duration,
+    ) -> (SocketAddr, tokio::task::JoinHandle<()>) {
+        let route = warp::path!("slow").then(move || async move {
+            tokio::time::sleep(delay).await;
+            "done"
+        });
+        let (addr, server) = warp::serve(route)
+            .try_bind_with_graceful_shutdown(([127, 0, 0, 1], 0), token.clone().cancelled_owned())
+            .unwrap();
+        (addr, tokio::spawn(server))
+    }
+
+    async fn get(addr: SocketAddr) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn the_http_server_stops_accepting_connections_once_the_token_fires() {
+        let token = CancellationToken::new();
+        let (addr, server) = serve(&token, Duration::ZERO);
+        assert!(get(addr).await.unwrap().ends_with("done"));
+
+        token.cancel();
+
+        timeout(Duration::from_secs(2), server)
+            .await
+            .expect("the server did not stop")
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_request_in_flight_is_answered_before_the_http_server_stops() {
+        let token = CancellationToken::new();
+        let (addr, server) = serve(&token, Duration::from_millis(300));
+        let request = tokio::spawn(get(addr));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        token.cancel();
+
+        let response = request.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+        timeout(Duration::from_secs(2), server)
+            .await
+            .expect("the server did not stop")
+            .unwrap();
+    }
+}
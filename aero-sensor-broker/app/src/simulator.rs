@@ -0,0 +1,297 @@
+// simulator.rs
+//
+// A source generating synthetic frames instead of reading a device, so that the parsing,
+// aggregation, cache, and sink paths can run on a machine without an Arduino. Every interval it
+// emits a JSON frame with one item per configured measurement, and replaces some frames with
+// malformed ones when asked to. With a seed, the same configuration always generates the same
+// frames.
+
+use crate::arduino::is_valid_frame;
+use crate::config::{SimulatedMeasurement, SimulationConfig, SimulationPattern};
+use crate::errors::AppError;
+use crate::frame_log::FrameLog;
+use crate::metrics::{Metrics, SourceMetrics};
+use crate::read_now::COMMAND as READ_NOW;
+use crate::source::SensorSource;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::f64::consts::TAU;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex, Notify};
+use tokio::time::{interval, Duration, Interval, MissedTickBehavior};
+
+use log::{debug, info, warn};
+
+// Frames the sketch could send when something goes wrong on the device: a truncated frame, a
+// frame missing a reading, and an item without its value.
+const MALFORMED_FRAMES: [&str; 3] = [
+    r#"[{"type": "temperature", "value": 2"#,
+    "<21.5|40.2>",
+    r#"{"type": "humidity"}"#,
+];
+
+// Raw lines buffered per subscriber before the oldest are dropped.
+const RAW_LINES_CAPACITY: usize = 256;
+
+pub struct Simulator {
+    name: String,
+    config: SimulationConfig,
+    state: Mutex<SimulationState>,
+    // Unix time in milliseconds of the last valid frame, 0 before the first one.
+    last_frame_ms: AtomicU64,
+    metrics: Arc<Metrics>,
+    source_metrics: Arc<SourceMetrics>,
+    raw_lines: broadcast::Sender<String>,
+    frames: FrameLog,
+    // Notified by `READ_NOW` to generate the next frame without waiting for the interval.
+    read_now: Notify,
+}
+
+struct SimulationState {
+    rng: StdRng,
+    ticks: Interval,
+    // Frames generated so far, which also drives the sine waves.
+    frames: u64,
+}
+
+impl Simulator {
+    pub fn new(
+        name: &str,
+        config: &SimulationConfig,
+        metrics: Arc<Metrics>,
+        frames: FrameLog,
+    ) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut ticks = interval(Duration::from_millis(config.interval_ms));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        info!(
+            "Simulated source {} generating {} measurement(s) every {} ms",
+            name,
+            config.measurements.len(),
+            config.interval_ms
+        );
+        Self {
+            name: name.to_string(),
+            config: config.clone(),
+            state: Mutex::new(SimulationState {
+                rng,
+                ticks,
+                frames: 0,
+            }),
+            last_frame_ms: AtomicU64::new(0),
+            source_metrics: metrics.source(name),
+            metrics,
+            raw_lines: broadcast::channel(RAW_LINES_CAPACITY).0,
+            frames,
+            read_now: Notify::new(),
+        }
+    }
+
+    // Waits for the next interval, or for `READ_NOW`, and generates its frame.
+    async fn next_frame(&self) -> String {
+        let mut state = self.state.lock().await;
+        tokio::select! {
+            _ = state.ticks.tick() => {}
+            _ = self.read_now.notified() => {}
+        }
+        state.frames += 1;
+
+        if self.config.malformed_ratio > 0.0 && state.rng.gen_bool(self.config.malformed_ratio) {
+            let frame = MALFORMED_FRAMES[state.rng.gen_range(0..MALFORMED_FRAMES.len())];
+            return frame.to_string();
+        }
+
+        let elapsed_secs = (state.frames * self.config.interval_ms) as f64 / 1000.0;
+        let items: Vec<Value> = self
+            .config
+            .measurements
+            .iter()
+            .map(|measurement| {
+                let value = generate(measurement, elapsed_secs, &mut state.rng);
+                json!({"type": measurement.name, "value": value})
+            })
+            .collect();
+        Value::Array(items).to_string()
+    }
+}
+
+// A value of the measurement, rounded like the sketch does, `elapsed_secs` into the simulation.
+fn generate(measurement: &SimulatedMeasurement, elapsed_secs: f64, rng: &mut StdRng) -> f64 {
+    let (min, max) = (measurement.min, measurement.max);
+    let value = match measurement.pattern {
+        _ if min == max => min,
+        SimulationPattern::Random => rng.gen_range(min..=max),
+        SimulationPattern::Sine => {
+            let phase = TAU * elapsed_secs / measurement.period_secs as f64;
+            let noise = rng.gen_range(-0.01..=0.01) * (max - min);
+            let wave = min + (max - min) * (1.0 + phase.sin()) / 2.0;
+            (wave + noise).clamp(min, max)
+        }
+    };
+    (value * 100.0).round() / 100.0
+}
+
+#[async_trait]
+impl SensorSource for Simulator {
+    async fn read_data(&self) -> Result<String, AppError> {
+        loop {
+            let frame = self.next_frame().await;
+            // Sending only fails when there is no subscriber.
+            let _ = self.raw_lines.send(frame.clone());
+            self.metrics.frames_received.fetch_add(1, Ordering::Relaxed);
+            self.source_metrics
+                .frames_received
+                .fetch_add(1, Ordering::Relaxed);
+            let valid = is_valid_frame(&frame);
+            self.frames.record(&frame, valid);
+            if !valid {
+                warn!("Invalid data format: '{}'", frame);
+                self.metrics.frames_invalid.fetch_add(1, Ordering::Relaxed);
+                self.source_metrics
+                    .frames_invalid
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            debug!("Simulated frame: '{}'", frame);
+            self.last_frame_ms
+                .store(Utc::now().timestamp_millis() as u64, Ordering::Relaxed);
+            return Ok(frame);
+        }
+    }
+
+    // There is nothing to reopen.
+    async fn reconnect(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    // Answers PING and READ_NOW like the sketch; other commands are not understood.
+    async fn write_command(&self, command: &str) -> Result<(), AppError> {
+        match command.trim() {
+            "PING" => {
+                let _ = self.raw_lines.send("PONG".to_string());
+                Ok(())
+            }
+            READ_NOW => {
+                self.read_now.notify_one();
+                Ok(())
+            }
+            command => Err(AppError::Device(format!(
+                "simulated source {} does not understand '{}'",
+                self.name, command
+            ))),
+        }
+    }
+
+    fn subscribe_raw_lines(&self) -> broadcast::Receiver<String> {
+        self.raw_lines.subscribe()
+    }
+
+    async fn check_health(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn health_age(&self) -> Option<Duration> {
+        None
+    }
+
+    fn source_metrics(&self) -> &SourceMetrics {
+        &self.source_metrics
+    }
+
+    fn port_name(&self) -> String {
+        "simulated".to_string()
+    }
+
+    fn recent_frames(&self) -> &FrameLog {
+        &self.frames
+    }
+
+    fn last_frame_age(&self) -> Option<Duration> {
+        match self.last_frame_ms.load(Ordering::Relaxed) {
+            0 => None,
+            at => {
+                let now = Utc::now().timestamp_millis() as u64;
+                Some(Duration::from_millis(now.saturating_sub(at)))
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulator(seed: u64, malformed_ratio: f64) -> Simulator {
+        let measurement = |name: &str, pattern| SimulatedMeasurement {
+            name: name.to_string(),
+            min: 15.0,
+            max: 30.0,
+            pattern,
+            period_secs: 60,
+        };
+        let config = SimulationConfig {
+            interval_ms: 1000,
+            seed: Some(seed),
+            malformed_ratio,
+            measurements: vec![
+                measurement("temperature", SimulationPattern::Sine),
+                measurement("humidity", SimulationPattern::Random),
+            ],
+        };
+        Simulator::new("bench", &config, Arc::default(), FrameLog::new(0))
+    }
+
+    async fn frames(simulator: &Simulator, count: usize) -> Vec<String> {
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            frames.push(simulator.next_frame().await);
+        }
+        frames
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_same_seed_generates_the_same_frames() {
+        let first = frames(&simulator(7, 0.2), 100).await;
+
+        assert_eq!(frames(&simulator(7, 0.2), 100).await, first);
+        assert_ne!(frames(&simulator(8, 0.2), 100).await, first);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_frames_come_at_the_configured_rate() {
+        let frames = frames(&simulator(7, 0.25), 2000).await;
+
+        let malformed = frames
+            .iter()
+            .filter(|frame| MALFORMED_FRAMES.contains(&frame.as_str()))
+            .count();
+        assert!((400..600).contains(&malformed), "{} malformed", malformed);
+        // The others are the frames of the configured measurements
+        let well_formed = frames
+            .iter()
+            .filter(|frame| !MALFORMED_FRAMES.contains(&frame.as_str()));
+        for frame in well_formed {
+            let items: Vec<Value> = serde_json::from_str(frame).unwrap();
+            assert_eq!(items.len(), 2, "{}", frame);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_frame_is_malformed_by_default() {
+        let frames = frames(&simulator(7, 0.0), 200).await;
+
+        assert!(frames.iter().all(|frame| is_valid_frame(frame)));
+    }
+}
@@ -0,0 +1,252 @@
+// sink.rs
+//
+// Defines the `DataSink` abstraction the flush path writes aggregated points to. Keeping the
+// cache, the health routes, and main unaware of the concrete backend lets us swap in other
+// outputs (or mock sinks) without touching the pipeline itself. `FanOutSink` combines several
+// sinks so every batch is written to all of them at once, see `BufferedFanOutSink` for sinks
+// written independently; `DryRunSink` only logs what would be written.
+
+use crate::line_protocol::render;
+use async_trait::async_trait;
+use futures::future::join_all;
+use influxdb2::models::DataPoint;
+
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum SinkError {
+    // The data itself was rejected; writing the same batch again will fail the same way.
+    Rejected(String),
+    // The sink could not be reached or was temporarily unable to accept the data.
+    Unavailable(String),
+    // The write did not complete in time; the sink may or may not have stored the data.
+    TimedOut(String),
+    // The batch was written in several parts (per bucket, per sink) and some of them failed;
+    // each entry holds the points that were not written there together with the reason.
+    Partial(Vec<(SinkError, Vec<DataPoint>)>),
+}
+
+impl SinkError {
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            SinkError::Rejected(_) => true,
+            SinkError::Unavailable(_) | SinkError::TimedOut(_) => false,
+            SinkError::Partial(failures) => failures.iter().all(|(e, _)| e.is_permanent()),
+        }
+    }
+
+    // Splits the error into the points that were not written and why, given the batch that
+    // was passed to `write`.
+    pub fn into_failures(self, batch: Vec<DataPoint>) -> Vec<(SinkError, Vec<DataPoint>)> {
+        match self {
+            SinkError::Partial(failures) => failures,
+            error => vec![(error, batch)],
+        }
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Rejected(message) => write!(f, "data rejected: {}", message),
+            SinkError::Unavailable(message) => write!(f, "sink unavailable: {}", message),
+            SinkError::TimedOut(message) => write!(f, "sink timed out: {}", message),
+            SinkError::Partial(failures) => {
+                write!(f, "{} part(s) of the batch failed", failures.len())?;
+                for (error, points) in failures {
+                    write!(f, "; {} points: {}", points.len(), error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for SinkError {}
+
+#[async_trait]
+pub trait DataSink: Send + Sync {
+    // Short name used in logs and health reports.
+    fn name(&self) -> &str;
+
+    // Writes a batch of points.
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError>;
+
+    // Checks that the sink is reachable and able to accept writes.
+    async fn check_health(&self) -> Result<(), SinkError>;
+
+    // Age of the result `check_health` reports, for sinks that cache it.
+    fn health_age(&self) -> Option<Duration> {
+        None
+    }
+
+    // Sink-specific state worth showing in the health output.
+    fn status(&self) -> Value {
+        Value::Null
+    }
+
+    // The sinks a composite sink writes to, checked one by one for the health output; empty
+    // for a sink that stands on its own.
+    fn parts(&self) -> Vec<Arc<dyn DataSink>> {
+        Vec::new()
+    }
+
+    // Waits for the points the sink accepted but did not write yet, at shutdown. Only a sink
+    // that buffers has anything to do.
+    async fn close(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+// Stands in for the configured sinks in dry-run mode: every point is rendered as line protocol
+// and logged instead of being written anywhere.
+pub struct DryRunSink;
+
+#[async_trait]
+impl DataSink for DryRunSink {
+    fn name(&self) -> &str {
+        "dry-run"
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        info!("Dry run: would write {} points", points.len());
+        for point in &points {
+            info!("Dry run: {}", render(point));
+        }
+        Ok(())
+    }
+
+    // Nothing is contacted in dry-run mode.
+    async fn check_health(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn status(&self) -> Value {
+        json!({ "mode": "dry-run" })
+    }
+}
+
+// Writes every batch to several sinks concurrently. A failing sink does not prevent the others
+// from receiving the batch; the errors are reported alongside the points that were not written,
+// each point once however many sinks failed it.
+pub struct FanOutSink {
+    sinks: Vec<Arc<dyn DataSink>>,
+}
+
+impl FanOutSink {
+    pub fn new(sinks: Vec<Arc<dyn DataSink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+#[async_trait]
+impl DataSink for FanOutSink {
+    fn name(&self) -> &str {
+        "fan-out"
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let writes = self.sinks.iter().map(|sink| {
+            let points = points.clone();
+            async move { (sink.name(), sink.write(points).await) }
+        });
+
+        let mut failures = Vec::new();
+        for (name, result) in join_all(writes).await {
+            if let Err(e) = result {
+                error!(
+                    "Sink {} failed to write {} points: {}",
+                    name,
+                    points.len(),
+                    e
+                );
+                failures.extend(e.into_failures(points.clone()));
+            }
+        }
+
+        let mut failures = merge_failures(failures);
+        match failures.len() {
+            0 => Ok(()),
+            1 if failures[0].1.len() == points.len() => Err(failures.remove(0).0),
+            _ => Err(SinkError::Partial(failures)),
+        }
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        let checks = self
+            .sinks
+            .iter()
+            .map(|sink| async move { (sink.name(), sink.check_health().await) });
+
+        let unhealthy: Vec<String> = join_all(checks)
+            .await
+            .into_iter()
+            .filter_map(|(name, result)| result.err().map(|e| format!("{}: {}", name, e)))
+            .collect();
+
+        if unhealthy.is_empty() {
+            Ok(())
+        } else {
+            Err(SinkError::Unavailable(unhealthy.join("; ")))
+        }
+    }
+
+    // The oldest cached result among the sinks.
+    fn health_age(&self) -> Option<Duration> {
+        self.sinks.iter().filter_map(|sink| sink.health_age()).max()
+    }
+
+    fn status(&self) -> Value {
+        self.sinks
+            .iter()
+            .map(|sink| (sink.name().to_string(), sink.status()))
+            .collect::<Map<_, _>>()
+            .into()
+    }
+
+    fn parts(&self) -> Vec<Arc<dyn DataSink>> {
+        self.sinks.clone()
+    }
+}
+
+// Merges the failures of sinks given the same batch, so that a point is retried or dead-lettered
+// once rather than once per sink. A point some sink may still accept is retried, the points every
+// failing sink rejected are reported as rejected; each group keeps the first error of its kind.
+fn merge_failures(failures: Vec<(SinkError, Vec<DataPoint>)>) -> Vec<(SinkError, Vec<DataPoint>)> {
+    let retried: HashSet<String> = failures
+        .iter()
+        .filter(|(error, _)| !error.is_permanent())
+        .flat_map(|(_, points)| points.iter().map(render))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let (mut transient, mut rejected) = (None, None);
+    for (error, points) in failures {
+        let permanent = error.is_permanent();
+        let group = match permanent {
+            true => &mut rejected,
+            false => &mut transient,
+        };
+        let (_, kept) = group.get_or_insert_with(|| (error, Vec::new()));
+        for point in points {
+            let line = render(&point);
+            if permanent && retried.contains(&line) {
+                continue;
+            }
+            if seen.insert((permanent, line)) {
+                kept.push(point);
+            }
+        }
+    }
+    [transient, rejected]
+        .into_iter()
+        .flatten()
+        .filter(|(_, points)| !points.is_empty())
+        .collect()
+}
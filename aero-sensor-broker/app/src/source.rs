@@ -0,0 +1,221 @@
+// source.rs
+//
+// A configured device together with what its pipeline branch needs: the device connection, the
+// tags of its points, and its parser settings. Every source is read by its own loop; the loops
+// share the cache and the sinks. The device is anything implementing `SensorSource`: an Arduino
+// on a serial port, or a simulator generating frames.
+
+use crate::arduino::ArduinoManager;
+use crate::clock::{self, Clock};
+use crate::config::{IngestLimitConfig, ParserConfig, SourceConfig, SourceKind};
+use crate::data_manipulation::Protocol;
+use crate::errors::AppError;
+use crate::frame_log::FrameLog;
+use crate::freshness::Freshness;
+use crate::metrics::{Metrics, SourceMetrics};
+use crate::read_now::ReadNow;
+use crate::simulator::Simulator;
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+// The tag naming the source every point was read from.
+pub const SOURCE_TAG: &str = "source";
+
+// A device the read loop takes frames from, and that the health and device session routes talk
+// to.
+#[async_trait]
+pub trait SensorSource: Send + Sync {
+    // Opens the device at startup. Fails when it cannot be opened yet, and is then retried. There
+    // is nothing to open by default.
+    async fn connect(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    // Waits for the next frame that looks valid; frames that do not are counted and skipped.
+    async fn read_data(&self) -> Result<String, AppError>;
+
+    // Opens the device again after read errors. Fails when it is not back yet.
+    async fn reconnect(&self) -> Result<(), AppError>;
+
+    // Writes one command line to the device; the answers arrive as raw lines.
+    async fn write_command(&self, command: &str) -> Result<(), AppError>;
+
+    // Receives every line read from the device from now on, valid frame or not.
+    fn subscribe_raw_lines(&self) -> broadcast::Receiver<String>;
+
+    // Whether the device answers, possibly from a cached result.
+    async fn check_health(&self) -> Result<(), AppError>;
+
+    // Age of the result `check_health` reports, when it is cached.
+    fn health_age(&self) -> Option<Duration>;
+
+    fn source_metrics(&self) -> &SourceMetrics;
+
+    // Where the frames come from, e.g. the serial port.
+    fn port_name(&self) -> String;
+
+    // Time since the last valid frame was read, if any was.
+    fn last_frame_age(&self) -> Option<Duration>;
+
+    // The last lines received, valid frames or not.
+    fn recent_frames(&self) -> &FrameLog;
+
+    // The frame protocol negotiated when the device was opened; `None` for devices that do not
+    // negotiate one, or before it was opened.
+    fn protocol(&self) -> Option<Protocol> {
+        None
+    }
+
+    // Releases the device at shutdown; it cannot be read afterwards.
+    async fn shutdown(&self) -> Result<(), AppError>;
+}
+
+#[derive(Clone)]
+pub struct Source {
+    name: String,
+    device: Arc<dyn SensorSource>,
+    // The global tags, then the tags of the source, then `source` set to its name.
+    tags: BTreeMap<String, String>,
+    // Overrides the reloadable `[parser]` settings when set.
+    parser: Option<ParserConfig>,
+    max_consecutive_errors: u32,
+    ingest_limit: Option<IngestLimitConfig>,
+    freshness: Arc<Freshness>,
+    // The `/admin/read-now` requests waiting for the next frame parsed.
+    read_now: Arc<ReadNow>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Source {
+    // Creates the device of the source, which `SensorSource::connect` opens. It keeps the last
+    // `recent_frames` lines it receives.
+    pub fn new(
+        config: &SourceConfig,
+        global_tags: &BTreeMap<String, String>,
+        metrics: Arc<Metrics>,
+        recent_frames: usize,
+    ) -> Self {
+        let frames = FrameLog::new(recent_frames);
+        let device: Arc<dyn SensorSource> = match config.kind {
+            SourceKind::Serial => Arc::new(ArduinoManager::new(
+                &config.name,
+                &config.serial,
+                metrics,
+                frames,
+            )),
+            SourceKind::Simulated => Arc::new(Simulator::new(
+                &config.name,
+                &config.simulation.clone().unwrap_or_default(),
+                metrics,
+                frames,
+            )),
+        };
+        Self::with_device(config, global_tags, device)
+    }
+
+    // The source configured by `config`, reading from `device` instead of its own, e.g. to
+    // replay a recording of it.
+    pub fn with_device(
+        config: &SourceConfig,
+        global_tags: &BTreeMap<String, String>,
+        device: Arc<dyn SensorSource>,
+    ) -> Self {
+        let mut tags = global_tags.clone();
+        tags.extend(config.tags.clone());
+        tags.insert(SOURCE_TAG.to_string(), config.name.clone());
+        Self {
+            name: config.name.clone(),
+            device,
+            tags,
+            parser: config.parser.clone(),
+            max_consecutive_errors: config.serial.max_consecutive_errors,
+            ingest_limit: config.ingest_limit.clone(),
+            freshness: Arc::new(Freshness::default()),
+            read_now: Arc::new(ReadNow::default()),
+            clock: clock::system(),
+        }
+    }
+
+    // Times the windows and ages the frames of the source by `clock` instead of the system
+    // clock; the device keeps its own.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.freshness = Arc::new(Freshness::new(clock.clone()));
+        self.clock = clock;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn device(&self) -> &Arc<dyn SensorSource> {
+        &self.device
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    // When the source last parsed a frame, and whether the watchdog found it stale.
+    pub fn freshness(&self) -> &Freshness {
+        &self.freshness
+    }
+
+    pub(crate) fn read_now(&self) -> &ReadNow {
+        &self.read_now
+    }
+
+    // The parser settings of the source, or `fallback` when it has none of its own.
+    pub fn parser<'a>(&'a self, fallback: &'a ParserConfig) -> &'a ParserConfig {
+        self.parser.as_ref().unwrap_or(fallback)
+    }
+
+    pub fn ingest_limit(&self) -> Option<&IngestLimitConfig> {
+        self.ingest_limit.as_ref()
+    }
+
+    // Whether `consecutive_errors` read errors and rejected frames in a row are enough to give
+    // up on the source.
+    pub fn should_give_up(&self, consecutive_errors: u32) -> bool {
+        self.max_consecutive_errors > 0 && consecutive_errors >= self.max_consecutive_errors
+    }
+
+    // Counters and port of the source, for `/stats`.
+    pub fn stats(&self) -> Value {
+        let metrics = self.device.source_metrics();
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let (recent_frames, recent_frames_limit) = self.device.recent_frames().usage();
+        json!({
+            "port": self.device.port_name(),
+            "frames_received": load(&metrics.frames_received),
+            "frames_invalid": load(&metrics.frames_invalid),
+            "points_parsed": load(&metrics.points_parsed),
+            "frames_rejected": load(&metrics.frames_rejected),
+            "device_timestamps": load(&metrics.device_timestamps),
+            "host_timestamps": load(&metrics.host_timestamps),
+            "frames_limited": load(&metrics.frames_limited),
+            "points_limited": load(&metrics.points_limited),
+            "points_suppressed": load(&metrics.points_suppressed),
+            "frames_oversized": load(&metrics.frames_oversized),
+            "frames_missed": load(&metrics.frames_missed),
+            "readings_missing": load(&metrics.readings_missing),
+            "lines_dropped": load(&metrics.lines_dropped),
+            "readings_missing_by_measurement": *metrics
+                .readings_missing_by_measurement
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            "last_frame_secs_ago": self.device.last_frame_age().map(|age| age.as_secs()),
+            "recent_frames": {"used": recent_frames, "limit": recent_frames_limit},
+        })
+    }
+}
@@ -0,0 +1,169 @@
+// startup.rs
+//
+// Tracks the components brought up in the background once the HTTP server is listening: the
+// device of every source and, when points are written to it, the InfluxDB validation. Each is
+// retried until it comes up, so that a missing Arduino or an unreachable database leaves the
+// broker running and reporting "starting" on its probes instead of exiting. The broker is ready
+// once every component is up, or offline: a component the broker goes on without, such as
+// InfluxDB when the network is not up at boot, is still being brought up meanwhile.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+use log::info;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ComponentState {
+    Starting {
+        // Failed attempts so far.
+        attempts: u32,
+        last_error: Option<String>,
+    },
+    Ready,
+    // The component cannot come up, whatever the number of attempts.
+    Failed {
+        error: String,
+    },
+    // The component did not come up in time, and the broker runs without it until it does.
+    Offline {
+        error: String,
+    },
+}
+
+impl ComponentState {
+    // Whether the component no longer holds the startup back.
+    fn is_up(&self) -> bool {
+        matches!(self, ComponentState::Ready | ComponentState::Offline { .. })
+    }
+}
+
+#[derive(Clone)]
+pub struct Startup {
+    states: Arc<watch::Sender<BTreeMap<String, ComponentState>>>,
+    started: Instant,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Self {
+            states: Arc::new(watch::Sender::new(BTreeMap::new())),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Startup {
+    // Adds a component to bring up before the broker is ready.
+    pub fn register(&self, name: &str) {
+        self.set(
+            name,
+            ComponentState::Starting {
+                attempts: 0,
+                last_error: None,
+            },
+        );
+    }
+
+    // Records a failed attempt at bringing the component up.
+    pub fn attempt_failed(&self, name: &str, error: String) {
+        self.states.send_modify(|states| {
+            if let Some(ComponentState::Starting {
+                attempts,
+                last_error,
+            }) = states.get_mut(name)
+            {
+                *attempts += 1;
+                *last_error = Some(error);
+            }
+        });
+    }
+
+    pub fn ready(&self, name: &str) {
+        info!(
+            "{} is up, {:.1}s after startup",
+            name,
+            self.started.elapsed().as_secs_f64()
+        );
+        self.set(name, ComponentState::Ready);
+    }
+
+    // Records that the broker goes on without the component, which is still being brought up.
+    pub fn go_offline(&self, name: &str, error: String) {
+        self.set(name, ComponentState::Offline { error });
+    }
+
+    // Records that the component cannot come up, which aborts the startup.
+    pub fn failed(&self, name: &str, error: String) {
+        self.set(name, ComponentState::Failed { error });
+    }
+
+    fn set(&self, name: &str, state: ComponentState) {
+        self.states.send_modify(|states| {
+            states.insert(name.to_string(), state);
+        });
+    }
+
+    // The state of the component, or `None` when it is not brought up in the background.
+    pub fn state(&self, name: &str) -> Option<ComponentState> {
+        self.states.borrow().get(name).cloned()
+    }
+
+    pub fn states(&self) -> BTreeMap<String, ComponentState> {
+        self.states.borrow().clone()
+    }
+
+    // Time since the broker started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Whether every component is up, or offline.
+    pub fn is_complete(&self) -> bool {
+        self.states.borrow().values().all(ComponentState::is_up)
+    }
+
+    // Waits until every component is up or offline, or one of them failed, whose error is
+    // returned.
+    pub async fn complete(&self) -> Result<Duration, String> {
+        let mut states = self.states.subscribe();
+        let states = states
+            .wait_for(|states| {
+                let mut states = states.values();
+                states.clone().all(ComponentState::is_up)
+                    || states.any(|state| matches!(state, ComponentState::Failed { .. }))
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        match states.iter().find_map(|(name, state)| match state {
+            ComponentState::Failed { error } => Some(format!("{}: {}", name, error)),
+            _ => None,
+        }) {
+            Some(failure) => Err(failure),
+            None => Ok(self.started.elapsed()),
+        }
+    }
+
+    // Names of the components the broker goes on without.
+    pub fn offline(&self) -> Vec<String> {
+        self.states
+            .borrow()
+            .iter()
+            .filter(|(_, state)| matches!(state, ComponentState::Offline { .. }))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Names of the components still starting.
+    pub fn pending(&self) -> Vec<String> {
+        self.states
+            .borrow()
+            .iter()
+            .filter(|(_, state)| matches!(state, ComponentState::Starting { .. }))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,162 @@
+// stats.rs
+//
+// Counters describing how writes to InfluxDB are going: how many succeeded or failed, how many
+// points they carried, why they failed, and how long they took. Everything is kept in atomics so
+// recording a write costs next to nothing on the flush path; `snapshot` produces the
+// serializable view served over HTTP.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Weight of the latest write in the rolling latency average.
+const LATENCY_ALPHA: f64 = 0.1;
+
+// Why a write failed, as counted in the stats.
+#[derive(Clone, Copy, Debug)]
+pub enum FailureKind {
+    Timeout,
+    ClientError,
+    ServerError,
+    Connection,
+    Other,
+}
+
+#[derive(Default)]
+pub struct WriteStats {
+    writes_ok: AtomicU64,
+    writes_failed: AtomicU64,
+    points_ok: AtomicU64,
+    points_failed: AtomicU64,
+    timeouts: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    connection_errors: AtomicU64,
+    other_errors: AtomicU64,
+    last_latency_us: AtomicU64,
+    max_latency_us: AtomicU64,
+    // f64 bits of the exponentially weighted average latency
+    avg_latency_us: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct WriteStatsSnapshot {
+    pub writes: Outcomes,
+    pub points: Outcomes,
+    pub failures: FailureCounts,
+    pub latency_ms: LatencySummary,
+}
+
+// Writes, or the points they carried, split by whether the write succeeded.
+#[derive(Serialize)]
+pub struct Outcomes {
+    pub ok: u64,
+    pub failed: u64,
+}
+
+#[derive(Serialize)]
+pub struct FailureCounts {
+    pub timeout: u64,
+    pub client_error: u64,
+    pub server_error: u64,
+    pub connection: u64,
+    pub other: u64,
+}
+
+#[derive(Serialize)]
+pub struct LatencySummary {
+    pub last: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+impl WriteStats {
+    // Records one write of `points` points that took `latency`.
+    pub fn record(&self, points: usize, latency: Duration, failure: Option<FailureKind>) {
+        match failure {
+            None => {
+                self.writes_ok.fetch_add(1, Ordering::Relaxed);
+                self.points_ok.fetch_add(points as u64, Ordering::Relaxed);
+            }
+            Some(kind) => {
+                self.writes_failed.fetch_add(1, Ordering::Relaxed);
+                self.points_failed
+                    .fetch_add(points as u64, Ordering::Relaxed);
+                self.failure_counter(kind).fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.last_latency_us.store(micros, Ordering::Relaxed);
+        self.max_latency_us.fetch_max(micros, Ordering::Relaxed);
+        let _ = self
+            .avg_latency_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let avg = f64::from_bits(bits);
+                let updated = if avg == 0.0 {
+                    micros as f64
+                } else {
+                    avg + LATENCY_ALPHA * (micros as f64 - avg)
+                };
+                Some(updated.to_bits())
+            });
+    }
+
+    pub fn snapshot(&self) -> WriteStatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let millis = |micros: f64| micros / 1000.0;
+        WriteStatsSnapshot {
+            writes: Outcomes {
+                ok: load(&self.writes_ok),
+                failed: load(&self.writes_failed),
+            },
+            points: Outcomes {
+                ok: load(&self.points_ok),
+                failed: load(&self.points_failed),
+            },
+            failures: FailureCounts {
+                timeout: load(&self.timeouts),
+                client_error: load(&self.client_errors),
+                server_error: load(&self.server_errors),
+                connection: load(&self.connection_errors),
+                other: load(&self.other_errors),
+            },
+            latency_ms: LatencySummary {
+                last: millis(load(&self.last_latency_us) as f64),
+                max: millis(load(&self.max_latency_us) as f64),
+                avg: millis(f64::from_bits(load(&self.avg_latency_us))),
+            },
+        }
+    }
+
+    fn failure_counter(&self, kind: FailureKind) -> &AtomicU64 {
+        match kind {
+            FailureKind::Timeout => &self.timeouts,
+            FailureKind::ClientError => &self.client_errors,
+            FailureKind::ServerError => &self.server_errors,
+            FailureKind::Connection => &self.connection_errors,
+            FailureKind::Other => &self.other_errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_writes_are_counted_apart_from_the_successful_ones() {
+        let stats = WriteStats::default();
+        stats.record(10, Duration::from_millis(20), None);
+        stats.record(4, Duration::from_millis(5), Some(FailureKind::ServerError));
+        stats.record(6, Duration::from_millis(30), None);
+
+        let snapshot = stats.snapshot();
+
+        assert_eq!((snapshot.writes.ok, snapshot.writes.failed), (2, 1));
+        assert_eq!((snapshot.points.ok, snapshot.points.failed), (16, 4));
+        assert_eq!(snapshot.failures.server_error, 1);
+        assert_eq!(snapshot.latency_ms.last, 30.0);
+        assert_eq!(snapshot.latency_ms.max, 30.0);
+    }
+}
@@ -0,0 +1,173 @@
+// supervisor.rs
+//
+// Runs the background tasks of the broker and watches them end. A task that panics or returns
+// while the broker is not shutting down is either restarted, after a growing delay and within a
+// budget of restarts, or, when it cannot be restarted, shuts the whole process down through the
+// shutdown token. The state of every task is kept in `Liveness` for the health routes. Giving up
+// on a task that panicked leaves the crash marker, as a panic of the main thread does.
+
+use crate::crash;
+use crate::liveness::Liveness;
+use log::{error, warn};
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{sleep, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// Restarts allowed in a row before the supervisor gives up on a task.
+const MAX_RESTARTS: u32 = 5;
+
+// A task that ran this long before stopping gets its restart budget back.
+const RESTART_BUDGET_RESET: Duration = Duration::from_secs(600);
+
+// Longest delay before a restart; the delay doubles from one second with every restart.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+// How a task ended.
+struct Outcome {
+    description: String,
+    panicked: bool,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.description)
+    }
+}
+
+#[derive(Clone)]
+pub struct Supervisor {
+    liveness: Liveness,
+    shutdown: CancellationToken,
+    // Set when a task failure shut the broker down.
+    failed: Arc<AtomicBool>,
+}
+
+impl Supervisor {
+    pub fn new(liveness: Liveness, shutdown: CancellationToken) -> Self {
+        Self {
+            liveness,
+            shutdown,
+            failed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Whether the broker is shutting down because a task failed.
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    // Runs the task built by `task`, building and running a new one whenever it stops before
+    // shutdown. Once the restart budget is spent the broker shuts down.
+    pub fn spawn_restartable<F, Fut>(&self, name: &'static str, mut task: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let mut restarts = 0;
+            loop {
+                let started = Instant::now();
+                let outcome = supervisor.run(name, task()).await;
+                if supervisor.shutdown.is_cancelled() {
+                    return;
+                }
+                if started.elapsed() >= RESTART_BUDGET_RESET {
+                    restarts = 0;
+                }
+                if restarts >= MAX_RESTARTS {
+                    error!(
+                        "Task {} {} after {} restarts, shutting down",
+                        name, outcome, restarts
+                    );
+                    supervisor
+                        .liveness
+                        .record_failure(name, &outcome.description, false);
+                    supervisor.fail(&outcome);
+                    return;
+                }
+
+                let backoff = Duration::from_secs(1 << restarts).min(MAX_RESTART_BACKOFF);
+                restarts += 1;
+                warn!("Task {} {}, restarting it in {:?}", name, outcome, backoff);
+                supervisor
+                    .liveness
+                    .record_failure(name, &outcome.description, true);
+                tokio::select! {
+                    _ = sleep(backoff) => {}
+                    _ = supervisor.shutdown.cancelled() => return,
+                }
+            }
+        })
+    }
+
+    // Runs a task that cannot be restarted. When it stops before shutdown, the broker shuts
+    // down.
+    pub fn spawn_critical<Fut>(&self, name: &'static str, task: Fut) -> JoinHandle<()>
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let outcome = supervisor.run(name, task).await;
+            if !supervisor.shutdown.is_cancelled() {
+                error!("Task {} {}, shutting down", name, outcome);
+                supervisor
+                    .liveness
+                    .record_failure(name, &outcome.description, false);
+                supervisor.fail(&outcome);
+            }
+        })
+    }
+
+    // Runs the task in a tokio task of its own, so that a panic is caught, and describes how it
+    // ended.
+    async fn run<Fut>(&self, name: &'static str, task: Fut) -> Outcome
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let _alive = self.liveness.track(name);
+        match tokio::spawn(task).await {
+            Ok(()) => Outcome {
+                description: "stopped".to_string(),
+                panicked: false,
+            },
+            Err(e) => describe(e),
+        }
+    }
+
+    fn fail(&self, outcome: &Outcome) {
+        if outcome.panicked {
+            crash::record_fatal_panic();
+        }
+        self.failed.store(true, Ordering::Relaxed);
+        self.shutdown.cancel();
+    }
+}
+
+fn describe(error: JoinError) -> Outcome {
+    match error.try_into_panic() {
+        Ok(payload) => Outcome {
+            description: format!("panicked: {}", panic_message(payload.as_ref())),
+            panicked: true,
+        },
+        Err(error) => Outcome {
+            description: format!("failed: {}", error),
+            panicked: false,
+        },
+    }
+}
+
+// The message of a panic, when it was raised with one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
@@ -0,0 +1,87 @@
+// telemetry.rs
+//
+// Distributed traces of the flush and write path, exported over OTLP (gRPC) to a collector when
+// a `[tracing]` section is configured. Spans are created with the `tracing` crate around each
+// flush, each InfluxDB write and write attempt, and each HTTP request, while logging stays with
+// the `log` facade: every log record is also recorded as an event of the current span, so that
+// a trace shows what was logged while it ran. Without the section no subscriber is installed and
+// spans cost next to nothing.
+//
+// Spans are exported in batches by a background task. When the collector cannot be reached the
+// batches are dropped once the queue is full, so the pipeline never waits on the exporter.
+
+use crate::config::TracingConfig;
+
+use log::{info, warn, Record};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use tokio::time::{timeout, Duration};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+// How long an export may take before the batch is dropped.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the spans still queued may take to be exported at shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Telemetry {
+    provider: TracerProvider,
+}
+
+impl Telemetry {
+    // Starts exporting the spans to the configured collector. Must be called from within the
+    // tokio runtime, which runs the exporter. The collector does not need to be reachable yet.
+    pub fn init(config: &TracingConfig) -> Result<Self, String> {
+        let exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()
+            .map_err(|e| format!("cannot create the OTLP exporter: {}", e))?;
+        // A trace started upstream, e.g. by a caller of the HTTP API, keeps its sampling decision
+        let sampler =
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_ratio)));
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, Tokio)
+            .with_sampler(sampler)
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )]))
+            .build();
+
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()
+            .map_err(|e| format!("cannot install the tracing subscriber: {}", e))?;
+        info!(
+            "Exporting traces to {} as {}, sampling {}",
+            config.endpoint, config.service_name, config.sampling_ratio
+        );
+        Ok(Self { provider })
+    }
+
+    // Exports the spans still queued, giving up after a short while.
+    pub async fn shutdown(self) {
+        let provider = self.provider;
+        let shutdown = tokio::task::spawn_blocking(move || provider.shutdown());
+        match timeout(SHUTDOWN_TIMEOUT, shutdown).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(e))) => warn!("Failed to export the last spans: {}", e),
+            Ok(Err(e)) => warn!("Failed to export the last spans: {}", e),
+            Err(_) => warn!("Timed out exporting the last spans"),
+        }
+    }
+}
+
+// Records the log record as an event of the current span, if traces are exported.
+pub(crate) fn record_log(record: &Record) {
+    // Never fails, the result only exists for compatibility
+    let _ = tracing_log::format_trace(record);
+}
@@ -0,0 +1,613 @@
+// testing.rs
+//
+// Support for the integration tests, built with the `testing` feature. `MockInfluxDB` serves the
+// `/api/v2/write` and `/health` endpoints of InfluxDB on a local port and records every write it
+// receives, decompressed, so that the tests can drive the real `InfluxDBManager`, `Cache`, and
+// flush against it and assert the exact line protocol that reached the server. The next writes
+// can be answered with an error status instead, to exercise the retries and the dead letters,
+// and the mock can serve HTTPS with a given certificate, to exercise the TLS settings.
+//
+// `MockSink` stands for any sink where the HTTP round trip does not matter: it records the line
+// protocol of every batch it is given and answers as scripted, so that the cache, the fan-out,
+// and the health routes can be driven through failures without a server.
+//
+// `MockSource` stands for a device: it hands the read loop the frames the test feeds it, and its
+// health check and its shutdown can be made to hang. `point` and `temperatures` build the points
+// the tests write.
+
+use crate::config::InfluxDBConfig;
+use crate::errors::AppError;
+use crate::frame_log::FrameLog;
+use crate::line_protocol::render;
+use crate::metrics::SourceMetrics;
+use crate::sink::{DataSink, SinkError};
+use crate::source::SensorSource;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use influxdb2::models::DataPoint;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::{Reply as _, Response};
+use warp::Filter;
+
+pub const ORG: &str = "aero";
+pub const BUCKET: &str = "sensors";
+pub const TOKEN: &str = "test-token";
+
+// 2023-11-14T22:13:20Z, when the points of the tests were taken.
+pub const TIMESTAMP: i64 = 1_700_000_000_000_000_000;
+
+// How long `MockSource::wait_for_reads` waits for the read loop.
+const READ_DEADLINE: Duration = Duration::from_secs(2);
+
+// A write request, as the mock received it.
+#[derive(Clone, Debug)]
+pub struct ReceivedWrite {
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub precision: Option<String>,
+    pub authorization: Option<String>,
+    pub gzip: bool,
+    // The body as it came over the wire, compressed or not.
+    pub raw_body: Vec<u8>,
+    // The line protocol of the body, decompressed.
+    pub body: String,
+    // Whether the mock answered 204.
+    pub accepted: bool,
+}
+
+// How the mock answers a write.
+#[derive(Clone, Debug)]
+pub enum Reply {
+    // 204, the points are written.
+    Accept,
+    // The status with the JSON error body of InfluxDB, its `message` set to the text.
+    Error(u16, String),
+    // 429, asking to retry after the given seconds.
+    Throttle(u64),
+}
+
+#[derive(Default)]
+struct State {
+    writes: Vec<ReceivedWrite>,
+    // The replies to the next writes, in order. Writes are accepted once it is empty.
+    replies: VecDeque<Reply>,
+    // The replies to the next writes to a bucket, ahead of `replies`.
+    bucket_replies: HashMap<String, VecDeque<Reply>>,
+    unhealthy: bool,
+}
+
+pub struct MockInfluxDB {
+    address: SocketAddr,
+    tls: bool,
+    state: Arc<Mutex<State>>,
+    shutdown: CancellationToken,
+}
+
+impl MockInfluxDB {
+    // Serves on a free local port until dropped.
+    pub async fn start() -> Self {
+        Self::serve(None)
+    }
+
+    // Serves HTTPS with the PEM certificate and key at the given paths, e.g. a self-signed pair.
+    pub async fn start_tls(cert_path: &Path, key_path: &Path) -> Self {
+        Self::serve(Some((cert_path, key_path)))
+    }
+
+    fn serve(tls: Option<(&Path, &Path)>) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = CancellationToken::new();
+
+        let writes = state.clone();
+        let write = warp::post()
+            .and(warp::path!("api" / "v2" / "write"))
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::header::optional::<String>("content-encoding"))
+            .and(warp::body::bytes())
+            .map(move |query, authorization, encoding, body| {
+                record_write(&writes, query, authorization, encoding, body)
+            });
+        let health_state = state.clone();
+        let health = warp::get().and(warp::path!("health")).map(move || {
+            let unhealthy = health_state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .unhealthy;
+            let (status, check) = match unhealthy {
+                false => (StatusCode::OK, "pass"),
+                true => (StatusCode::SERVICE_UNAVAILABLE, "fail"),
+            };
+            let body = json!({
+                "name": "influxdb",
+                "message": "mock",
+                "status": check,
+                "checks": [],
+                "version": "v2.7.0",
+                "commit": "mock",
+            });
+            warp::reply::with_status(warp::reply::json(&body), status).into_response()
+        });
+
+        let cancelled = shutdown.clone();
+        let stopped = async move { cancelled.cancelled().await };
+        let server = warp::serve(write.or(health));
+        let address = match tls {
+            None => {
+                let (address, server) =
+                    server.bind_with_graceful_shutdown(([127, 0, 0, 1], 0), stopped);
+                tokio::spawn(server);
+                address
+            }
+            Some((cert_path, key_path)) => {
+                let (address, server) = server
+                    .tls()
+                    .cert_path(cert_path)
+                    .key_path(key_path)
+                    .bind_with_graceful_shutdown(([127, 0, 0, 1], 0), stopped);
+                tokio::spawn(server);
+                address
+            }
+        };
+        Self {
+            address,
+            tls: tls.is_some(),
+            state,
+            shutdown,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        let scheme = match self.tls {
+            false => "http",
+            true => "https",
+        };
+        format!("{}://{}", scheme, self.address)
+    }
+
+    // Answers the next `times` writes with `reply`, after the replies queued before.
+    pub fn reply(&self, reply: Reply, times: usize) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.replies.extend((0..times).map(|_| reply.clone()));
+    }
+
+    // Answers the next `times` writes to `bucket` with `reply`, whatever the other buckets get.
+    pub fn reply_to_bucket(&self, bucket: &str, reply: Reply, times: usize) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let replies = state.bucket_replies.entry(bucket.to_string()).or_default();
+        replies.extend((0..times).map(|_| reply.clone()));
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .unhealthy = !healthy;
+    }
+
+    // Every write received so far, the refused ones included.
+    pub fn writes(&self) -> Vec<ReceivedWrite> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.writes.clone()
+    }
+
+    // The lines of the accepted writes, in the order they were received.
+    pub fn written_lines(&self) -> Vec<String> {
+        self.writes()
+            .iter()
+            .filter(|write| write.accepted)
+            .flat_map(|write| write.body.lines().map(str::to_string).collect::<Vec<_>>())
+            .collect()
+    }
+
+    // A configuration writing to the mock, with retries short enough for the tests and no
+    // startup preflight, which the mock does not serve.
+    pub fn config(&self) -> InfluxDBConfig {
+        serde_json::from_value(json!({
+            "url": self.url(),
+            "org": ORG,
+            "bucket": BUCKET,
+            "auth_token": TOKEN,
+            "retry": {
+                "max_attempts": 3,
+                "initial_backoff_ms": 10,
+                "max_backoff_ms": 50,
+                "deadline_secs": 10,
+            },
+            "write_timeout_secs": 5,
+            "preflight": "off",
+        }))
+        .expect("the mock configuration deserializes")
+    }
+}
+
+impl Drop for MockInfluxDB {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+fn record_write(
+    state: &Mutex<State>,
+    query: HashMap<String, String>,
+    authorization: Option<String>,
+    encoding: Option<String>,
+    body: Bytes,
+) -> Response {
+    let gzip = encoding.as_deref() == Some("gzip");
+    let raw_body = body.to_vec();
+    let body = match gzip {
+        true => {
+            let mut decoded = String::new();
+            match GzDecoder::new(&body[..]).read_to_string(&mut decoded) {
+                Ok(_) => decoded,
+                Err(e) => return error(400, &format!("invalid gzip body: {}", e)),
+            }
+        }
+        false => String::from_utf8_lossy(&body).into_owned(),
+    };
+
+    let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+    let bucket_reply = query
+        .get("bucket")
+        .and_then(|bucket| state.bucket_replies.get_mut(bucket))
+        .and_then(VecDeque::pop_front);
+    let reply = bucket_reply
+        .or_else(|| state.replies.pop_front())
+        .unwrap_or(Reply::Accept);
+    state.writes.push(ReceivedWrite {
+        org: query.get("org").cloned(),
+        bucket: query.get("bucket").cloned(),
+        precision: query.get("precision").cloned(),
+        authorization,
+        gzip,
+        raw_body,
+        body,
+        accepted: matches!(reply, Reply::Accept),
+    });
+    match reply {
+        Reply::Accept => StatusCode::NO_CONTENT.into_response(),
+        Reply::Error(status, message) => error(status, &message),
+        Reply::Throttle(secs) => {
+            let response = error(429, "too many requests");
+            warp::reply::with_header(response, "Retry-After", secs.to_string()).into_response()
+        }
+    }
+}
+
+// An error answer, with the body InfluxDB sends.
+fn error(status: u16, message: &str) -> Response {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let body = json!({ "code": "invalid", "message": message });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+// A new empty directory under the system temporary directory, e.g. for the dead letters.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "aero-sensor-broker-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).expect("the temporary directory can be created");
+    path
+}
+
+// How `MockSink` answers a write or a health check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkReply {
+    Accept,
+    // `SinkError::Rejected`, for good.
+    Reject,
+    // `SinkError::Unavailable`, worth retrying.
+    Unavailable,
+    // Never answers.
+    Hang,
+}
+
+// A batch, as the mock sink was given it, and when on the monotonic clock of tokio.
+#[derive(Clone, Debug)]
+pub struct SinkWrite {
+    pub lines: Vec<String>,
+    pub accepted: bool,
+    pub at: Instant,
+}
+
+#[derive(Default)]
+struct SinkState {
+    writes: Vec<SinkWrite>,
+    // The replies to the next writes, in order. Writes are accepted once it is empty.
+    replies: VecDeque<SinkReply>,
+    health: Option<SinkReply>,
+}
+
+pub struct MockSink {
+    name: String,
+    state: Mutex<SinkState>,
+}
+
+impl MockSink {
+    pub fn new(name: &str) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_string(),
+            state: Mutex::default(),
+        })
+    }
+
+    // Answers the next `times` writes with `reply`, after the replies queued before.
+    pub fn reply(&self, reply: SinkReply, times: usize) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.replies.extend((0..times).map(|_| reply));
+    }
+
+    // Answers the health checks with `reply` from now on.
+    pub fn set_health(&self, reply: SinkReply) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .health = Some(reply);
+    }
+
+    // Every batch given so far, the refused ones included.
+    pub fn writes(&self) -> Vec<SinkWrite> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.writes.clone()
+    }
+
+    // The lines of the accepted batches, in the order they were given.
+    pub fn written_lines(&self) -> Vec<String> {
+        self.writes()
+            .into_iter()
+            .filter(|write| write.accepted)
+            .flat_map(|write| write.lines)
+            .collect()
+    }
+
+    async fn answer(&self, reply: SinkReply, what: &str) -> Result<(), SinkError> {
+        match reply {
+            SinkReply::Accept => Ok(()),
+            SinkReply::Reject => Err(SinkError::Rejected(format!(
+                "{} rejected by {}",
+                what, self.name
+            ))),
+            SinkReply::Unavailable => Err(SinkError::Unavailable(format!("{} is down", self.name))),
+            SinkReply::Hang => std::future::pending().await,
+        }
+    }
+}
+
+#[async_trait]
+impl DataSink for MockSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        let reply = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let reply = state.replies.pop_front().unwrap_or(SinkReply::Accept);
+            state.writes.push(SinkWrite {
+                lines: points.iter().map(render).collect(),
+                accepted: reply == SinkReply::Accept,
+                at: Instant::now(),
+            });
+            reply
+        };
+        self.answer(reply, "batch").await
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        let reply = self
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .health
+            .unwrap_or(SinkReply::Accept);
+        self.answer(reply, "health check").await
+    }
+}
+
+// A point of the measurement with the tags and a `value` field.
+pub fn point(measurement: &str, tags: &[(&str, &str)], value: f64, timestamp: i64) -> DataPoint {
+    tags.iter()
+        .fold(DataPoint::builder(measurement), |builder, (key, value)| {
+            builder.tag(*key, *value)
+        })
+        .field("value", value)
+        .timestamp(timestamp)
+        .build()
+        .expect("the point has a field")
+}
+
+// Temperatures with the values, a nanosecond apart from `TIMESTAMP + offset` on.
+pub fn temperatures(values: &[f64], offset: i64) -> Vec<DataPoint> {
+    values
+        .iter()
+        .zip(0..)
+        .map(|(value, index)| point("temperature", &[], *value, TIMESTAMP + offset + index))
+        .collect()
+}
+
+// What `MockSource` tells its observer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceEvent {
+    // A read started.
+    Read,
+    // The source is shutting down.
+    Shutdown,
+}
+
+type SourceObserver = Arc<dyn Fn(SourceEvent) + Send + Sync>;
+
+pub struct MockSource {
+    name: String,
+    feed: mpsc::UnboundedSender<String>,
+    frames: tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>,
+    // Frames fed, and reads started: once there is one more read than frames, the read loop
+    // handled every frame fed.
+    fed: AtomicUsize,
+    reads: AtomicUsize,
+    hang_health_checks: AtomicBool,
+    hang_on_close: AtomicBool,
+    // The next read fails as with an unplugged device, which then never comes back.
+    lost: AtomicBool,
+    observer: Mutex<Option<SourceObserver>>,
+    metrics: SourceMetrics,
+    recent_frames: FrameLog,
+}
+
+impl MockSource {
+    pub fn new(name: &str) -> Arc<Self> {
+        let (feed, frames) = mpsc::unbounded_channel();
+        Arc::new(Self {
+            name: name.to_string(),
+            feed,
+            frames: tokio::sync::Mutex::new(frames),
+            fed: AtomicUsize::new(0),
+            reads: AtomicUsize::new(0),
+            hang_health_checks: AtomicBool::new(false),
+            hang_on_close: AtomicBool::new(false),
+            lost: AtomicBool::new(false),
+            observer: Mutex::new(None),
+            metrics: SourceMetrics::default(),
+            recent_frames: FrameLog::new(0),
+        })
+    }
+
+    // Hands the frame to the next read.
+    pub fn feed(&self, frame: &str) {
+        self.fed.fetch_add(1, Ordering::SeqCst);
+        // The source holds the receiver, so the channel never closes
+        let _ = self.feed.send(frame.to_string());
+    }
+
+    // Waits until the read loop handled every frame fed and waits for the next one.
+    pub async fn wait_for_reads(&self) {
+        let waited = tokio::time::timeout(READ_DEADLINE, async {
+            while self.reads.load(Ordering::SeqCst) <= self.fed.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        })
+        .await;
+        assert!(
+            waited.is_ok(),
+            "the frames fed to {} were not read",
+            self.name
+        );
+    }
+
+    // Makes the health checks hang from now on.
+    pub fn hang_health_checks(&self) {
+        self.hang_health_checks.store(true, Ordering::Relaxed);
+    }
+
+    // Makes the shutdown hang.
+    pub fn hang_on_close(&self) {
+        self.hang_on_close.store(true, Ordering::Relaxed);
+    }
+
+    // Makes the next read fail as an unplugged device does, and reopening the device hang, so
+    // that the read loop stops answering to the shutdown.
+    pub fn lose(&self) {
+        self.lost.store(true, Ordering::Relaxed);
+        // Wakes the read waiting for a frame
+        self.feed("");
+    }
+
+    // Calls `observer` on every read and at shutdown, before the read or the shutdown is done.
+    pub fn observe(&self, observer: impl Fn(SourceEvent) + Send + Sync + 'static) {
+        *self.observer.lock().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(observer));
+    }
+
+    fn notify(&self, event: SourceEvent) {
+        let observer = self
+            .observer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if let Some(observer) = observer {
+            observer(event);
+        }
+    }
+}
+
+#[async_trait]
+impl SensorSource for MockSource {
+    async fn read_data(&self) -> Result<String, AppError> {
+        self.notify(SourceEvent::Read);
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        match self.frames.lock().await.recv().await {
+            Some(_) if self.lost.load(Ordering::Relaxed) => {
+                Err(AppError::Device("device unplugged".to_string()))
+            }
+            Some(frame) => Ok(frame),
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn reconnect(&self) -> Result<(), AppError> {
+        if self.lost.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+        Ok(())
+    }
+
+    async fn write_command(&self, _command: &str) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn subscribe_raw_lines(&self) -> broadcast::Receiver<String> {
+        broadcast::channel(1).1
+    }
+
+    async fn check_health(&self) -> Result<(), AppError> {
+        if self.hang_health_checks.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+        Ok(())
+    }
+
+    fn health_age(&self) -> Option<Duration> {
+        None
+    }
+
+    fn source_metrics(&self) -> &SourceMetrics {
+        &self.metrics
+    }
+
+    fn port_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn last_frame_age(&self) -> Option<Duration> {
+        None
+    }
+
+    fn recent_frames(&self) -> &FrameLog {
+        &self.recent_frames
+    }
+
+    async fn shutdown(&self) -> Result<(), AppError> {
+        self.notify(SourceEvent::Shutdown);
+        if self.hang_on_close.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+// tls.rs
+//
+// TLS termination of the HTTP API, configured by `[http.tls]`. The certificate chain, the key,
+// and the client CA are checked before the server binds, so that files which do not parse, or a
+// key which is not the one of the certificate, fail the startup with a readable error instead of
+// every handshake.
+//
+// cert-manager rotates the files in place. warp cannot swap the certificate of a listening
+// server, and closing it gracefully would wait for the `/api/stream` subscribers forever, so the
+// files are watched instead: a rotation is checked and logged as requiring a restart, which a
+// rolling restart of the deployment takes care of.
+
+use crate::config::HttpTlsConfig;
+
+use log::{error, warn};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, SignatureVerificationAlgorithm};
+use rustls::SignatureScheme;
+use std::fs;
+use std::io::BufReader;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use webpki::EndEntityCert;
+
+// How often the files are read again to tell a rotation.
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Signed with the key and verified with the certificate to tell that they belong together.
+const PROBE_MESSAGE: &[u8] = b"aero-sensor-broker tls key check";
+
+// Schemes the key may sign with, and the algorithms verifying them.
+const SCHEMES: [(SignatureScheme, &dyn SignatureVerificationAlgorithm); 4] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        webpki::ring::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        webpki::ring::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, webpki::ring::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        webpki::ring::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+];
+
+// Checks that the files parse, that the key is the one of the certificate, and that the client
+// CA, if any, holds certificates. Returns what the server presents, for the logs.
+pub fn check(config: &HttpTlsConfig) -> Result<String, String> {
+    let chain = read_certs(&config.cert_path)?;
+    let key = read_key(&config.key_path)?;
+    check_key_matches(&chain[0], &key).map_err(|e| {
+        format!(
+            "{} does not match {}: {}",
+            config.key_path, config.cert_path, e
+        )
+    })?;
+    if let Some(path) = &config.client_ca_path {
+        for ca in read_certs(path)? {
+            webpki::anchor_from_trusted_cert(&ca)
+                .map_err(|e| format!("{}: invalid CA certificate: {}", path, e))?;
+        }
+    }
+    Ok(format!(
+        "{} ({} certificate(s)){}",
+        config.cert_path,
+        chain.len(),
+        match &config.client_ca_path {
+            Some(path) => format!(", client certificates signed by {}", path),
+            None => String::new(),
+        }
+    ))
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: invalid PEM: {}", path, e))?;
+    match certs.is_empty() {
+        true => Err(format!("{}: no certificate found", path)),
+        false => Ok(certs),
+    }
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: invalid PEM: {}", path, e))?
+        .ok_or_else(|| format!("{}: no private key found", path))
+}
+
+// Signs with the key and verifies the signature with the public key of the certificate.
+fn check_key_matches(cert: &CertificateDer<'_>, key: &PrivateKeyDer<'_>) -> Result<(), String> {
+    let key = any_supported_type(key).map_err(|e| format!("unsupported key: {}", e))?;
+    let offered: Vec<SignatureScheme> = SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = key
+        .choose_scheme(&offered)
+        .ok_or("the key cannot sign with any supported scheme")?;
+    let signature = signer.sign(PROBE_MESSAGE).map_err(|e| e.to_string())?;
+    let algorithm = SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .ok_or("the key signed with an unexpected scheme")?;
+    let cert = EndEntityCert::try_from(cert).map_err(|e| format!("invalid certificate: {}", e))?;
+    cert.verify_signature(algorithm, PROBE_MESSAGE, &signature)
+        .map_err(|_| "the certificate is for another key".to_string())
+}
+
+// Reads the files every `WATCH_INTERVAL` until `shutdown` is cancelled, and logs when they
+// changed that the server must be restarted to use them, or that they are invalid.
+pub async fn watch(config: HttpTlsConfig, shutdown: CancellationToken) {
+    let mut paths = vec![config.cert_path.as_str(), config.key_path.as_str()];
+    paths.extend(config.client_ca_path.as_deref());
+    let read =
+        || -> Vec<Option<Vec<u8>>> { paths.iter().map(|path| fs::read(path).ok()).collect() };
+    let mut seen = read();
+    let mut ticks = interval(WATCH_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let current = read();
+        if current == seen {
+            continue;
+        }
+        seen = current;
+        // Files caught half-written are reported as invalid, and checked again once complete
+        match check(&config) {
+            Ok(_) => warn!(
+                "TLS certificate files {} were rotated, restart required: the HTTP server \
+                 keeps serving the previous certificate until then",
+                paths.join(", ")
+            ),
+            Err(e) => error!(
+                "TLS certificate files {} changed but are invalid, the HTTP server keeps serving \
+                 the previous certificate: {}",
+                paths.join(", "),
+                e
+            ),
+        }
+    }
+}
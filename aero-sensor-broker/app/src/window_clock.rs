@@ -0,0 +1,137 @@
+// window_clock.rs
+//
+// Decides when an aggregation window is over, going by the monotonic clock so that the windows
+// keep their length whatever the wall clock does; the wall clock only stamps the points. It also
+// watches for the wall clock jumping: when the host wakes up from a suspend (kiosk gateways on
+// laptop hardware do), the wall clock is hours ahead while the monotonic one, which stops during
+// the suspend, is not. Without that check the points read before the suspend would be averaged
+// with those read after it, into a point stamped somewhere in the middle. The read loop rather
+// closes the window before the jump on its own, or discards it, and starts a new one.
+//
+// The times are passed in rather than read here, so the behaviour can be checked with any
+// sequence of clock readings.
+
+use crate::config::AggregationConfig;
+
+use tokio::time::{Duration, Instant};
+
+pub struct WindowClock {
+    window: Duration,
+    // Drift of the wall clock from the monotonic one taken for a jump, `None` when not checked.
+    jump_threshold: Option<Duration>,
+    started: Instant,
+    // The wall clock in Unix milliseconds and the monotonic one when last observed.
+    last_seen: Option<(i64, Instant)>,
+}
+
+impl WindowClock {
+    pub fn new(config: &AggregationConfig, now: Instant) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            jump_threshold: jump_threshold(config),
+            started: now,
+            last_seen: None,
+        }
+    }
+
+    // Applies reloaded settings; the current window keeps its start.
+    pub fn reconfigure(&mut self, config: &AggregationConfig) {
+        self.window = Duration::from_secs(config.window_secs);
+        self.jump_threshold = jump_threshold(config);
+    }
+
+    // How far the wall clock jumped, forwards or backwards, since it was last observed beyond
+    // what the monotonic clock advanced meanwhile, if more than the threshold.
+    pub fn observe(&mut self, wall_ms: i64, now: Instant) -> Option<i64> {
+        let last_seen = self.last_seen.replace((wall_ms, now));
+        let threshold = self.jump_threshold?;
+        let (last_wall_ms, last_now) = last_seen?;
+        let monotonic_ms = now.saturating_duration_since(last_now).as_millis() as i64;
+        let jump_ms = (wall_ms - last_wall_ms) - monotonic_ms;
+        (jump_ms.unsigned_abs() > threshold.as_millis() as u64).then_some(jump_ms)
+    }
+
+    pub fn is_over(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.started) >= self.window
+    }
+
+    // Time left until the window is over, none once it is.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        (self.started + self.window).saturating_duration_since(now)
+    }
+
+    pub fn restart(&mut self, now: Instant) {
+        self.started = now;
+    }
+}
+
+fn jump_threshold(config: &AggregationConfig) -> Option<Duration> {
+    (config.time_jump_secs > 0).then(|| Duration::from_secs(config.time_jump_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // 2023-11-14T22:13:20Z
+    const WALL_MS: i64 = 1_700_000_000_000;
+
+    fn window_clock(now: Instant) -> WindowClock {
+        let config: AggregationConfig =
+            serde_json::from_value(json!({"window_secs": 60, "time_jump_secs": 300})).unwrap();
+        WindowClock::new(&config, now)
+    }
+
+    #[test]
+    fn the_wall_clock_keeping_pace_is_no_jump() {
+        let start = Instant::now();
+        let mut clock = window_clock(start);
+
+        assert_eq!(clock.observe(WALL_MS, start), None);
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(clock.observe(WALL_MS + 3_600_000, later), None);
+    }
+
+    #[test]
+    fn a_jump_is_told_once_it_crosses_the_threshold() {
+        let start = Instant::now();
+        let mut clock = window_clock(start);
+        clock.observe(WALL_MS, start);
+
+        // A suspend: the wall clock moves on while the monotonic one stands still
+        let just_within = WALL_MS + 300_000;
+        assert_eq!(clock.observe(just_within, start), None);
+        let past = just_within + 300_001;
+        assert_eq!(clock.observe(past, start), Some(300_001));
+        // A step back of the wall clock
+        assert_eq!(clock.observe(WALL_MS, start), Some(-600_001));
+    }
+
+    #[test]
+    fn the_window_goes_by_the_monotonic_clock_alone() {
+        let start = Instant::now();
+        let mut clock = window_clock(start);
+        clock.observe(WALL_MS, start);
+
+        // Hours on the wall clock, seconds on the monotonic one
+        let now = start + Duration::from_secs(59);
+        assert!(clock.observe(WALL_MS + 7_200_000, now).is_some());
+        assert!(!clock.is_over(now));
+
+        clock.restart(now);
+        assert!(!clock.is_over(now + Duration::from_secs(59)));
+        assert!(clock.is_over(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn a_threshold_of_0_turns_the_check_off() {
+        let config: AggregationConfig =
+            serde_json::from_value(json!({"time_jump_secs": 0})).unwrap();
+        let start = Instant::now();
+        let mut clock = WindowClock::new(&config, start);
+        clock.observe(WALL_MS, start);
+
+        assert_eq!(clock.observe(WALL_MS + 86_400_000, start), None);
+    }
+}
@@ -0,0 +1,116 @@
+// aggregation.rs
+//
+// The series the aggregator averages apart: points of different sources, or of different
+// devices of a source, must never end up in the same average, whatever their measurement.
+
+use aero_sensor_broker::config::AggregationConfig;
+use aero_sensor_broker::data_manipulation::{Aggregator, MyDataPoint, Reading};
+use aero_sensor_broker::line_protocol::render;
+
+use serde_json::json;
+use std::collections::BTreeMap;
+
+// 2023-11-14T22:13:20Z
+const TIMESTAMP: i64 = 1_700_000_000_000_000_000;
+
+fn aggregator() -> Aggregator {
+    let config: AggregationConfig = serde_json::from_value(json!({})).unwrap();
+    Aggregator::new(&config)
+}
+
+fn temperature(source: &str, device_id: &str, value: f64, offset_secs: i64) -> MyDataPoint {
+    let tags = BTreeMap::from([
+        ("source".to_string(), source.to_string()),
+        ("device_id".to_string(), device_id.to_string()),
+    ]);
+    MyDataPoint::from_reading(
+        "temperature".to_string(),
+        tags,
+        Reading::Value(value),
+        TIMESTAMP + offset_secs * 1_000_000_000,
+    )
+}
+
+fn rendered(mut aggregator: Aggregator, points: Vec<MyDataPoint>) -> Vec<String> {
+    let mut lines: Vec<String> = aggregator.aggregate(points).iter().map(render).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn two_sources_measuring_the_same_are_averaged_apart() {
+    let points = vec![
+        temperature("intake", "board-a", 20.0, 0),
+        temperature("exhaust", "board-b", 30.0, 0),
+        temperature("intake", "board-a", 22.0, 10),
+        temperature("exhaust", "board-b", 32.0, 10),
+    ];
+
+    assert_eq!(
+        rendered(aggregator(), points),
+        [
+            "temperature,device_id=board-a,source=intake value=21 1700000005000000000",
+            "temperature,device_id=board-b,source=exhaust value=31 1700000005000000000",
+        ]
+    );
+}
+
+#[test]
+fn two_devices_of_a_source_are_averaged_apart() {
+    let points = vec![
+        temperature("intake", "board-a", 20.0, 0),
+        temperature("intake", "board-b", 30.0, 0),
+    ];
+
+    assert_eq!(
+        rendered(aggregator(), points),
+        [
+            "temperature,device_id=board-a,source=intake value=20 1700000000000000000",
+            "temperature,device_id=board-b,source=intake value=30 1700000000000000000",
+        ]
+    );
+}
+
+#[test]
+fn the_same_device_id_on_two_sources_is_averaged_apart() {
+    let points = vec![
+        temperature("intake", "board-a", 20.0, 0),
+        temperature("exhaust", "board-a", 30.0, 0),
+    ];
+
+    assert_eq!(
+        rendered(aggregator(), points),
+        [
+            "temperature,device_id=board-a,source=exhaust value=30 1700000000000000000",
+            "temperature,device_id=board-a,source=intake value=20 1700000000000000000",
+        ]
+    );
+}
+
+#[test]
+fn every_window_keeps_the_series_apart() {
+    let mut aggregator = aggregator();
+    for window in 0..3 {
+        let offset = window * 60;
+        let points = vec![
+            temperature("intake", "board-a", 20.0, offset),
+            temperature("exhaust", "board-b", 30.0, offset),
+        ];
+        let mut lines: Vec<String> = aggregator.aggregate(points).iter().map(render).collect();
+        lines.sort();
+        let timestamp = TIMESTAMP + offset * 1_000_000_000;
+        assert_eq!(
+            lines,
+            [
+                format!(
+                    "temperature,device_id=board-a,source=intake value=20 {}",
+                    timestamp
+                ),
+                format!(
+                    "temperature,device_id=board-b,source=exhaust value=30 {}",
+                    timestamp
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+// auth.rs
+//
+// The bearer token required by every route but the probes, over the health routes and a
+// stand-in for the protected routes mounted as the broker mounts them, with the `MockSink` of the
+// `testing` module behind the probes. Run with `cargo test`.
+
+use aero_sensor_broker::build_info::BuildInfo;
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::config::HealthConfig;
+use aero_sensor_broker::liveness::Liveness;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::pause::IngestionControl;
+use aero_sensor_broker::routes::{create_health_route, handle_rejection, with_auth, HealthPolicy};
+use aero_sensor_broker::sink::DataSink;
+use aero_sensor_broker::startup::Startup;
+use aero_sensor_broker::testing::MockSink;
+
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::Filter;
+
+const TOKEN: &str = "s3cr3t-t0k3n";
+
+// Requests `path` with the given `Authorization` header, if any, from the routes of a broker
+// configured with `token`.
+async fn request(
+    token: Option<&str>,
+    path: &str,
+    authorization: Option<&str>,
+) -> (StatusCode, Option<String>) {
+    let sink: Arc<dyn DataSink> = MockSink::new("influxdb");
+    let health_route = create_health_route(
+        Arc::new(Vec::new()),
+        sink,
+        Cache::new(100, Arc::new(Metrics::default())),
+        IngestionControl::default(),
+        Liveness::default(),
+        Startup::default(),
+        BuildInfo::current(),
+        HealthPolicy::new(&HealthConfig::default(), Duration::from_millis(200)),
+    );
+    let protected_routes = warp::path!("version").and(warp::get()).map(|| "aero");
+    let routes = health_route
+        .or(with_auth(token.map(str::to_string)).and(protected_routes))
+        .recover(handle_rejection);
+
+    let request = warp::test::request().path(path);
+    let request = match authorization {
+        Some(authorization) => request.header("authorization", authorization),
+        None => request,
+    };
+    let response = request.reply(&routes).await;
+    let challenge = response
+        .headers()
+        .get("www-authenticate")
+        .map(|value| value.to_str().unwrap().to_string());
+    (response.status(), challenge)
+}
+
+#[tokio::test]
+async fn a_request_without_a_token_is_challenged() {
+    let (status, challenge) = request(Some(TOKEN), "/version", None).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(challenge.as_deref(), Some("Bearer"));
+}
+
+#[tokio::test]
+async fn the_configured_token_is_accepted() {
+    let authorization = format!("Bearer {}", TOKEN);
+
+    let (status, _) = request(Some(TOKEN), "/version", Some(&authorization)).await;
+
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn the_scheme_is_matched_whatever_its_case() {
+    for scheme in ["bearer", "BEARER", "BeArEr"] {
+        let authorization = format!("{} {}", scheme, TOKEN);
+
+        let (status, _) = request(Some(TOKEN), "/version", Some(&authorization)).await;
+
+        assert_eq!(status, StatusCode::OK, "{}", scheme);
+    }
+}
+
+#[tokio::test]
+async fn a_wrong_token_of_the_same_length_is_rejected() {
+    let wrong = "s3cr3t-t0k3m";
+    assert_eq!(wrong.len(), TOKEN.len());
+    let authorization = format!("Bearer {}", wrong);
+
+    let (status, challenge) = request(Some(TOKEN), "/version", Some(&authorization)).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(challenge.as_deref(), Some("Bearer"));
+}
+
+#[tokio::test]
+async fn the_token_under_another_scheme_is_rejected() {
+    let authorization = format!("Basic {}", TOKEN);
+
+    let (status, _) = request(Some(TOKEN), "/version", Some(&authorization)).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn the_probes_answer_without_a_token() {
+    for probe in ["/livez", "/readyz"] {
+        let (status, challenge) = request(Some(TOKEN), probe, None).await;
+
+        assert_eq!(status, StatusCode::OK, "{}", probe);
+        assert_eq!(challenge, None);
+    }
+}
+
+#[tokio::test]
+async fn every_request_goes_through_without_a_configured_token() {
+    let (status, _) = request(None, "/version", None).await;
+
+    assert_eq!(status, StatusCode::OK);
+}
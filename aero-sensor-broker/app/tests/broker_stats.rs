@@ -0,0 +1,111 @@
+// broker_stats.rs
+//
+// The `broker_stats` point the periodic flush adds to the cache, as it reaches the sink. Its
+// field names are relied on by dashboards, so they are checked one by one. The sink is the
+// `MockSink` of the `testing` module. Run with `cargo test`.
+
+use aero_sensor_broker::broker_stats::BrokerStats;
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::testing::{point, MockSink, TIMESTAMP};
+
+use influxdb2::models::DataPoint;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+fn metrics() -> Arc<Metrics> {
+    let metrics = Metrics::default();
+    metrics.frames_received.store(10, Ordering::Relaxed);
+    metrics.frames_invalid.store(3, Ordering::Relaxed);
+    metrics.points_written.store(40, Ordering::Relaxed);
+    metrics.flush_failures.store(1, Ordering::Relaxed);
+    metrics.cache_evictions.store(2, Ordering::Relaxed);
+    metrics.serial_reconnects.store(4, Ordering::Relaxed);
+    metrics.mqtt_reconnects.store(1, Ordering::Relaxed);
+    Arc::new(metrics)
+}
+
+fn reading(offset: i64) -> DataPoint {
+    point("temperature", &[], 21.5, TIMESTAMP + offset)
+}
+
+// The tags and the fields of a line, without the values of the fields the test cannot know.
+fn parse(line: &str) -> (String, BTreeMap<String, String>) {
+    let parts: Vec<&str> = line.split(' ').collect();
+    let fields = parts[1]
+        .split(',')
+        .map(|field| {
+            let (name, value) = field.split_once('=').unwrap();
+            let value = match name {
+                "rss_bytes" => "*".to_string(),
+                _ => value.to_string(),
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+    (parts[0].to_string(), fields)
+}
+
+#[tokio::test]
+async fn the_stats_reach_the_sink_with_the_flush() {
+    let metrics = metrics();
+    let cache = Arc::new(Cache::new(1000, metrics.clone()));
+    let sink = MockSink::new("influxdb");
+    let tags = BTreeMap::from([("location".to_string(), "lab".to_string())]);
+    let stats = Arc::new(BrokerStats::new(metrics, tags));
+    let (_interval, receiver) = watch::channel(FLUSH_INTERVAL);
+    let shutdown = CancellationToken::new();
+    cache.add(vec![reading(0), reading(1)]).await;
+
+    let flush = tokio::spawn({
+        let (cache, sink, shutdown) = (cache.clone(), sink.clone(), shutdown.clone());
+        async move {
+            cache
+                .periodic_flush(sink, receiver, None, None, Some(stats), shutdown)
+                .await
+        }
+    });
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while sink.written_lines().is_empty() {
+            tokio::time::sleep(FLUSH_INTERVAL / 5).await;
+        }
+    })
+    .await
+    .expect("nothing was flushed");
+    shutdown.cancel();
+    flush.await.unwrap();
+
+    let lines = sink.written_lines();
+    assert_eq!(lines.len(), 3, "{:?}", lines);
+    let stats = lines
+        .iter()
+        .find(|line| line.starts_with("broker_stats"))
+        .unwrap_or_else(|| panic!("no broker_stats in {:?}", lines));
+    let (series, fields) = parse(stats);
+    assert_eq!(series, "broker_stats,location=lab");
+    let mut expected: BTreeMap<String, String> = [
+        ("frames_valid", "7i"),
+        ("frames_invalid", "3i"),
+        ("points_written", "40i"),
+        ("flush_failures", "1i"),
+        // The two readings waiting when the point was taken
+        ("cache_len", "2i"),
+        ("evictions", "2i"),
+        ("reconnects", "5i"),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect();
+    // Left out where /proc cannot be read
+    if Path::new("/proc/self/status").exists() {
+        expected.insert("rss_bytes".to_string(), "*".to_string());
+    }
+    assert_eq!(fields, expected);
+}
@@ -0,0 +1,278 @@
+// clock.rs
+//
+// The read loop on a `MockClock`: the aggregation windows roll over, and the window open when
+// the wall clock jumps is closed or discarded, as the mock clock is moved, without waiting for
+// any of it. Only the window of a silent source takes a moment, until the read loop checks the
+// clock. The window open when ingestion is paused through the admin route is closed too. The
+// frames are fed one at a time by the test to the `MockSource` of the `testing` module, and the
+// points reach its `MockSink` through the final flush. Run with `cargo test`.
+
+use aero_sensor_broker::clock::{Clock, MockClock};
+use aero_sensor_broker::config::{ConfigSettings, ConfigSource};
+use aero_sensor_broker::errors::AppError;
+use aero_sensor_broker::reload::Reloader;
+use aero_sensor_broker::source::SensorSource;
+use aero_sensor_broker::testing::{temp_dir, MockSink, MockSource};
+use aero_sensor_broker::{run_with, Overrides};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const WINDOW: Duration = Duration::from_secs(60);
+// Longer than the read loop takes to notice that the window of a silent source is over.
+const CHECK_MARGIN: Duration = Duration::from_millis(1500);
+
+struct Broker {
+    clock: Arc<MockClock>,
+    source: Arc<MockSource>,
+    sink: Arc<MockSink>,
+    shutdown: CancellationToken,
+    run: tokio::task::JoinHandle<Result<(), AppError>>,
+}
+
+impl Broker {
+    // Runs the broker with a flush interval longer than the test, so that the points only reach
+    // the sink through the shutdown, and without the HTTP server.
+    async fn start(aggregation: Value) -> Self {
+        Self::start_with(aggregation, json!({"enabled": false})).await
+    }
+
+    async fn start_with(aggregation: Value, http: Value) -> Self {
+        let state_dir = temp_dir("clock");
+        let settings: ConfigSettings = serde_json::from_value(json!({
+            "influxdb": {
+                "url": "http://127.0.0.1:9",
+                "org": "aero",
+                "bucket": "sensors",
+                "auth_token": "unused",
+            },
+            "sources": [{"name": "bench"}],
+            "aggregation": aggregation,
+            "cache": {"flush_interval_secs": 3600, "heartbeat": false},
+            "http": http,
+            "state_file": state_dir.join("state.json"),
+        }))
+        .unwrap();
+
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let source = MockSource::new("bench");
+        let sink = MockSink::new("mock");
+        let overrides = Overrides {
+            devices: BTreeMap::from([(
+                "bench".to_string(),
+                source.clone() as Arc<dyn SensorSource>,
+            )]),
+            sinks: Some(vec![sink.clone()]),
+            clock: Some(clock.clone()),
+        };
+        let reloader = Reloader::new(ConfigSource::default(), false, &settings);
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(run_with(settings, reloader, overrides, shutdown.clone()));
+
+        Self {
+            clock,
+            source,
+            sink,
+            shutdown,
+            run,
+        }
+    }
+
+    // Feeds a temperature read `ago` before now on the mock clock, which is the host clock of
+    // the broker, and waits until it was handled.
+    async fn feed(&self, value: f64, ago: Duration) -> i64 {
+        let timestamp = self.clock.now_utc().timestamp() - ago.as_secs() as i64;
+        let frame = json!({"type": "temperature", "value": value, "timestamp": timestamp});
+        self.source.feed(&frame.to_string());
+        self.source.wait_for_reads().await;
+        timestamp * 1_000_000_000
+    }
+
+    // Stops the broker, returning the temperature lines written; the device timestamps also
+    // get the skew of the device clock written, which does not matter here.
+    async fn stop(self) -> Vec<String> {
+        self.shutdown.cancel();
+        let result = self.run.await.unwrap();
+        assert!(result.is_ok(), "{:?}", result);
+        let mut lines = self.sink.written_lines();
+        lines.retain(|line| !line.starts_with("clock_skew_ms,"));
+        lines
+    }
+}
+
+// Posts to a route of the HTTP server listening on `port`, once it does.
+async fn post(port: u16, path: &str) {
+    let url = format!("http://127.0.0.1:{}{}", port, path);
+    let client = reqwest::Client::new();
+    for _ in 0..50 {
+        match client.post(&url).send().await {
+            Ok(response) => {
+                assert!(
+                    response.status().is_success(),
+                    "{}: {}",
+                    url,
+                    response.status()
+                );
+                return;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+    panic!("{} never answered", url);
+}
+
+// The value, whether the window was closed early, and the timestamp of a temperature line.
+fn parse(line: &str) -> (f64, bool, i64) {
+    let parts: Vec<&str> = line.split(' ').collect();
+    assert!(parts[0].starts_with("temperature,"), "{}", line);
+    let field = |name: &str| {
+        parts[1]
+            .split(',')
+            .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+    };
+    let value = field("value").unwrap().parse().unwrap();
+    (
+        value,
+        field("covered_secs").is_some(),
+        parts[2].parse().unwrap(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_window_rolls_over_as_the_clock_advances() {
+    let broker = Broker::start(json!({"window_secs": WINDOW.as_secs()})).await;
+
+    let first = broker.feed(20.0, Duration::from_secs(12)).await;
+    broker.clock.advance(WINDOW / 2);
+    broker.feed(22.0, Duration::from_secs(10)).await;
+    broker.clock.advance(WINDOW / 2);
+    let last = broker.feed(24.0, Duration::from_secs(8)).await;
+    // The window was over as the third frame came, the fourth opens the next one
+    broker.clock.advance(Duration::from_secs(5));
+    let partial = broker.feed(30.0, Duration::from_secs(6)).await;
+
+    let lines = broker.stop().await;
+    let points: Vec<(f64, bool, i64)> = lines.iter().map(|line| parse(line)).collect();
+    assert_eq!(
+        points,
+        [(22.0, false, (first + last) / 2), (30.0, true, partial)],
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_window_open_when_the_wall_clock_jumps_is_closed_with_its_own_timestamps() {
+    let broker = Broker::start(json!({
+        "window_secs": WINDOW.as_secs(),
+        "time_jump_secs": 300,
+        "on_time_jump": "close",
+    }))
+    .await;
+
+    let before = broker.feed(20.0, Duration::from_secs(10)).await;
+    broker.clock.advance(Duration::from_secs(1));
+    // A suspend of two hours
+    broker.clock.jump(chrono::Duration::hours(2));
+    let after = broker.feed(30.0, Duration::from_secs(5)).await;
+
+    let lines = broker.stop().await;
+    let points: Vec<(f64, bool, i64)> = lines.iter().map(|line| parse(line)).collect();
+    assert_eq!(
+        points,
+        [(20.0, false, before), (30.0, true, after)],
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_window_open_when_the_wall_clock_jumps_can_be_discarded() {
+    let broker = Broker::start(json!({
+        "window_secs": WINDOW.as_secs(),
+        "time_jump_secs": 300,
+        "on_time_jump": "discard",
+    }))
+    .await;
+
+    broker.feed(20.0, Duration::from_secs(10)).await;
+    broker.clock.jump(chrono::Duration::hours(-2));
+    let after = broker.feed(30.0, Duration::from_secs(5)).await;
+
+    let lines = broker.stop().await;
+    let points: Vec<(f64, bool, i64)> = lines.iter().map(|line| parse(line)).collect();
+    assert_eq!(points, [(30.0, true, after)], "{:?}", lines);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_jump_within_the_threshold_leaves_the_window_open() {
+    let broker = Broker::start(json!({
+        "window_secs": WINDOW.as_secs(),
+        "time_jump_secs": 300,
+    }))
+    .await;
+
+    let before = broker.feed(20.0, Duration::from_secs(10)).await;
+    broker.clock.jump(chrono::Duration::seconds(299));
+    let after = broker.feed(30.0, Duration::from_secs(5)).await;
+
+    let lines = broker.stop().await;
+    let points: Vec<(f64, bool, i64)> = lines.iter().map(|line| parse(line)).collect();
+    assert_eq!(points, [(25.0, true, (before + after) / 2)], "{:?}", lines);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_window_of_a_silent_source_is_closed_on_time_and_counts_zero_samples() {
+    let broker = Broker::start(json!({
+        "window_secs": WINDOW.as_secs(),
+        "sample_count": "measurement",
+    }))
+    .await;
+
+    broker.feed(20.0, Duration::from_secs(10)).await;
+    // No frame comes any more: the windows are closed by the read loop checking the clock
+    broker.clock.advance(WINDOW);
+    tokio::time::sleep(CHECK_MARGIN).await;
+    broker.clock.advance(WINDOW);
+    tokio::time::sleep(CHECK_MARGIN).await;
+
+    let lines = broker.stop().await;
+    let counts: Vec<&str> = lines
+        .iter()
+        .filter_map(|line| line.strip_prefix("sample_count,"))
+        .filter_map(|line| line.split(' ').nth(1))
+        .collect();
+    assert_eq!(counts, ["value=1i", "value=0i"], "{:?}", lines);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_window_open_when_ingestion_is_paused_is_not_averaged_with_the_frames_after() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let http = json!({"bind_address": "127.0.0.1", "port": port});
+    let broker = Broker::start_with(json!({"window_secs": WINDOW.as_secs()}), http).await;
+
+    let before = broker.feed(20.0, Duration::from_secs(10)).await;
+    post(port, "/admin/pause").await;
+    broker.feed(99.0, Duration::from_secs(8)).await;
+    broker.feed(99.0, Duration::from_secs(7)).await;
+    post(port, "/admin/resume").await;
+    let after = broker.feed(30.0, Duration::from_secs(5)).await;
+
+    let lines = broker.stop().await;
+    let points: Vec<(f64, bool, i64)> = lines.iter().map(|line| parse(line)).collect();
+    assert_eq!(
+        points,
+        [(20.0, false, before), (30.0, true, after)],
+        "{:?}",
+        lines
+    );
+}
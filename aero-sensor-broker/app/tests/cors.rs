@@ -0,0 +1,105 @@
+// cors.rs
+//
+// The CORS filter built from `http.cors`, over a stand-in for the routes mounted as the broker
+// mounts them: allowed origins get the CORS headers, others are turned away, and preflight
+// requests are answered without reaching the handlers.
+
+use aero_sensor_broker::config::CorsConfig;
+use aero_sensor_broker::routes::{cors, handle_rejection};
+
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use warp::http::{Response, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Filter;
+
+const DASHBOARD: &str = "https://dashboard.example.com";
+
+fn config(origins: &[&str]) -> CorsConfig {
+    serde_json::from_value(json!({"allowed_origins": origins})).unwrap()
+}
+
+// Sends `request` to a stand-in route behind the CORS filter of `config`, returning the
+// response and how many times the handler ran.
+async fn send(
+    config: &CorsConfig,
+    request: warp::test::RequestBuilder,
+) -> (Response<Bytes>, usize) {
+    let handled = Arc::new(AtomicUsize::new(0));
+    let counter = handled.clone();
+    let route = warp::path!("version")
+        .and(warp::get())
+        .map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            "aero"
+        })
+        .with(cors(config, false).unwrap())
+        .recover(handle_rejection);
+    let response = request.reply(&route).await;
+    (response, handled.load(Ordering::SeqCst))
+}
+
+fn allowed_origin(response: &Response<Bytes>) -> Option<&str> {
+    response
+        .headers()
+        .get("access-control-allow-origin")
+        .map(|value| value.to_str().unwrap())
+}
+
+#[test]
+fn any_origin_is_rejected_while_auth_is_enabled() {
+    let error = cors(&config(&["*"]), true).err().unwrap();
+    assert!(error.contains("http.auth_token"), "{}", error);
+    assert!(cors(&config(&["*"]), false).is_ok());
+    assert!(cors(&config(&[DASHBOARD]), true).is_ok());
+}
+
+#[test]
+fn an_invalid_origin_is_rejected() {
+    let error = cors(&config(&["dashboard.example.com"]), false)
+        .err()
+        .unwrap();
+    assert!(error.contains("dashboard.example.com"), "{}", error);
+}
+
+#[tokio::test]
+async fn an_allowed_origin_gets_the_cors_headers() {
+    let request = warp::test::request()
+        .path("/version")
+        .header("origin", DASHBOARD);
+    let (response, handled) = send(&config(&[DASHBOARD]), request).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(allowed_origin(&response), Some(DASHBOARD));
+    assert_eq!(handled, 1);
+}
+
+#[tokio::test]
+async fn another_origin_is_turned_away() {
+    let request = warp::test::request()
+        .path("/version")
+        .header("origin", "https://elsewhere.example.com");
+    let (response, handled) = send(&config(&[DASHBOARD]), request).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(allowed_origin(&response), None);
+    assert_eq!(handled, 0);
+}
+
+#[tokio::test]
+async fn a_preflight_request_is_answered_without_reaching_the_handlers() {
+    let request = warp::test::request()
+        .method("OPTIONS")
+        .path("/version")
+        .header("origin", DASHBOARD)
+        .header("access-control-request-method", "GET");
+    let (response, handled) = send(&config(&[DASHBOARD]), request).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(allowed_origin(&response), Some(DASHBOARD));
+    assert!(response
+        .headers()
+        .contains_key("access-control-allow-methods"));
+    assert_eq!(handled, 0);
+}
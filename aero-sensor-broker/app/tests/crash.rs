@@ -0,0 +1,52 @@
+// crash.rs
+//
+// The crash marker is left by a fatal panic only: a panic of the main thread, or the panic of a
+// task the supervisor gives up on, and not a task panic it recovers from. An orderly exit removes
+// it. The hook and the marker are global to the process, so the cases run in a single test. Run
+// with `cargo test`.
+
+use aero_sensor_broker::crash::{
+    clear_marker, install_panic_hook, record_fatal_panic, CrashReport,
+};
+use aero_sensor_broker::testing::temp_dir;
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+fn marker_report(marker: &Path) -> CrashReport {
+    serde_json::from_slice(&fs::read(marker).unwrap()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn only_a_fatal_panic_leaves_the_marker_and_an_orderly_exit_removes_it() {
+    let marker = temp_dir("crash").join("crash-marker.json");
+    install_panic_hook(marker.clone());
+
+    // A task panic, as the supervisor sees it before deciding to restart the task
+    let task = tokio::spawn(async { panic!("task gone wrong") });
+    assert!(task.await.unwrap_err().is_panic());
+    assert!(!marker.exists());
+
+    // The supervisor gives up on it
+    record_fatal_panic();
+    assert_eq!(marker_report(&marker).message, "task gone wrong");
+
+    clear_marker();
+    assert!(!marker.exists());
+
+    // A panic of the main thread is fatal by itself
+    let main = thread::Builder::new()
+        .name("main".to_string())
+        .spawn(|| panic!("main gone wrong"))
+        .unwrap();
+    assert!(main.join().is_err());
+    let report = marker_report(&marker);
+    assert_eq!(report.thread, "main");
+    assert_eq!(report.message, "main gone wrong");
+
+    clear_marker();
+    assert!(!marker.exists());
+    // Nothing left to remove
+    clear_marker();
+}
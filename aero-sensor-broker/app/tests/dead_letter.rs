@@ -0,0 +1,295 @@
+// dead_letter.rs
+//
+// Batches a sink rejects for good are kept in the dead-letter directory rather than retried, the
+// others stay cached for the next flush. The sink is the scripted `MockSink` of the `testing`
+// module, standing for any writer. Run with `cargo test`.
+
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::config::DeadLetterConfig;
+use aero_sensor_broker::dead_letter::{DeadLetterBatch, DeadLetterWriter};
+use aero_sensor_broker::influxdb::InfluxDBManager;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::routes::create_dead_letter_routes;
+use aero_sensor_broker::sink::{DataSink, FanOutSink};
+use aero_sensor_broker::testing::{
+    point, temp_dir, temperatures, MockInfluxDB, MockSink, Reply, SinkReply, BUCKET, ORG, TIMESTAMP,
+};
+
+use influxdb2::models::DataPoint;
+use std::sync::Arc;
+use std::time::Duration;
+
+const FLUSH_DEADLINE: Duration = Duration::from_secs(5);
+
+fn batch() -> Vec<DataPoint> {
+    temperatures(&[21.5, 22.0], 0)
+}
+
+const BATCH_LINES: [&str; 2] = [
+    "temperature value=21.5 1700000000000000000",
+    "temperature value=22 1700000000000000001",
+];
+
+fn dead_letter() -> DeadLetterWriter {
+    dead_letter_within("dead-letter", 1024 * 1024)
+}
+
+fn dead_letter_within(name: &str, max_total_bytes: u64) -> DeadLetterWriter {
+    DeadLetterWriter::new(&DeadLetterConfig {
+        directory: temp_dir(name).display().to_string(),
+        max_total_bytes,
+    })
+    .unwrap()
+}
+
+// The lines of every dead-letter file, oldest first.
+fn dead_letters(dead_letter: &DeadLetterWriter) -> Vec<String> {
+    dead_letter
+        .list()
+        .unwrap()
+        .iter()
+        .flat_map(|(name, _)| {
+            let body = dead_letter.read(name).unwrap();
+            body.lines().map(str::to_string).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn a_permanently_rejected_batch_is_dropped_to_the_dead_letters() {
+    let sink = MockSink::new("writer");
+    sink.reply(SinkReply::Reject, 1);
+    let cache = Cache::new(100, Arc::new(Metrics::default()));
+    let dead_letter = dead_letter();
+    cache.add(batch()).await;
+
+    assert!(cache
+        .shutdown(sink.as_ref(), FLUSH_DEADLINE, Some(&dead_letter))
+        .await
+        .is_err());
+
+    assert_eq!(dead_letters(&dead_letter), BATCH_LINES);
+    assert!(cache.is_empty().await);
+
+    // Not retried by the next flush
+    cache
+        .shutdown(sink.as_ref(), FLUSH_DEADLINE, Some(&dead_letter))
+        .await
+        .unwrap();
+    assert_eq!(sink.writes().len(), 1);
+}
+
+#[tokio::test]
+async fn a_rejected_batch_is_dropped_without_a_dead_letter_directory() {
+    let sink = MockSink::new("writer");
+    sink.reply(SinkReply::Reject, 1);
+    let cache = Cache::new(100, Arc::new(Metrics::default()));
+    cache.add(batch()).await;
+
+    assert!(cache
+        .shutdown(sink.as_ref(), FLUSH_DEADLINE, None)
+        .await
+        .is_err());
+
+    assert!(cache.is_empty().await);
+    assert_eq!(sink.writes().len(), 1);
+}
+
+#[tokio::test]
+async fn an_unavailable_sink_keeps_the_batch_cached_instead() {
+    let sink = MockSink::new("writer");
+    sink.reply(SinkReply::Unavailable, 1);
+    let cache = Cache::new(100, Arc::new(Metrics::default()));
+    let dead_letter = dead_letter();
+    cache.add(batch()).await;
+
+    assert!(cache
+        .shutdown(sink.as_ref(), FLUSH_DEADLINE, Some(&dead_letter))
+        .await
+        .is_err());
+
+    assert!(dead_letters(&dead_letter).is_empty());
+    assert_eq!(cache.len().await, 2);
+
+    cache
+        .shutdown(sink.as_ref(), FLUSH_DEADLINE, Some(&dead_letter))
+        .await
+        .unwrap();
+    assert_eq!(sink.written_lines(), BATCH_LINES);
+}
+
+fn fan_out(sinks: &[&Arc<MockSink>]) -> FanOutSink {
+    FanOutSink::new(
+        sinks
+            .iter()
+            .map(|sink| Arc::clone(sink) as Arc<dyn DataSink>)
+            .collect(),
+    )
+}
+
+#[tokio::test]
+async fn a_batch_every_sink_rejects_is_dropped_to_the_dead_letters_once() {
+    let (influxdb, mqtt) = (MockSink::new("influxdb"), MockSink::new("mqtt"));
+    influxdb.reply(SinkReply::Reject, 1);
+    mqtt.reply(SinkReply::Reject, 1);
+    let sink = fan_out(&[&influxdb, &mqtt]);
+    let cache = Cache::new(100, Arc::new(Metrics::default()));
+    let dead_letter = dead_letter();
+    cache.add(batch()).await;
+
+    assert!(cache
+        .shutdown(&sink, FLUSH_DEADLINE, Some(&dead_letter))
+        .await
+        .is_err());
+
+    assert_eq!(dead_letter.list().unwrap().len(), 1);
+    assert_eq!(dead_letters(&dead_letter), BATCH_LINES);
+    assert!(cache.is_empty().await);
+}
+
+#[tokio::test]
+async fn a_batch_another_sink_may_still_take_is_cached_once_instead() {
+    let (influxdb, mqtt) = (MockSink::new("influxdb"), MockSink::new("mqtt"));
+    influxdb.reply(SinkReply::Reject, 1);
+    mqtt.reply(SinkReply::Unavailable, 1);
+    let sink = fan_out(&[&influxdb, &mqtt]);
+    let cache = Cache::new(100, Arc::new(Metrics::default()));
+    let dead_letter = dead_letter();
+    cache.add(batch()).await;
+
+    assert!(cache
+        .shutdown(&sink, FLUSH_DEADLINE, Some(&dead_letter))
+        .await
+        .is_err());
+
+    assert!(dead_letters(&dead_letter).is_empty());
+    assert_eq!(cache.len().await, 2);
+}
+
+#[test]
+fn the_file_just_written_is_never_pruned() {
+    // Smaller than a single batch
+    let dead_letter = dead_letter_within("dead-letter-prune", 10);
+
+    let first = dead_letter.write(&batch()).unwrap();
+    let second = dead_letter.write(&batch()).unwrap();
+
+    assert!(!first.exists());
+    assert!(second.exists());
+    assert_eq!(dead_letter.list().unwrap().len(), 1);
+}
+
+#[test]
+fn batches_written_at_once_get_files_of_their_own() {
+    let dead_letter = dead_letter_within("dead-letter-names", 1024 * 1024);
+
+    let paths: Vec<_> = (0..20)
+        .map(|_| dead_letter.write(&batch()).unwrap())
+        .collect();
+
+    let names: Vec<_> = dead_letter
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names.len(), 20);
+    // Oldest first, in the order they were written
+    let written: Vec<_> = paths
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names, written);
+}
+
+#[tokio::test]
+async fn a_resubmitted_file_goes_back_to_the_org_and_buckets_it_was_headed_for() {
+    let influxdb = MockInfluxDB::start().await;
+    let mut config = influxdb.config();
+    config.bucket_routing = [("humidity".to_string(), "climate".to_string())].into();
+    let dead_letter = dead_letter_within("dead-letter-target", 1024 * 1024).with_target(&config);
+    let humidity = point("humidity", &[], 40.0, TIMESTAMP);
+    let mut points = batch();
+    points.push(humidity);
+    let path = dead_letter.write(&points).unwrap();
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+    assert_eq!(
+        dead_letter.read_batches(&name).unwrap(),
+        [
+            DeadLetterBatch {
+                org: Some(ORG.to_string()),
+                bucket: Some("climate".to_string()),
+                lines: "humidity value=40 1700000000000000000\n".to_string(),
+            },
+            DeadLetterBatch {
+                org: Some(ORG.to_string()),
+                bucket: Some(BUCKET.to_string()),
+                lines: BATCH_LINES.map(|line| format!("{}\n", line)).concat(),
+            },
+        ]
+    );
+
+    let manager = InfluxDBManager::new(&config, Arc::new(Metrics::default())).unwrap();
+    let routes = create_dead_letter_routes(Some(dead_letter.clone()), manager, BUCKET.into());
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/admin/dead-letter/{}/resubmit", name))
+        .reply(&routes)
+        .await;
+
+    assert_eq!(response.status(), 200);
+    let targets: Vec<_> = influxdb
+        .writes()
+        .into_iter()
+        .map(|write| (write.org.unwrap(), write.bucket.unwrap(), write.body))
+        .collect();
+    assert_eq!(
+        targets,
+        [
+            (
+                ORG.to_string(),
+                "climate".to_string(),
+                "humidity value=40 1700000000000000000\n".to_string()
+            ),
+            (
+                ORG.to_string(),
+                BUCKET.to_string(),
+                BATCH_LINES.map(|line| format!("{}\n", line)).concat()
+            ),
+        ]
+    );
+    assert!(dead_letter.list().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn a_failed_resubmit_answers_with_the_id_of_the_request() {
+    let influxdb = MockInfluxDB::start().await;
+    influxdb.reply(Reply::Error(400, "unknown field type".to_string()), 1);
+    let config = influxdb.config();
+    let dead_letter =
+        dead_letter_within("dead-letter-request-id", 1024 * 1024).with_target(&config);
+    let path = dead_letter.write(&batch()).unwrap();
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    let manager = InfluxDBManager::new(&config, Arc::new(Metrics::default())).unwrap();
+    let routes = create_dead_letter_routes(Some(dead_letter.clone()), manager, BUCKET.into());
+
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/admin/dead-letter/{}/resubmit", name))
+        .header("x-request-id", "resubmit-1")
+        .reply(&routes)
+        .await;
+
+    assert_eq!(response.status(), 502);
+    assert_eq!(response.headers()["x-request-id"], "resubmit-1");
+    assert_eq!(dead_letter.list().unwrap().len(), 1);
+
+    // Without one, the id the handler logged with is returned for the access log
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/admin/dead-letter/{}/resubmit", name))
+        .reply(&routes)
+        .await;
+    assert!(response.headers().contains_key("x-request-id"));
+}
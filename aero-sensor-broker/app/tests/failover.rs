@@ -0,0 +1,174 @@
+// failover.rs
+//
+// Writes failing over from the primary InfluxDB to a fallback endpoint, with a mock InfluxDB of
+// the `testing` module for each. Run with `cargo test`.
+
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::config::InfluxDBConfig;
+use aero_sensor_broker::influxdb::InfluxDBManager;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::testing::{temperatures, MockInfluxDB, Reply, ORG, TIMESTAMP, TOKEN};
+
+use influxdb2::models::DataPoint;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+const FLUSH_DEADLINE: Duration = Duration::from_secs(30);
+
+// More failures than any flush makes attempts
+const ALWAYS: usize = 100;
+
+fn batch(offset: i64) -> Vec<DataPoint> {
+    temperatures(&[21.5, 21.75, 22.0], offset)
+}
+
+fn lines(offset: i64) -> Vec<String> {
+    ["21.5", "21.75", "22"]
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            format!(
+                "temperature value={} {}",
+                value,
+                TIMESTAMP + offset + index as i64
+            )
+        })
+        .collect()
+}
+
+struct Failover {
+    primary: MockInfluxDB,
+    fallback: MockInfluxDB,
+    manager: InfluxDBManager,
+    cache: Cache,
+}
+
+impl Failover {
+    async fn new(stickiness_secs: u64) -> Self {
+        let primary = MockInfluxDB::start().await;
+        let fallback = MockInfluxDB::start().await;
+        let mut config: InfluxDBConfig = primary.config();
+        config.fallbacks = vec![serde_json::from_value(json!({
+            "url": fallback.url(),
+            "org": ORG,
+            "bucket": "central",
+            "auth_token": TOKEN,
+        }))
+        .unwrap()];
+        config.failover_stickiness_secs = stickiness_secs;
+        let metrics = Arc::new(Metrics::default());
+        let manager = InfluxDBManager::new(&config, metrics.clone()).unwrap();
+        Self {
+            primary,
+            fallback,
+            manager,
+            cache: Cache::new(1000, metrics),
+        }
+    }
+
+    async fn flush(&self, points: Vec<DataPoint>) -> Result<(), String> {
+        self.cache.add(points).await;
+        self.cache
+            .shutdown(&self.manager, FLUSH_DEADLINE, None)
+            .await
+    }
+}
+
+#[tokio::test]
+async fn a_failing_primary_fails_over_without_losing_points() {
+    let failover = Failover::new(300).await;
+    failover
+        .primary
+        .reply(Reply::Error(500, "down".to_string()), ALWAYS);
+
+    failover.flush(batch(0)).await.unwrap();
+
+    assert!(failover.cache.is_empty().await);
+    assert!(failover.primary.written_lines().is_empty());
+    assert_eq!(failover.fallback.written_lines(), lines(0));
+    assert_eq!(
+        failover.fallback.writes()[0].bucket.as_deref(),
+        Some("central")
+    );
+    assert_eq!(
+        failover.manager.last_write_endpoint(),
+        Some(failover.fallback.url())
+    );
+
+    // Within the stickiness period the fallback is written to first
+    let tried = failover.primary.writes().len();
+    failover.flush(batch(10)).await.unwrap();
+    assert_eq!(failover.primary.writes().len(), tried);
+    assert_eq!(
+        failover.fallback.written_lines(),
+        [lines(0), lines(10)].concat()
+    );
+}
+
+#[tokio::test]
+async fn writes_return_to_the_primary_after_the_stickiness_period() {
+    let failover = Failover::new(0).await;
+    failover
+        .primary
+        .reply(Reply::Error(500, "down".to_string()), 1);
+
+    failover.flush(batch(0)).await.unwrap();
+    failover.flush(batch(10)).await.unwrap();
+
+    assert_eq!(failover.fallback.written_lines(), lines(0));
+    assert_eq!(failover.primary.written_lines(), lines(10));
+    assert_eq!(
+        failover.manager.last_write_endpoint(),
+        Some(failover.primary.url())
+    );
+}
+
+#[tokio::test]
+async fn the_primary_is_probed_again_while_the_fallback_stays_healthy() {
+    let failover = Failover::new(1).await;
+    failover
+        .primary
+        .reply(Reply::Error(500, "down".to_string()), 1);
+
+    // The switch to the fallback starts the stickiness period; further successful flushes to
+    // the fallback must not extend it
+    failover.flush(batch(0)).await.unwrap();
+    for offset in [10, 20] {
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        failover.flush(batch(offset)).await.unwrap();
+    }
+    assert_eq!(
+        failover.fallback.written_lines(),
+        [lines(0), lines(10), lines(20)].concat()
+    );
+    assert!(failover.primary.written_lines().is_empty());
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    failover.flush(batch(30)).await.unwrap();
+
+    assert_eq!(failover.primary.written_lines(), lines(30));
+    assert_eq!(failover.fallback.written_lines().len(), 9);
+    assert_eq!(
+        failover.manager.last_write_endpoint(),
+        Some(failover.primary.url())
+    );
+}
+
+#[tokio::test]
+async fn points_stay_cached_while_every_endpoint_fails() {
+    let failover = Failover::new(300).await;
+    failover
+        .primary
+        .reply(Reply::Error(500, "down".to_string()), ALWAYS);
+    failover
+        .fallback
+        .reply(Reply::Error(503, "down".to_string()), ALWAYS);
+
+    assert!(failover.flush(batch(0)).await.is_err());
+
+    assert_eq!(failover.cache.len().await, 3);
+    assert!(failover.primary.written_lines().is_empty());
+    assert!(failover.fallback.written_lines().is_empty());
+    assert_eq!(failover.manager.last_write_endpoint(), None);
+}
@@ -0,0 +1,129 @@
+// fan_out.rs
+//
+// Several sinks written independently by the `BufferedFanOutSink`, one of them failing while the
+// other keeps up. Both are the scripted `MockSink` of the `testing` module. Run with `cargo test`.
+
+use aero_sensor_broker::buffered_fan_out::BufferedFanOutSink;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::sink::DataSink;
+use aero_sensor_broker::testing::{temperatures, MockSink, SinkReply, TIMESTAMP};
+
+use influxdb2::models::DataPoint;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// A failed batch is tried again a flush interval later.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(300);
+
+// Well within a flush interval: how long the healthy sink may take to get a batch.
+const ON_TIME: Duration = Duration::from_millis(100);
+
+fn batch(offset: i64) -> Vec<DataPoint> {
+    temperatures(&[21.5; 2], offset)
+}
+
+fn lines(offsets: &[i64]) -> Vec<String> {
+    offsets
+        .iter()
+        .flat_map(|offset| {
+            (0..2)
+                .map(move |index| format!("temperature value=21.5 {}", TIMESTAMP + offset + index))
+        })
+        .collect()
+}
+
+struct FanOut {
+    healthy: Arc<MockSink>,
+    failing: Arc<MockSink>,
+    fan_out: BufferedFanOutSink,
+    // Keeps the flush interval alive for the lanes.
+    _flush_interval: watch::Sender<Duration>,
+}
+
+impl FanOut {
+    fn start() -> Self {
+        let healthy = MockSink::new("influxdb");
+        let failing = MockSink::new("mqtt");
+        let (flush_interval, receiver) = watch::channel(FLUSH_INTERVAL);
+        let sinks: Vec<Arc<dyn DataSink>> = vec![healthy.clone(), failing.clone()];
+        let fan_out =
+            BufferedFanOutSink::start(sinks, 1000, Arc::new(Metrics::default()), receiver, None);
+        Self {
+            healthy,
+            failing,
+            fan_out,
+            _flush_interval: flush_interval,
+        }
+    }
+
+    // Queues the batch and waits until the healthy sink has it, which must not take longer
+    // than `ON_TIME` whatever the failing sink does.
+    async fn write_on_time(&self, offset: i64) {
+        let expected = self.healthy.written_lines().len() + 2;
+        self.fan_out.write(batch(offset)).await.unwrap();
+        tokio::time::timeout(ON_TIME, async {
+            while self.healthy.written_lines().len() < expected {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("the healthy sink was held up");
+    }
+}
+
+#[tokio::test]
+async fn a_failing_sink_does_not_hold_up_the_other() {
+    let fan_out = FanOut::start();
+    fan_out.failing.reply(SinkReply::Unavailable, 1);
+
+    fan_out.write_on_time(0).await;
+    fan_out.write_on_time(10).await;
+    assert_eq!(fan_out.healthy.written_lines(), lines(&[0, 10]));
+    assert_eq!(fan_out.failing.writes().len(), 1);
+    assert!(fan_out.failing.written_lines().is_empty());
+
+    // The failed batch went back in front of the one queued meanwhile, and both are written
+    // together once the flush interval passed
+    tokio::time::sleep(FLUSH_INTERVAL + ON_TIME).await;
+    let writes = fan_out.failing.writes();
+    assert_eq!(writes.len(), 2);
+    assert_eq!(writes[1].lines, lines(&[0, 10]));
+    assert!(writes[1].accepted);
+
+    let status = fan_out.fan_out.status();
+    assert_eq!(status["mqtt"]["queue"]["failures"], 1);
+    assert_eq!(status["mqtt"]["queue"]["written"], 4);
+    assert_eq!(status["influxdb"]["queue"]["failures"], 0);
+    assert!(fan_out.fan_out.close().await.is_ok());
+}
+
+#[tokio::test]
+async fn a_hanging_sink_does_not_hold_up_the_other() {
+    let fan_out = FanOut::start();
+    fan_out.failing.reply(SinkReply::Hang, 1);
+
+    for offset in [0, 10, 20] {
+        fan_out.write_on_time(offset).await;
+    }
+    assert_eq!(fan_out.healthy.written_lines(), lines(&[0, 10, 20]));
+
+    // The write timed out within the flush interval and everything is tried again
+    tokio::time::sleep(3 * FLUSH_INTERVAL).await;
+    assert_eq!(fan_out.failing.written_lines(), lines(&[0, 10, 20]));
+}
+
+#[tokio::test]
+async fn closing_reports_the_points_a_failing_sink_lost() {
+    let fan_out = FanOut::start();
+    fan_out.failing.reply(SinkReply::Unavailable, 100);
+
+    fan_out.write_on_time(0).await;
+    fan_out.write_on_time(10).await;
+
+    let error = fan_out.fan_out.close().await.unwrap_err().to_string();
+    assert!(error.contains("mqtt: 4 points"), "{}", error);
+    assert!(!error.contains("influxdb"), "{}", error);
+    assert_eq!(fan_out.healthy.written_lines(), lines(&[0, 10]));
+    assert!(fan_out.failing.written_lines().is_empty());
+}
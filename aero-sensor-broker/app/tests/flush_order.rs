@@ -0,0 +1,105 @@
+// flush_order.rs
+//
+// Points reach the sink in the order they were cached however often its writes fail, and the
+// `flush_seq` they are stamped with never goes down. The sink is the scripted `MockSink` of the
+// `testing` module, failing every other write. A point that cannot be stamped is still written,
+// and counted. Run with `cargo test`.
+
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::testing::{temperatures, MockSink, SinkReply, TIMESTAMP};
+
+use influxdb2::models::DataPoint;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+const FLUSH_DEADLINE: Duration = Duration::from_secs(5);
+
+const ROUNDS: i64 = 10;
+const POINTS_PER_ROUND: i64 = 3;
+
+fn batch(round: i64) -> Vec<DataPoint> {
+    temperatures(&[21.5; POINTS_PER_ROUND as usize], round * POINTS_PER_ROUND)
+}
+
+// The timestamp and the `flush_seq` of a line.
+fn parse(line: &str) -> (i64, i64) {
+    let parts: Vec<&str> = line.split(' ').collect();
+    let timestamp = parts[2].parse().unwrap();
+    let seq = parts[1]
+        .split(',')
+        .find_map(|field| field.strip_prefix("flush_seq="))
+        .and_then(|seq| seq.strip_suffix('i'))
+        .unwrap_or_else(|| panic!("no flush_seq in {}", line))
+        .parse()
+        .unwrap();
+    (timestamp, seq)
+}
+
+#[tokio::test]
+async fn points_are_delivered_in_order_through_failing_writes() {
+    let sink = MockSink::new("influxdb");
+    let cache = Cache::new(1000, Arc::new(Metrics::default())).with_flush_seq(true);
+
+    for round in 0..ROUNDS {
+        if round % 2 == 0 {
+            sink.reply(SinkReply::Unavailable, 1);
+        }
+        cache.add(batch(round)).await;
+        let flushed = cache.shutdown(sink.as_ref(), FLUSH_DEADLINE, None).await;
+        assert_eq!(flushed.is_ok(), round % 2 == 1, "round {}", round);
+    }
+    assert!(cache.is_empty().await);
+
+    let delivered: Vec<(i64, i64)> = sink
+        .written_lines()
+        .iter()
+        .map(|line| parse(line))
+        .collect();
+    let expected: Vec<i64> = (0..ROUNDS * POINTS_PER_ROUND)
+        .map(|offset| TIMESTAMP + offset)
+        .collect();
+    let timestamps: Vec<i64> = delivered.iter().map(|(timestamp, _)| *timestamp).collect();
+    // Every point exactly once, strictly in the order it was cached
+    assert_eq!(timestamps, expected);
+    // A retried point keeps the number of the flush that first tried it, so the numbers seen
+    // never go down and each successful write carries a higher one than the previous
+    assert!(delivered.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    let last_seqs: Vec<i64> = sink
+        .writes()
+        .iter()
+        .filter(|write| write.accepted)
+        .map(|write| parse(write.lines.last().unwrap()).1)
+        .collect();
+    assert_eq!(last_seqs.len() as i64, ROUNDS / 2);
+    assert!(last_seqs.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[tokio::test]
+async fn a_point_that_cannot_be_stamped_is_written_as_it_is_and_counted() {
+    let sink = MockSink::new("influxdb");
+    let metrics = Arc::new(Metrics::default());
+    let cache = Cache::new(1000, metrics.clone()).with_flush_seq(true);
+    // A tag value ending in a backslash does not survive being decoded
+    let odd = DataPoint::builder("temperature")
+        .tag("probe", "C:\\")
+        .field("value", 21.5)
+        .timestamp(TIMESTAMP)
+        .build()
+        .unwrap();
+    let mut points = vec![odd];
+    points.extend(temperatures(&[22.0], 1));
+
+    cache.add(points).await;
+    cache
+        .shutdown(sink.as_ref(), FLUSH_DEADLINE, None)
+        .await
+        .unwrap();
+
+    let lines = sink.written_lines();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert!(!lines[0].contains("flush_seq"), "{}", lines[0]);
+    assert_eq!(parse(&lines[1]), (TIMESTAMP + 1, 1));
+    assert_eq!(metrics.points_unstamped.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,202 @@
+// health.rs
+//
+// The `/readyz` route over sinks scripted with the `MockSink` of the `testing` module, and over a
+// device whose health check never answers. Run with `cargo test`.
+
+use aero_sensor_broker::build_info::BuildInfo;
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::config::{HealthConfig, SourceConfig};
+use aero_sensor_broker::liveness::Liveness;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::pause::IngestionControl;
+use aero_sensor_broker::routes::{create_health_route, HealthPolicy};
+use aero_sensor_broker::sink::{DataSink, FanOutSink};
+use aero_sensor_broker::source::Source;
+use aero_sensor_broker::startup::Startup;
+use aero_sensor_broker::testing::{MockSink, MockSource, SinkReply};
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+
+// The timeout of the checks in the tests of hanging components, and how long `/readyz` may take
+// on top of it.
+const CHECK_TIMEOUT_MS: u64 = 100;
+const ANSWER_MARGIN: Duration = Duration::from_millis(400);
+
+// A device that is never heard from, whose health check never answers.
+fn hanging_source(name: &str) -> Source {
+    let config: SourceConfig = serde_json::from_value(json!({ "name": name })).unwrap();
+    let device = MockSource::new(name);
+    device.hang_health_checks();
+    Source::with_device(&config, &BTreeMap::new(), device)
+}
+
+// A device that answers its health check but has not sent a frame yet.
+fn quiet_source(name: &str) -> Source {
+    let config: SourceConfig = serde_json::from_value(json!({ "name": name })).unwrap();
+    Source::with_device(&config, &BTreeMap::new(), MockSource::new(name))
+}
+
+struct Broker {
+    sources: Vec<Source>,
+    sinks: Vec<Arc<MockSink>>,
+    control: IngestionControl,
+    health: HealthConfig,
+}
+
+impl Broker {
+    // A broker without sources writing to the given sinks.
+    fn new(sinks: &[&str]) -> Self {
+        Self {
+            sources: Vec::new(),
+            sinks: sinks.iter().map(|name| MockSink::new(name)).collect(),
+            control: IngestionControl::default(),
+            health: HealthConfig::default(),
+        }
+    }
+
+    async fn readyz(&self) -> (StatusCode, Value) {
+        let sinks: Vec<Arc<dyn DataSink>> = self
+            .sinks
+            .iter()
+            .map(|sink| sink.clone() as Arc<dyn DataSink>)
+            .collect();
+        let route = create_health_route(
+            Arc::new(self.sources.clone()),
+            Arc::new(FanOutSink::new(sinks)),
+            Cache::new(100, Arc::new(Metrics::default())),
+            self.control.clone(),
+            Liveness::default(),
+            Startup::default(),
+            BuildInfo::current(),
+            HealthPolicy::new(&self.health, Duration::from_millis(200)),
+        );
+        let response = warp::test::request().path("/readyz").reply(&route).await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    // `readyz`, which has to answer within the check timeout, whatever the components do.
+    async fn readyz_in_time(&self) -> (StatusCode, Value) {
+        let started = Instant::now();
+        let limit = Duration::from_millis(CHECK_TIMEOUT_MS) + ANSWER_MARGIN;
+        let answer = tokio::time::timeout(limit, self.readyz())
+            .await
+            .expect("/readyz waited for a hanging check");
+        assert!(started.elapsed() >= Duration::from_millis(CHECK_TIMEOUT_MS));
+        answer
+    }
+}
+
+#[tokio::test]
+async fn healthy_sinks_make_a_healthy_broker() {
+    let broker = Broker::new(&["influxdb", "mqtt"]);
+
+    let (status, body) = broker.readyz().await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "healthy");
+}
+
+#[tokio::test]
+async fn a_failing_required_sink_makes_the_broker_unhealthy() {
+    let broker = Broker::new(&["influxdb", "mqtt"]);
+    broker.sinks[0].set_health(SinkReply::Unavailable);
+
+    let (status, body) = broker.readyz().await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "unhealthy");
+}
+
+#[tokio::test]
+async fn a_failing_optional_sink_only_degrades_the_broker() {
+    let mut broker = Broker::new(&["influxdb", "mqtt"]);
+    broker.health.required = Some(vec!["influxdb".to_string()]);
+    broker.sinks[1].set_health(SinkReply::Unavailable);
+
+    let (status, body) = broker.readyz().await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "degraded");
+}
+
+#[tokio::test]
+async fn a_paused_broker_is_paused_even_with_a_failing_required_sink() {
+    let broker = Broker::new(&["influxdb"]);
+    broker.control.set_paused(true, "test".to_string());
+    broker.sinks[0].set_health(SinkReply::Unavailable);
+
+    let (status, body) = broker.readyz().await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "paused");
+}
+
+#[tokio::test]
+async fn a_hanging_required_sink_times_out_and_makes_the_broker_unhealthy() {
+    let mut broker = Broker::new(&["influxdb", "mqtt"]);
+    broker.health.sink_timeout_ms = Some(CHECK_TIMEOUT_MS);
+    broker.sinks[0].set_health(SinkReply::Hang);
+
+    let (status, body) = broker.readyz_in_time().await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "unhealthy");
+    assert_eq!(body["influxdb"]["status"], "timeout");
+    assert_eq!(body["mqtt"]["status"], "ok");
+}
+
+#[tokio::test]
+async fn a_hanging_optional_sink_times_out_and_only_degrades_the_broker() {
+    let mut broker = Broker::new(&["influxdb", "mqtt"]);
+    broker.health.required = Some(vec!["influxdb".to_string()]);
+    broker.health.sink_timeout_ms = Some(CHECK_TIMEOUT_MS);
+    broker.sinks[1].set_health(SinkReply::Hang);
+
+    let (status, body) = broker.readyz_in_time().await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "degraded");
+    assert_eq!(body["mqtt"]["status"], "timeout");
+}
+
+#[tokio::test]
+async fn a_hanging_source_times_out() {
+    let mut broker = Broker::new(&["influxdb"]);
+    broker.health.arduino_timeout_ms = Some(CHECK_TIMEOUT_MS);
+    broker.sources.push(hanging_source("bench"));
+
+    let (status, body) = broker.readyz_in_time().await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "unhealthy");
+    assert_eq!(body["sources"]["bench"]["status"], "timeout");
+    assert_eq!(body["influxdb"]["status"], "ok");
+}
+
+#[tokio::test]
+async fn a_source_without_a_frame_keeps_the_broker_starting_during_the_grace_period() {
+    let mut broker = Broker::new(&["influxdb"]);
+    broker.sources.push(quiet_source("bench"));
+
+    let (status, body) = broker.readyz().await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "starting");
+}
+
+#[tokio::test]
+async fn a_source_without_a_frame_degrades_the_broker_after_the_grace_period() {
+    let mut broker = Broker::new(&["influxdb"]);
+    broker.health.first_frame_grace_secs = 0;
+    broker.sources.push(quiet_source("bench"));
+
+    let (status, body) = broker.readyz().await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "degraded");
+}
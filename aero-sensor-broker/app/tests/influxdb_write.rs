@@ -0,0 +1,410 @@
+// influxdb_write.rs
+//
+// The write path end to end: points cached, flushed by the `Cache`, and written by the real
+// `InfluxDBManager` to the mock InfluxDB of the `testing` module. Run with `cargo test`.
+
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::config::{DeadLetterConfig, InfluxDBConfig};
+use aero_sensor_broker::dead_letter::DeadLetterWriter;
+use aero_sensor_broker::influxdb::InfluxDBManager;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::sink::DataSink;
+use aero_sensor_broker::testing::{
+    point, temp_dir, MockInfluxDB, Reply, BUCKET, ORG, TIMESTAMP, TOKEN,
+};
+
+use flate2::read::GzDecoder;
+use influxdb2::models::DataPoint;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const FLUSH_DEADLINE: Duration = Duration::from_secs(30);
+
+fn bme280(measurement: &str, value: f64, offset_secs: i64) -> DataPoint {
+    let timestamp = TIMESTAMP + offset_secs * 1_000_000_000;
+    point(measurement, &[("sensor", "bme280")], value, timestamp)
+}
+
+fn batch() -> Vec<DataPoint> {
+    vec![
+        bme280("temperature", 21.5, 0),
+        bme280("humidity", 48.25, 0),
+        bme280("temperature", 21.75, 10),
+    ]
+}
+
+const BATCH_LINES: [&str; 3] = [
+    "temperature,sensor=bme280 value=21.5 1700000000000000000",
+    "humidity,sensor=bme280 value=48.25 1700000000000000000",
+    "temperature,sensor=bme280 value=21.75 1700000010000000000",
+];
+
+struct Pipeline {
+    influxdb: MockInfluxDB,
+    manager: InfluxDBManager,
+    cache: Cache,
+    metrics: Arc<Metrics>,
+    dead_letter: DeadLetterWriter,
+}
+
+impl Pipeline {
+    async fn new() -> Self {
+        Self::with_config(|_| {}).await
+    }
+
+    async fn with_config(configure: impl FnOnce(&mut InfluxDBConfig)) -> Self {
+        let influxdb = MockInfluxDB::start().await;
+        let mut config = influxdb.config();
+        configure(&mut config);
+        let metrics = Arc::new(Metrics::default());
+        let manager = InfluxDBManager::new(&config, metrics.clone()).unwrap();
+        let cache = Cache::new(1000, metrics.clone());
+        let dead_letter = DeadLetterWriter::new(&DeadLetterConfig {
+            directory: temp_dir("dead-letter").display().to_string(),
+            max_total_bytes: 1024 * 1024,
+        })
+        .unwrap();
+        Self {
+            influxdb,
+            manager,
+            cache,
+            metrics,
+            dead_letter,
+        }
+    }
+
+    // Runs one flush of the cache, as the periodic flush and the shutdown do.
+    async fn flush(&self) -> Result<(), String> {
+        self.cache
+            .shutdown(&self.manager, FLUSH_DEADLINE, Some(&self.dead_letter))
+            .await
+    }
+
+    // The lines of every dead-letter file, oldest first.
+    fn dead_letters(&self) -> Vec<String> {
+        self.dead_letter
+            .list()
+            .unwrap()
+            .iter()
+            .flat_map(|(name, _)| {
+                let body = self.dead_letter.read(name).unwrap();
+                body.lines().map(str::to_string).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn flush_writes_the_cached_points_as_line_protocol() {
+    let pipeline = Pipeline::new().await;
+    pipeline.cache.add(batch()).await;
+
+    pipeline.flush().await.unwrap();
+
+    let writes = pipeline.influxdb.writes();
+    assert_eq!(writes.len(), 1);
+    let write = &writes[0];
+    assert_eq!(write.org.as_deref(), Some(ORG));
+    assert_eq!(write.bucket.as_deref(), Some(BUCKET));
+    assert_eq!(write.precision.as_deref(), Some("ns"));
+    assert_eq!(
+        write.authorization.as_deref(),
+        Some(format!("Token {}", TOKEN).as_str())
+    );
+    assert!(!write.gzip);
+    assert_eq!(write.body, BATCH_LINES.join("\n") + "\n");
+    assert!(pipeline.cache.is_empty().await);
+    assert_eq!(pipeline.metrics.flush_successes.load(Ordering::Relaxed), 1);
+    assert_eq!(pipeline.metrics.points_written.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn an_empty_cache_writes_nothing() {
+    let pipeline = Pipeline::new().await;
+
+    pipeline.flush().await.unwrap();
+
+    assert!(pipeline.influxdb.writes().is_empty());
+}
+
+#[tokio::test]
+async fn gzipped_bodies_hold_the_same_line_protocol() {
+    let pipeline = Pipeline::with_config(|config| {
+        config.gzip = true;
+        config.gzip_min_bytes = 1;
+    })
+    .await;
+    pipeline.cache.add(batch()).await;
+
+    pipeline.flush().await.unwrap();
+
+    let writes = pipeline.influxdb.writes();
+    assert_eq!(writes.len(), 1);
+    assert!(writes[0].gzip);
+    assert_eq!(pipeline.influxdb.written_lines(), BATCH_LINES);
+}
+
+#[tokio::test]
+async fn gzipped_bodies_are_gzip_on_the_wire() {
+    let pipeline = Pipeline::with_config(|config| {
+        config.gzip = true;
+        config.gzip_min_bytes = 1;
+    })
+    .await;
+    // Line protocol this repetitive compresses well
+    let points: Vec<DataPoint> = (0..100)
+        .map(|offset| bme280("temperature", 21.5, offset))
+        .collect();
+    pipeline.cache.add(points).await;
+
+    pipeline.flush().await.unwrap();
+
+    let write = &pipeline.influxdb.writes()[0];
+    assert!(write.gzip);
+    // The magic number and deflate method of a gzip member
+    assert_eq!(write.raw_body[..3], [0x1f, 0x8b, 0x08]);
+    assert!(write.raw_body.len() < write.body.len() / 4);
+    let mut decoded = String::new();
+    GzDecoder::new(&write.raw_body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, write.body);
+}
+
+#[tokio::test]
+async fn bodies_below_the_threshold_are_sent_uncompressed() {
+    let pipeline = Pipeline::with_config(|config| {
+        config.gzip = true;
+        config.gzip_min_bytes = 64 * 1024;
+    })
+    .await;
+    pipeline.cache.add(batch()).await;
+
+    pipeline.flush().await.unwrap();
+
+    let write = &pipeline.influxdb.writes()[0];
+    assert!(!write.gzip);
+    assert_eq!(write.raw_body, (BATCH_LINES.join("\n") + "\n").into_bytes());
+}
+
+#[tokio::test]
+async fn routed_measurements_are_written_to_their_bucket() {
+    let pipeline = Pipeline::with_config(|config| {
+        config.bucket_routing = BTreeMap::from([("humidity".to_string(), "climate".to_string())]);
+    })
+    .await;
+    pipeline.cache.add(batch()).await;
+
+    pipeline.flush().await.unwrap();
+
+    let by_bucket: BTreeMap<String, String> = pipeline
+        .influxdb
+        .writes()
+        .into_iter()
+        .map(|write| (write.bucket.unwrap(), write.body))
+        .collect();
+    assert_eq!(
+        by_bucket,
+        BTreeMap::from([
+            ("climate".to_string(), format!("{}\n", BATCH_LINES[1])),
+            (
+                BUCKET.to_string(),
+                format!("{}\n{}\n", BATCH_LINES[0], BATCH_LINES[2])
+            ),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn a_failing_bucket_does_not_keep_the_others_from_being_written() {
+    let pipeline = Pipeline::with_config(|config| {
+        config.bucket_routing = BTreeMap::from([("humidity".to_string(), "climate".to_string())]);
+    })
+    .await;
+    pipeline.influxdb.reply_to_bucket(
+        "climate",
+        Reply::Error(500, "internal error".to_string()),
+        3,
+    );
+    pipeline.cache.add(batch()).await;
+
+    assert!(pipeline.flush().await.is_err());
+
+    // Only the points of the failing bucket are kept, for the next flush
+    assert_eq!(
+        pipeline.influxdb.written_lines(),
+        [BATCH_LINES[0], BATCH_LINES[2]]
+    );
+    assert_eq!(pipeline.cache.len().await, 1);
+    assert!(pipeline.dead_letters().is_empty());
+
+    pipeline.flush().await.unwrap();
+    let climate: Vec<_> = pipeline
+        .influxdb
+        .writes()
+        .into_iter()
+        .filter(|write| write.accepted && write.bucket.as_deref() == Some("climate"))
+        .collect();
+    assert_eq!(climate.len(), 1);
+    assert_eq!(climate[0].body, format!("{}\n", BATCH_LINES[1]));
+}
+
+#[tokio::test]
+async fn a_bucket_rejecting_its_points_does_not_keep_the_others_from_being_written() {
+    let pipeline = Pipeline::with_config(|config| {
+        config.bucket_routing = BTreeMap::from([("humidity".to_string(), "climate".to_string())]);
+    })
+    .await;
+    pipeline.influxdb.reply_to_bucket(
+        "climate",
+        Reply::Error(400, "unable to parse points".to_string()),
+        1,
+    );
+    pipeline.cache.add(batch()).await;
+
+    assert!(pipeline.flush().await.is_err());
+
+    assert_eq!(
+        pipeline.influxdb.written_lines(),
+        [BATCH_LINES[0], BATCH_LINES[2]]
+    );
+    assert!(pipeline.cache.is_empty().await);
+    assert_eq!(pipeline.dead_letters(), [BATCH_LINES[1]]);
+}
+
+#[tokio::test]
+async fn server_errors_are_retried_with_the_same_body() {
+    let pipeline = Pipeline::new().await;
+    pipeline
+        .influxdb
+        .reply(Reply::Error(503, "not ready".to_string()), 1);
+    pipeline
+        .influxdb
+        .reply(Reply::Error(500, "internal error".to_string()), 1);
+    pipeline.cache.add(batch()).await;
+
+    pipeline.flush().await.unwrap();
+
+    let writes = pipeline.influxdb.writes();
+    assert_eq!(writes.len(), 3);
+    assert!(writes.iter().all(|write| write.body == writes[0].body));
+    assert_eq!(
+        writes
+            .iter()
+            .map(|write| write.accepted)
+            .collect::<Vec<_>>(),
+        [false, false, true]
+    );
+    assert_eq!(pipeline.influxdb.written_lines(), BATCH_LINES);
+    assert!(pipeline.dead_letters().is_empty());
+}
+
+#[tokio::test]
+async fn throttled_writes_wait_as_long_as_asked() {
+    let pipeline = Pipeline::new().await;
+    pipeline.influxdb.reply(Reply::Throttle(1), 1);
+    pipeline.cache.add(batch()).await;
+
+    let started = Instant::now();
+    pipeline.flush().await.unwrap();
+
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(pipeline.influxdb.writes().len(), 2);
+    assert_eq!(pipeline.influxdb.written_lines(), BATCH_LINES);
+}
+
+#[tokio::test]
+async fn points_stay_cached_when_the_retries_are_exhausted() {
+    let pipeline = Pipeline::new().await;
+    pipeline
+        .influxdb
+        .reply(Reply::Error(500, "internal error".to_string()), 3);
+    pipeline.cache.add(batch()).await;
+
+    assert!(pipeline.flush().await.is_err());
+
+    assert_eq!(pipeline.influxdb.writes().len(), 3);
+    assert!(pipeline.influxdb.written_lines().is_empty());
+    assert_eq!(pipeline.cache.len().await, 3);
+    assert!(pipeline.dead_letters().is_empty());
+    assert_eq!(pipeline.metrics.flush_failures.load(Ordering::Relaxed), 1);
+
+    // The points kept are written first by the next flush, before those cached meanwhile
+    pipeline
+        .cache
+        .add(vec![bme280("temperature", 22.0, 20)])
+        .await;
+    pipeline.flush().await.unwrap();
+
+    let mut expected = BATCH_LINES.to_vec();
+    expected.push("temperature,sensor=bme280 value=22 1700000020000000000");
+    assert_eq!(pipeline.influxdb.written_lines(), expected);
+    assert!(pipeline.cache.is_empty().await);
+}
+
+#[tokio::test]
+async fn rejected_batches_go_to_the_dead_letters() {
+    let pipeline = Pipeline::new().await;
+    let message = "unable to parse 'temperature,sensor=bme280 value=': missing field value";
+    pipeline
+        .influxdb
+        .reply(Reply::Error(400, message.to_string()), 1);
+    pipeline.cache.add(batch()).await;
+
+    assert!(pipeline.flush().await.is_err());
+
+    // Not retried, the same batch would be rejected again
+    assert_eq!(pipeline.influxdb.writes().len(), 1);
+    assert!(pipeline.cache.is_empty().await);
+    assert_eq!(pipeline.dead_letters(), BATCH_LINES);
+}
+
+#[tokio::test]
+async fn only_the_rejected_points_of_a_partial_write_go_to_the_dead_letters() {
+    let pipeline = Pipeline::new().await;
+    let message = "partial write: field type conflict: input field \"value\" on measurement \
+                   \"humidity\" is type float, already exists as type integer dropped=1";
+    pipeline
+        .influxdb
+        .reply(Reply::Error(422, message.to_string()), 1);
+    pipeline.cache.add(batch()).await;
+
+    assert!(pipeline.flush().await.is_err());
+
+    assert_eq!(pipeline.influxdb.writes().len(), 1);
+    assert!(pipeline.cache.is_empty().await);
+    assert_eq!(pipeline.dead_letters(), [BATCH_LINES[1]]);
+}
+
+#[tokio::test]
+async fn only_the_unparsable_points_of_a_partial_write_go_to_the_dead_letters() {
+    let pipeline = Pipeline::new().await;
+    let message = "partial write has occurred, errors encountered on line(s): line 1: unable to \
+                   parse 'temperature,sensor=bme280 value=21.5 1700000000000000000': bad \
+                   timestamp; line 3: unable to parse 'temperature,sensor=bme280 value=21.75 \
+                   1700000010000000000': bad timestamp";
+    pipeline
+        .influxdb
+        .reply(Reply::Error(400, message.to_string()), 1);
+    pipeline.cache.add(batch()).await;
+
+    assert!(pipeline.flush().await.is_err());
+
+    assert_eq!(pipeline.influxdb.writes().len(), 1);
+    assert!(pipeline.cache.is_empty().await);
+    assert_eq!(pipeline.dead_letters(), [BATCH_LINES[0], BATCH_LINES[2]]);
+}
+
+#[tokio::test]
+async fn health_follows_the_server() {
+    let pipeline = Pipeline::new().await;
+
+    assert!(pipeline.manager.probe_health().await.is_ok());
+    pipeline.influxdb.set_healthy(false);
+    assert!(pipeline.manager.probe_health().await.is_err());
+    pipeline.influxdb.set_healthy(true);
+    assert!(DataSink::check_health(&pipeline.manager).await.is_ok());
+}
@@ -0,0 +1,56 @@
+// latest.rs
+//
+// The admin route reading back the latest values InfluxDB stored, `/admin/latest/{measurement}`,
+// in front of the mock InfluxDB of the `testing` module. Run with `cargo test`.
+
+use aero_sensor_broker::influxdb::InfluxDBManager;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::routes::create_latest_route;
+use aero_sensor_broker::testing::{MockInfluxDB, BUCKET};
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+fn manager(influxdb: &MockInfluxDB) -> InfluxDBManager {
+    let mut config = influxdb.config();
+    config.bucket_routing = BTreeMap::from([("humidity".to_string(), "climate".to_string())]);
+    InfluxDBManager::new(&config, Arc::new(Metrics::default())).unwrap()
+}
+
+#[tokio::test]
+async fn only_the_buckets_the_broker_writes_to_may_be_queried() {
+    let influxdb = MockInfluxDB::start().await;
+    let routes = create_latest_route(manager(&influxdb));
+
+    let response = warp::test::request()
+        .path("/admin/latest/temperature?bucket=_monitoring")
+        .reply(&routes)
+        .await;
+
+    assert_eq!(response.status(), 400);
+    let body: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(
+        body["error"],
+        format!(
+            "bucket '_monitoring' is not one the broker writes to: climate, {}",
+            BUCKET
+        )
+    );
+}
+
+#[tokio::test]
+async fn a_routed_bucket_may_be_queried_for_another_measurement() {
+    let influxdb = MockInfluxDB::start().await;
+    let routes = create_latest_route(manager(&influxdb));
+
+    let response = warp::test::request()
+        .path("/admin/latest/temperature?bucket=climate")
+        .reply(&routes)
+        .await;
+
+    // The mock does not serve queries: the bucket was accepted and the query sent
+    assert_eq!(response.status(), 502);
+    let body: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["measurement"], "temperature");
+}
@@ -0,0 +1,302 @@
+// line_protocol.rs
+//
+// Escaping of the special characters of the line protocol in measurements, tag keys and values,
+// field keys, and string field values, as `render` shows them in dry-run mode and `to_line`
+// writes them, and their way back through `decode_line`. Then the examples of the line protocol
+// reference, the integer suffix, the timestamp precisions, and the `to_lines` payloads.
+
+use aero_sensor_broker::line_protocol::{
+    decode, decode_line, decoded_to_line, measurement_of, render, to_line, to_lines, Precision,
+};
+
+use influxdb2::models::{DataPoint, FieldValue};
+
+// 2023-11-14T22:13:20Z
+const TIMESTAMP: i64 = 1_700_000_000_000_000_000;
+
+struct Case {
+    measurement: &'static str,
+    tag: (&'static str, &'static str),
+    field: (&'static str, FieldValue),
+    line: &'static str,
+}
+
+fn cases() -> Vec<Case> {
+    let case = |measurement, tag, field, line| Case {
+        measurement,
+        tag,
+        field,
+        line,
+    };
+    let value = || FieldValue::F64(21.5);
+    vec![
+        case(
+            "air temperature",
+            ("site", "hangar"),
+            ("value", value()),
+            "air\\ temperature,site=hangar value=21.5",
+        ),
+        case(
+            "temperature,indoor",
+            ("site", "hangar"),
+            ("value", value()),
+            "temperature\\,indoor,site=hangar value=21.5",
+        ),
+        case(
+            "a=b",
+            ("site", "hangar"),
+            ("value", value()),
+            "a=b,site=hangar value=21.5",
+        ),
+        case(
+            "\"quoted\"",
+            ("site", "hangar"),
+            ("value", value()),
+            "\"quoted\",site=hangar value=21.5",
+        ),
+        case(
+            "temperature",
+            ("site", "hangar 2"),
+            ("value", value()),
+            "temperature,site=hangar\\ 2 value=21.5",
+        ),
+        case(
+            "temperature",
+            ("site", "hangar,bay"),
+            ("value", value()),
+            "temperature,site=hangar\\,bay value=21.5",
+        ),
+        case(
+            "temperature",
+            ("site", "bay=2"),
+            ("value", value()),
+            "temperature,site=bay\\=2 value=21.5",
+        ),
+        case(
+            "temperature",
+            ("site", "\"hangar\""),
+            ("value", value()),
+            "temperature,site=\"hangar\" value=21.5",
+        ),
+        case(
+            "temperature",
+            ("site name, full=", "hangar"),
+            ("value", value()),
+            "temperature,site\\ name\\,\\ full\\==hangar value=21.5",
+        ),
+        case(
+            "temperature",
+            ("site", "hangar"),
+            ("air value,c=", value()),
+            "temperature,site=hangar air\\ value\\,c\\==21.5",
+        ),
+        case(
+            "status",
+            ("site", "hangar"),
+            (
+                "message",
+                FieldValue::String("door \"B\", open = yes".to_string()),
+            ),
+            "status,site=hangar message=\"door \\\"B\\\", open = yes\"",
+        ),
+    ]
+}
+
+fn point(case: &Case) -> DataPoint {
+    DataPoint::builder(case.measurement)
+        .tag(case.tag.0, case.tag.1)
+        .field(case.field.0, case.field.1.clone())
+        .timestamp(TIMESTAMP)
+        .build()
+        .unwrap()
+}
+
+fn expected(case: &Case) -> String {
+    format!("{} {}", case.line, TIMESTAMP)
+}
+
+#[test]
+fn render_escapes_the_special_characters() {
+    for case in cases() {
+        assert_eq!(render(&point(&case)), expected(&case), "{}", case.line);
+    }
+}
+
+#[test]
+fn to_line_escapes_the_special_characters() {
+    for case in cases() {
+        let line = to_line(&point(&case), Precision::Nanoseconds).unwrap();
+        assert_eq!(line, expected(&case), "{}", case.line);
+    }
+}
+
+#[test]
+fn escaped_lines_decode_to_the_original_parts() {
+    for case in cases() {
+        let decoded = decode_line(&expected(&case)).unwrap();
+        assert_eq!(decoded.measurement, case.measurement, "{}", case.line);
+        assert_eq!(decoded.tags.len(), 1);
+        assert_eq!(decoded.tags[case.tag.0], case.tag.1, "{}", case.line);
+        assert_eq!(decoded.fields.len(), 1);
+        assert_eq!(decoded.fields[case.field.0], case.field.1, "{}", case.line);
+        assert_eq!(decoded.timestamp, Some(TIMESTAMP));
+        assert_eq!(decode(&point(&case)), Some(decoded));
+    }
+}
+
+#[test]
+fn the_measurement_is_read_unescaped() {
+    for case in cases() {
+        assert_eq!(
+            measurement_of(&point(&case)),
+            case.measurement,
+            "{}",
+            case.line
+        );
+    }
+    let untagged = DataPoint::builder("air quality")
+        .field("value", 1)
+        .build()
+        .unwrap();
+    assert_eq!(measurement_of(&untagged), "air quality");
+}
+
+// Examples of the line protocol reference, whose fields are in the order `to_line` writes them.
+const SPEC_EXAMPLES: [&str; 8] = [
+    "myMeasurement,tag1=value1,tag2=value2 fieldKey=\"fieldValue\" 1556813561098000000",
+    "my\\ Measurement fieldKey=\"string value\"",
+    "myMeasurement,tag\\ Key1=tag\\ Value1,tag\\ Key2=tag\\ Value2 fieldKey=100",
+    "myMeasurement fieldKey=\"\\\"string\\\" within a string\"",
+    "myMeasurement fieldKey=\"Launch 🚀\"",
+    "myMeasurement fieldKey=1i",
+    "myMeasurement fieldKey=12485903i",
+    "myMeasurement fieldKey=true",
+];
+
+#[test]
+fn the_examples_of_the_reference_round_trip() {
+    for example in SPEC_EXAMPLES {
+        let decoded = decode_line(example).unwrap_or_else(|| panic!("{}", example));
+        let line = decoded_to_line(&decoded, Precision::Nanoseconds).unwrap();
+        assert_eq!(line, example);
+    }
+}
+
+#[test]
+fn a_point_serializes_to_the_example_of_the_reference() {
+    let point = DataPoint::builder("myMeasurement")
+        .tag("tag1", "value1")
+        .tag("tag2", "value2")
+        .field("fieldKey", "fieldValue")
+        .timestamp(1_556_813_561_098_000_000)
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        to_line(&point, Precision::Nanoseconds).unwrap(),
+        SPEC_EXAMPLES[0]
+    );
+}
+
+#[test]
+fn integers_carry_the_i_suffix_and_floats_none() {
+    let point = DataPoint::builder("door")
+        .field("count", 42_i64)
+        .field("offset", -7_i64)
+        .field("ratio", 42.0)
+        .field("open", true)
+        .build()
+        .unwrap();
+
+    let line = to_line(&point, Precision::Nanoseconds).unwrap();
+
+    assert_eq!(line, "door count=42i,offset=-7i,open=true,ratio=42");
+    let fields = decode_line(&line).unwrap().fields;
+    assert_eq!(fields["count"], FieldValue::I64(42));
+    assert_eq!(fields["offset"], FieldValue::I64(-7));
+    assert_eq!(fields["ratio"], FieldValue::F64(42.0));
+}
+
+#[test]
+fn timestamps_are_written_at_the_precision_rounding_down() {
+    let point = |timestamp| {
+        DataPoint::builder("temperature")
+            .field("value", 21.5)
+            .timestamp(timestamp)
+            .build()
+            .unwrap()
+    };
+    let line = |timestamp, precision| to_line(&point(timestamp), precision).unwrap();
+
+    let timestamp = 1_556_813_561_098_765_432;
+    assert_eq!(
+        line(timestamp, Precision::Microseconds),
+        "temperature value=21.5 1556813561098765"
+    );
+    assert_eq!(
+        line(timestamp, Precision::Milliseconds),
+        "temperature value=21.5 1556813561098"
+    );
+    assert_eq!(
+        line(timestamp, Precision::Seconds),
+        "temperature value=21.5 1556813561"
+    );
+    // Before the epoch, down is further from it
+    assert_eq!(line(-1, Precision::Seconds), "temperature value=21.5 -1");
+    assert_eq!(
+        [
+            Precision::Nanoseconds,
+            Precision::Microseconds,
+            Precision::Milliseconds,
+            Precision::Seconds,
+        ]
+        .map(Precision::as_str),
+        ["ns", "us", "ms", "s"]
+    );
+}
+
+#[test]
+fn a_batch_is_one_line_per_point() {
+    // The sample data of the getting started guide, in seconds
+    let point = |room, co: i64, hum: f64, temp: f64| {
+        DataPoint::builder("home")
+            .tag("room", room)
+            .field("temp", temp)
+            .field("hum", hum)
+            .field("co", co)
+            .timestamp(1_641_024_000_000_000_000)
+            .build()
+            .unwrap()
+    };
+    let points = [
+        point("Living Room", 0, 35.9, 21.1),
+        point("Kitchen", 0, 36.2, 21.0),
+    ];
+
+    assert_eq!(
+        to_lines(&points, Precision::Seconds).unwrap(),
+        "home,room=Living\\ Room co=0i,hum=35.9,temp=21.1 1641024000\n\
+         home,room=Kitchen co=0i,hum=36.2,temp=21 1641024000\n"
+    );
+    assert_eq!(to_lines(&[], Precision::Seconds).unwrap(), "");
+}
+
+#[test]
+fn a_point_that_cannot_be_written_fails_the_batch() {
+    let valid = DataPoint::builder("temperature")
+        .field("value", 21.5)
+        .build()
+        .unwrap();
+    let not_finite = DataPoint::builder("temperature")
+        .field("value", f64::NAN)
+        .build()
+        .unwrap();
+
+    let error = to_lines(&[valid, not_finite], Precision::Nanoseconds).unwrap_err();
+
+    assert!(
+        error.to_string().contains("not a finite number"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,147 @@
+// pacing.rs
+//
+// The periodic flush of a large backlog spread over the interval, run on the paused clock of
+// tokio rather than on a `MockClock`, which cannot move the timers the batches wait on: the waits
+// elapse at once, and the times the `MockSink` of the `testing` module records are those the
+// pacing scheduled. Run with `cargo test`.
+
+use aero_sensor_broker::cache::Cache;
+use aero_sensor_broker::config::PacingConfig;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::testing::{temperatures, MockSink, SinkReply};
+
+use influxdb2::models::DataPoint;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+const INTERVAL: Duration = Duration::from_secs(60);
+
+fn points(count: usize) -> Vec<DataPoint> {
+    let values: Vec<f64> = (0..count).map(|index| index as f64).collect();
+    temperatures(&values, 0)
+}
+
+// When each batch the sink accepted was written, from `started`, and how many points it held.
+fn writes(sink: &MockSink, started: Instant) -> Vec<(Duration, usize)> {
+    sink.writes()
+        .into_iter()
+        .filter(|write| write.accepted)
+        .map(|write| (write.at - started, write.lines.len()))
+        .collect()
+}
+
+// 100 points a batch over half the interval, from 200 points on.
+fn cache() -> Cache {
+    let pacing = PacingConfig {
+        batch_size: 100,
+        spread: 0.5,
+        min_points: 200,
+    };
+    Cache::new(10_000, Arc::new(Metrics::default())).with_pacing(Some(&pacing))
+}
+
+// Runs the periodic flush, asked to flush right away.
+fn flush(cache: &Cache, sink: Arc<MockSink>, shutdown: &CancellationToken) -> JoinHandle<()> {
+    let cache = cache.clone();
+    let shutdown = shutdown.clone();
+    let (_, interval) = watch::channel(INTERVAL);
+    cache.flush_soon();
+    tokio::spawn(async move {
+        cache
+            .periodic_flush(sink, interval, None, None, None, shutdown)
+            .await
+    })
+}
+
+async fn wait_for_points(sink: &MockSink, points: usize) {
+    while sink.written_lines().len() < points {
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_large_backlog_is_spread_over_the_interval() {
+    let cache = cache();
+    let sink = MockSink::new("mock");
+    let shutdown = CancellationToken::new();
+    let started = Instant::now();
+    cache.add(points(1000)).await;
+
+    let task = flush(&cache, sink.clone(), &shutdown);
+    wait_for_points(&sink, 1000).await;
+    shutdown.cancel();
+    task.await.unwrap();
+
+    // 10 batches over 30 seconds, one every 3 seconds
+    let writes = writes(&sink, started);
+    assert_eq!(writes.len(), 10);
+    assert!(writes.iter().all(|(_, points)| *points == 100));
+    for (previous, next) in writes.iter().zip(&writes[1..]) {
+        assert_eq!(next.0 - previous.0, Duration::from_secs(3));
+    }
+    assert!(cache.is_empty().await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_small_backlog_is_written_at_once() {
+    let cache = cache();
+    let sink = MockSink::new("mock");
+    let shutdown = CancellationToken::new();
+    let started = Instant::now();
+    cache.add(points(150)).await;
+
+    let task = flush(&cache, sink.clone(), &shutdown);
+    wait_for_points(&sink, 150).await;
+    shutdown.cancel();
+    task.await.unwrap();
+
+    assert_eq!(writes(&sink, started), [(Duration::ZERO, 150)]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_shutdown_writes_the_remaining_batches_right_away() {
+    let cache = cache();
+    let sink = MockSink::new("mock");
+    let shutdown = CancellationToken::new();
+    let started = Instant::now();
+    cache.add(points(1000)).await;
+
+    let task = flush(&cache, sink.clone(), &shutdown);
+    wait_for_points(&sink, 200).await;
+    shutdown.cancel();
+    let cancelled = started.elapsed();
+    task.await.unwrap();
+
+    let writes = writes(&sink, started);
+    assert_eq!(sink.written_lines().len(), 1000);
+    assert_eq!(writes[1].0, Duration::from_secs(3));
+    assert!(writes[2..].iter().all(|(at, _)| *at == cancelled));
+    assert!(cache.is_empty().await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_batches_after_a_failure_stay_cached() {
+    let cache = cache();
+    let sink = MockSink::new("mock");
+    sink.reply(SinkReply::Accept, 2);
+    sink.reply(SinkReply::Unavailable, 1);
+    let shutdown = CancellationToken::new();
+    cache.add(points(1000)).await;
+
+    let task = flush(&cache, sink.clone(), &shutdown);
+    wait_for_points(&sink, 200).await;
+    // The third batch fails 6 seconds in, the flush ends there
+    sleep(Duration::from_secs(10)).await;
+    shutdown.cancel();
+    task.await.unwrap();
+
+    assert_eq!(sink.written_lines().len(), 200);
+    assert_eq!(cache.len().await, 800);
+    // From the first point of the failed batch on, in order
+    let kept = cache.retrieve_and_clear().await;
+    let expected = points(1000).split_off(200);
+    assert_eq!(format!("{:?}", kept), format!("{:?}", expected));
+}
@@ -0,0 +1,146 @@
+// sequence.rs
+//
+// The frame numbers of protocol 3 devices: frames in order, gaps, duplicates, and the wraparound
+// of the u32 counter of the firmware, which must not read as a gap.
+
+use aero_sensor_broker::data_manipulation::{MyDataPoint, Reading};
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::sequence::{Sequence, SequenceTracker};
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+fn observe_all(seqs: &[u32]) -> Vec<Sequence> {
+    let mut tracker = SequenceTracker::new();
+    seqs.iter().map(|&seq| tracker.observe(seq)).collect()
+}
+
+fn frame(seq: Option<u32>) -> Vec<MyDataPoint> {
+    ["temperature", "humidity"]
+        .iter()
+        .map(|measurement| {
+            MyDataPoint::from_reading(
+                measurement.to_string(),
+                BTreeMap::new(),
+                Reading::Value(21.5),
+                1_700_000_000_000_000_000,
+            )
+            .with_seq(seq)
+        })
+        .collect()
+}
+
+#[test]
+fn frames_in_order_miss_nothing() {
+    assert_eq!(
+        observe_all(&[7, 8, 9, 10]),
+        [
+            Sequence::First,
+            Sequence::InOrder,
+            Sequence::InOrder,
+            Sequence::InOrder
+        ]
+    );
+}
+
+#[test]
+fn a_gap_reports_the_frames_lost() {
+    assert_eq!(
+        observe_all(&[7, 8, 12, 13]),
+        [
+            Sequence::First,
+            Sequence::InOrder,
+            Sequence::Gap {
+                missed: 3,
+                from: 9,
+                to: 11
+            },
+            Sequence::InOrder,
+        ]
+    );
+}
+
+#[test]
+fn a_duplicate_is_neither_a_gap_nor_a_restart() {
+    assert_eq!(
+        observe_all(&[7, 8, 8, 9]),
+        [
+            Sequence::First,
+            Sequence::InOrder,
+            Sequence::Duplicate,
+            Sequence::InOrder,
+        ]
+    );
+}
+
+#[test]
+fn the_counter_wraps_around_without_a_gap() {
+    assert_eq!(
+        observe_all(&[u32::MAX - 1, u32::MAX, 0, 1]),
+        [
+            Sequence::First,
+            Sequence::InOrder,
+            Sequence::InOrder,
+            Sequence::InOrder,
+        ]
+    );
+}
+
+#[test]
+fn a_gap_across_the_wraparound_counts_the_frames_on_both_sides() {
+    assert_eq!(
+        observe_all(&[u32::MAX - 1, 1]),
+        [
+            Sequence::First,
+            Sequence::Gap {
+                missed: 2,
+                from: u32::MAX,
+                to: 0
+            },
+        ]
+    );
+}
+
+#[test]
+fn numbers_going_back_are_a_restart() {
+    assert_eq!(
+        observe_all(&[5000, 0, 1]),
+        [
+            Sequence::First,
+            Sequence::Restarted { previous: 5000 },
+            Sequence::InOrder,
+        ]
+    );
+}
+
+#[test]
+fn a_reset_starts_over() {
+    let mut tracker = SequenceTracker::new();
+    tracker.observe(7);
+    tracker.reset();
+    assert_eq!(tracker.observe(20), Sequence::First);
+    assert_eq!(tracker.observe(21), Sequence::InOrder);
+}
+
+#[test]
+fn missed_frames_are_counted_per_source() {
+    let metrics = Metrics::default();
+    let intake = metrics.source("intake");
+    let mut tracker = SequenceTracker::new();
+
+    for seq in [1, 2, 5, 5, 6, 10] {
+        tracker.observe_frame("intake", &frame(Some(seq)), &intake);
+    }
+    // Frames without a number are not followed
+    tracker.observe_frame("intake", &frame(None), &intake);
+    tracker.observe_frame("intake", &frame(Some(11)), &intake);
+
+    assert_eq!(intake.frames_missed.load(Ordering::Relaxed), 5);
+    assert_eq!(
+        metrics
+            .source("exhaust")
+            .frames_missed
+            .load(Ordering::Relaxed),
+        0
+    );
+}
@@ -0,0 +1,279 @@
+// settings.rs
+//
+// How `load_settings` layers the defaults, the configuration files, and the environment
+// variables. The files are written to a temporary directory, which `SENSORFLOW_CONFIG_DIR`
+// points at, but for the same settings in every format, kept in `fixtures/settings`. Run with
+// `cargo test`.
+
+use aero_sensor_broker::config::{load_settings, ConfigSettings, ConfigSource};
+use aero_sensor_broker::reload::Reloader;
+use aero_sensor_broker::routes::create_config_route;
+use aero_sensor_broker::testing::temp_dir;
+
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// The environment is shared by the tests, which take turns.
+static ENV: Mutex<()> = Mutex::new(());
+
+// Settings every test starts from, without the keys they layer.
+const BASE: &str = r#"
+[influxdb]
+url = "http://localhost:8086"
+org = "aero"
+bucket = "sensors"
+auth_token = "token"
+
+[[sources]]
+name = "bench"
+device_name = "Arduino"
+
+[tags]
+location = "lab"
+"#;
+
+// Holds the environment for a test, and clears what it set once done.
+struct Env {
+    vars: Vec<&'static str>,
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl Env {
+    fn take() -> Self {
+        Self {
+            vars: Vec::new(),
+            _turn: ENV.lock().unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+
+    fn set(&mut self, var: &'static str, value: impl AsRef<std::ffi::OsStr>) {
+        std::env::set_var(var, value);
+        self.vars.push(var);
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        for var in &self.vars {
+            std::env::remove_var(var);
+        }
+    }
+}
+
+fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+// The defaults of the settings `load_settings` does not find in any layer.
+fn defaults() -> ConfigSettings {
+    serde_json::from_value(json!({
+        "influxdb": {"url": "http://localhost:8086", "org": "aero", "bucket": "sensors"},
+    }))
+    .unwrap()
+}
+
+fn load_default_files() -> ConfigSettings {
+    load_settings(&ConfigSource::default()).unwrap()
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/settings")
+        .join(name)
+}
+
+// What the settings of a file come to, the secrets included but not where the state is kept,
+// which depends on where the file is.
+fn resolved(source: &ConfigSource) -> serde_json::Value {
+    let mut settings = load_settings(source).unwrap();
+    settings.state_file = String::new();
+    let mut resolved = settings.redacted();
+    resolved["influxdb"]["auth_token"] = json!(settings.influxdb.auth_token().unwrap());
+    resolved["influxdb"]["fallbacks"][0]["auth_token"] =
+        json!(settings.influxdb.fallbacks[0].auth_token().unwrap());
+    resolved
+}
+
+fn file(path: PathBuf) -> ConfigSource {
+    ConfigSource {
+        paths: vec![path],
+        ..ConfigSource::default()
+    }
+}
+
+#[test]
+fn each_layer_overrides_a_nested_key_of_the_previous_ones() {
+    let mut env = Env::take();
+    let dir = temp_dir("settings");
+    env.set("SENSORFLOW_CONFIG_DIR", &dir);
+
+    write(&dir, "Settings.toml", BASE);
+    let settings = load_default_files();
+    assert_eq!(settings.cache.max_size, defaults().cache.max_size);
+
+    let base = format!(
+        "{}\n[cache]\nmax_size = 100\nflush_interval_secs = 120\n",
+        BASE
+    );
+    write(&dir, "Settings.toml", &base);
+    assert_eq!(load_default_files().cache.max_size, 100);
+
+    write(&dir, "Settings.local.toml", "[cache]\nmax_size = 200\n");
+    let settings = load_default_files();
+    assert_eq!(settings.cache.max_size, 200);
+    // The other keys of the section are those of the files before
+    assert_eq!(settings.cache.flush_interval_secs, 120);
+
+    env.set("SENSORFLOW_CACHE__MAX_SIZE", "300");
+    let settings = load_default_files();
+    assert_eq!(settings.cache.max_size, 300);
+    assert_eq!(settings.cache.flush_interval_secs, 120);
+}
+
+#[test]
+fn the_local_file_is_only_read_with_the_default_files() {
+    let mut env = Env::take();
+    let dir = temp_dir("settings");
+    env.set("SENSORFLOW_CONFIG_DIR", temp_dir("settings-unused"));
+    let base = write(&dir, "Settings.toml", BASE);
+    write(&dir, "Settings.local.toml", "[cache]\nmax_size = 200\n");
+
+    let source = ConfigSource {
+        paths: vec![base],
+        ..ConfigSource::default()
+    };
+    let settings = load_settings(&source).unwrap();
+
+    assert_eq!(settings.cache.max_size, defaults().cache.max_size);
+}
+
+#[test]
+fn every_format_resolves_to_the_same_settings() {
+    let _env = Env::take();
+
+    let toml = resolved(&file(fixture("Settings.toml")));
+    assert_eq!(toml["influxdb"]["auth_token"], "fixture-token");
+    assert_eq!(toml["sources"][0]["tags"]["rack"], "a1");
+    assert_eq!(toml["cache"]["max_size"], 5000);
+    assert_eq!(resolved(&file(fixture("Settings.yaml"))), toml);
+    assert_eq!(resolved(&file(fixture("Settings.json"))), toml);
+}
+
+#[test]
+fn a_file_without_an_extension_is_read_in_the_given_format() {
+    let _env = Env::take();
+    let dir = temp_dir("settings");
+    let path = dir.join("settings");
+    fs::copy(fixture("Settings.yaml"), &path).unwrap();
+
+    let source = ConfigSource {
+        format: Some(config::FileFormat::Yaml),
+        ..file(path)
+    };
+
+    assert_eq!(resolved(&source), resolved(&file(fixture("Settings.toml"))));
+}
+
+fn profile(name: &str) -> ConfigSource {
+    ConfigSource {
+        profile: Some(name.to_string()),
+        ..ConfigSource::default()
+    }
+}
+
+#[test]
+fn a_profile_layers_its_table_then_its_file_before_the_local_file_and_the_environment() {
+    let mut env = Env::take();
+    let dir = temp_dir("settings");
+    env.set("SENSORFLOW_CONFIG_DIR", &dir);
+    let base = format!(
+        "{}\n[cache]\nmax_size = 100\n\n{}",
+        BASE, "[profile.dev.cache]\nmax_size = 150\nflush_interval_secs = 90\n"
+    );
+    write(&dir, "Settings.toml", &base);
+
+    let settings = load_settings(&profile("dev")).unwrap();
+    assert_eq!(settings.active_profile.as_deref(), Some("dev"));
+    assert_eq!(settings.cache.max_size, 150);
+    assert_eq!(settings.cache.flush_interval_secs, 90);
+    // The table of another profile is not applied
+    assert_eq!(load_default_files().cache.max_size, 100);
+
+    write(&dir, "Settings.dev.toml", "[cache]\nmax_size = 250\n");
+    let settings = load_settings(&profile("dev")).unwrap();
+    assert_eq!(settings.cache.max_size, 250);
+    assert_eq!(settings.cache.flush_interval_secs, 90);
+
+    write(&dir, "Settings.local.toml", "[cache]\nmax_size = 350\n");
+    assert_eq!(load_settings(&profile("dev")).unwrap().cache.max_size, 350);
+
+    env.set("SENSORFLOW_CACHE__MAX_SIZE", "450");
+    assert_eq!(load_settings(&profile("dev")).unwrap().cache.max_size, 450);
+}
+
+#[test]
+fn a_profile_file_alone_is_enough() {
+    let mut env = Env::take();
+    let dir = temp_dir("settings");
+    env.set("SENSORFLOW_CONFIG_DIR", &dir);
+    write(&dir, "Settings.toml", BASE);
+    write(&dir, "Settings.dev.toml", "[cache]\nmax_size = 250\n");
+
+    assert_eq!(load_settings(&profile("dev")).unwrap().cache.max_size, 250);
+}
+
+#[test]
+fn a_profile_with_neither_a_table_nor_a_file_is_an_error() {
+    let mut env = Env::take();
+    let dir = temp_dir("settings");
+    env.set("SENSORFLOW_CONFIG_DIR", &dir);
+    write(&dir, "Settings.toml", &format!("{}\n[profile.dev]\n", BASE));
+    write(&dir, "Settings.dev.toml", "");
+
+    let error = load_settings(&profile("prd")).unwrap_err().to_string();
+
+    assert!(
+        error.contains("profile 'prd' has neither a [profile.prd] table nor a"),
+        "{}",
+        error
+    );
+    assert!(error.contains("Settings.prd.toml"), "{}", error);
+}
+
+#[tokio::test]
+async fn the_config_route_serves_the_settings_of_the_last_reload() {
+    let _env = Env::take();
+    let dir = temp_dir("settings-reload");
+    let path = write(&dir, "Settings.toml", BASE);
+    let source = file(path.clone());
+    let reloader = Reloader::new(source.clone(), false, &load_settings(&source).unwrap());
+    let route = create_config_route(reloader.running());
+    let served = || async {
+        let response = warp::test::request()
+            .path("/admin/config")
+            .reply(&route)
+            .await;
+        serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+    };
+    assert_eq!(served().await["cache"]["flush_interval_secs"], 60);
+
+    let changed = format!(
+        "{}\n[cache]\nmax_size = 100\nflush_interval_secs = 120\n",
+        BASE
+    );
+    write(&dir, "Settings.toml", &changed);
+    assert!(reloader.reload("test").succeeded);
+
+    let config = served().await;
+    assert_eq!(config["cache"]["flush_interval_secs"], 120);
+    // Not in effect until a restart
+    assert_eq!(
+        config["cache"]["max_size"],
+        json!(defaults().cache.max_size)
+    );
+    assert_eq!(config["influxdb"]["auth_token"], "***");
+}
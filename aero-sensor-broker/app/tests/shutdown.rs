@@ -0,0 +1,267 @@
+// shutdown.rs
+//
+// The order the broker is torn down in once its shutdown token is cancelled: reading stops, the
+// open aggregation window is closed, the cache is flushed, the HTTP server stops, and the serial
+// ports are closed. The broker runs on a `MockClock`, with the `MockSource` and the `MockSink` of
+// the `testing` module, both writing what they see to a shared log. Run with `cargo test`.
+
+use aero_sensor_broker::clock::MockClock;
+use aero_sensor_broker::config::{ConfigSettings, ConfigSource};
+use aero_sensor_broker::errors::AppError;
+use aero_sensor_broker::reload::Reloader;
+use aero_sensor_broker::sink::{DataSink, SinkError};
+use aero_sensor_broker::source::SensorSource;
+use aero_sensor_broker::testing::{temp_dir, MockSink, MockSource, SourceEvent};
+use aero_sensor_broker::{run_with, Overrides};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use influxdb2::models::DataPoint;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const FRAMES: [&str; 3] = [
+    r#"[{"type": "temperature", "value": 20.0}]"#,
+    r#"[{"type": "temperature", "value": 21.0}]"#,
+    r#"[{"type": "temperature", "value": 22.0}]"#,
+];
+
+// What the components saw, in the order they saw it.
+#[derive(Clone)]
+struct Events {
+    events: Arc<Mutex<Vec<String>>>,
+    // Where the HTTP server of the broker listens.
+    http: SocketAddr,
+}
+
+impl Events {
+    fn new(http: SocketAddr) -> Self {
+        Self {
+            events: Arc::default(),
+            http,
+        }
+    }
+
+    fn push(&self, event: &str) {
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(event.to_string());
+    }
+
+    // The event, with whether the HTTP server accepts connections at that point.
+    fn push_with_http(&self, event: &str) {
+        let listening = TcpStream::connect_timeout(&self.http, Duration::from_millis(200));
+        let http = match listening.is_ok() {
+            true => "http up",
+            false => "http down",
+        };
+        self.push(&format!("{} ({})", event, http));
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+// The mock sink, logging every write.
+struct LoggingSink {
+    sink: Arc<MockSink>,
+    events: Events,
+}
+
+#[async_trait]
+impl DataSink for LoggingSink {
+    fn name(&self) -> &str {
+        self.sink.name()
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), SinkError> {
+        self.events
+            .push_with_http(&format!("write {} point(s)", points.len()));
+        self.sink.write(points).await
+    }
+
+    async fn check_health(&self) -> Result<(), SinkError> {
+        self.sink.check_health().await
+    }
+}
+
+// A port nothing listens on, for the HTTP server.
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+struct Broker {
+    clock: Arc<MockClock>,
+    events: Events,
+    sink: Arc<MockSink>,
+    source: Arc<MockSource>,
+    shutdown: CancellationToken,
+    run: tokio::task::JoinHandle<Result<(), AppError>>,
+}
+
+impl Broker {
+    // Runs the broker with a window and a flush interval longer than the test, so that the
+    // points only reach the sink through the shutdown.
+    async fn start(hang_on_close: bool) -> Self {
+        let port = free_port();
+        let state_dir = temp_dir("shutdown");
+        let settings: ConfigSettings = serde_json::from_value(json!({
+            "influxdb": {
+                "url": "http://127.0.0.1:9",
+                "org": "aero",
+                "bucket": "sensors",
+                "auth_token": "unused",
+            },
+            "sources": [{"name": "bench"}],
+            "aggregation": {"window_secs": 3600},
+            "cache": {"flush_interval_secs": 3600, "heartbeat": false},
+            "http": {"bind_address": "127.0.0.1", "port": port},
+            "state_file": state_dir.join("state.json"),
+        }))
+        .unwrap();
+
+        let events = Events::new(SocketAddr::from(([127, 0, 0, 1], port)));
+        let sink = MockSink::new("mock");
+        // Sends the frames, then nothing
+        let source = MockSource::new("bench");
+        for frame in FRAMES {
+            source.feed(frame);
+        }
+        if hang_on_close {
+            source.hang_on_close();
+        }
+        source.observe({
+            let events = events.clone();
+            move |event| match event {
+                SourceEvent::Read => events.push("read"),
+                SourceEvent::Shutdown => events.push_with_http("close serial"),
+            }
+        });
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let overrides = Overrides {
+            devices: BTreeMap::from([(
+                "bench".to_string(),
+                source.clone() as Arc<dyn SensorSource>,
+            )]),
+            sinks: Some(vec![Arc::new(LoggingSink {
+                sink: sink.clone(),
+                events: events.clone(),
+            })]),
+            clock: Some(clock.clone()),
+        };
+        let reloader = Reloader::new(ConfigSource::default(), false, &settings);
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(run_with(settings, reloader, overrides, shutdown.clone()));
+
+        let broker = Self {
+            clock,
+            events,
+            sink,
+            source,
+            shutdown,
+            run,
+        };
+        broker.source.wait_for_reads().await;
+        broker
+    }
+
+    async fn stop(self) -> (Result<(), AppError>, Vec<String>, Arc<MockSink>) {
+        self.shutdown.cancel();
+        let result = self.run.await.unwrap();
+        (result, self.events.list(), self.sink)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_broker_is_torn_down_in_order() {
+    let broker = Broker::start(false).await;
+    assert!(broker.sink.writes().is_empty());
+
+    let (result, events, sink) = broker.stop().await;
+
+    assert!(result.is_ok(), "{:?}", result);
+    // Reading stopped before anything else; the final flush ran while the HTTP server still
+    // answered, and the port was closed once it had stopped
+    let reads = events.iter().take_while(|event| *event == "read").count();
+    assert_eq!(reads, FRAMES.len() + 1);
+    assert_eq!(
+        events[reads..],
+        [
+            "write 1 point(s) (http up)".to_string(),
+            "close serial (http down)".to_string(),
+        ]
+    );
+    // The window open at the shutdown was closed into the final flush
+    let lines = sink.written_lines();
+    assert_eq!(lines.len(), 1);
+    assert!(
+        lines[0].starts_with("temperature,") && lines[0].contains("value=21"),
+        "{}",
+        lines[0]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_stage_timing_out_fails_the_run() {
+    let broker = Broker::start(true).await;
+
+    let (result, events, sink) = broker.stop().await;
+
+    // Exits with EXIT_RUNTIME_FAILURE, after the earlier stages completed
+    assert!(matches!(result, Err(AppError::Runtime(_))), "{:?}", result);
+    assert_eq!(events.last().unwrap(), "close serial (http down)");
+    assert_eq!(sink.written_lines().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_partial_window_reaches_the_sink_with_the_time_it_covered() {
+    let broker = Broker::start(false).await;
+    broker.clock.advance(Duration::from_secs(90));
+
+    let (result, _, sink) = broker.stop().await;
+
+    assert!(result.is_ok(), "{:?}", result);
+    let lines = sink.written_lines();
+    assert_eq!(lines.len(), 1);
+    let fields = lines[0].split(' ').nth(1).unwrap();
+    assert!(fields.contains("value=21"), "{}", lines[0]);
+    // The window was open for the whole run, less than the hour it would have lasted
+    let covered: f64 = fields
+        .split(',')
+        .find_map(|field| field.strip_prefix("covered_secs="))
+        .unwrap_or_else(|| panic!("no covered_secs in {}", lines[0]))
+        .parse()
+        .unwrap();
+    assert_eq!(covered, 90.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_open_window_is_closed_even_when_reading_does_not_stop_in_time() {
+    let broker = Broker::start(false).await;
+    // The read loop ends up reopening a device that never comes back, deaf to the shutdown,
+    // once it waited a second before the first attempt
+    broker.source.lose();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    let (result, _, sink) = broker.stop().await;
+
+    // Reading timed out, which fails the run, but the window was closed into the final flush
+    assert!(matches!(result, Err(AppError::Runtime(_))), "{:?}", result);
+    let lines = sink.written_lines();
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    assert!(
+        lines[0].starts_with("temperature,") && lines[0].contains("value=21"),
+        "{}",
+        lines[0]
+    );
+}
@@ -0,0 +1,89 @@
+// tls.rs
+//
+// Writes to the mock InfluxDB of the `testing` module served over HTTPS, with the self-signed
+// certificate in `tests/fixtures/tls`. Run with `cargo test`.
+
+use aero_sensor_broker::config::InfluxDBConfig;
+use aero_sensor_broker::influxdb::InfluxDBManager;
+use aero_sensor_broker::metrics::Metrics;
+use aero_sensor_broker::sink::DataSink;
+use aero_sensor_broker::testing::{temperatures, MockInfluxDB};
+
+use influxdb2::models::DataPoint;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls", name]
+        .iter()
+        .collect()
+}
+
+async fn start() -> MockInfluxDB {
+    MockInfluxDB::start_tls(&fixture("cert.pem"), &fixture("key.pem")).await
+}
+
+fn manager(config: &InfluxDBConfig) -> InfluxDBManager {
+    InfluxDBManager::new(config, Arc::new(Metrics::default())).unwrap()
+}
+
+fn points() -> Vec<DataPoint> {
+    temperatures(&[21.5], 0)
+}
+
+const LINE: &str = "temperature value=21.5 1700000000000000000";
+
+#[tokio::test]
+async fn a_server_whose_certificate_is_the_configured_ca_is_written_to() {
+    let influxdb = start().await;
+    let mut config = influxdb.config();
+    config.ca_cert_path = Some(fixture("cert.pem").display().to_string());
+    let manager = manager(&config);
+
+    assert!(influxdb.url().starts_with("https://"));
+    manager.write(points()).await.unwrap();
+    manager.check_health().await.unwrap();
+
+    assert_eq!(influxdb.written_lines(), [LINE]);
+}
+
+#[tokio::test]
+async fn a_self_signed_certificate_is_refused_without_the_ca() {
+    let influxdb = start().await;
+    let manager = manager(&influxdb.config());
+
+    assert!(manager.write(points()).await.is_err());
+    assert!(manager.check_health().await.is_err());
+
+    // The handshake fails, no request gets through
+    assert!(influxdb.writes().is_empty());
+}
+
+#[tokio::test]
+async fn accept_invalid_certs_writes_to_a_self_signed_server() {
+    let influxdb = start().await;
+    let mut config = influxdb.config();
+    config.accept_invalid_certs = true;
+    let manager = manager(&config);
+
+    manager.write(points()).await.unwrap();
+
+    assert_eq!(influxdb.written_lines(), [LINE]);
+}
+
+#[test]
+fn an_unreadable_ca_certificate_is_a_configuration_error() {
+    let mut config: InfluxDBConfig = serde_json::from_value(serde_json::json!({
+        "url": "https://localhost:8086",
+        "org": "org",
+        "bucket": "bucket",
+        "auth_token": "token",
+    }))
+    .unwrap();
+    config.ca_cert_path = Some(fixture("missing.pem").display().to_string());
+
+    let error = InfluxDBManager::new(&config, Arc::new(Metrics::default()))
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("cannot read CA certificate"));
+}